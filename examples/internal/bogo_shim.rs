@@ -170,7 +170,8 @@ impl rustls::ServerCertVerifier for DummyServerAuth {
                           _roots: &rustls::RootCertStore,
                           _certs: &[rustls::Certificate],
                           _hostname: webpki::DNSNameRef,
-                          _ocsp: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+                          _ocsp: &[u8],
+                          _context: &rustls::ServerCertContext) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
         Ok(rustls::ServerCertVerified::assertion())
     }
 }
@@ -227,16 +228,30 @@ impl ClientCacheWithoutKxHints {
 }
 
 impl rustls::StoresClientSessions for ClientCacheWithoutKxHints {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        if key.len() > 2 && key[0] == b'k' && key[1] == b'x' {
-            true
-        } else {
-            self.0.put(key, value)
-        }
+    fn set_kx_hint(&self, _: &rustls::ClientSessionKey, _: rustls::NamedGroup) {}
+
+    fn kx_hint(&self, _: &rustls::ClientSessionKey) -> Option<rustls::NamedGroup> {
+        None
+    }
+
+    fn set_tls12_session(&self, key: &rustls::ClientSessionKey, value: Vec<u8>) {
+        self.0.set_tls12_session(key, value)
+    }
+
+    fn tls12_session(&self, key: &rustls::ClientSessionKey) -> Option<Vec<u8>> {
+        self.0.tls12_session(key)
+    }
+
+    fn remove_tls12_session(&self, key: &rustls::ClientSessionKey) {
+        self.0.remove_tls12_session(key)
+    }
+
+    fn insert_tls13_ticket(&self, key: &rustls::ClientSessionKey, value: Vec<u8>) {
+        self.0.insert_tls13_ticket(key, value)
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.0.get(key)
+    fn take_tls13_ticket(&self, key: &rustls::ClientSessionKey) -> Option<Vec<u8>> {
+        self.0.take_tls13_ticket(key)
     }
 }
 
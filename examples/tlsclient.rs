@@ -118,6 +118,9 @@ impl TlsClient {
         let processed = self.tls_session.process_new_packets();
         if processed.is_err() {
             println!("TLS error: {:?}", processed.unwrap_err());
+            if self.tls_session.server_warned_unrecognized_name() {
+                println!("(the server doesn't know this hostname)");
+            }
             self.closing = true;
             return;
         }
@@ -255,14 +258,24 @@ impl PersistCache {
     }
 }
 
-impl rustls::StoresClientSessions for PersistCache {
+impl PersistCache {
+    fn key(kind: &str, session_key: &rustls::ClientSessionKey) -> Vec<u8> {
+        let server_name: &str = session_key.get_server_name().into();
+        let mut key = Vec::new();
+        key.extend_from_slice(kind.as_bytes());
+        key.push(b':');
+        key.extend_from_slice(server_name.as_bytes());
+        key.push(b':');
+        key.extend_from_slice(session_key.get_config_digest());
+        key
+    }
+
     /// put: insert into in-memory cache, and perhaps persist to disk.
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) {
         self.cache.lock()
             .unwrap()
             .insert(key, value);
         self.save();
-        true
     }
 
     /// get: from in-memory cache
@@ -271,6 +284,55 @@ impl rustls::StoresClientSessions for PersistCache {
             .unwrap()
             .get(key).cloned()
     }
+
+    /// remove: from in-memory cache, and perhaps from disk.
+    fn remove(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.cache.lock()
+            .unwrap()
+            .remove(key);
+        if value.is_some() {
+            self.save();
+        }
+        value
+    }
+}
+
+/// This keeps just one TLS1.3 ticket per server.
+impl rustls::StoresClientSessions for PersistCache {
+    fn set_kx_hint(&self, key: &rustls::ClientSessionKey, group: rustls::NamedGroup) {
+        use rustls::internal::msgs::codec::Codec;
+
+        let mut value = Vec::new();
+        group.encode(&mut value);
+        self.put(PersistCache::key("kx", key), value);
+    }
+
+    fn kx_hint(&self, key: &rustls::ClientSessionKey) -> Option<rustls::NamedGroup> {
+        use rustls::internal::msgs::codec::Codec;
+
+        self.get(&PersistCache::key("kx", key))
+            .and_then(|value| rustls::NamedGroup::read_bytes(&value))
+    }
+
+    fn set_tls12_session(&self, key: &rustls::ClientSessionKey, value: Vec<u8>) {
+        self.put(PersistCache::key("tls12", key), value);
+    }
+
+    fn tls12_session(&self, key: &rustls::ClientSessionKey) -> Option<Vec<u8>> {
+        self.get(&PersistCache::key("tls12", key))
+    }
+
+    fn remove_tls12_session(&self, key: &rustls::ClientSessionKey) {
+        self.remove(&PersistCache::key("tls12", key));
+    }
+
+    fn insert_tls13_ticket(&self, key: &rustls::ClientSessionKey, value: Vec<u8>) {
+        self.put(PersistCache::key("tls13", key), value);
+    }
+
+    fn take_tls13_ticket(&self, key: &rustls::ClientSessionKey) -> Option<Vec<u8>> {
+        self.remove(&PersistCache::key("tls13", key))
+    }
 }
 
 const USAGE: &'static str = "
@@ -391,30 +453,12 @@ fn load_key_and_cert(config: &mut rustls::ClientConfig, keyfile: &str, certsfile
     config.set_single_client_cert(certs, privkey);
 }
 
-#[cfg(feature = "dangerous_configuration")]
-mod danger {
-    use super::rustls;
-    use webpki;
-
-    pub struct NoCertificateVerification {}
-
-    impl rustls::ServerCertVerifier for NoCertificateVerification {
-        fn verify_server_cert(&self,
-                              _roots: &rustls::RootCertStore,
-                              _presented_certs: &[rustls::Certificate],
-                              _dns_name: webpki::DNSNameRef,
-                              _ocsp: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
-            Ok(rustls::ServerCertVerified::assertion())
-        }
-    }
-}
-
 #[cfg(feature = "dangerous_configuration")]
 fn apply_dangerous_options(args: &Args, cfg: &mut rustls::ClientConfig) {
     if args.flag_insecure {
         cfg
             .dangerous()
-            .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+            .set_certificate_verifier(Arc::new(rustls::danger::NoServerCertVerification));
     }
 }
 
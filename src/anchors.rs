@@ -5,7 +5,11 @@ pub use msgs::handshake::{DistinguishedName, DistinguishedNames};
 use pemfile;
 use x509;
 use key;
+use msgs::base::PayloadU16;
+use msgs::codec::Codec;
+use ring::digest;
 use std::io;
+use std::collections::BTreeMap;
 
 /// This is like a `webpki::TrustAnchor`, except it owns
 /// rather than borrows its memory.  That prevents lifetimes
@@ -26,6 +30,13 @@ impl OwnedTrustAnchor {
         }
     }
 
+    /// The anchor's subject name: the DER encoding of the Name,
+    /// without its outer SEQUENCE.
+    pub fn subject(&self) -> &[u8] {
+        &self.subject
+    }
+
+    /// Borrow this as a `webpki::TrustAnchor`.
     pub fn to_trust_anchor(&self) -> webpki::TrustAnchor {
         webpki::TrustAnchor {
             subject: &self.subject,
@@ -35,18 +46,101 @@ impl OwnedTrustAnchor {
     }
 }
 
+/// Hash `ota` onto `prev`, the digest of the roots before it.
+fn chain_digest(prev: &[u8], ota: &OwnedTrustAnchor) -> Vec<u8> {
+    let mut bytes = prev.to_vec();
+    PayloadU16::new(ota.subject.clone()).encode(&mut bytes);
+    PayloadU16::new(ota.spki.clone()).encode(&mut bytes);
+    PayloadU16::new(ota.name_constraints.clone().unwrap_or_default()).encode(&mut bytes);
+    digest::digest(&digest::SHA256, &bytes).as_ref().to_vec()
+}
+
 /// A container for root certificates able to provide a root-of-trust
 /// for connection authentication.
+///
+/// Roots are indexed by subject, so verification only considers
+/// those which could have issued a presented certificate.
 #[derive(Debug, Clone)]
 pub struct RootCertStore {
-    /// The list of roots.
+    /// The list of roots.  If you change this directly rather than
+    /// with the methods here, call `reindex` afterwards.
     pub roots: Vec<OwnedTrustAnchor>,
+
+    /// Indices into `roots`, by subject.
+    by_subject: BTreeMap<Vec<u8>, Vec<usize>>,
+
+    /// How many of `roots` are in `by_subject`.
+    indexed: usize,
+
+    /// A digest of the first `indexed` roots, in order.
+    digest: Vec<u8>,
 }
 
 impl RootCertStore {
     /// Make a new, empty `RootCertStore`.
     pub fn empty() -> RootCertStore {
-        RootCertStore { roots: Vec::new() }
+        RootCertStore {
+            roots: Vec::new(),
+            by_subject: BTreeMap::new(),
+            indexed: 0,
+            digest: Vec::new(),
+        }
+    }
+
+    /// Rebuild the subject index, after `roots` is changed directly.
+    pub fn reindex(&mut self) {
+        self.by_subject.clear();
+        self.indexed = 0;
+        self.digest.clear();
+        self.index_new_roots();
+    }
+
+    fn index_new_roots(&mut self) {
+        for (i, ota) in self.roots.iter().enumerate().skip(self.indexed) {
+            self.by_subject.entry(ota.subject.clone())
+                .or_insert_with(Vec::new)
+                .push(i);
+            self.digest = chain_digest(&self.digest, ota);
+        }
+        self.indexed = self.roots.len();
+    }
+
+    /// Return a SHA256 digest of the roots, in order.  Stores
+    /// with the same roots have the same digest.
+    ///
+    /// This is kept up to date as roots are added, unless `roots`
+    /// has been changed without calling `reindex`; then it is
+    /// computed afresh.
+    pub fn get_digest(&self) -> Vec<u8> {
+        if self.indexed != self.roots.len() {
+            return self.roots
+                .iter()
+                .fold(Vec::new(), |prev, ota| chain_digest(&prev, ota));
+        }
+
+        self.digest.clone()
+    }
+
+    /// Return the roots whose subject is `subject`: the DER encoding
+    /// of a Name, without its outer SEQUENCE.
+    ///
+    /// This is a lookup in the index, unless `roots` has been
+    /// changed without calling `reindex`; then all roots are searched.
+    pub fn find_by_subject(&self, subject: &[u8]) -> Vec<&OwnedTrustAnchor> {
+        if self.indexed != self.roots.len() {
+            return self.roots
+                .iter()
+                .filter(|ota| ota.subject == subject)
+                .collect();
+        }
+
+        match self.by_subject.get(subject) {
+            Some(indices) => indices.iter()
+                .filter_map(|i| self.roots.get(*i))
+                .filter(|ota| ota.subject == subject)
+                .collect(),
+            None => Vec::new(),
+        }
     }
 
     /// Return true if there are no certificates.
@@ -82,6 +176,7 @@ impl RootCertStore {
 
         let ota = OwnedTrustAnchor::from_trust_anchor(&ta);
         self.roots.push(ota);
+        self.index_new_roots();
         Ok(())
     }
 
@@ -93,6 +188,7 @@ impl RootCertStore {
         for ta in anchors {
             self.roots.push(OwnedTrustAnchor::from_trust_anchor(ta));
         }
+        self.index_new_roots();
     }
 
     /// Parse a PEM file and add all certificates found inside.
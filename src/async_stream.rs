@@ -0,0 +1,253 @@
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use session::Session;
+
+/// This type implements `AsyncRead` and `AsyncWrite` from the
+/// `futures-io` crate, encapsulating a Session `S` and an underlying
+/// asynchronous transport `T`.
+///
+/// This is the asynchronous counterpart of `Stream`: it does the
+/// `wants_read`/`wants_write` scheduling for you, so it works with
+/// any runtime whose sockets implement the `futures-io` traits.
+///
+/// The handshake is done by the first read or write, or explicitly
+/// with `poll_handshake`.  A close_notify from the peer is reported
+/// as EOF.
+pub struct AsyncStream<S: Session, T> {
+    /// Our session
+    pub sess: S,
+
+    /// The underlying transport, like a socket
+    pub sock: T,
+}
+
+/// Lets a `Session` do blocking-style IO on an asynchronous
+/// transport: `Poll::Pending` is turned into `WouldBlock`.
+struct SyncAdapter<'a, 'b: 'a, T: 'a> {
+    io: &'a mut T,
+    cx: &'a mut Context<'b>,
+}
+
+impl<'a, 'b, T: AsyncRead + Unpin> Read for SyncAdapter<'a, 'b, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<'a, 'b, T: AsyncWrite + Unpin> Write for SyncAdapter<'a, 'b, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn would_block<R>(result: io::Result<R>) -> Poll<io::Result<R>> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+        other => Poll::Ready(other),
+    }
+}
+
+impl<S, T> AsyncStream<S, T>
+    where S: Session + Unpin, T: AsyncRead + AsyncWrite + Unpin
+{
+    /// Make a new AsyncStream using the Session `sess` and the
+    /// transport `sock`.  This does not fail and does no IO.
+    pub fn new(sess: S, sock: T) -> AsyncStream<S, T> {
+        AsyncStream { sess, sock }
+    }
+
+    /// Returns the session and transport.
+    pub fn into_inner(self) -> (S, T) {
+        (self.sess, self.sock)
+    }
+
+    /// Do IO until the handshake is complete and our last handshake
+    /// messages are written.  This returns immediately once that
+    /// has happened.
+    pub fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.drive_handshake(cx, true)
+    }
+
+    /// Do IO until the handshake is complete.  If `flush`, also
+    /// wait until our last handshake messages are written.
+    ///
+    /// Reads must not wait for writes: a peer may not read what
+    /// we write until we've read what it sent.
+    fn drive_handshake(&mut self, cx: &mut Context, flush: bool) -> Poll<io::Result<()>> {
+        loop {
+            let write_pending = match self.poll_write_tls(cx) {
+                Poll::Ready(Ok(())) => false,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => true,
+            };
+
+            if !self.sess.is_handshaking() {
+                return if write_pending && flush { Poll::Pending } else { Poll::Ready(Ok(())) };
+            }
+
+            match self.poll_read_tls(cx) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Write TLS data until the session has none left.
+    fn poll_write_tls(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.sess.wants_write() {
+            let result = {
+                let mut adapter = SyncAdapter { io: &mut self.sock, cx };
+                self.sess.write_tls(&mut adapter)
+            };
+
+            match would_block(result) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Read some TLS data and process it.  Returns how many bytes
+    /// were read, so zero means EOF.
+    fn poll_read_tls(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let result = {
+            let mut adapter = SyncAdapter { io: &mut self.sock, cx };
+            self.sess.read_tls(&mut adapter)
+        };
+
+        let len = match would_block(result) {
+            Poll::Ready(Ok(len)) => len,
+            other => return other,
+        };
+
+        if let Err(err) = self.sess.process_new_packets() {
+            // Try a last-gasp write of any alert describing this error.
+            let _ignored = self.poll_write_tls(cx);
+            return Poll::Ready(Err(io::Error::from(err)));
+        }
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl<S, T> AsyncRead for AsyncStream<S, T>
+    where S: Session + Unpin, T: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.drive_handshake(cx, false) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map(|r| r.map(|_| 0)),
+        }
+
+        loop {
+            match this.sess.read(buf) {
+                Ok(0) if !buf.is_empty() => {}
+                Ok(len) => return Poll::Ready(Ok(len)),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    return Poll::Ready(Ok(0));
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            // Processing what we read may have made something to send,
+            // like a KeyUpdate.  Don't wait for it to be written.
+            if let Poll::Ready(Err(e)) = this.poll_write_tls(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            match this.poll_read_tls(cx) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S, T> AsyncWrite for AsyncStream<S, T>
+    where S: Session + Unpin, T: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.poll_handshake(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other.map(|r| r.map(|_| 0)),
+        }
+
+        loop {
+            let len = this.sess.write(buf)?;
+
+            // Once `sess` has taken some of `buf` we must report it
+            // as written, even if `sock` can't take it yet.
+            match this.poll_write_tls(cx) {
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                _ if len > 0 || buf.is_empty() => return Poll::Ready(Ok(len)),
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_handshake(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+
+        this.sess.flush()?;
+
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+
+        Pin::new(&mut this.sock).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // This only sends one close_notify, however often we're polled.
+        this.sess.send_close_notify();
+
+        match this.poll_write_tls(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+
+        Pin::new(&mut this.sock).poll_close(cx)
+    }
+}
@@ -0,0 +1,47 @@
+use msgs::enums::{ContentType, ProtocolVersion};
+
+/// Whether a captured message was sent or received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageDirection {
+    /// We sent this message.
+    Sent,
+
+    /// The peer sent this message.
+    Received,
+}
+
+/// A TLS record-layer message, as seen by `CapturesMessages`.
+///
+/// Sent messages are captured after fragmentation but before
+/// encryption.  Received messages are captured after decryption
+/// but before being joined or parsed.  So one handshake message
+/// may appear as several captured messages, and one captured
+/// message may contain several handshake messages.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "message_capture"), allow(dead_code))]
+pub struct CapturedMessage<'a> {
+    /// Whether we sent or received the message.
+    pub direction: MessageDirection,
+
+    /// The record's content type.  For TLS1.3 encrypted records,
+    /// this is the inner content type.
+    pub typ: ContentType,
+
+    /// The record's protocol version.
+    pub version: ProtocolVersion,
+
+    /// The record's plaintext payload.
+    pub payload: &'a [u8],
+}
+
+/// Something which sees every TLS message a session sends or
+/// receives, in plaintext.  This is intended for protocol
+/// debugging and for capturing traces for conformance testing.
+///
+/// This sees secret material, including application data and
+/// session tickets.  It should never be enabled in production.
+pub trait CapturesMessages : Send + Sync {
+    /// Called with each message as it is sent or received.
+    /// `is_client` says which end of the session this is.
+    fn message(&self, is_client: bool, msg: &CapturedMessage);
+}
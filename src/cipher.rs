@@ -3,12 +3,13 @@ use std::io::Write;
 use msgs::codec;
 use msgs::codec::Codec;
 use msgs::enums::{ContentType, ProtocolVersion};
-use msgs::message::{BorrowMessage, Message, MessagePayload};
+use msgs::message::{Message, MessagePayload};
 use msgs::fragmenter::MAX_FRAGMENT_LEN;
 use error::TLSError;
 use session::SessionSecrets;
 use suites::{SupportedCipherSuite, BulkAlgorithm};
 use key_schedule::{derive_traffic_key, derive_traffic_iv};
+use util;
 
 // accum[i] ^= offset[i] for all i in 0..len(accum)
 fn xor(accum: &mut [u8], offset: &[u8]) {
@@ -17,28 +18,197 @@ fn xor(accum: &mut [u8], offset: &[u8]) {
     }
 }
 
+/// Where `MessageDecrypter::decrypt_in_place` left the plaintext.
+pub struct PlainRecord {
+    /// The real content type of the record.
+    pub typ: ContentType,
+
+    /// The protocol version of the record.
+    pub version: ProtocolVersion,
+
+    /// The plaintext is this many bytes at the start of the buffer.
+    pub len: usize,
+}
+
 /// Objects with this trait can decrypt TLS messages.
 pub trait MessageDecrypter : Send + Sync {
-    fn decrypt(&self, m: Message, seq: u64) -> Result<Message, TLSError>;
+    /// Decrypt `buf`, the payload of a record with header type `typ`
+    /// and version `version`, in place.  The plaintext is left at the
+    /// start of `buf`.
+    fn decrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        seq: u64) -> Result<PlainRecord, TLSError>;
+
+    /// Decrypt the owned message `m`.
+    fn decrypt(&self, mut m: Message, seq: u64) -> Result<Message, TLSError> {
+        let mut buf = m.take_opaque_payload()
+            .ok_or(TLSError::DecryptError)?
+            .0;
+        let plain = self.decrypt_in_place(m.typ, m.version, &mut buf, seq)?;
+        buf.truncate(plain.len);
+
+        Ok(Message {
+            typ: plain.typ,
+            version: plain.version,
+            payload: MessagePayload::new_opaque(buf),
+        })
+    }
 }
 
 /// Objects with this trait can encrypt TLS messages.
 pub trait MessageEncrypter : Send + Sync {
-    fn encrypt(&self, m: BorrowMessage, seq: u64) -> Result<Message, TLSError>;
+    /// Encrypt the payload of a record with type `typ` and version
+    /// `version` in place.  `buf` must be `encrypted_len(plain_len)`
+    /// bytes long, with the plaintext at `payload_offset()`; the rest
+    /// of `buf` is overwritten.  Returns the type and version to
+    /// use in the record header.
+    fn encrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        plain_len: usize,
+                        seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError>;
+
+    /// The length of the payload `encrypt_in_place` produces for
+    /// a plaintext payload of `plain_len` bytes.
+    fn encrypted_len(&self, plain_len: usize) -> usize;
+
+    /// Where the plaintext goes in the buffer passed to
+    /// `encrypt_in_place`.
+    fn payload_offset(&self) -> usize {
+        0
+    }
 }
 
 impl MessageEncrypter {
+    /// A `MessageEncrypter` which always fails.
     pub fn invalid() -> Box<MessageEncrypter> {
         Box::new(InvalidMessageEncrypter {})
     }
 }
 
 impl MessageDecrypter {
+    /// A `MessageDecrypter` which always fails.
     pub fn invalid() -> Box<MessageDecrypter> {
         Box::new(InvalidMessageDecrypter {})
     }
 }
 
+/// A bulk AEAD algorithm which can protect TLS1.2 records.
+///
+/// Implement this and name it with `BulkAlgorithm::Tls12Custom`
+/// to add cipher suites which rustls does not provide.
+pub trait Tls12AeadAlgorithm : Send + Sync {
+    /// Make a decrypter from `key` and the fixed part of the
+    /// nonce, `iv`.  These are `enc_key_len` and `fixed_iv_len`
+    /// bytes long respectively.
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter>;
+
+    /// Make an encrypter from `key` and `iv`.  `explicit_nonce` is
+    /// `explicit_nonce_len` further bytes of key block, which can
+    /// be used as a starting point for the explicit part of the nonce.
+    fn encrypter(&self,
+                 key: &[u8],
+                 iv: &[u8],
+                 explicit_nonce: &[u8]) -> Box<MessageEncrypter>;
+}
+
+/// A bulk AEAD algorithm which can protect TLS1.3 records.
+///
+/// Implement this and name it with `BulkAlgorithm::Tls13Custom`
+/// to add cipher suites which rustls does not provide.
+pub trait Tls13AeadAlgorithm : Send + Sync {
+    /// Make a decrypter from the traffic `key` and `iv`, which are
+    /// `enc_key_len` and `fixed_iv_len` bytes long respectively.
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter>;
+
+    /// Make an encrypter from the traffic `key` and `iv`.
+    fn encrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageEncrypter>;
+
+    /// How many records one key may safely encrypt.  We send a
+    /// KeyUpdate before reaching this.
+    ///
+    /// The default is no limit beyond the sequence number space.
+    fn confidentiality_limit(&self) -> u64 {
+        u64::max_value()
+    }
+}
+
+struct GCMAead(&'static ring::aead::Algorithm);
+
+impl Tls12AeadAlgorithm for GCMAead {
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter> {
+        Box::new(GCMMessageDecrypter::new(self.0, key, iv))
+    }
+
+    fn encrypter(&self,
+                 key: &[u8],
+                 iv: &[u8],
+                 explicit_nonce: &[u8]) -> Box<MessageEncrypter> {
+        Box::new(GCMMessageEncrypter::new(self.0, key, iv, explicit_nonce))
+    }
+}
+
+struct ChaCha20Poly1305Aead;
+
+impl Tls12AeadAlgorithm for ChaCha20Poly1305Aead {
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter> {
+        Box::new(ChaCha20Poly1305MessageDecrypter::new(&ring::aead::CHACHA20_POLY1305,
+                                                       key,
+                                                       iv))
+    }
+
+    fn encrypter(&self,
+                 key: &[u8],
+                 iv: &[u8],
+                 _explicit_nonce: &[u8]) -> Box<MessageEncrypter> {
+        Box::new(ChaCha20Poly1305MessageEncrypter::new(&ring::aead::CHACHA20_POLY1305,
+                                                       key,
+                                                       iv))
+    }
+}
+
+struct TLS13Aead(&'static ring::aead::Algorithm);
+
+impl Tls13AeadAlgorithm for TLS13Aead {
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter> {
+        Box::new(TLS13MessageDecrypter::new(self.0, key, iv))
+    }
+
+    fn encrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageEncrypter> {
+        Box::new(TLS13MessageEncrypter::new(self.0, key, iv))
+    }
+}
+
+static AES_128_GCM_TLS12: GCMAead = GCMAead(&ring::aead::AES_128_GCM);
+static AES_256_GCM_TLS12: GCMAead = GCMAead(&ring::aead::AES_256_GCM);
+static CHACHA20_POLY1305_TLS12: ChaCha20Poly1305Aead = ChaCha20Poly1305Aead;
+static AES_128_GCM_TLS13: TLS13Aead = TLS13Aead(&ring::aead::AES_128_GCM);
+static AES_256_GCM_TLS13: TLS13Aead = TLS13Aead(&ring::aead::AES_256_GCM);
+static CHACHA20_POLY1305_TLS13: TLS13Aead = TLS13Aead(&ring::aead::CHACHA20_POLY1305);
+
+fn tls12_aead(bulk: &BulkAlgorithm) -> Option<&'static Tls12AeadAlgorithm> {
+    match *bulk {
+        BulkAlgorithm::AES_128_GCM => Some(&AES_128_GCM_TLS12),
+        BulkAlgorithm::AES_256_GCM => Some(&AES_256_GCM_TLS12),
+        BulkAlgorithm::CHACHA20_POLY1305 => Some(&CHACHA20_POLY1305_TLS12),
+        BulkAlgorithm::Tls12Custom(aead) => Some(aead),
+        BulkAlgorithm::Tls13Custom(_) => None,
+    }
+}
+
+fn tls13_aead(bulk: &BulkAlgorithm) -> Option<&'static Tls13AeadAlgorithm> {
+    match *bulk {
+        BulkAlgorithm::AES_128_GCM => Some(&AES_128_GCM_TLS13),
+        BulkAlgorithm::AES_256_GCM => Some(&AES_256_GCM_TLS13),
+        BulkAlgorithm::CHACHA20_POLY1305 => Some(&CHACHA20_POLY1305_TLS13),
+        BulkAlgorithm::Tls12Custom(_) => None,
+        BulkAlgorithm::Tls13Custom(aead) => Some(aead),
+    }
+}
+
 pub type MessageCipherPair = (Box<MessageDecrypter>, Box<MessageEncrypter>);
 
 const TLS12_AAD_SIZE: usize = 8 + 1 + 2 + 2;
@@ -60,8 +230,16 @@ pub fn new_tls12(scs: &'static SupportedCipherSuite,
                  -> MessageCipherPair {
     // Make a key block, and chop it up.
     // nb. we don't implement any ciphersuites with nonzero mac_key_len.
-    let key_block = secrets.make_key_block(scs.key_block_len());
+    let mut key_block = secrets.make_key_block(scs.key_block_len());
+    let pair = split_key_block(scs, secrets, &key_block);
+    util::wipe(&mut key_block);
+    pair
+}
 
+fn split_key_block(scs: &'static SupportedCipherSuite,
+                   secrets: &SessionSecrets,
+                   key_block: &[u8])
+                   -> MessageCipherPair {
     let mut offs = 0;
     let client_write_key = &key_block[offs..offs + scs.enc_key_len];
     offs += scs.enc_key_len;
@@ -85,49 +263,39 @@ pub fn new_tls12(scs: &'static SupportedCipherSuite,
         (client_write_key, client_write_iv)
     };
 
-    let aead_alg = scs.get_aead_alg();
-
-    match scs.bulk {
-        BulkAlgorithm::AES_128_GCM |
-        BulkAlgorithm::AES_256_GCM => {
-            (Box::new(GCMMessageDecrypter::new(aead_alg,
-                                               read_key,
-                                               read_iv)),
-             Box::new(GCMMessageEncrypter::new(aead_alg,
-                                               write_key,
-                                               write_iv,
-                                               explicit_nonce_offs)))
-        }
-
-        BulkAlgorithm::CHACHA20_POLY1305 => {
-            (Box::new(ChaCha20Poly1305MessageDecrypter::new(aead_alg,
-                                                            read_key,
-                                                            read_iv)),
-             Box::new(ChaCha20Poly1305MessageEncrypter::new(aead_alg,
-                                                            write_key,
-                                                            write_iv)))
-        }
+    match tls12_aead(&scs.bulk) {
+        Some(aead) => (aead.decrypter(read_key, read_iv),
+                       aead.encrypter(write_key, write_iv, explicit_nonce_offs)),
+        None => (MessageDecrypter::invalid(), MessageEncrypter::invalid()),
     }
 }
 
 pub fn new_tls13_read(scs: &'static SupportedCipherSuite,
                       secret: &[u8]) -> Box<MessageDecrypter> {
     let hash = scs.get_hash();
-    let key = derive_traffic_key(hash, secret, scs.enc_key_len);
+    let mut key = derive_traffic_key(hash, secret, scs.enc_key_len);
     let iv = derive_traffic_iv(hash, secret, scs.fixed_iv_len);
-    let aead_alg = scs.get_aead_alg();
 
-    Box::new(TLS13MessageDecrypter::new(aead_alg, &key, &iv))
+    let ret = match tls13_aead(&scs.bulk) {
+        Some(aead) => aead.decrypter(&key, &iv),
+        None => MessageDecrypter::invalid(),
+    };
+    util::wipe(&mut key);
+    ret
 }
 
 pub fn new_tls13_write(scs: &'static SupportedCipherSuite,
                        secret: &[u8]) -> Box<MessageEncrypter> {
     let hash = scs.get_hash();
-    let key = derive_traffic_key(hash, secret, scs.enc_key_len);
+    let mut key = derive_traffic_key(hash, secret, scs.enc_key_len);
     let iv = derive_traffic_iv(hash, secret, scs.fixed_iv_len);
-    let aead_alg = scs.get_aead_alg();
 
-    Box::new(TLS13MessageEncrypter::new(aead_alg, &key, &iv))
+    let ret = match tls13_aead(&scs.bulk) {
+        Some(aead) => aead.encrypter(&key, &iv),
+        None => MessageEncrypter::invalid(),
+    };
+    util::wipe(&mut key);
+    ret
 }
 
 /// A `MessageEncrypter` for AES-GCM AEAD ciphersuites. TLS 1.2 only.
@@ -148,11 +316,11 @@ const GCM_EXPLICIT_NONCE_LEN: usize = 8;
 const GCM_OVERHEAD: usize = GCM_EXPLICIT_NONCE_LEN + 16;
 
 impl MessageDecrypter for GCMMessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TLSError> {
-        let payload = msg.take_opaque_payload()
-            .ok_or(TLSError::DecryptError)?;
-        let mut buf = payload.0;
-
+    fn decrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        seq: u64) -> Result<PlainRecord, TLSError> {
         if buf.len() < GCM_OVERHEAD {
             return Err(TLSError::DecryptError);
         }
@@ -162,13 +330,13 @@ impl MessageDecrypter for GCMMessageDecrypter {
         nonce[4..].as_mut().write_all(&buf[..8]).unwrap();
 
         let mut aad = [0u8; TLS12_AAD_SIZE];
-        make_tls12_aad(seq, msg.typ, msg.version, buf.len() - GCM_OVERHEAD, &mut aad);
+        make_tls12_aad(seq, typ, version, buf.len() - GCM_OVERHEAD, &mut aad);
 
         let plain_len = ring::aead::open_in_place(&self.dec_key,
                                                   &nonce,
                                                   &aad,
                                                   GCM_EXPLICIT_NONCE_LEN,
-                                                  &mut buf)
+                                                  buf)
             .map_err(|_| TLSError::DecryptError)?
             .len();
 
@@ -176,18 +344,17 @@ impl MessageDecrypter for GCMMessageDecrypter {
             return Err(TLSError::PeerSentOversizedRecord);
         }
 
-        buf.truncate(plain_len);
-
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
-        })
+        Ok(PlainRecord { typ, version, len: plain_len })
     }
 }
 
 impl MessageEncrypter for GCMMessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TLSError> {
+    fn encrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        plain_len: usize,
+                        seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError> {
         // The GCM nonce is constructed from a 32-bit 'salt' derived
         // from the master-secret, and a 64-bit explicit part,
         // with no specified construction.  Thanks for that.
@@ -201,25 +368,25 @@ impl MessageEncrypter for GCMMessageEncrypter {
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce[4..], &self.nonce_offset);
 
-        // make output buffer with room for nonce/tag
-        let tag_len = self.alg.tag_len();
-        let total_len = 8 + msg.payload.len() + tag_len;
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(&nonce[4..]);
-        buf.extend_from_slice(msg.payload);
-        buf.resize(total_len, 0u8);
+        // the explicit nonce goes before the payload
+        buf[..8].copy_from_slice(&nonce[4..]);
 
         let mut aad = [0u8; TLS12_AAD_SIZE];
-        make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len(), &mut aad);
+        make_tls12_aad(seq, typ, version, plain_len, &mut aad);
 
+        let tag_len = self.alg.tag_len();
         ring::aead::seal_in_place(&self.enc_key, &nonce, &aad, &mut buf[8..], tag_len)
             .map_err(|_| TLSError::General("encrypt failed".to_string()))?;
 
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
-        })
+        Ok((typ, version))
+    }
+
+    fn payload_offset(&self) -> usize {
+        8
+    }
+
+    fn encrypted_len(&self, plain_len: usize) -> usize {
+        8 + plain_len + self.alg.tag_len()
     }
 }
 
@@ -272,81 +439,76 @@ struct TLS13MessageDecrypter {
     dec_offset: [u8; 12],
 }
 
-fn unpad_tls13(v: &mut Vec<u8>) -> ContentType {
-    loop {
-        match v.pop() {
-            Some(0) => {}
-
-            Some(content_type) => return ContentType::read_bytes(&[content_type]).unwrap(),
-
-            None => return ContentType::Unknown(0),
-        }
+/// Find the content type at the end of a TLSInnerPlaintext, and
+/// return it with the length of the content before it.
+fn unpad_tls13(v: &[u8]) -> (ContentType, usize) {
+    match v.iter().rposition(|&b| b != 0) {
+        Some(pos) => (ContentType::read_bytes(&v[pos..pos + 1]).unwrap(), pos),
+        None => (ContentType::Unknown(0), 0),
     }
 }
 
 impl MessageEncrypter for TLS13MessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TLSError> {
+    fn encrypt_in_place(&self,
+                        typ: ContentType,
+                        _version: ProtocolVersion,
+                        buf: &mut [u8],
+                        plain_len: usize,
+                        seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError> {
         let mut nonce = [0u8; 12];
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce, &self.enc_offset);
 
-        // make output buffer with room for content type and tag
-        let tag_len = self.alg.tag_len();
-        let total_len = msg.payload.len() + 1 + tag_len;
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(msg.payload);
-        msg.typ.encode(&mut buf);
-        buf.resize(total_len, 0u8);
+        // the real content type follows the payload
+        buf[plain_len] = typ.get_u8();
 
-        ring::aead::seal_in_place(&self.enc_key, &nonce, &[], &mut buf, tag_len)
+        ring::aead::seal_in_place(&self.enc_key, &nonce, &[], buf, self.alg.tag_len())
             .map_err(|_| TLSError::General("encrypt failed".to_string()))?;
 
-        Ok(Message {
-            typ: ContentType::ApplicationData,
-            version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::new_opaque(buf),
-        })
+        Ok((ContentType::ApplicationData, ProtocolVersion::TLSv1_2))
+    }
+
+    fn encrypted_len(&self, plain_len: usize) -> usize {
+        plain_len + 1 + self.alg.tag_len()
     }
 }
 
 impl MessageDecrypter for TLS13MessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TLSError> {
+    fn decrypt_in_place(&self,
+                        _typ: ContentType,
+                        _version: ProtocolVersion,
+                        buf: &mut [u8],
+                        seq: u64) -> Result<PlainRecord, TLSError> {
         let mut nonce = [0u8; 12];
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce, &self.dec_offset);
 
-        let payload = msg.take_opaque_payload()
-            .ok_or(TLSError::DecryptError)?;
-        let mut buf = payload.0;
-
         if buf.len() < self.alg.tag_len() {
             return Err(TLSError::DecryptError);
         }
 
-        let plain_len = ring::aead::open_in_place(&self.dec_key, &nonce, &[], 0, &mut buf)
+        let plain_len = ring::aead::open_in_place(&self.dec_key, &nonce, &[], 0, buf)
             .map_err(|_| TLSError::DecryptError)?
             .len();
 
-        buf.truncate(plain_len);
-
-        if buf.len() > MAX_FRAGMENT_LEN + 1 {
+        if plain_len > MAX_FRAGMENT_LEN + 1 {
             return Err(TLSError::PeerSentOversizedRecord);
         }
 
-        let content_type = unpad_tls13(&mut buf);
+        let (content_type, len) = unpad_tls13(&buf[..plain_len]);
         if content_type == ContentType::Unknown(0) {
             let msg = "peer sent bad TLSInnerPlaintext".to_string();
             return Err(TLSError::PeerMisbehavedError(msg));
         }
 
-        if buf.len() > MAX_FRAGMENT_LEN {
+        if len > MAX_FRAGMENT_LEN {
             return Err(TLSError::PeerSentOversizedRecord);
         }
 
-        Ok(Message {
+        Ok(PlainRecord {
             typ: content_type,
             version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::new_opaque(buf),
+            len,
         })
     }
 }
@@ -430,11 +592,11 @@ impl ChaCha20Poly1305MessageDecrypter {
 const CHACHAPOLY1305_OVERHEAD: usize = 16;
 
 impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
-    fn decrypt(&self, mut msg: Message, seq: u64) -> Result<Message, TLSError> {
-        let payload = msg.take_opaque_payload()
-            .ok_or(TLSError::DecryptError)?;
-        let mut buf = payload.0;
-
+    fn decrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        seq: u64) -> Result<PlainRecord, TLSError> {
         if buf.len() < CHACHAPOLY1305_OVERHEAD {
             return Err(TLSError::DecryptError);
         }
@@ -445,9 +607,9 @@ impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
         xor(&mut nonce, &self.dec_offset);
 
         let mut aad = [0u8; TLS12_AAD_SIZE];
-        make_tls12_aad(seq, msg.typ, msg.version, buf.len() - CHACHAPOLY1305_OVERHEAD, &mut aad);
+        make_tls12_aad(seq, typ, version, buf.len() - CHACHAPOLY1305_OVERHEAD, &mut aad);
 
-        let plain_len = ring::aead::open_in_place(&self.dec_key, &nonce, &aad, 0, &mut buf)
+        let plain_len = ring::aead::open_in_place(&self.dec_key, &nonce, &aad, 0, buf)
             .map_err(|_| TLSError::DecryptError)?
             .len();
 
@@ -455,40 +617,32 @@ impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
             return Err(TLSError::PeerSentOversizedRecord);
         }
 
-        buf.truncate(plain_len);
-
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
-        })
+        Ok(PlainRecord { typ, version, len: plain_len })
     }
 }
 
 impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
-    fn encrypt(&self, msg: BorrowMessage, seq: u64) -> Result<Message, TLSError> {
+    fn encrypt_in_place(&self,
+                        typ: ContentType,
+                        version: ProtocolVersion,
+                        buf: &mut [u8],
+                        plain_len: usize,
+                        seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError> {
         let mut nonce = [0u8; 12];
         codec::put_u64(seq, &mut nonce[4..]);
         xor(&mut nonce, &self.enc_offset);
 
         let mut aad = [0u8; TLS12_AAD_SIZE];
-        make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len(), &mut aad);
-
-        // make result buffer with room for tag, etc.
-        let tag_len = self.alg.tag_len();
-        let total_len = msg.payload.len() + tag_len;
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(msg.payload);
-        buf.resize(total_len, 0u8);
+        make_tls12_aad(seq, typ, version, plain_len, &mut aad);
 
-        ring::aead::seal_in_place(&self.enc_key, &nonce, &aad, &mut buf, tag_len)
+        ring::aead::seal_in_place(&self.enc_key, &nonce, &aad, buf, self.alg.tag_len())
             .map_err(|_| TLSError::General("encrypt failed".to_string()))?;
 
-        Ok(Message {
-            typ: msg.typ,
-            version: msg.version,
-            payload: MessagePayload::new_opaque(buf),
-        })
+        Ok((typ, version))
+    }
+
+    fn encrypted_len(&self, plain_len: usize) -> usize {
+        plain_len + self.alg.tag_len()
     }
 }
 
@@ -496,16 +650,29 @@ impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
 pub struct InvalidMessageEncrypter {}
 
 impl MessageEncrypter for InvalidMessageEncrypter {
-    fn encrypt(&self, _m: BorrowMessage, _seq: u64) -> Result<Message, TLSError> {
+    fn encrypt_in_place(&self,
+                        _typ: ContentType,
+                        _version: ProtocolVersion,
+                        _buf: &mut [u8],
+                        _plain_len: usize,
+                        _seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError> {
         Err(TLSError::General("encrypt not yet available".to_string()))
     }
+
+    fn encrypted_len(&self, plain_len: usize) -> usize {
+        plain_len
+    }
 }
 
 /// A `MessageDecrypter` which doesn't work.
 pub struct InvalidMessageDecrypter {}
 
 impl MessageDecrypter for InvalidMessageDecrypter {
-    fn decrypt(&self, _m: Message, _seq: u64) -> Result<Message, TLSError> {
+    fn decrypt_in_place(&self,
+                        _typ: ContentType,
+                        _version: ProtocolVersion,
+                        _buf: &mut [u8],
+                        _seq: u64) -> Result<PlainRecord, TLSError> {
         Err(TLSError::DecryptError)
     }
 }
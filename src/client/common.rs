@@ -6,6 +6,7 @@ use msgs::handshake::ServerExtension;
 use msgs::persist;
 use msgs::enums::ExtensionType;
 use msgs::enums::NamedGroup;
+use client::ClientSessionKey;
 use session::SessionRandoms;
 use hash_hs;
 use sign;
@@ -48,6 +49,30 @@ impl ServerKXDetails {
     }
 }
 
+/// The Finished verify_data of a TLS1.2 handshake, which
+/// binds a renegotiation to it (RFC5746).
+#[derive(Clone)]
+pub struct VerifyData {
+    pub client: Vec<u8>,
+    pub server: Vec<u8>,
+}
+
+impl VerifyData {
+    pub fn new() -> VerifyData {
+        VerifyData {
+            client: Vec::new(),
+            server: Vec::new(),
+        }
+    }
+
+    /// The renegotiation_info we expect from the server.
+    pub fn server_renegotiation_info(&self) -> Vec<u8> {
+        let mut ret = self.client.clone();
+        ret.extend_from_slice(&self.server);
+        ret
+    }
+}
+
 pub struct HandshakeDetails {
     pub transcript: hash_hs::HandshakeHash,
     pub resuming_session: Option<persist::ClientSessionValue>,
@@ -56,10 +81,14 @@ pub struct HandshakeDetails {
     pub session_id: SessionID,
     pub sent_tls13_fake_ccs: bool,
     pub dns_name: webpki::DNSName,
+    pub session_key: ClientSessionKey,
+    pub secure_renegotiation: bool,
+    pub verify_data: VerifyData,
+    pub renegotiating: Option<VerifyData>,
 }
 
 impl HandshakeDetails {
-    pub fn new(host_name: webpki::DNSName) -> HandshakeDetails {
+    pub fn new(host_name: webpki::DNSName, session_key: ClientSessionKey) -> HandshakeDetails {
         HandshakeDetails {
             transcript: hash_hs::HandshakeHash::new(),
             resuming_session: None,
@@ -68,6 +97,10 @@ impl HandshakeDetails {
             session_id: SessionID::empty(),
             sent_tls13_fake_ccs: false,
             dns_name: host_name,
+            session_key,
+            secure_renegotiation: false,
+            verify_data: VerifyData::new(),
+            renegotiating: None,
         }
     }
 }
@@ -75,6 +108,7 @@ impl HandshakeDetails {
 pub struct ClientHelloDetails {
     pub sent_extensions: Vec<ExtensionType>,
     pub offered_key_shares: Vec<suites::KeyExchange>,
+    pub session_id: SessionID,
 }
 
 impl ClientHelloDetails {
@@ -82,6 +116,7 @@ impl ClientHelloDetails {
         ClientHelloDetails {
             sent_extensions: Vec::new(),
             offered_key_shares: Vec::new(),
+            session_id: SessionID::empty(),
         }
     }
 
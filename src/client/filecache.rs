@@ -0,0 +1,507 @@
+use msgs::enums::NamedGroup;
+use msgs::codec::{self, Codec, Reader};
+use msgs::base::{PayloadU8, PayloadU16};
+use client::{self, ClientSessionKey};
+use clock;
+use webpki;
+use untrusted;
+
+use std::collections;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+/// The version of our file format.
+const FILE_VERSION: u8 = 2;
+
+/// How many TLS1.3 tickets we keep for each server.
+const MAX_TLS13_TICKETS_PER_SERVER: usize = 8;
+
+/// Makes temporary file names unique within this process.
+static NEXT_TEMP_FILE: AtomicUsize = AtomicUsize::new(0);
+
+fn now_secs() -> u64 {
+    clock::unix_time()
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EntryKind {
+    KxHint,
+    TLS12Session,
+    TLS13Ticket,
+}
+
+impl EntryKind {
+    fn get_u8(&self) -> u8 {
+        match *self {
+            EntryKind::KxHint => 0,
+            EntryKind::TLS12Session => 1,
+            EntryKind::TLS13Ticket => 2,
+        }
+    }
+
+    fn from_u8(x: u8) -> Option<EntryKind> {
+        match x {
+            0 => Some(EntryKind::KxHint),
+            1 => Some(EntryKind::TLS12Session),
+            2 => Some(EntryKind::TLS13Ticket),
+            _ => None,
+        }
+    }
+}
+
+/// One item in the cache file.
+#[derive(Debug)]
+struct Entry {
+    server_name: PayloadU8,
+    config_digest: PayloadU8,
+    kind: EntryKind,
+    stored_at: u64,
+    value: PayloadU16,
+}
+
+impl Codec for Entry {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.server_name.encode(bytes);
+        self.config_digest.encode(bytes);
+        codec::encode_u8(self.kind.get_u8(), bytes);
+        codec::encode_u64(self.stored_at, bytes);
+        self.value.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Entry> {
+        let server_name = try_ret!(PayloadU8::read(r));
+        let config_digest = try_ret!(PayloadU8::read(r));
+        let kind = try_ret!(EntryKind::from_u8(try_ret!(codec::read_u8(r))));
+        let stored_at = try_ret!(codec::read_u64(r));
+        let value = try_ret!(PayloadU16::read(r));
+
+        Some(Entry { server_name, config_digest, kind, stored_at, value })
+    }
+}
+
+/// A value, and when we stored it.
+type Stored = (Vec<u8>, u64);
+
+#[derive(Default)]
+struct ServerData {
+    kx_hint: Option<Stored>,
+    tls12: Option<Stored>,
+    tls13: collections::VecDeque<Stored>,
+}
+
+impl ServerData {
+    fn expire(&mut self, oldest: u64) {
+        let live = |stored: &Stored| stored.1 >= oldest;
+
+        if !self.kx_hint.as_ref().map(&live).unwrap_or(true) {
+            self.kx_hint = None;
+        }
+        if !self.tls12.as_ref().map(&live).unwrap_or(true) {
+            self.tls12 = None;
+        }
+        self.tls13.retain(live);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.kx_hint.is_none() && self.tls12.is_none() && self.tls13.is_empty()
+    }
+}
+
+/// An implementor of `StoresClientSessions` which keeps everything
+/// in memory, and writes it to a file after each change.  This lets
+/// short-lived processes resume sessions made by earlier runs.
+///
+/// Items older than `max_age` seconds are discarded.  At most eight
+/// TLS1.3 tickets are kept for each server.
+///
+/// The file contains enough key material to break the security
+/// of the stored sessions, so should be readable only by the
+/// user running the program.  Errors writing the file are logged
+/// and otherwise ignored.
+pub struct ClientSessionFileCache {
+    path: PathBuf,
+    max_age: u64,
+    servers: Mutex<collections::HashMap<ClientSessionKey, ServerData>>,
+}
+
+impl ClientSessionFileCache {
+    /// Make a new ClientSessionFileCache, storing data in the file
+    /// at `path`.  Any existing contents of the file are loaded; if
+    /// the file is missing or corrupt we start empty.
+    pub fn new<P: AsRef<Path>>(path: P, max_age: u64) -> Arc<ClientSessionFileCache> {
+        let cache = ClientSessionFileCache {
+            path: path.as_ref().to_path_buf(),
+            max_age,
+            servers: Mutex::new(collections::HashMap::new()),
+        };
+        cache.load();
+        Arc::new(cache)
+    }
+
+    fn oldest_live(&self) -> u64 {
+        now_secs().saturating_sub(self.max_age)
+    }
+
+    fn load(&self) {
+        let mut data = Vec::new();
+        if let Err(err) = fs::File::open(&self.path)
+            .and_then(|mut f| f.read_to_end(&mut data)) {
+            debug!("Not loading session cache {:?}: {}", self.path, err);
+            return;
+        }
+
+        let entries = match decode_file(&data) {
+            Some(entries) => entries,
+            None => {
+                warn!("Ignoring corrupt session cache {:?}", self.path);
+                return;
+            }
+        };
+
+        let oldest = self.oldest_live();
+        let mut servers = self.servers.lock().unwrap();
+
+        for entry in entries {
+            if entry.stored_at < oldest {
+                continue;
+            }
+
+            let name = untrusted::Input::from(&entry.server_name.0);
+            let key = match webpki::DNSNameRef::try_from_ascii(name) {
+                Ok(name) => ClientSessionKey::from_parts(name, &entry.config_digest.0),
+                Err(_) => continue,
+            };
+            let data = servers.entry(key)
+                .or_insert_with(ServerData::default);
+            let stored = (entry.value.0, entry.stored_at);
+
+            match entry.kind {
+                EntryKind::KxHint => data.kx_hint = Some(stored),
+                EntryKind::TLS12Session => data.tls12 = Some(stored),
+                EntryKind::TLS13Ticket => {
+                    data.tls13.push_back(stored);
+                    if data.tls13.len() > MAX_TLS13_TICKETS_PER_SERVER {
+                        data.tls13.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    fn save(&self, servers: &collections::HashMap<ClientSessionKey, ServerData>) {
+        let mut entries = Vec::new();
+
+        for (key, data) in servers.iter() {
+            let name: &str = key.get_server_name().into();
+            let entry = |kind, stored: &Stored| Entry {
+                server_name: PayloadU8::new(name.as_bytes().to_vec()),
+                config_digest: PayloadU8::new(key.get_config_digest().to_vec()),
+                kind,
+                stored_at: stored.1,
+                value: PayloadU16::new(stored.0.clone()),
+            };
+
+            if let Some(ref stored) = data.kx_hint {
+                entries.push(entry(EntryKind::KxHint, stored));
+            }
+            if let Some(ref stored) = data.tls12 {
+                entries.push(entry(EntryKind::TLS12Session, stored));
+            }
+            for stored in data.tls13.iter() {
+                entries.push(entry(EntryKind::TLS13Ticket, stored));
+            }
+        }
+
+        // Write a new file and rename it over the old one, so
+        // a concurrent reader never sees a partial file.  The name
+        // is unique so concurrent writers don't share it.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.{}.tmp",
+                         process::id(),
+                         NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)));
+        let tmp = PathBuf::from(tmp);
+
+        let result = create_private(&tmp)
+            .and_then(|mut f| f.write_all(&encode_file(&entries)))
+            .and_then(|_| fs::rename(&tmp, &self.path));
+
+        if let Err(err) = result {
+            warn!("Cannot write session cache {:?}: {}", self.path, err);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    /// Apply `f` to the data for `key`.  `f` returns whether it
+    /// changed anything: the file is only rewritten if so.
+    fn update<F>(&self, key: &ClientSessionKey, f: F)
+        where F: FnOnce(&mut ServerData, u64) -> bool
+    {
+        let oldest = self.oldest_live();
+        let mut servers = self.servers.lock().unwrap();
+
+        for data in servers.values_mut() {
+            data.expire(oldest);
+        }
+
+        let changed = f(servers.entry(key.clone())
+                            .or_insert_with(ServerData::default),
+                        now_secs());
+
+        servers.retain(|_, data| !data.is_empty());
+        if changed {
+            self.save(&servers);
+        }
+    }
+
+    fn lookup<T, F>(&self, key: &ClientSessionKey, f: F) -> Option<T>
+        where F: FnOnce(&mut ServerData) -> Option<T>
+    {
+        let oldest = self.oldest_live();
+        let mut servers = self.servers.lock().unwrap();
+
+        servers.get_mut(key)
+            .and_then(|data| {
+                data.expire(oldest);
+                f(data)
+            })
+    }
+}
+
+fn encode_file(entries: &[Entry]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    codec::encode_u8(FILE_VERSION, &mut bytes);
+    for entry in entries {
+        entry.encode(&mut bytes);
+    }
+    bytes
+}
+
+fn decode_file(data: &[u8]) -> Option<Vec<Entry>> {
+    let mut rd = Reader::init(data);
+    if try_ret!(codec::read_u8(&mut rd)) != FILE_VERSION {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while rd.any_left() {
+        entries.push(try_ret!(Entry::read(&mut rd)));
+    }
+    Some(entries)
+}
+
+impl client::StoresClientSessions for ClientSessionFileCache {
+    fn set_kx_hint(&self, key: &ClientSessionKey, group: NamedGroup) {
+        self.update(key, |data, now| {
+            data.kx_hint = Some((group.get_encoding(), now));
+            true
+        });
+    }
+
+    fn kx_hint(&self, key: &ClientSessionKey) -> Option<NamedGroup> {
+        self.lookup(key, |data| {
+            data.kx_hint.as_ref()
+                .and_then(|stored| NamedGroup::read_bytes(&stored.0))
+        })
+    }
+
+    fn set_tls12_session(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.update(key, |data, now| {
+            data.tls12 = Some((value, now));
+            true
+        });
+    }
+
+    fn tls12_session(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        self.lookup(key, |data| {
+            data.tls12.as_ref().map(|stored| stored.0.clone())
+        })
+    }
+
+    fn remove_tls12_session(&self, key: &ClientSessionKey) {
+        self.update(key, |data, _| data.tls12.take().is_some());
+    }
+
+    fn insert_tls13_ticket(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.update(key, |data, now| {
+            data.tls13.push_back((value, now));
+            if data.tls13.len() > MAX_TLS13_TICKETS_PER_SERVER {
+                data.tls13.pop_front();
+            }
+            true
+        });
+    }
+
+    fn take_tls13_ticket(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        let mut ticket = None;
+        self.update(key, |data, _| {
+            ticket = data.tls13.pop_back();
+            ticket.is_some()
+        });
+        ticket.map(|stored| stored.0)
+    }
+}
+
+/// Create a new file at `path` which only we can read: it holds
+/// session secrets.
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use StoresClientSessions;
+    use std::env;
+    use std::process;
+
+    fn key(s: &str) -> ClientSessionKey {
+        let name = webpki::DNSNameRef::try_from_ascii_str(s).unwrap();
+        ClientSessionKey::from_parts(name, &[0x01, 0x02])
+    }
+
+    fn temp_path(test: &str) -> PathBuf {
+        let path = env::temp_dir()
+            .join(format!("rustls-filecache-{}-{}", process::id(), test));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_filecache_persists_across_instances() {
+        let path = temp_path("persists");
+
+        {
+            let c = ClientSessionFileCache::new(&path, 3600);
+            c.set_kx_hint(&key("a.com"), NamedGroup::X25519);
+            c.set_tls12_session(&key("a.com"), vec![0x01]);
+            c.insert_tls13_ticket(&key("b.com"), vec![0x02]);
+            c.insert_tls13_ticket(&key("b.com"), vec![0x03]);
+        }
+
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.kx_hint(&key("a.com")), Some(NamedGroup::X25519));
+        assert_eq!(c.tls12_session(&key("a.com")), Some(vec![0x01]));
+        assert_eq!(c.take_tls13_ticket(&key("b.com")), Some(vec![0x03]));
+
+        // Taking a ticket is persisted too.
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.take_tls13_ticket(&key("b.com")), Some(vec![0x02]));
+        assert_eq!(c.take_tls13_ticket(&key("b.com")), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_filecache_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = temp_path("private");
+        let c = ClientSessionFileCache::new(&path, 3600);
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_filecache_discards_old_items() {
+        let path = temp_path("expiry");
+        let now = now_secs();
+        let entries = vec![
+            Entry {
+                server_name: PayloadU8::new(b"a.com".to_vec()),
+                config_digest: PayloadU8::new(vec![0x01, 0x02]),
+                kind: EntryKind::TLS12Session,
+                stored_at: now - 7200,
+                value: PayloadU16::new(vec![0x01]),
+            },
+            Entry {
+                server_name: PayloadU8::new(b"a.com".to_vec()),
+                config_digest: PayloadU8::new(vec![0x01, 0x02]),
+                kind: EntryKind::TLS13Ticket,
+                stored_at: now,
+                value: PayloadU16::new(vec![0x02]),
+            },
+        ];
+        fs::File::create(&path)
+            .and_then(|mut f| f.write_all(&encode_file(&entries)))
+            .unwrap();
+
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.tls12_session(&key("a.com")), None);
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), Some(vec![0x02]));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_filecache_only_writes_changes() {
+        let path = temp_path("unchanged");
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), None);
+        c.remove_tls12_session(&key("a.com"));
+        assert!(!path.exists());
+
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+        assert!(path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_filecache_limits_loaded_tickets() {
+        let path = temp_path("ticket-limit");
+        let now = now_secs();
+        let entries = (0..MAX_TLS13_TICKETS_PER_SERVER + 2)
+            .map(|i| Entry {
+                server_name: PayloadU8::new(b"a.com".to_vec()),
+                config_digest: PayloadU8::new(vec![0x01, 0x02]),
+                kind: EntryKind::TLS13Ticket,
+                stored_at: now,
+                value: PayloadU16::new(vec![i as u8]),
+            })
+            .collect::<Vec<_>>();
+        fs::File::create(&path)
+            .and_then(|mut f| f.write_all(&encode_file(&entries)))
+            .unwrap();
+
+        let c = ClientSessionFileCache::new(&path, 3600);
+        for i in (2..MAX_TLS13_TICKETS_PER_SERVER + 2).rev() {
+            assert_eq!(c.take_tls13_ticket(&key("a.com")), Some(vec![i as u8]));
+        }
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_filecache_ignores_corrupt_file() {
+        let path = temp_path("corrupt");
+        fs::File::create(&path)
+            .and_then(|mut f| f.write_all(b"\x02\x05a.c"))
+            .unwrap();
+
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.tls12_session(&key("a.com")), None);
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+
+        let c = ClientSessionFileCache::new(&path, 3600);
+        assert_eq!(c.tls12_session(&key("a.com")), Some(vec![0x01]));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
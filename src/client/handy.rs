@@ -1,7 +1,8 @@
-use msgs::enums::SignatureScheme;
+use msgs::enums::{SignatureScheme, NamedGroup};
 use sign;
 use key;
 use client;
+use client::ClientSessionKey;
 
 use std::collections;
 use std::sync::{Arc, Mutex};
@@ -10,56 +11,156 @@ use std::sync::{Arc, Mutex};
 pub struct NoClientSessionStorage {}
 
 impl client::StoresClientSessions for NoClientSessionStorage {
-    fn put(&self, _key: Vec<u8>, _value: Vec<u8>) -> bool {
-        false
+    fn set_kx_hint(&self, _key: &ClientSessionKey, _group: NamedGroup) {}
+
+    fn kx_hint(&self, _key: &ClientSessionKey) -> Option<NamedGroup> {
+        None
     }
 
-    fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
+    fn set_tls12_session(&self, _key: &ClientSessionKey, _value: Vec<u8>) {}
+
+    fn tls12_session(&self, _key: &ClientSessionKey) -> Option<Vec<u8>> {
         None
     }
+
+    fn remove_tls12_session(&self, _key: &ClientSessionKey) {}
+
+    fn insert_tls13_ticket(&self, _key: &ClientSessionKey, _value: Vec<u8>) {}
+
+    fn take_tls13_ticket(&self, _key: &ClientSessionKey) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn enabled(&self) -> bool {
+        false
+    }
+}
+
+/// How many TLS1.3 tickets `ClientSessionMemoryCache` keeps
+/// for each server.
+const MAX_TLS13_TICKETS_PER_SERVER: usize = 8;
+
+#[derive(Default)]
+struct ServerData {
+    kx_hint: Option<NamedGroup>,
+    tls12: Option<Vec<u8>>,
+    tls13: collections::VecDeque<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct ServerDataCache {
+    servers: collections::HashMap<ClientSessionKey, ServerData>,
+    // Keys, least recently stored first.
+    order: collections::VecDeque<ClientSessionKey>,
 }
 
 /// An implementor of `StoresClientSessions` that stores everything
-/// in memory.  It enforces a limit on the number of entries
-/// to bound memory usage.
+/// in memory.  It enforces a limit on the number of servers it
+/// stores data for, and on the number of TLS1.3 tickets for each
+/// server, to bound memory usage.
 pub struct ClientSessionMemoryCache {
-    cache: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
-    max_entries: usize,
+    cache: Mutex<ServerDataCache>,
+    max_servers: usize,
+    eviction_hook: Option<Box<Fn(&str) + Send + Sync>>,
 }
 
 impl ClientSessionMemoryCache {
     /// Make a new ClientSessionMemoryCache.  `size` is the
-    /// maximum number of stored sessions.
+    /// maximum number of servers to store data for.  A server
+    /// used with several configs counts once for each: see
+    /// `ClientSessionKey`.
     pub fn new(size: usize) -> Arc<ClientSessionMemoryCache> {
         debug_assert!(size > 0);
         Arc::new(ClientSessionMemoryCache {
-            cache: Mutex::new(collections::HashMap::new()),
-            max_entries: size,
+            cache: Mutex::new(ServerDataCache::default()),
+            max_servers: size,
+            eviction_hook: None,
+        })
+    }
+
+    /// Like `new`, but `hook` is called with a server's name
+    /// when its data is dropped to make room for another server.
+    pub fn new_with_eviction_hook(size: usize,
+                                  hook: Box<Fn(&str) + Send + Sync>)
+                                  -> Arc<ClientSessionMemoryCache> {
+        debug_assert!(size > 0);
+        Arc::new(ClientSessionMemoryCache {
+            cache: Mutex::new(ServerDataCache::default()),
+            max_servers: size,
+            eviction_hook: Some(hook),
         })
     }
 
-    fn limit_size(&self) {
-        let mut cache = self.cache.lock().unwrap();
-        while cache.len() > self.max_entries {
-            let k = cache.keys().next().unwrap().clone();
-            cache.remove(&k);
+    fn update<F>(&self, key: &ClientSessionKey, f: F)
+        where F: FnOnce(&mut ServerData)
+    {
+        let evicted = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.order.retain(|k| k != key);
+            cache.order.push_back(key.clone());
+            f(cache.servers
+                .entry(key.clone())
+                .or_insert_with(ServerData::default));
+
+            let mut evicted = Vec::new();
+            while cache.order.len() > self.max_servers {
+                let old = cache.order.pop_front().unwrap();
+                cache.servers.remove(&old);
+                evicted.push(old);
+            }
+            evicted
+        };
+
+        if let Some(ref hook) = self.eviction_hook {
+            for old in evicted {
+                hook(old.get_server_name().into());
+            }
         }
     }
-}
 
-impl client::StoresClientSessions for ClientSessionMemoryCache {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+    fn lookup<T, F>(&self, key: &ClientSessionKey, f: F) -> Option<T>
+        where F: FnOnce(&mut ServerData) -> Option<T>
+    {
         self.cache.lock()
             .unwrap()
-            .insert(key, value);
-        self.limit_size();
-        true
+            .servers
+            .get_mut(key)
+            .and_then(f)
     }
+}
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache.lock()
-            .unwrap()
-            .get(key).cloned()
+impl client::StoresClientSessions for ClientSessionMemoryCache {
+    fn set_kx_hint(&self, key: &ClientSessionKey, group: NamedGroup) {
+        self.update(key, |data| data.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, key: &ClientSessionKey) -> Option<NamedGroup> {
+        self.lookup(key, |data| data.kx_hint)
+    }
+
+    fn set_tls12_session(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.update(key, |data| data.tls12 = Some(value));
+    }
+
+    fn tls12_session(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        self.lookup(key, |data| data.tls12.clone())
+    }
+
+    fn remove_tls12_session(&self, key: &ClientSessionKey) {
+        self.lookup(key, |data| data.tls12.take());
+    }
+
+    fn insert_tls13_ticket(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.update(key, |data| {
+            data.tls13.push_back(value);
+            if data.tls13.len() > MAX_TLS13_TICKETS_PER_SERVER {
+                data.tls13.pop_front();
+            }
+        });
+    }
+
+    fn take_tls13_ticket(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        self.lookup(key, |data| data.tls13.pop_back())
     }
 }
 
@@ -106,60 +207,103 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
 mod test {
     use super::*;
     use StoresClientSessions;
+    use webpki;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn key(s: &str) -> ClientSessionKey {
+        let name = webpki::DNSNameRef::try_from_ascii_str(s).unwrap();
+        ClientSessionKey::from_parts(name, &[])
+    }
 
     #[test]
-    fn test_noclientsessionstorage_drops_put() {
+    fn test_noclientsessionstorage_stores_nothing() {
         let c = NoClientSessionStorage {};
-        assert_eq!(c.put(vec![0x01], vec![0x02]), false);
+        c.set_kx_hint(&key("a.com"), NamedGroup::X25519);
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+        c.insert_tls13_ticket(&key("a.com"), vec![0x02]);
+        assert_eq!(c.kx_hint(&key("a.com")), None);
+        assert_eq!(c.tls12_session(&key("a.com")), None);
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), None);
     }
 
     #[test]
-    fn test_noclientsessionstorage_denies_gets() {
-        let c = NoClientSessionStorage {};
-        c.put(vec![0x01], vec![0x02]);
-        assert_eq!(c.get(&[]), None);
-        assert_eq!(c.get(&[0x01]), None);
-        assert_eq!(c.get(&[0x02]), None);
+    fn test_clientsessionmemorycache_keeps_tls12_session() {
+        let c = ClientSessionMemoryCache::new(4);
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+        assert_eq!(c.tls12_session(&key("a.com")), Some(vec![0x01]));
+        assert_eq!(c.tls12_session(&key("a.com")), Some(vec![0x01]));
+        assert_eq!(c.tls12_session(&key("b.com")), None);
+
+        c.set_tls12_session(&key("a.com"), vec![0x02]);
+        assert_eq!(c.tls12_session(&key("a.com")), Some(vec![0x02]));
+
+        c.remove_tls12_session(&key("a.com"));
+        assert_eq!(c.tls12_session(&key("a.com")), None);
     }
 
     #[test]
-    fn test_clientsessionmemorycache_accepts_put() {
+    fn test_clientsessionmemorycache_takes_tls13_tickets_once() {
         let c = ClientSessionMemoryCache::new(4);
-        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
+        c.insert_tls13_ticket(&key("a.com"), vec![0x01]);
+        c.insert_tls13_ticket(&key("a.com"), vec![0x02]);
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), Some(vec![0x02]));
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), Some(vec![0x01]));
+        assert_eq!(c.take_tls13_ticket(&key("a.com")), None);
     }
 
     #[test]
-    fn test_clientsessionmemorycache_persists_put() {
+    fn test_clientsessionmemorycache_limits_tls13_tickets() {
         let c = ClientSessionMemoryCache::new(4);
-        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
-        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
-        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        for i in 0..MAX_TLS13_TICKETS_PER_SERVER + 2 {
+            c.insert_tls13_ticket(&key("a.com"), vec![i as u8]);
+        }
+
+        let mut count = 0;
+        while c.take_tls13_ticket(&key("a.com")).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, MAX_TLS13_TICKETS_PER_SERVER);
     }
 
     #[test]
-    fn test_clientsessionmemorycache_overwrites_put() {
+    fn test_clientsessionmemorycache_keeps_kx_hint() {
         let c = ClientSessionMemoryCache::new(4);
-        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
-        assert_eq!(c.put(vec![0x01], vec![0x04]), true);
-        assert_eq!(c.get(&[0x01]), Some(vec![0x04]));
+        c.set_kx_hint(&key("a.com"), NamedGroup::secp384r1);
+        assert_eq!(c.kx_hint(&key("a.com")), Some(NamedGroup::secp384r1));
+        assert_eq!(c.kx_hint(&key("b.com")), None);
     }
 
     #[test]
-    fn test_clientsessionmemorycache_drops_to_maintain_size_invariant() {
+    fn test_clientsessionmemorycache_separates_configs() {
         let c = ClientSessionMemoryCache::new(4);
-        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
-        assert_eq!(c.put(vec![0x03], vec![0x04]), true);
-        assert_eq!(c.put(vec![0x05], vec![0x06]), true);
-        assert_eq!(c.put(vec![0x07], vec![0x08]), true);
-        assert_eq!(c.put(vec![0x09], vec![0x0a]), true);
+        let name = webpki::DNSNameRef::try_from_ascii_str("a.com").unwrap();
+        let first = ClientSessionKey::from_parts(name, &[0x01]);
+        let second = ClientSessionKey::from_parts(name, &[0x02]);
 
-        let mut count = 0;
-        if c.get(&[0x01]).is_some() { count += 1; }
-        if c.get(&[0x03]).is_some() { count += 1; }
-        if c.get(&[0x05]).is_some() { count += 1; }
-        if c.get(&[0x07]).is_some() { count += 1; }
-        if c.get(&[0x09]).is_some() { count += 1; }
+        c.set_tls12_session(&first, vec![0x01]);
+        c.insert_tls13_ticket(&first, vec![0x02]);
+        assert_eq!(c.tls12_session(&second), None);
+        assert_eq!(c.take_tls13_ticket(&second), None);
+        assert_eq!(c.tls12_session(&first), Some(vec![0x01]));
+        assert_eq!(c.take_tls13_ticket(&first), Some(vec![0x02]));
+    }
+
+    #[test]
+    fn test_clientsessionmemorycache_evicts_oldest_server() {
+        let evictions = Arc::new(AtomicUsize::new(0));
+        let counter = evictions.clone();
+        let c = ClientSessionMemoryCache::new_with_eviction_hook(2, Box::new(move |name: &str| {
+            assert_eq!(name, "a.com");
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        c.set_tls12_session(&key("a.com"), vec![0x01]);
+        c.set_tls12_session(&key("b.com"), vec![0x02]);
+        c.set_tls12_session(&key("c.com"), vec![0x03]);
 
-        assert_eq!(count, 4);
+        assert_eq!(c.tls12_session(&key("a.com")), None);
+        assert_eq!(c.tls12_session(&key("b.com")), Some(vec![0x02]));
+        assert_eq!(c.tls12_session(&key("c.com")), Some(vec![0x03]));
+        assert_eq!(evictions.load(Ordering::SeqCst), 1);
     }
 }
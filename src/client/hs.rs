@@ -7,7 +7,7 @@ use msgs::handshake::{SessionID, Random, ServerHelloPayload};
 use msgs::handshake::{ClientExtension, HasServerExtensions};
 use msgs::handshake::{SupportedSignatureSchemes, SupportedMandatedSignatureSchemes};
 use msgs::handshake::DecomposedSignatureScheme;
-use msgs::handshake::{NamedGroups, SupportedGroups, KeyShareEntry, EncryptedExtensions};
+use msgs::handshake::{KeyShareEntry, EncryptedExtensions};
 use msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use msgs::handshake::{ProtocolNameList, ConvertProtocolNameList};
 use msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
@@ -16,24 +16,31 @@ use msgs::handshake::DigitallySignedStruct;
 use msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer, HelloRetryRequest};
 use msgs::handshake::{CertificateStatusRequest, SCTList};
 use msgs::enums::{ClientCertificateType, PSKKeyExchangeMode, ECPointFormat};
+use msgs::enums::CipherSuite;
 use msgs::codec::Codec;
 use msgs::persist;
 use msgs::ccs::ChangeCipherSpecPayload;
-use client::ClientSessionImpl;
-use session::SessionSecrets;
+use client::{ClientSessionImpl, InspectsClientHello, ClientHelloInfo, DowngradeInfo};
+use client::{DowngradeProtection, ClientSessionKey, ReceivedTicket, ReceivedSCT};
+use client::config_digest;
+use session::{SessionSecrets, TLS12Flags, HelloRetryInfo, SessionEvent, HandshakePhase};
 use key_schedule::{KeySchedule, SecretKind};
 use cipher;
 use suites;
+use kx;
 use hash_hs;
 use verify;
 use rand;
 use ticketer;
 use error::TLSError;
 use handshake::{check_message, check_handshake_message};
+use custom_ext;
 
 use client::common::{ServerCertDetails, ServerKXDetails, HandshakeDetails};
 use client::common::{ClientHelloDetails, ReceivedTicketDetails, ClientAuthDetails};
 
+use util;
+use std::cmp;
 use std::mem;
 use ring::constant_time;
 use webpki;
@@ -70,6 +77,7 @@ type NextState = Box<State + Send + Sync>;
 type NextStateOrError = Result<NextState, TLSError>;
 
 pub trait State {
+    fn phase(&self) -> HandshakePhase;
     fn check_message(&self, m: &Message) -> CheckResult;
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError;
 }
@@ -87,42 +95,79 @@ fn check_aligned_handshake(sess: &mut ClientSessionImpl) -> Result<(), TLSError>
     }
 }
 
-fn find_session(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef)
-                -> Option<persist::ClientSessionValue> {
-    let key = persist::ClientSessionKey::session_for_dns_name(dns_name);
-    let key_buf = key.get_encoding();
-
-    let maybe_value = sess.config.session_persistence.get(&key_buf);
+/// Decode a stored session, then erase the encoding: it contains
+/// the session's secret.
+fn decode_session(mut value: Vec<u8>) -> Option<persist::ClientSessionValue> {
+    let result = persist::ClientSessionValue::read_bytes(&value);
+    util::wipe(&mut value);
+    result
+}
 
-    if maybe_value.is_none() {
-        debug!("No cached session for {:?}", dns_name);
-        return None;
+fn find_session(sess: &mut ClientSessionImpl, key: &ClientSessionKey)
+                -> Option<persist::ClientSessionValue> {
+    let store = &sess.config.session_persistence;
+    let now = ticketer::timebase();
+
+    // Tickets are single-use, so keep taking them until we find
+    // one which is usable.
+    if sess.enables_version(ProtocolVersion::TLSv1_3) {
+        while let Some(value) = store.take_tls13_ticket(key) {
+            match decode_session(value) {
+                Some(ref result) if result.has_expired(now) => {}
+                Some(result) => return Some(result),
+                None => {}
+            }
+            debug!("Discarding unusable ticket for {:?}", key);
+        }
     }
 
-    let value = maybe_value.unwrap();
-    if let Some(result) = persist::ClientSessionValue::read_bytes(&value) {
-        if result.has_expired(ticketer::timebase()) {
-            None
-        } else {
-            Some(result)
+    if sess.enables_version(ProtocolVersion::TLSv1_2) {
+        if let Some(value) = store.tls12_session(key) {
+            match decode_session(value) {
+                Some(ref result) if result.has_expired(now) => {}
+                Some(result) => return Some(result),
+                None => {}
+            }
+            debug!("Discarding unusable session for {:?}", key);
+            store.remove_tls12_session(key);
         }
-    } else {
-        None
     }
-}
 
-fn find_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef) -> Option<NamedGroup> {
-    let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
-    let key_buf = key.get_encoding();
+    debug!("No cached session for {:?}", key);
+    None
+}
 
-    let maybe_value = sess.config.session_persistence.get(&key_buf);
-    maybe_value.and_then(|enc| NamedGroup::read_bytes(&enc))
+fn find_kx_hint(sess: &mut ClientSessionImpl, key: &ClientSessionKey) -> Option<NamedGroup> {
+    sess.config.session_persistence.kx_hint(key)
 }
 
-fn save_kx_hint(sess: &mut ClientSessionImpl, dns_name: webpki::DNSNameRef, group: NamedGroup) {
-    let key = persist::ClientSessionKey::hint_for_dns_name(dns_name);
+/// The groups we send key shares for in our first ClientHello.
+fn initial_key_share_groups(sess: &mut ClientSessionImpl,
+                            key: &ClientSessionKey) -> Vec<NamedGroup> {
+    let count = cmp::max(sess.config.key_share_count, 1);
+    let mut groups = Vec::new();
 
-    sess.config.session_persistence.put(key.get_encoding(), group.get_encoding());
+    // The hint may name a group this config no longer has.
+    let hint = find_kx_hint(sess, key)
+        .and_then(|group| kx::find_group(&sess.config.kx_groups, group))
+        .map(|grp| grp.name());
+    let preferred = hint.into_iter()
+        .chain(sess.config.kx_groups.iter().map(|grp| grp.name()));
+    for group in preferred {
+        if groups.len() == count {
+            break;
+        }
+
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+fn save_kx_hint(sess: &mut ClientSessionImpl, key: &ClientSessionKey, group: NamedGroup) {
+    sess.config.session_persistence.set_kx_hint(key, group);
 }
 
 /// If we have a ticket, we use the sessionid as a signal that we're
@@ -171,9 +216,9 @@ struct InitialState {
 }
 
 impl InitialState {
-    fn new(host_name: webpki::DNSName) -> InitialState {
+    fn new(host_name: webpki::DNSName, session_key: ClientSessionKey) -> InitialState {
         InitialState {
-            handshake: HandshakeDetails::new(host_name),
+            handshake: HandshakeDetails::new(host_name, session_key),
         }
     }
 
@@ -188,7 +233,9 @@ impl InitialState {
 
 
 pub fn start_handshake(sess: &mut ClientSessionImpl, host_name: webpki::DNSName) -> NextState {
-    InitialState::new(host_name)
+    let digest = config_digest(&sess.config, sess.alpn_protocols(), &sess.alternate_names);
+    let session_key = ClientSessionKey::from_parts(host_name.as_ref(), &digest);
+    InitialState::new(host_name, session_key)
         .emit_initial_client_hello(sess)
 }
 
@@ -230,12 +277,67 @@ fn compatible_suite(sess: &ClientSessionImpl,
     }
 }
 
+fn inspect_client_hello(inspector: &InspectsClientHello,
+                        cipher_suites: &[CipherSuite],
+                        exts: &mut Vec<ClientExtension>) {
+    let order = {
+        let types = exts.iter()
+            .map(|ext| ext.get_type())
+            .collect::<Vec<ExtensionType>>();
+        let named_groups = exts.iter()
+            .filter_map(|ext| match *ext {
+                ClientExtension::NamedGroups(ref groups) => Some(groups.as_slice()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(&[]);
+        let signature_schemes = exts.iter()
+            .filter_map(|ext| match *ext {
+                ClientExtension::SignatureAlgorithms(ref schemes) => Some(schemes.as_slice()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(&[]);
+
+        let info = ClientHelloInfo {
+            cipher_suites,
+            extensions: &types,
+            named_groups,
+            signature_schemes,
+        };
+
+        inspector.inspect(&info);
+        inspector.order_extensions(&info)
+    };
+
+    if let Some(order) = order {
+        // This is a stable sort, so unmentioned extensions keep their order.
+        exts.sort_by_key(|ext| {
+            let typ = ext.get_type();
+            if typ == ExtensionType::PreSharedKey {
+                return order.len() + 1;
+            }
+            order.iter()
+                .position(|t| *t == typ)
+                .unwrap_or(order.len())
+        });
+    }
+}
+
 fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                                mut handshake: HandshakeDetails,
                                mut hello: ClientHelloDetails,
                                retryreq: Option<&HelloRetryRequest>) -> NextState {
-    // Do we have a SessionID or ticket cached for this host?
-    handshake.resuming_session = find_session(sess, handshake.dns_name.as_ref());
+    // Do we have a SessionID or ticket cached for this host?  When
+    // renegotiating we always want a full handshake.  When retrying,
+    // we already took any ticket we're going to use.
+    if handshake.renegotiating.is_none() && retryreq.is_none() &&
+       !sess.overrides.disable_resumption {
+        handshake.resuming_session = find_session(sess, &handshake.session_key);
+    }
+    if retryreq.is_none() {
+        sess.common.emit_event(SessionEvent::HandshakeStarted);
+    }
     let (session_id, ticket, resume_version) = if handshake.resuming_session.is_some() {
         let resuming = handshake.resuming_session.as_mut().unwrap();
         if resuming.version == ProtocolVersion::TLSv1_2 {
@@ -248,8 +350,11 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         (SessionID::empty(), Vec::new(), ProtocolVersion::Unknown(0))
     };
 
-    let support_tls12 = sess.config.versions.contains(&ProtocolVersion::TLSv1_2);
-    let support_tls13 = sess.config.versions.contains(&ProtocolVersion::TLSv1_3);
+    // Renegotiation is TLS1.2-only.
+    let renegotiating = handshake.renegotiating.is_some();
+    let support_tls12 = sess.enables_version(ProtocolVersion::TLSv1_2);
+    let support_tls13 = sess.enables_version(ProtocolVersion::TLSv1_3) &&
+        !renegotiating;
 
     let mut supported_versions = Vec::new();
     if support_tls13 {
@@ -267,13 +372,13 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         // - if we've been asked via HelloRetryRequest for a specific
         //   one, do that.
         // - if not, we might have a hint of what the server supports
-        // - if not, send just X25519.
+        // - then fill up to `key_share_count` with our most preferred
+        //   groups.
         //
-        let groups = retryreq.and_then(|req| req.get_requested_key_share_group())
-            .or_else(|| find_kx_hint(sess, handshake.dns_name.as_ref()))
-            .or_else(|| Some(NamedGroup::X25519))
-            .map(|grp| vec![ grp ])
-            .unwrap();
+        let groups = match retryreq.and_then(|req| req.get_requested_key_share_group()) {
+            Some(group) => vec![ group ],
+            None => initial_key_share_groups(sess, &handshake.session_key),
+        };
 
         for group in groups {
             // in reply to HelloRetryRequest, we must not alter any existing key
@@ -284,7 +389,9 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
                 continue;
             }
 
-            if let Some(key_share) = suites::KeyExchange::start_ecdhe(group) {
+            let key_share = kx::find_group(&sess.config.kx_groups, group)
+                .and_then(|kx_group| suites::KeyExchange::start(kx_group.as_ref()));
+            if let Some(key_share) = key_share {
                 key_shares.push(KeyShareEntry::new(group, &key_share.pubkey));
                 hello.offered_key_shares.push(key_share);
             }
@@ -292,15 +399,26 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
     }
 
     let mut exts = Vec::new();
-    if !supported_versions.is_empty() {
+    if !supported_versions.is_empty() && !renegotiating {
         exts.push(ClientExtension::SupportedVersions(supported_versions));
     }
-    if sess.config.enable_sni {
+    if sess.send_sni {
         exts.push(ClientExtension::make_sni(handshake.dns_name.as_ref()));
     }
     exts.push(ClientExtension::ECPointFormats(ECPointFormatList::supported()));
-    exts.push(ClientExtension::NamedGroups(NamedGroups::supported()));
-    exts.push(ClientExtension::SignatureAlgorithms(SupportedSignatureSchemes::supported_verify()));
+    exts.push(ClientExtension::NamedGroups(sess.config.get_kx_groups()));
+    let mut sigschemes = SupportedSignatureSchemes::supported_verify();
+    if !sess.config.enable_rsa_pkcs1 {
+        sigschemes.retain(|scheme| !scheme.is_rsa_pkcs1());
+    }
+    if let Some(ref verifier) = sess.config.signature_verifier {
+        for scheme in verifier.supported_schemes() {
+            if !sigschemes.contains(&scheme) {
+                sigschemes.push(scheme);
+            }
+        }
+    }
+    exts.push(ClientExtension::SignatureAlgorithms(sigschemes));
     exts.push(ClientExtension::ExtendedMasterSecretRequest);
     exts.push(ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()));
 
@@ -316,20 +434,24 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
-    if support_tls13 && sess.config.enable_tickets {
+    if support_tls13 && sess.enables_tickets() {
         // We could support PSK_KE here too. Such connections don't
         // have forward secrecy, and are similar to TLS1.2 resumption.
         let psk_modes = vec![ PSKKeyExchangeMode::PSK_DHE_KE ];
         exts.push(ClientExtension::PresharedKeyModes(psk_modes));
     }
 
-    if !sess.config.alpn_protocols.is_empty() {
-        exts.push(ClientExtension::Protocols(ProtocolNameList::from_strings(&sess.config
-            .alpn_protocols)));
+    if !sess.alpn_protocols().is_empty() && !renegotiating {
+        exts.push(ClientExtension::Protocols(ProtocolNameList::from_strings(sess.alpn_protocols())));
     }
 
+    if let Some(ref prev) = handshake.renegotiating {
+        exts.push(ClientExtension::RenegotiationInfo(PayloadU8::new(prev.client.clone())));
+    }
+
+    exts.extend(custom_ext::client_extensions(&sess.config.custom_extensions));
 
-    let fill_in_binder = if support_tls13 && sess.config.enable_tickets &&
+    let fill_in_binder = if support_tls13 && sess.enables_tickets() &&
                             resume_version == ProtocolVersion::TLSv1_3 &&
                             !ticket.is_empty() {
         let resuming_suite = handshake.resuming_session
@@ -359,7 +481,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         } else {
             false
         }
-    } else if sess.config.enable_tickets {
+    } else if sess.enables_tickets() {
         // If we have a ticket, include it.  Otherwise, request one.
         if ticket.is_empty() {
             exts.push(ClientExtension::SessionTicketRequest);
@@ -376,13 +498,24 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
         .map(|ext| ext.get_type())
         .collect();
 
+    let mut cipher_suites = sess.get_cipher_suites();
+    if renegotiating {
+        cipher_suites.retain(|cs| *cs != CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+    }
+
+    if let Some(ref inspector) = sess.config.hello_inspector {
+        inspect_client_hello(inspector.as_ref(), &cipher_suites, &mut exts);
+    }
+
+    hello.session_id = session_id;
+
     let mut chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
         payload: HandshakePayload::ClientHello(ClientHelloPayload {
             client_version: ProtocolVersion::TLSv1_2,
             random: Random::from_slice(&handshake.randoms.client),
             session_id: session_id,
-            cipher_suites: sess.get_cipher_suites(),
+            cipher_suites,
             compression_methods: vec![Compression::Null],
             extensions: exts,
         }),
@@ -394,7 +527,7 @@ fn emit_client_hello_for_retry(sess: &mut ClientSessionImpl,
 
     let ch = Message {
         typ: ContentType::Handshake,
-        version: if retryreq.is_some() {
+        version: if retryreq.is_some() || renegotiating {
             ProtocolVersion::TLSv1_2
         } else {
             ProtocolVersion::TLSv1_0
@@ -457,12 +590,28 @@ fn validate_server_hello_tls13(sess: &mut ClientSessionImpl,
     Ok(())
 }
 
+fn check_session_id_echo(sess: &mut ClientSessionImpl,
+                         hello: &ClientHelloDetails,
+                         received: &SessionID)
+                         -> Result<(), TLSError> {
+    if hello.session_id == *received {
+        return Ok(());
+    }
+
+    if !sess.config.require_session_id_echo {
+        warn!("Ignoring server's mismatched session_id {:?}", received);
+        return Ok(());
+    }
+
+    Err(illegal_param(sess, "server did not echo our session_id"))
+}
+
 fn process_alpn_protocol(sess: &mut ClientSessionImpl,
                          proto: Option<&str>)
                          -> Result<(), TLSError> {
     sess.alpn_protocol = proto.map(|s| s.to_owned());
     if sess.alpn_protocol.is_some() &&
-        !sess.config.alpn_protocols.contains(sess.alpn_protocol.as_ref().unwrap()) {
+        !sess.alpn_protocols().contains(sess.alpn_protocol.as_ref().unwrap()) {
         return Err(illegal_param(sess, "server sent non-offered ALPN protocol"));
     }
     debug!("ALPN protocol is {:?}", sess.alpn_protocol);
@@ -492,6 +641,7 @@ impl ExpectServerHello {
                 }
 
                 debug!("Resuming using PSK");
+                sess.common.emit_event(SessionEvent::ResumptionAccepted);
                 key_schedule.input_secret(&resuming.master_secret.0);
             } else {
                 return Err(TLSError::PeerMisbehavedError("server selected unoffered psk".to_string()));
@@ -499,7 +649,9 @@ impl ExpectServerHello {
         } else {
             debug!("Not resuming");
             key_schedule.input_empty();
-            self.handshake.resuming_session.take();
+            if self.handshake.resuming_session.take().is_some() {
+                sess.common.emit_event(SessionEvent::ResumptionRejected);
+            }
         }
 
         let their_key_share = server_hello.get_key_share()
@@ -514,7 +666,7 @@ impl ExpectServerHello {
             .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed"
                                                          .to_string()))?;
 
-        save_kx_hint(sess, self.handshake.dns_name.as_ref(), their_key_share.group);
+        save_kx_hint(sess, &self.handshake.session_key, their_key_share.group);
         key_schedule.input_secret(&shared.premaster_secret);
 
         check_aligned_handshake(sess)?;
@@ -524,6 +676,9 @@ impl ExpectServerHello {
         let read_key = key_schedule.derive(SecretKind::ServerHandshakeTrafficSecret, &handshake_hash);
         sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
         sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+        let client_random = &self.handshake.randoms.client;
+        sess.common.log_tls13_secret(SecretKind::ClientHandshakeTrafficSecret, client_random, &write_key);
+        sess.common.log_tls13_secret(SecretKind::ServerHandshakeTrafficSecret, client_random, &read_key);
         key_schedule.current_client_traffic_secret = write_key;
         key_schedule.current_server_traffic_secret = read_key;
         sess.common.set_key_schedule(key_schedule);
@@ -573,6 +728,10 @@ impl ExpectServerHello {
 }
 
 impl State for ExpectServerHello {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingHello
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::ServerHello])
     }
@@ -591,12 +750,11 @@ impl State for ExpectServerHello {
         };
 
         match server_version {
-            TLSv1_3 | ProtocolVersion::Unknown(TLS13_DRAFT) if sess.config
-                .versions
-                .contains(&TLSv1_3) => {
+            TLSv1_3 | ProtocolVersion::Unknown(TLS13_DRAFT) if sess.enables_version(TLSv1_3) &&
+                self.handshake.renegotiating.is_none() => {
                 sess.common.negotiated_version = Some(TLSv1_3);
             }
-            TLSv1_2 if sess.config.versions.contains(&TLSv1_2) => {
+            TLSv1_2 if sess.enables_version(TLSv1_2) => {
                 sess.common.negotiated_version = Some(TLSv1_2);
 
                 if server_hello.get_supported_versions().is_some() {
@@ -610,6 +768,36 @@ impl State for ExpectServerHello {
             }
         };
 
+        let offered_max = if sess.enables_version(TLSv1_3) &&
+            self.handshake.renegotiating.is_none() {
+            TLSv1_3
+        } else {
+            TLSv1_2
+        };
+        let negotiated = sess.common.negotiated_version.unwrap();
+        let protection = sess.config.downgrade_protection;
+        let sentinel_checked = offered_max == TLSv1_3 && negotiated == TLSv1_2 &&
+            protection != DowngradeProtection::Disabled;
+        let sentinel_found = sentinel_checked && server_hello.random.has_tls12_downgrade_sentinel();
+
+        sess.downgrade_info = Some(DowngradeInfo {
+            offered_max,
+            negotiated,
+            server_supported_version: server_hello.get_supported_versions(),
+            downgraded: negotiated != offered_max,
+            sentinel_checked,
+            sentinel_found,
+        });
+
+        if sentinel_found {
+            if protection == DowngradeProtection::Strict {
+                sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
+                return Err(TLSError::DowngradeDetected);
+            }
+
+            warn!("Server supports TLS1.3, but negotiated TLS1.2");
+        }
+
         if server_hello.compression_method != Compression::Null {
             return Err(illegal_param(sess, "server chose non-Null compression"));
         }
@@ -626,8 +814,9 @@ impl State for ExpectServerHello {
             return Err(TLSError::PeerMisbehavedError("server sent unsolicited extension".to_string()));
         }
 
-        // Extract ALPN protocol
-        if !sess.common.is_tls13() {
+        // Extract ALPN protocol.  This is kept from the original handshake
+        // when renegotiating.
+        if !sess.common.is_tls13() && self.handshake.renegotiating.is_none() {
             process_alpn_protocol(sess, server_hello.get_alpn_protocol())?;
         }
 
@@ -666,6 +855,7 @@ impl State for ExpectServerHello {
         // For TLS1.3, start message encryption using
         // handshake_traffic_secret.
         if sess.common.is_tls13() {
+            check_session_id_echo(sess, &self.hello, &server_hello.session_id)?;
             validate_server_hello_tls13(sess, server_hello)?;
             self.start_handshake_traffic(sess, server_hello)?;
             emit_fake_ccs(&mut self.handshake, sess);
@@ -674,6 +864,10 @@ impl State for ExpectServerHello {
 
         // TLS1.2 only from here-on
 
+        custom_ext::collect_from_server(&sess.config.custom_extensions,
+                                        &server_hello.extensions,
+                                        &mut sess.received_custom_extensions);
+
         // Save ServerRandom and SessionID
         server_hello.random.write_slice(&mut self.handshake.randoms.server);
         self.handshake.session_id = server_hello.session_id;
@@ -683,6 +877,31 @@ impl State for ExpectServerHello {
             self.handshake.using_ems = true;
         }
 
+        // RFC5746 secure renegotiation.  The server's renegotiation_info
+        // is empty in the initial handshake, and binds a renegotiation to
+        // the previous handshake's Finished messages.
+        let expect_reneg_info = self.handshake.renegotiating
+            .as_ref()
+            .map(|prev| prev.server_renegotiation_info());
+
+        match (server_hello.get_renegotiation_info(), expect_reneg_info) {
+            (Some(info), expected) => {
+                let expected = expected.unwrap_or_else(Vec::new);
+                if constant_time::verify_slices_are_equal(&expected, &info.0).is_err() {
+                    sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+                    return Err(TLSError::PeerMisbehavedError("server sent wrong renegotiation_info"
+                                                             .to_string()));
+                }
+                self.handshake.secure_renegotiation = true;
+            }
+            (None, Some(_)) => {
+                sess.common.send_fatal_alert(AlertDescription::HandshakeFailure);
+                return Err(TLSError::PeerMisbehavedError("server omitted renegotiation_info"
+                                                         .to_string()));
+            }
+            (None, None) => {}
+        }
+
         // Might the server send a ticket?
         let with_tickets = if server_hello.find_extension(ExtensionType::SessionTicket).is_some() {
             debug!("Server supports tickets");
@@ -692,6 +911,12 @@ impl State for ExpectServerHello {
         };
         self.must_issue_new_ticket = with_tickets;
 
+        sess.common.tls12_flags = Some(TLS12Flags {
+            extended_master_secret: self.handshake.using_ems,
+            secure_renegotiation: self.handshake.secure_renegotiation,
+            session_ticket: with_tickets,
+        });
+
         // Might the server send a CertificateStatus between Certificate and
         // ServerKeyExchange?
         if server_hello.find_extension(ExtensionType::StatusRequest).is_some() {
@@ -737,6 +962,14 @@ impl State for ExpectServerHello {
             }
         }
 
+        if self.handshake.resuming_session.is_some() {
+            sess.common.emit_event(if abbreviated_handshake {
+                SessionEvent::ResumptionAccepted
+            } else {
+                SessionEvent::ResumptionRejected
+            });
+        }
+
         if abbreviated_handshake {
             // Since we're resuming, we verified the certificate and
             // proof of possession in the prior session.
@@ -776,7 +1009,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
 
         // Or asks for us to retry on an unsupported group.
         if let Some(group) = req_group {
-            if !NamedGroups::supported().contains(&group) {
+            if kx::find_group(&sess.config.kx_groups, group).is_none() {
                 return Err(illegal_param(sess, "server requested hrr with bad group"));
             }
         }
@@ -798,6 +1031,9 @@ impl ExpectServerHelloOrHelloRetryRequest {
             return Err(illegal_param(sess, "server send duplicate hrr extensions"));
         }
 
+        // Or doesn't echo our session_id.
+        check_session_id_echo(sess, &self.0.hello, &hrr.session_id)?;
+
         // Or asks us to change nothing.
         if !has_cookie && req_group.is_none() {
             return Err(illegal_param(sess, "server requested hrr with no changes"));
@@ -828,9 +1064,15 @@ impl ExpectServerHelloOrHelloRetryRequest {
 
         // This is the draft19 change where the transcript became a tree
         self.0.handshake.transcript.start_hash(cs.get_hash());
-        self.0.handshake.transcript.rollup_for_hrr();
+        let client_hello_hash = self.0.handshake.transcript.rollup_for_hrr();
         self.0.handshake.transcript.add_message(&m);
 
+        sess.common.hello_retry = Some(HelloRetryInfo {
+            requested_group: req_group,
+            has_cookie,
+            client_hello_hash,
+        });
+
         Ok(emit_client_hello_for_retry(sess,
                                        self.0.handshake,
                                        self.0.hello,
@@ -839,6 +1081,10 @@ impl ExpectServerHelloOrHelloRetryRequest {
 }
 
 impl State for ExpectServerHelloOrHelloRetryRequest {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingHello
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m,
                                 &[HandshakeType::ServerHello,
@@ -908,6 +1154,10 @@ impl ExpectTLS13EncryptedExtensions {
 }
 
 impl State for ExpectTLS13EncryptedExtensions {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::EncryptedExtensions])
     }
@@ -919,6 +1169,9 @@ impl State for ExpectTLS13EncryptedExtensions {
 
         validate_encrypted_extensions(sess, &self.hello, exts)?;
         process_alpn_protocol(sess, exts.get_alpn_protocol())?;
+        custom_ext::collect_from_server(&sess.config.custom_extensions,
+                                        exts,
+                                        &mut sess.received_custom_extensions);
 
         if self.handshake.resuming_session.is_some() {
             let certv = verify::ServerCertVerified::assertion();
@@ -931,6 +1184,23 @@ impl State for ExpectTLS13EncryptedExtensions {
 }
 
 
+/// Verify the SCTs sent with the server's certificate, if we asked
+/// for them, keeping the results for `ClientSession::get_received_scts`.
+fn verify_server_scts(sess: &mut ClientSessionImpl,
+                      server_cert: &ServerCertDetails) -> Result<(), TLSError> {
+    let (scts, logs) = match (server_cert.scts.as_ref(), sess.config.ct_logs) {
+        (Some(scts), Some(logs)) => (scts, logs),
+        (_, _) => return Ok(()),
+    };
+
+    let verdicts = verify::verify_scts(&server_cert.cert_chain[0], scts, logs)?;
+    sess.received_scts = scts.iter()
+        .zip(verdicts)
+        .map(|(sct, verdict)| ReceivedSCT { sct: sct.0.clone(), verdict })
+        .collect();
+    Ok(())
+}
+
 fn sct_list_is_invalid(scts: &SCTList) -> bool {
     scts.is_empty() ||
         scts.iter().any(|sct| sct.0.is_empty())
@@ -953,6 +1223,10 @@ impl ExpectTLS13Certificate {
 }
 
 impl State for ExpectTLS13Certificate {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::Certificate])
     }
@@ -1021,6 +1295,10 @@ impl ExpectTLS12Certificate {
 }
 
 impl State for ExpectTLS12Certificate {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::Certificate])
     }
@@ -1056,6 +1334,10 @@ impl ExpectTLS12CertificateStatus {
 }
 
 impl State for ExpectTLS12CertificateStatus {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::CertificateStatus])
     }
@@ -1095,6 +1377,10 @@ impl ExpectTLS12CertificateStatusOrServerKX {
 }
 
 impl State for ExpectTLS12CertificateStatusOrServerKX {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m,
                                 &[HandshakeType::ServerKeyExchange,
@@ -1133,6 +1419,10 @@ impl ExpectTLS13CertificateOrCertReq {
 }
 
 impl State for ExpectTLS13CertificateOrCertReq {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m,
                                 &[HandshakeType::Certificate,
@@ -1166,6 +1456,10 @@ impl ExpectTLS12ServerKX {
 }
 
 impl State for ExpectTLS12ServerKX {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::ServerKeyExchange])
     }
@@ -1215,6 +1509,61 @@ impl ExpectTLS13CertificateVerify {
     }
 }
 
+/// Verify the server's certificate chain for `dns_name`, or failing
+/// that for any of the session's alternate names.  If none match,
+/// the error for `dns_name` is returned.
+///
+/// `Ok(None)` means the verifier deferred its decision: the caller
+/// should park the message with `defer_message` and try again once
+/// the application has supplied a verdict.  That verdict covers the
+/// alternate names too, which the verifier sees in its context.
+fn verify_server_cert(sess: &mut ClientSessionImpl,
+                      server_cert: &ServerCertDetails,
+                      dns_name: webpki::DNSNameRef)
+                      -> Result<Option<verify::ServerCertVerified>, TLSError> {
+    if let Some(verdict) = sess.server_cert_verdict.take() {
+        return verdict.map(Some);
+    }
+
+    let context = verify::ServerCertContext {
+        version: sess.common.negotiated_version.unwrap(),
+        suite: sess.common.get_suite_assert(),
+        offered_alpn: sess.alpn_protocols(),
+        alpn_protocol: sess.alpn_protocol.as_ref().map(|s| s.as_str()),
+        alternate_names: &sess.alternate_names,
+    };
+    let verifier = sess.config.get_verifier();
+    let verify_for = |name| {
+        verifier.verify_server_cert_or_defer(&sess.config.root_store,
+                                             &server_cert.cert_chain,
+                                             name,
+                                             &server_cert.ocsp_response,
+                                             &context)
+    };
+
+    let err = match verify_for(dns_name) {
+        Ok(verified) => return Ok(verified),
+        Err(err) => err,
+    };
+
+    for name in &sess.alternate_names {
+        if let Ok(verified) = verify_for(name.as_ref()) {
+            debug!("Server cert matched alternate name {:?}", name);
+            return Ok(verified);
+        }
+    }
+
+    Err(err)
+}
+
+/// Park `m` until the application supplies a verdict on the server's
+/// certificate, leaving the handshake in state `st`.
+fn defer_message(sess: &mut ClientSessionImpl, m: Message, st: NextState) -> NextStateOrError {
+    debug!("Server cert verification deferred");
+    sess.deferred_message = Some(m);
+    Ok(st)
+}
+
 fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TLSError) -> TLSError {
     match err {
         TLSError::WebPKIError(webpki::Error::BadDER) => {
@@ -1232,13 +1581,15 @@ fn send_cert_error_alert(sess: &mut ClientSessionImpl, err: TLSError) -> TLSErro
 }
 
 impl State for ExpectTLS13CertificateVerify {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::CertificateVerify])
     }
 
     fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
-        let cert_verify = extract_handshake!(m, HandshakePayload::CertificateVerify).unwrap();
-
         debug!("Server cert is {:?}", self.server_cert.cert_chain);
 
         // 1. Verify the certificate chain.
@@ -1246,31 +1597,26 @@ impl State for ExpectTLS13CertificateVerify {
             return Err(TLSError::NoCertificatesPresented);
         }
 
-        let certv = sess.config
-            .get_verifier()
-            .verify_server_cert(&sess.config.root_store,
-                                &self.server_cert.cert_chain,
-                                self.handshake.dns_name.as_ref(),
-                                &self.server_cert.ocsp_response)
-            .map_err(|err| send_cert_error_alert(sess, err))?;
+        let certv = match verify_server_cert(sess, &self.server_cert, self.handshake.dns_name.as_ref())
+            .map_err(|err| send_cert_error_alert(sess, err))? {
+            Some(certv) => certv,
+            None => return defer_message(sess, m, self),
+        };
+
+        let cert_verify = extract_handshake!(m, HandshakePayload::CertificateVerify).unwrap();
 
         // 2. Verify their signature on the handshake.
         let handshake_hash = self.handshake.transcript.get_current_hash();
         let sigv = verify::verify_tls13(&self.server_cert.cert_chain[0],
                                         cert_verify,
                                         &handshake_hash,
-                                        b"TLS 1.3, server CertificateVerify\x00")
+                                        b"TLS 1.3, server CertificateVerify\x00",
+                                        sess.config.signature_verifier.as_ref()
+                                            .map(|v| v.as_ref()))
             .map_err(|err| send_cert_error_alert(sess, err))?;
 
         // 3. Verify any included SCTs.
-        match (self.server_cert.scts.as_ref(), sess.config.ct_logs) {
-            (Some(scts), Some(logs)) => {
-                verify::verify_scts(&self.server_cert.cert_chain[0],
-                                    scts,
-                                    logs)?;
-            }
-            (_, _) => {}
-        }
+        verify_server_scts(sess, &self.server_cert)?;
 
         sess.server_cert_chain = self.server_cert.take_chain();
         self.handshake.transcript.add_message(&m);
@@ -1365,6 +1711,7 @@ fn emit_finished(handshake: &mut HandshakeDetails,
         .as_ref()
         .unwrap()
         .client_verify_data(&vh);
+    handshake.verify_data.client = verify_data.clone();
     let verify_data_payload = Payload::new(verify_data);
 
     let f = Message {
@@ -1403,6 +1750,10 @@ impl ExpectTLS12CertificateRequest {
 }
 
 impl State for ExpectTLS12CertificateRequest {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::CertificateRequest])
     }
@@ -1428,12 +1779,17 @@ impl State for ExpectTLS12CertificateRequest {
             .iter()
             .map(|p| p.0.as_slice())
             .collect::<Vec<&[u8]>>();
+        let mut sigschemes = certreq.sigschemes.clone();
+        if !sess.config.enable_rsa_pkcs1 {
+            sigschemes.retain(|scheme| !scheme.is_rsa_pkcs1());
+        }
+
         let maybe_certkey =
-            sess.config.client_auth_cert_resolver.resolve(&canames, &certreq.sigschemes);
+            sess.config.client_auth_cert_resolver.resolve(&canames, &sigschemes);
 
         if let Some(mut certkey) = maybe_certkey {
             debug!("Attempting client auth");
-            let maybe_signer = certkey.key.choose_scheme(&certreq.sigschemes);
+            let maybe_signer = certkey.key.choose_scheme(&sigschemes);
             client_auth.cert = Some(certkey.take_cert());
             client_auth.signer = maybe_signer;
         } else {
@@ -1462,6 +1818,10 @@ impl ExpectTLS13CertificateRequest {
 }
 
 impl State for ExpectTLS13CertificateRequest {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::CertificateRequest])
     }
@@ -1548,6 +1908,10 @@ impl ExpectTLS12ServerDoneOrCertReq {
 }
 
 impl State for ExpectTLS12ServerDoneOrCertReq {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m,
                                 &[HandshakeType::CertificateRequest,
@@ -1599,13 +1963,16 @@ impl ExpectTLS12ServerDone {
 }
 
 impl State for ExpectTLS12ServerDone {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::ServerHelloDone])
     }
 
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
         let mut st = *self;
-        st.handshake.transcript.add_message(&m);
 
         debug!("Server cert is {:?}", st.server_cert.cert_chain);
         debug!("Server DNS name is {:?}", st.handshake.dns_name);
@@ -1627,23 +1994,16 @@ impl State for ExpectTLS12ServerDone {
             return Err(TLSError::NoCertificatesPresented);
         }
 
-        let certv = sess.config
-            .get_verifier()
-            .verify_server_cert(&sess.config.root_store,
-                                &st.server_cert.cert_chain,
-                                st.handshake.dns_name.as_ref(),
-                                &st.server_cert.ocsp_response)
-            .map_err(|err| send_cert_error_alert(sess, err))?;
+        let certv = match verify_server_cert(sess, &st.server_cert, st.handshake.dns_name.as_ref())
+            .map_err(|err| send_cert_error_alert(sess, err))? {
+            Some(certv) => certv,
+            None => return defer_message(sess, m, Box::new(st)),
+        };
+
+        st.handshake.transcript.add_message(&m);
 
         // 2. Verify any included SCTs.
-        match (st.server_cert.scts.as_ref(), sess.config.ct_logs) {
-            (Some(scts), Some(logs)) => {
-                verify::verify_scts(&st.server_cert.cert_chain[0],
-                                    scts,
-                                    logs)?;
-            }
-            (_, _) => {}
-        }
+        verify_server_scts(sess, &st.server_cert)?;
 
         // 3.
         // Build up the contents of the signed message.
@@ -1664,6 +2024,12 @@ impl State for ExpectTLS12ServerDone {
                 return Err(TLSError::PeerMisbehavedError(error_message));
             }
 
+            if !sess.config.enable_rsa_pkcs1 && sig.scheme.is_rsa_pkcs1() {
+                sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
+                return Err(TLSError::PeerMisbehavedError("peer signed kx with RSA PKCS#1"
+                                                             .to_string()));
+            }
+
             verify::verify_signed_struct(&message,
                                          &st.server_cert.cert_chain[0],
                                          sig)
@@ -1679,7 +2045,7 @@ impl State for ExpectTLS12ServerDone {
 
         // 5a.
         let kxd = sess.common.get_suite_assert()
-            .do_client_kx(&st.server_kx.kx_params)
+            .do_client_kx(&st.server_kx.kx_params, &sess.config.kx_groups)
             .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
 
         // 5b.
@@ -1694,10 +2060,7 @@ impl State for ExpectTLS12ServerDone {
                             sess)?;
         }
 
-        // 5d.
-        emit_ccs(sess);
-
-        // 5e. Now commit secrets.
+        // 5d. Now commit secrets.
         let hashalg = sess.common.get_suite_assert().get_hash();
         let secrets = if st.handshake.using_ems {
             SessionSecrets::new_ems(&st.handshake.randoms,
@@ -1711,6 +2074,9 @@ impl State for ExpectTLS12ServerDone {
         };
         sess.common.start_encryption_tls12(secrets);
 
+        // 5e.
+        emit_ccs(sess);
+
         // 6.
         emit_finished(&mut st.handshake, sess);
 
@@ -1744,6 +2110,10 @@ impl ExpectTLS12CCS {
 }
 
 impl State for ExpectTLS12CCS {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_message(m, &[ContentType::ChangeCipherSpec], &[])
     }
@@ -1786,27 +2156,57 @@ impl ExpectTLS12NewTicket {
 }
 
 impl State for ExpectTLS12NewTicket {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::NewSessionTicket])
     }
 
-    fn handle(mut self: Box<Self>, _sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
+    fn handle(mut self: Box<Self>, sess: &mut ClientSessionImpl, m: Message) -> NextStateOrError {
         self.handshake.transcript.add_message(&m);
 
         let nst = extract_handshake_mut!(m, HandshakePayload::NewSessionTicket).unwrap();
+        if !nst.ticket.0.is_empty() {
+            sess.common.emit_event(SessionEvent::TicketReceived);
+        }
         let recvd = ReceivedTicketDetails::from(nst.ticket.0, nst.lifetime_hint);
         Ok(self.into_expect_tls12_ccs(recvd))
     }
 }
 
+fn report_ticket(sess: &ClientSessionImpl,
+                 session_key: &ClientSessionKey,
+                 version: ProtocolVersion,
+                 lifetime: u32,
+                 max_early_data_size: u32) {
+    if let Some(ref observer) = sess.config.ticket_observer {
+        observer.ticket_stored(&ReceivedTicket {
+            server_name: session_key.get_server_name(),
+            version,
+            lifetime,
+            max_early_data_size,
+        });
+    }
+}
+
 // -- Waiting for their finished --
 fn save_session(handshake: &mut HandshakeDetails,
                 recvd_ticket: &mut ReceivedTicketDetails,
+                resuming: bool,
                 sess: &mut ClientSessionImpl) {
-    // Save a ticket.  If we got a new ticket, save that.  Otherwise, save the
-    // original ticket again.
+    if sess.overrides.disable_resumption {
+        debug!("Session not saved: resumption disabled");
+        return;
+    }
+
+    // Save a ticket.  If we got a new ticket, save that.  Otherwise, if we
+    // resumed, save the original ticket again: it still goes with this
+    // session's master secret.
+    let got_new_ticket = !recvd_ticket.new_ticket.is_empty();
     let mut ticket = mem::replace(&mut recvd_ticket.new_ticket, Vec::new());
-    if ticket.is_empty() && handshake.resuming_session.is_some() {
+    if ticket.is_empty() && resuming && handshake.resuming_session.is_some() {
         ticket = handshake.resuming_session.as_mut().unwrap().take_ticket();
     }
 
@@ -1815,8 +2215,6 @@ fn save_session(handshake: &mut HandshakeDetails,
         return;
     }
 
-    let key = persist::ClientSessionKey::session_for_dns_name(handshake.dns_name.as_ref());
-
     let scs = sess.common.get_suite_assert();
     let master_secret = sess.common.secrets.as_ref().unwrap().get_master_secret();
     let version = sess.get_protocol_version().unwrap();
@@ -1832,13 +2230,13 @@ fn save_session(handshake: &mut HandshakeDetails,
         value.set_extended_ms_used();
     }
 
-    let worked = sess.config.session_persistence.put(key.get_encoding(),
-                                                     value.get_encoding());
+    sess.config.session_persistence.set_tls12_session(&handshake.session_key,
+                                                      value.get_encoding());
+    debug!("Session saved");
 
-    if worked {
-        debug!("Session saved");
-    } else {
-        debug!("Session not saved");
+    if got_new_ticket {
+        report_ticket(sess, &handshake.session_key, ProtocolVersion::TLSv1_2,
+                      recvd_ticket.new_ticket_lifetime, 0);
     }
 }
 
@@ -1945,6 +2343,10 @@ impl ExpectTLS13Finished {
 }
 
 impl State for ExpectTLS13Finished {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::Finished])
     }
@@ -1977,6 +2379,8 @@ impl State for ExpectTLS13Finished {
             .derive(SecretKind::ServerApplicationTrafficSecret, &handshake_hash);
         let suite = sess.common.get_suite_assert();
         sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+        sess.common.log_tls13_secret(SecretKind::ServerApplicationTrafficSecret,
+                                     &st.handshake.randoms.client, &read_key);
         sess.common
             .get_mut_key_schedule()
             .current_server_traffic_secret = read_key;
@@ -1984,6 +2388,8 @@ impl State for ExpectTLS13Finished {
         let exporter_secret = sess.common
             .get_key_schedule()
             .derive(SecretKind::ExporterMasterSecret, &handshake_hash);
+        sess.common.log_tls13_secret(SecretKind::ExporterMasterSecret,
+                                     &st.handshake.randoms.client, &exporter_secret);
         sess.common
             .get_mut_key_schedule()
             .current_exporter_secret = exporter_secret;
@@ -2008,6 +2414,8 @@ impl State for ExpectTLS13Finished {
             .get_key_schedule()
             .derive(SecretKind::ClientApplicationTrafficSecret, &handshake_hash);
         sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
+        sess.common.log_tls13_secret(SecretKind::ClientApplicationTrafficSecret,
+                                     &st.handshake.randoms.client, &write_key);
         sess.common
             .get_mut_key_schedule()
             .current_client_traffic_secret = write_key;
@@ -2030,6 +2438,7 @@ impl ExpectTLS12Finished {
     fn into_expect_tls12_traffic(self,
                                  fin: verify::FinishedMessageVerified) -> NextState {
         Box::new(ExpectTLS12Traffic {
+            handshake: self.handshake,
             _cert_verified: self.cert_verified,
             _sig_verified: self.sig_verified,
             _fin_verified: fin,
@@ -2038,6 +2447,10 @@ impl ExpectTLS12Finished {
 }
 
 impl State for ExpectTLS12Finished {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_handshake_message(m, &[HandshakeType::Finished])
     }
@@ -2064,9 +2477,11 @@ impl State for ExpectTLS12Finished {
 
         // Hash this message too.
         st.handshake.transcript.add_message(&m);
+        st.handshake.verify_data.server = finished.0.clone();
 
         save_session(&mut st.handshake,
                      &mut st.ticket,
+                     st.resuming,
                      sess);
 
         if st.resuming {
@@ -2081,18 +2496,54 @@ impl State for ExpectTLS12Finished {
 }
 
 // -- Traffic transit state --
+// In this state we can be sent application data, and (if
+// enabled) HelloRequests asking us to renegotiate.
 struct ExpectTLS12Traffic {
+    handshake: HandshakeDetails,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
+impl ExpectTLS12Traffic {
+    fn renegotiate(self: Box<Self>, sess: &mut ClientSessionImpl) -> NextStateOrError {
+        // We only renegotiate securely.
+        if !self.handshake.secure_renegotiation {
+            sess.common.refuse_renegotiation()?;
+            return Ok(self);
+        }
+
+        debug!("Renegotiating");
+        let mut handshake = HandshakeDetails::new(self.handshake.dns_name.clone(),
+                                                  self.handshake.session_key.clone());
+        handshake.renegotiating = Some(self.handshake.verify_data.clone());
+        if sess.config.client_auth_cert_resolver.has_certs() {
+            handshake.transcript.set_client_auth_enabled();
+        }
+
+        // The server may choose a different suite this time.
+        sess.common.forget_suite();
+        sess.common.renegotiating = true;
+        Ok(emit_client_hello_for_retry(sess, handshake, ClientHelloDetails::new(), None))
+    }
+}
+
 impl State for ExpectTLS12Traffic {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::Traffic
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
-        check_message(m, &[ContentType::ApplicationData], &[])
+        check_message(m,
+                      &[ContentType::ApplicationData, ContentType::Handshake],
+                      &[HandshakeType::HelloRequest])
     }
 
     fn handle(self: Box<Self>, sess: &mut ClientSessionImpl, mut m: Message) -> NextStateOrError {
+        if m.is_handshake_type(HandshakeType::HelloRequest) {
+            return self.renegotiate(sess);
+        }
+
         sess.common.take_received_plaintext(m.take_opaque_payload().unwrap());
         Ok(self)
     }
@@ -2111,13 +2562,20 @@ struct ExpectTLS13Traffic {
 impl ExpectTLS13Traffic {
     fn handle_new_ticket_tls13(&mut self, sess: &mut ClientSessionImpl, m: Message) -> Result<(), TLSError> {
         let nst = extract_handshake!(m, HandshakePayload::NewSessionTicketTLS13).unwrap();
+        sess.common.emit_event(SessionEvent::TicketReceived);
+        if sess.overrides.disable_resumption {
+            debug!("Ticket not saved: resumption disabled");
+            return Ok(());
+        }
+
         let handshake_hash = self.handshake.transcript.get_current_hash();
-        let resumption_master_secret = sess.common
+        let mut resumption_master_secret = sess.common
             .get_key_schedule()
             .derive(SecretKind::ResumptionMasterSecret, &handshake_hash);
         let secret = sess.common
             .get_key_schedule()
             .derive_ticket_psk(&resumption_master_secret, &nst.nonce.0);
+        util::wipe(&mut resumption_master_secret);
 
         let mut value = persist::ClientSessionValue::new(ProtocolVersion::TLSv1_3,
                                                          sess.common.get_suite_assert().suite,
@@ -2128,16 +2586,12 @@ impl ExpectTLS13Traffic {
                         nst.lifetime,
                         nst.age_add);
 
-        let key = persist::ClientSessionKey::session_for_dns_name(self.handshake.dns_name.as_ref());
+        sess.config.session_persistence.insert_tls13_ticket(&self.handshake.session_key,
+                                                            value.get_encoding());
+        debug!("Ticket saved");
 
-        let worked = sess.config.session_persistence.put(key.get_encoding(),
-                                                         value.get_encoding());
-
-        if worked {
-            debug!("Ticket saved");
-        } else {
-            debug!("Ticket not saved");
-        }
+        report_ticket(sess, &self.handshake.session_key, ProtocolVersion::TLSv1_3,
+                      nst.lifetime, nst.get_max_early_data_size().unwrap_or(0));
         Ok(())
     }
 
@@ -2148,6 +2602,10 @@ impl ExpectTLS13Traffic {
 }
 
 impl State for ExpectTLS13Traffic {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::Traffic
+    }
+
     fn check_message(&self, m: &Message) -> Result<(), TLSError> {
         check_message(m,
                       &[ContentType::ApplicationData, ContentType::Handshake],
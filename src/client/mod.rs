@@ -1,47 +1,196 @@
 use msgs::enums::CipherSuite;
-use msgs::enums::{AlertDescription, HandshakeType};
-use session::{Session, SessionCommon};
-use suites::{SupportedCipherSuite, ALL_CIPHERSUITES};
+use msgs::enums::{AlertDescription, AlertLevel, HandshakeType};
+use session::{Session, SessionCommon, TLS12Flags, ObservesUncleanClose};
+use session::{HelloRetryInfo, ObservesSessionEvents, Received, ConnectionId};
+use session::{UnbufferedStatus, HandshakePhase, SessionEvent, SessionOverrides};
+use session::{WEB_ALPN_PROTOCOLS, WEB_BUFFER_LIMIT, RenegotiationPolicy};
+use key_log::KeyLog;
+use suites::{self, SupportedCipherSuite, SecurityProfile, ALL_CIPHERSUITES};
 use msgs::handshake::CertificatePayload;
-use msgs::enums::SignatureScheme;
+use msgs::enums::{SignatureScheme, ExtensionType, NamedGroup};
 use msgs::enums::{ContentType, ProtocolVersion};
-use msgs::message::Message;
+use msgs::message::{Message, MessagePayload};
+use msgs::handshake::{ProtocolNameList, ConvertProtocolNameList};
+use msgs::base::PayloadU16;
+use msgs::codec::{self, Codec};
 use verify;
 use anchors;
 use sign;
+use kx;
 use error::TLSError;
 use key;
+use custom_ext;
+use capture;
+use summary::{ConfigSummary, ClientAuthMode, ConfigWarning};
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::io;
 use std::fmt;
+use std::mem::MaybeUninit;
 
 use sct;
+use ring::digest;
 use webpki;
 
 mod hs;
 mod common;
 pub mod handy;
+#[cfg(feature = "file_session_cache")]
+pub mod filecache;
 
-/// A trait for the ability to store client session data.
-/// The keys and values are opaque.
+/// What `StoresClientSessions` keeps data under: the name we
+/// used for the server, and a digest of the `ClientConfig`
+/// settings which decide whether stored data can be used.
 ///
-/// Both the keys and values should be treated as
+/// Those settings are the ALPN protocols, trusted roots, how the
+/// server certificate is verified and whether client authentication
+/// is possible.  (Protocol versions are not included: sessions for
+/// versions a config doesn't enable are never used.)
+/// So one store can be shared by `ClientConfig`s which differ
+/// in them, and each config only resumes its own sessions.
+///
+/// webpki verification is identified by its `VerifierPolicy` and
+/// extra intermediates.  A verifier set with
+/// `DangerousClientConfig::set_certificate_verifier` or a name
+/// verifier set with `ClientConfig::set_server_name_verifier` is
+/// identified only within this process, so sessions it verified
+/// are not resumed by another process sharing a persistent store.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientSessionKey {
+    server_name: webpki::DNSName,
+    config_digest: Vec<u8>,
+}
+
+impl ClientSessionKey {
+    /// Make the key for connecting to `server_name` with `config`.
+    pub fn new(server_name: webpki::DNSNameRef, config: &ClientConfig) -> ClientSessionKey {
+        ClientSessionKey {
+            server_name: server_name.into(),
+            config_digest: config_digest(config, &config.alpn_protocols, &[]),
+        }
+    }
+
+    /// Remake a key from its parts.  This is for stores which
+    /// persist keys themselves.
+    pub fn from_parts(server_name: webpki::DNSNameRef, config_digest: &[u8]) -> ClientSessionKey {
+        ClientSessionKey {
+            server_name: server_name.into(),
+            config_digest: config_digest.to_vec(),
+        }
+    }
+
+    /// Returns the server's name.
+    pub fn get_server_name(&self) -> webpki::DNSNameRef {
+        self.server_name.as_ref()
+    }
+
+    /// Returns the digest of the config.
+    pub fn get_config_digest(&self) -> &[u8] {
+        &self.config_digest
+    }
+}
+
+/// Hands out ids for verifiers we can't otherwise tell apart.
+static NEXT_VERIFIER_ID: AtomicUsize = AtomicUsize::new(1);
+
+fn next_verifier_id() -> usize {
+    NEXT_VERIFIER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// How many bytes of SHA256 we keep in a `ClientSessionKey`.
+const CONFIG_DIGEST_LEN: usize = 16;
+
+/// Digests `config`, but with `alpn_protocols` in place of its own:
+/// sessions may override them.  `alternate_names` are the extra
+/// names a session accepts server certificates for: a session
+/// verified against one of them must not be resumed by a session
+/// which wouldn't accept it.
+pub fn config_digest(config: &ClientConfig,
+                     alpn_protocols: &[String],
+                     alternate_names: &[webpki::DNSName]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ProtocolNameList::from_strings(alpn_protocols).encode(&mut bytes);
+
+    let mut names = alternate_names.iter()
+        .map(|name| {
+            let name: &str = name.as_ref().into();
+            name.to_string()
+        })
+        .collect::<Vec<String>>();
+    names.sort();
+    names.dedup();
+    ProtocolNameList::from_strings(&names).encode(&mut bytes);
+
+    PayloadU16::new(config.root_store.get_digest()).encode(&mut bytes);
+
+    codec::encode_u64(config.verifier_id as u64, &mut bytes);
+    codec::encode_u64(config.name_verifier_id as u64, &mut bytes);
+    let policy = &config.verifier_policy;
+    codec::encode_u32(policy.min_rsa_bits as u32, &mut bytes);
+    codec::encode_vec_u16(&mut bytes, &policy.allowed_curves);
+    codec::encode_vec_u8(&mut bytes, &policy.allowed_hashes);
+    codec::encode_u32(config.extra_intermediates.len() as u32, &mut bytes);
+    for cert in &config.extra_intermediates {
+        PayloadU16::new(cert.0.clone()).encode(&mut bytes);
+    }
+
+    codec::encode_u8(config.client_auth_cert_resolver.has_certs() as u8, &mut bytes);
+
+    let hash = digest::digest(&digest::SHA256, &bytes);
+    hash.as_ref()[..CONFIG_DIGEST_LEN].to_vec()
+}
+
+/// A trait for the ability to store client session data, so
+/// later sessions with the same server can be resumed.
+///
+/// Data is stored per `ClientSessionKey`.  TLS1.2 sessions may
+/// be resumed many times, so are stored one per key.  TLS1.3
+/// tickets must be used only once, so are kept in a per-key
+/// pool and taken from it.
+///
+/// The stored values are opaque and should be treated as
 /// **highly sensitive data**, containing enough key material
 /// to break all security of the corresponding session.
 ///
-/// `put` is a mutating operation; this isn't expressed
+/// Storing is a mutating operation; this isn't expressed
 /// in the type system to allow implementations freedom in
 /// how to achieve interior mutability.  `Mutex` is a common
 /// choice.
 pub trait StoresClientSessions : Send + Sync {
-    /// Stores a new `value` for `key`.  Returns `true`
-    /// if the value was stored.
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool;
-
-    /// Returns the latest value for `key`.  Returns `None`
-    /// if there's no such value.
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    /// Remembers that the server for `key` chose key exchange
+    /// `group`, so we can offer it first next time.
+    fn set_kx_hint(&self, key: &ClientSessionKey, group: NamedGroup);
+
+    /// Returns the key exchange group the server for `key` last
+    /// chose, if known.
+    fn kx_hint(&self, key: &ClientSessionKey) -> Option<NamedGroup>;
+
+    /// Stores a TLS1.2 session for `key`, replacing any
+    /// existing one.
+    fn set_tls12_session(&self, key: &ClientSessionKey, value: Vec<u8>);
+
+    /// Returns the TLS1.2 session for `key`, if any, without
+    /// removing it.
+    fn tls12_session(&self, key: &ClientSessionKey) -> Option<Vec<u8>>;
+
+    /// Forgets the TLS1.2 session for `key`.  This is called
+    /// if the session is no longer usable.
+    fn remove_tls12_session(&self, key: &ClientSessionKey);
+
+    /// Adds a TLS1.3 ticket to the pool for `key`.
+    fn insert_tls13_ticket(&self, key: &ClientSessionKey, value: Vec<u8>);
+
+    /// Removes a TLS1.3 ticket from the pool for `key` and
+    /// returns it.  The same ticket must not be returned again.
+    /// Returning the most recently inserted ticket is recommended.
+    fn take_tls13_ticket(&self, key: &ClientSessionKey) -> Option<Vec<u8>>;
+
+    /// Returns false if this implementation never stores anything.
+    /// This is only used to describe configs.
+    fn enabled(&self) -> bool {
+        true
+    }
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -67,6 +216,148 @@ pub trait ResolvesClientCert : Send + Sync {
     fn has_certs(&self) -> bool;
 }
 
+/// A trait for deciding, per server, whether to send the
+/// Server Name Indication (SNI) extension.
+pub trait DecidesSNI : Send + Sync {
+    /// Return true if we should send SNI when connecting
+    /// to `dns_name`.
+    fn should_send_sni(&self, dns_name: webpki::DNSNameRef) -> bool;
+}
+
+/// The contents of a ClientHello we are about to send.
+pub struct ClientHelloInfo<'a> {
+    /// The offered ciphersuites, in preference order.
+    pub cipher_suites: &'a [CipherSuite],
+
+    /// The types of the extensions, in the order they will be sent.
+    pub extensions: &'a [ExtensionType],
+
+    /// The offered (EC)DH groups, from the supported_groups extension.
+    pub named_groups: &'a [NamedGroup],
+
+    /// The offered signature schemes, from the signature_algorithms
+    /// extension.
+    pub signature_schemes: &'a [SignatureScheme],
+}
+
+/// A session ticket which we received and stored, as reported
+/// to an `ObservesTickets`.
+#[derive(Clone, Debug)]
+pub struct ReceivedTicket<'a> {
+    /// The name of the server which issued the ticket.
+    pub server_name: webpki::DNSNameRef<'a>,
+
+    /// The protocol version of the session the ticket resumes.
+    pub version: ProtocolVersion,
+
+    /// How many seconds the ticket may be used for.  For TLS1.2
+    /// this is only a hint, and is zero if the server gave none.
+    pub lifetime: u32,
+
+    /// The most early data the server will accept when resuming
+    /// with this ticket.  This is zero if it allows none, and
+    /// always for TLS1.2.
+    pub max_early_data_size: u32,
+}
+
+/// Something which is told about each session ticket we receive
+/// from a server, once it is in `session_persistence`.  This lets
+/// a connection pool open resumed connections ahead of time, or
+/// an external store be populated asynchronously.
+///
+/// This is called synchronously during session processing, so
+/// should be quick.
+pub trait ObservesTickets : Send + Sync {
+    /// Called after `ticket` is stored.
+    fn ticket_stored(&self, ticket: &ReceivedTicket);
+}
+
+/// How the protocol version was negotiated, for detecting
+/// downgrade attacks or misconfigured intermediaries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DowngradeInfo {
+    /// The highest protocol version we offered.
+    pub offered_max: ProtocolVersion,
+
+    /// The protocol version the server chose.
+    pub negotiated: ProtocolVersion,
+
+    /// The version the server selected in its supported_versions
+    /// extension, if it sent one.  For TLS1.3 drafts this is the
+    /// draft version.
+    pub server_supported_version: Option<ProtocolVersion>,
+
+    /// True if `negotiated` is lower than `offered_max`.
+    pub downgraded: bool,
+
+    /// True if we checked the server's random for the TLS1.3
+    /// downgrade sentinel.  We do this when we offered TLS1.3, but
+    /// the server chose TLS1.2, unless `downgrade_protection` is
+    /// `DowngradeProtection::Disabled`.
+    pub sentinel_checked: bool,
+
+    /// True if the sentinel was present, meaning the server
+    /// supports TLS1.3 but something stopped it being used.
+    ///
+    /// By default this is not treated as an error, because servers
+    /// supporting a different draft of TLS1.3 to us legitimately
+    /// set it: see `ClientConfig::downgrade_protection`.
+    pub sentinel_found: bool,
+}
+
+/// A signed certificate timestamp (RFC6962) the server presented
+/// with its certificate, and what we made of it.
+#[derive(Clone, Debug)]
+pub struct ReceivedSCT {
+    /// The SCT, as sent by the server.
+    pub sct: Vec<u8>,
+
+    /// The log in `ClientConfig::ct_logs` which signed the SCT, or
+    /// why it couldn't be verified.  An SCT from a log we don't know
+    /// gives `sct::Error::UnknownLog`.
+    pub verdict: Result<&'static sct::Log<'static>, sct::Error>,
+}
+
+/// How strictly a client enforces the TLS1.3 downgrade sentinel,
+/// which a server supporting TLS1.3 puts in its random when it
+/// negotiates TLS1.2.  Seeing the sentinel when we offered TLS1.3
+/// means something stopped the server choosing TLS1.3: either an
+/// attacker, or a server implementing a different TLS1.3 draft.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DowngradeProtection {
+    /// Abort the handshake with `TLSError::DowngradeDetected`.
+    Strict,
+
+    /// Log a warning and continue.  The result is available from
+    /// `ClientSession::get_downgrade_info`.
+    LogOnly,
+
+    /// Don't look for the sentinel.  This is only useful for test
+    /// environments with deliberately downgraded servers.
+    Disabled,
+}
+
+/// A trait for observing each ClientHello before it is sent,
+/// for example to log it or audit its fingerprint.
+pub trait InspectsClientHello : Send + Sync {
+    /// Called with each ClientHello, after it is built and before
+    /// it is encoded and sent.
+    fn inspect(&self, hello: &ClientHelloInfo);
+
+    /// Optionally choose the order in which extensions are sent.
+    ///
+    /// Extensions are sent in the order of their types in the
+    /// returned list; extensions not mentioned keep their relative
+    /// order, after those that are.  A pre_shared_key extension is
+    /// always sent last, as TLS1.3 requires.
+    ///
+    /// The default implementation returns None, which means
+    /// extensions are sent in our default order.
+    fn order_extensions(&self, _hello: &ClientHelloInfo) -> Option<Vec<ExtensionType>> {
+        None
+    }
+}
+
 /// Common configuration for (typically) all connections made by
 /// a program.
 ///
@@ -90,6 +381,40 @@ pub struct ClientConfig {
     /// Our MTU.  If None, we don't limit TLS message sizes.
     pub mtu: Option<usize>,
 
+    /// The initial buffer limit for sessions made from this config:
+    /// see `Session::set_buffer_limit`.  If None, there is no limit.
+    pub buffer_limit: Option<usize>,
+
+    /// The most plaintext to put in each application data record
+    /// sent by sessions made from this config.  Smaller records let
+    /// the peer decrypt and act on data sooner, at the cost of more
+    /// per-record overhead.  This only affects what we send; unlike
+    /// `mtu` it isn't about what the network can carry, and
+    /// handshake messages are unaffected.
+    ///
+    /// If None or zero, records are as large as `mtu` allows.
+    /// The default is None.
+    pub max_appdata_record_len: Option<usize>,
+
+    /// Whether to treat TLS1.2 warning alerts, other than
+    /// close_notify, as fatal.  Normally rustls logs these and
+    /// carries on; if this is true, the session fails with
+    /// `TLSError::AlertReceived` instead.  TLS1.3 forbids
+    /// warning alerts, so they are always fatal there.
+    ///
+    /// The default is false.
+    pub fatal_warning_alerts: bool,
+
+    /// Whether reading plaintext fails with `io::ErrorKind::UnexpectedEof`
+    /// once `read_tls` has seen the end of the underlying transport
+    /// but no close_notify alert was received.  Without this, reads
+    /// then return no data, and the caller must use
+    /// `Session::peer_has_closed` to tell a truncated session from
+    /// a complete one.
+    ///
+    /// The default is false.
+    pub report_truncation: bool,
+
     /// How to decide what client auth certificate/keys to use.
     pub client_auth_cert_resolver: Arc<ResolvesClientCert>,
 
@@ -104,6 +429,19 @@ pub struct ClientConfig {
     /// is all supported versions.
     pub versions: Vec<ProtocolVersion>,
 
+    /// Key exchange groups we support, most preferred first.
+    /// The default is X25519, then NIST P-384 and P-256.
+    pub kx_groups: Vec<Arc<kx::SupportedKxGroup>>,
+
+    /// How many key shares to send in a TLS1.3 ClientHello.  We send
+    /// them for the group we remember the server choosing last time,
+    /// if any, then our most preferred `kx_groups`.  More key shares
+    /// make a bigger ClientHello, but save a HelloRetryRequest round
+    /// trip with servers preferring a different group.
+    ///
+    /// We always send at least one.  The default is 1.
+    pub key_share_count: usize,
+
     /// Collection of certificate transparency logs.
     /// If this collection is empty, then certificate transparency
     /// checking is disabled.
@@ -115,8 +453,123 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_sni: bool,
 
+    /// If set, this decides whether to send SNI for each server,
+    /// instead of `enable_sni`.  `ClientSession::new_with_sni`
+    /// overrides both.
+    ///
+    /// The default is None.
+    pub sni_decider: Option<Arc<DecidesSNI>>,
+
+    /// Whether to renegotiate a TLS1.2 session when the server asks
+    /// for it, rather than refusing with a `no_renegotiation` alert.
+    /// Some servers require this to ask for a client certificate
+    /// part-way through a session.
+    ///
+    /// Renegotiation is only done with servers which support RFC5746
+    /// secure renegotiation, and the server's renegotiation_info is
+    /// checked strictly.  Application data received from the server
+    /// while renegotiating is an error.
+    ///
+    /// The default is false.
+    pub enable_renegotiation: bool,
+
+    /// How to turn down a TLS1.2 server's request to renegotiate,
+    /// when `enable_renegotiation` is false or the server doesn't
+    /// support secure renegotiation.
+    ///
+    /// The default is `RenegotiationPolicy::Refuse`.
+    pub renegotiation_policy: RenegotiationPolicy,
+
+    /// Whether to offer and accept RSA PKCS#1 v1.5 signature
+    /// schemes.  If false, we leave them out of our
+    /// signature_algorithms extension, so TLS1.2 servers must sign
+    /// with RSA-PSS (or ECDSA), and we reject a TLS1.2 server
+    /// which uses them anyway.  TLS1.3 never uses them for
+    /// handshake signatures.
+    ///
+    /// The default is true.
+    pub enable_rsa_pkcs1: bool,
+
+    /// Something to verify TLS1.3 server signatures made with
+    /// schemes rustls doesn't implement, such as ML-DSA.  Its
+    /// schemes are added to our signature_algorithms extension.
+    ///
+    /// The default is None.
+    pub signature_verifier: Option<Arc<verify::VerifiesSignatures>>,
+
+    /// Something to call with each ClientHello before it is sent.
+    ///
+    /// The default is None.
+    pub hello_inspector: Option<Arc<InspectsClientHello>>,
+
+    /// Whether to reject a TLS1.3 ServerHello or HelloRetryRequest
+    /// which doesn't echo our ClientHello's session_id, as the
+    /// protocol requires.  Turning this off is only useful for
+    /// testing against deliberately broken servers: mismatches
+    /// are then logged and ignored.
+    ///
+    /// The default is true.
+    pub require_session_id_echo: bool,
+
+    /// What to do if the server's random says it supports TLS1.3,
+    /// but it chose TLS1.2 when we offered TLS1.3.  See
+    /// `DowngradeProtection`.
+    ///
+    /// The default is `DowngradeProtection::LogOnly`.
+    pub downgrade_protection: DowngradeProtection,
+
+    /// Counts sessions made from this config, for their
+    /// `ConnectionId`.
+    connection_counter: Arc<AtomicUsize>,
+
+    /// Something to tell about sessions which are dropped without
+    /// sending or receiving close_notify.  This is a debugging aid.
+    ///
+    /// The default is None.
+    pub unclean_close_observer: Option<Arc<ObservesUncleanClose>>,
+
+    /// Something to tell about events in each session, such as
+    /// handshakes finishing and alerts.
+    ///
+    /// The default is None.
+    pub event_observer: Option<Arc<ObservesSessionEvents>>,
+
+    /// Something to give each session's secrets to, for decrypting
+    /// its traffic later.  This is a debugging aid.
+    ///
+    /// The default is None.
+    pub key_log: Option<Arc<KeyLog>>,
+
+    /// Something to tell about each session ticket we receive.
+    ///
+    /// The default is None.
+    pub ticket_observer: Option<Arc<ObservesTickets>>,
+
     /// How to verify the server certificate chain.
     verifier: Arc<verify::ServerCertVerifier>,
+
+    /// The policy for `verifier`, if it is a `WebPKIVerifier`.
+    verifier_policy: verify::VerifierPolicy,
+
+    /// The name verifier for `verifier`, if it is a `WebPKIVerifier`.
+    server_name_verifier: Option<Arc<verify::VerifiesServerName>>,
+
+    /// Intermediate certificates for `verifier`, if it is a
+    /// `WebPKIVerifier`.
+    extra_intermediates: Vec<key::Certificate>,
+
+    /// Identifies `verifier` if it was set with
+    /// `DangerousClientConfig::set_certificate_verifier`, else zero.
+    verifier_id: usize,
+
+    /// Identifies `server_name_verifier`, or zero if there isn't one.
+    name_verifier_id: usize,
+
+    /// Application-defined extensions to send.
+    custom_extensions: Vec<Arc<custom_ext::CustomExtension>>,
+
+    /// Sees every message sent and received, for debugging.
+    message_capture: Option<Arc<capture::CapturesMessages>>,
 }
 
 impl ClientConfig {
@@ -132,20 +585,175 @@ impl ClientConfig {
             alpn_protocols: Vec::new(),
             session_persistence: handy::ClientSessionMemoryCache::new(32),
             mtu: None,
+            buffer_limit: None,
+            max_appdata_record_len: None,
+            fatal_warning_alerts: false,
+            report_truncation: false,
             client_auth_cert_resolver: Arc::new(handy::FailResolveClientCert {}),
             enable_tickets: true,
             versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            kx_groups: kx::default_kx_groups(),
+            key_share_count: 1,
             ct_logs: None,
             enable_sni: true,
-            verifier: Arc::new(verify::WebPKIVerifier::new())
+            sni_decider: None,
+            enable_renegotiation: false,
+            renegotiation_policy: RenegotiationPolicy::Refuse,
+            enable_rsa_pkcs1: true,
+            signature_verifier: None,
+            hello_inspector: None,
+            require_session_id_echo: true,
+            downgrade_protection: DowngradeProtection::LogOnly,
+            connection_counter: Arc::new(AtomicUsize::new(0)),
+            unclean_close_observer: None,
+            event_observer: None,
+            key_log: None,
+            ticket_observer: None,
+            verifier: Arc::new(verify::WebPKIVerifier::new()),
+            verifier_policy: verify::VerifierPolicy::default(),
+            server_name_verifier: None,
+            extra_intermediates: Vec::new(),
+            verifier_id: 0,
+            name_verifier_id: 0,
+            custom_extensions: Vec::new(),
+            message_capture: None,
         }
     }
 
+    /// Make a `ClientConfig` suitable for a typical web client:
+    /// the defaults of `new`, trusting `root_store`, offering HTTP/2
+    /// and HTTP/1.1 with ALPN, and with a buffer limit of 64KB.
+    ///
+    /// The result can be customised further like any other config.
+    pub fn for_web_client(root_store: anchors::RootCertStore) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.root_store = root_store;
+        config.alpn_protocols = WEB_ALPN_PROTOCOLS.iter()
+            .map(|proto| proto.to_string())
+            .collect();
+        config.buffer_limit = Some(WEB_BUFFER_LIMIT);
+        config
+    }
+
+    /// Verify server certificates using webpki with the extra
+    /// requirements in `policy`, for example to reject small RSA
+    /// keys or SHA-1 signatures.  This replaces any verifier set
+    /// with `DangerousClientConfig::set_certificate_verifier`.
+    pub fn set_verifier_policy(&mut self, policy: verify::VerifierPolicy) {
+        self.verifier_policy = policy;
+        self.use_webpki_verifier();
+    }
+
+    /// Verify server certificate chains using webpki, but let
+    /// `name_verifier` decide whether the certificate is valid for
+    /// the server's name.  This replaces any verifier set with
+    /// `DangerousClientConfig::set_certificate_verifier`.
+    pub fn set_server_name_verifier(&mut self, name_verifier: Arc<verify::VerifiesServerName>) {
+        self.server_name_verifier = Some(name_verifier);
+        self.name_verifier_id = next_verifier_id();
+        self.use_webpki_verifier();
+    }
+
+    /// Verify server certificate chains using webpki, also
+    /// considering `intermediates` when building a path to a root.
+    /// This helps with servers which don't send a complete chain.
+    ///
+    /// The intermediates are not trusted: a chain must still end
+    /// at a certificate in `root_store`.  This replaces any verifier
+    /// set with `DangerousClientConfig::set_certificate_verifier`.
+    pub fn set_extra_intermediates(&mut self, intermediates: Vec<key::Certificate>) {
+        self.extra_intermediates = intermediates;
+        self.use_webpki_verifier();
+    }
+
+    fn use_webpki_verifier(&mut self) {
+        let mut verifier = verify::WebPKIVerifier::with_policy(self.verifier_policy.clone());
+        verifier.name_verifier = self.server_name_verifier.clone();
+        verifier.extra_intermediates = self.extra_intermediates.clone();
+        self.verifier = Arc::new(verifier);
+        self.verifier_id = 0;
+    }
+
     #[doc(hidden)]
     pub fn get_verifier(&self) -> &verify::ServerCertVerifier {
         self.verifier.as_ref()
     }
 
+    /// Use the protocol versions, ciphersuites and key exchange
+    /// groups of `profile`.  This replaces `versions`, `ciphersuites`
+    /// and `kx_groups`; other settings are unchanged.
+    pub fn set_security_profile(&mut self, profile: SecurityProfile) {
+        self.versions = profile.versions();
+        self.ciphersuites = profile.ciphersuites();
+        self.kx_groups = profile.kx_groups();
+    }
+
+    /// Returns the protocol versions this config can use, most
+    /// preferred first.  Versions without any usable ciphersuite
+    /// in `ciphersuites` are left out.
+    pub fn get_effective_versions(&self) -> Vec<ProtocolVersion> {
+        suites::effective_versions(&self.versions, &self.ciphersuites)
+    }
+
+    /// Returns the ciphersuites this config can use, most preferred
+    /// first.  Suites for versions not in `versions` are left out.
+    pub fn get_effective_ciphersuites(&self) -> Vec<&'static SupportedCipherSuite> {
+        suites::effective_suites(&self.versions, &self.ciphersuites)
+    }
+
+    /// Returns the key exchange groups this config can use, most
+    /// preferred first.
+    pub fn get_kx_groups(&self) -> Vec<NamedGroup> {
+        kx::group_names(&self.kx_groups)
+    }
+
+    /// Returns the ALPN protocols, most preferred first.
+    pub fn get_alpn_protocols(&self) -> &[String] {
+        &self.alpn_protocols
+    }
+
+    /// Returns a summary of the security-relevant settings
+    /// sessions made from this config will use.
+    pub fn get_summary(&self) -> ConfigSummary {
+        ConfigSummary {
+            is_client: true,
+            versions: self.get_effective_versions(),
+            ciphersuites: self.get_effective_ciphersuites()
+                .iter()
+                .map(|scs| scs.suite)
+                .collect(),
+            kx_groups: self.get_kx_groups(),
+            alpn_protocols: self.alpn_protocols.clone(),
+            client_auth: if self.client_auth_cert_resolver.has_certs() {
+                ClientAuthMode::Optional
+            } else {
+                ClientAuthMode::Disabled
+            },
+            custom_verifier: self.verifier_id != 0 || self.name_verifier_id != 0,
+            enable_rsa_pkcs1: self.enable_rsa_pkcs1,
+            enable_renegotiation: self.enable_renegotiation,
+            renegotiation_policy: self.renegotiation_policy,
+            downgrade_protection: Some(self.downgrade_protection),
+            require_ems_for_resumption: false,
+            session_storage: self.session_persistence.enabled(),
+            config_resolver: false,
+            tickets: self.enable_tickets,
+            ticket_lifetime: None,
+        }
+    }
+
+    /// Returns the settings in this config which may be mistakes.
+    /// None of these stop the config being used.
+    pub fn get_warnings(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+
+        if self.get_effective_versions().is_empty() {
+            warnings.push(ConfigWarning::NoUsableVersions);
+        }
+
+        warnings
+    }
+
     /// Set the ALPN protocol list to the given protocol names.
     /// Overwrites any existing configured protocols.
     /// The first element in the `protocols` list is the most
@@ -189,6 +797,27 @@ impl ClientConfig {
         self.client_auth_cert_resolver = Arc::new(resolver);
     }
 
+    /// Adds an application-defined extension, which is sent in
+    /// our ClientHello.
+    ///
+    /// This fails if the extension's type is one rustls knows,
+    /// or is the type of an extension already added.
+    #[cfg(feature = "custom_extensions")]
+    pub fn add_custom_extension(&mut self, ext: Arc<custom_ext::CustomExtension>)
+                                -> Result<(), TLSError> {
+        custom_ext::check_new(&self.custom_extensions, ext.as_ref())?;
+        self.custom_extensions.push(ext);
+        Ok(())
+    }
+
+    /// Captures every TLS message sent and received by sessions
+    /// made from this config, in plaintext.  This exposes secrets,
+    /// so is only for debugging and testing.
+    #[cfg(feature = "message_capture")]
+    pub fn set_message_capture(&mut self, capture: Arc<capture::CapturesMessages>) {
+        self.message_capture = Some(capture);
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -202,8 +831,13 @@ impl ClientConfig {
 pub mod danger {
     use std::sync::Arc;
 
+    use webpki;
+
     use super::ClientConfig;
-    use super::verify::ServerCertVerifier;
+    use super::verify::{ServerCertVerifier, ServerCertVerified, ServerCertContext};
+    use anchors::RootCertStore;
+    use key::Certificate;
+    use error::TLSError;
 
     /// Accessor for dangerous configuration options.
     pub struct DangerousClientConfig<'a> {
@@ -216,6 +850,30 @@ pub mod danger {
         pub fn set_certificate_verifier(&mut self,
                                         verifier: Arc<ServerCertVerifier>) {
             self.cfg.verifier = verifier;
+            self.cfg.verifier_id = super::next_verifier_id();
+        }
+    }
+
+    /// A `ServerCertVerifier` which accepts any certificate chain,
+    /// for any name, without looking at it.
+    ///
+    /// **This completely disables server authentication.**  A session
+    /// using it is trivially open to man-in-the-middle attacks, so it
+    /// provides no more security than an unencrypted connection.  It
+    /// exists only so that tests and local development against
+    /// self-signed servers needn't write their own; never use it in
+    /// production.
+    pub struct NoServerCertVerification;
+
+    impl ServerCertVerifier for NoServerCertVerification {
+        fn verify_server_cert(&self,
+                              _roots: &RootCertStore,
+                              _presented_certs: &[Certificate],
+                              _dns_name: webpki::DNSNameRef,
+                              _ocsp_response: &[u8],
+                              _context: &ServerCertContext) -> Result<ServerCertVerified, TLSError> {
+            warn!("Server certificate NOT verified: NoServerCertVerification is in use");
+            Ok(ServerCertVerified::assertion())
         }
     }
 }
@@ -227,6 +885,15 @@ pub struct ClientSessionImpl {
     pub error: Option<TLSError>,
     pub state: Option<Box<hs::State + Send + Sync>>,
     pub server_cert_chain: CertificatePayload,
+    pub received_scts: Vec<ReceivedSCT>,
+    pub received_custom_extensions: custom_ext::ReceivedCustomExtensions,
+    pub send_sni: bool,
+    pub alternate_names: Vec<webpki::DNSName>,
+    pub downgrade_info: Option<DowngradeInfo>,
+    pub deferred_message: Option<Message>,
+    pub server_cert_verdict: Option<Result<verify::ServerCertVerified, TLSError>>,
+    pub server_warned_unrecognized_name: bool,
+    pub overrides: SessionOverrides,
 }
 
 impl fmt::Debug for ClientSessionImpl {
@@ -236,21 +903,73 @@ impl fmt::Debug for ClientSessionImpl {
 }
 
 impl ClientSessionImpl {
-    pub fn new(config: &Arc<ClientConfig>, hostname: webpki::DNSName)
+    pub fn new(config: &Arc<ClientConfig>,
+               hostname: webpki::DNSName,
+               send_sni: Option<bool>,
+               alternate_names: Vec<webpki::DNSName>,
+               overrides: SessionOverrides)
                -> ClientSessionImpl {
+        let send_sni = send_sni.unwrap_or_else(|| {
+            match config.sni_decider {
+                Some(ref decider) => decider.should_send_sni(hostname.as_ref()),
+                None => config.enable_sni,
+            }
+        });
+
+        let mut common = SessionCommon::new(config.mtu,
+                                            true,
+                                            ConnectionId::next(&config.connection_counter));
+        if let Some(limit) = config.buffer_limit {
+            common.set_buffer_limit(limit);
+        }
+        common.max_appdata_record_len = config.max_appdata_record_len;
+        common.fatal_warning_alerts = config.fatal_warning_alerts;
+        common.report_truncation = config.report_truncation;
+        common.renegotiation_policy = config.renegotiation_policy;
+        common.unclean_close_observer = config.unclean_close_observer.clone();
+        common.event_observer = config.event_observer.clone();
+        common.key_log = config.key_log.clone();
+        let name: &str = hostname.as_ref().into();
+        common.peer_name = Some(name.to_string());
+        common.message_capture = config.message_capture.clone();
+
         let mut cs = ClientSessionImpl {
             config: config.clone(),
             alpn_protocol: None,
-            common: SessionCommon::new(config.mtu, true),
+            common,
             error: None,
             state: None,
             server_cert_chain: Vec::new(),
+            received_scts: Vec::new(),
+            received_custom_extensions: Vec::new(),
+            send_sni,
+            alternate_names,
+            downgrade_info: None,
+            deferred_message: None,
+            server_cert_verdict: None,
+            server_warned_unrecognized_name: false,
+            overrides,
         };
 
         cs.state = Some(hs::start_handshake(&mut cs, hostname));
         cs
     }
 
+    /// The ALPN protocols we offer, perhaps overridden for this session.
+    pub fn alpn_protocols(&self) -> &[String] {
+        self.overrides.alpn_protocols(&self.config.alpn_protocols)
+    }
+
+    /// Is `version` enabled for this session?
+    pub fn enables_version(&self, version: ProtocolVersion) -> bool {
+        self.overrides.enables_version(&self.config.versions, version)
+    }
+
+    /// Do we use and store tickets in this session?
+    pub fn enables_tickets(&self) -> bool {
+        self.config.enable_tickets && !self.overrides.disable_resumption
+    }
+
     pub fn get_cipher_suites(&self) -> Vec<CipherSuite> {
         let mut ret = Vec::new();
 
@@ -258,7 +977,8 @@ impl ClientSessionImpl {
             ret.push(cs.suite);
         }
 
-        // We don't do renegotation at all, in fact.
+        // Indicate RFC5746 support.  This is replaced by the extension
+        // when renegotiating.
         ret.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
 
         ret
@@ -292,6 +1012,18 @@ impl ClientSessionImpl {
         !self.common.traffic
     }
 
+    pub fn get_handshake_phase(&self) -> Option<HandshakePhase> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        self.state.as_ref().map(|st| st.phase())
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.error.is_none() && self.common.is_usable()
+    }
+
     pub fn set_buffer_limit(&mut self, len: usize) {
         self.common.set_buffer_limit(len)
     }
@@ -301,6 +1033,7 @@ impl ClientSessionImpl {
         if self.common.is_tls13()
             && msg.is_content_type(ContentType::ChangeCipherSpec)
             && self.is_handshaking() {
+            self.common.capture_received(&msg);
             trace!("Dropping CCS");
             return Ok(());
         }
@@ -311,6 +1044,13 @@ impl ClientSessionImpl {
             msg = dm;
         }
 
+        self.process_plaintext_msg(msg)
+    }
+
+    /// Process `msg`, which has been decrypted if necessary.
+    fn process_plaintext_msg(&mut self, mut msg: Message) -> Result<(), TLSError> {
+        self.common.capture_received(&msg);
+
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self.common.handshake_joiner.want_message(&msg) {
@@ -331,27 +1071,67 @@ impl ClientSessionImpl {
 
         // For alerts, we have separate logic.
         if msg.is_content_type(ContentType::Alert) {
+            self.note_unrecognized_name(&msg);
             return self.common.process_alert(msg);
         }
 
         self.process_main_protocol(msg)
     }
 
+    /// Remember if the server warned us it doesn't know the name we
+    /// sent in SNI.  The handshake carries on, but it's a likely
+    /// explanation for later failures.
+    fn note_unrecognized_name(&mut self, msg: &Message) {
+        if let MessagePayload::Alert(ref alert) = msg.payload {
+            if alert.level == AlertLevel::Warning &&
+               alert.description == AlertDescription::UnrecognisedName {
+                self.server_warned_unrecognized_name = true;
+            }
+        }
+    }
+
     fn process_new_handshake_messages(&mut self) -> Result<(), TLSError> {
-        while let Some(msg) = self.common.handshake_joiner.frames.pop_front() {
-            self.process_main_protocol(msg)?;
+        while self.deferred_message.is_none() {
+            match self.common.handshake_joiner.frames.pop_front() {
+                Some(msg) => self.process_main_protocol(msg)?,
+                None => break,
+            }
         }
 
         Ok(())
     }
 
+    /// Are we waiting for the application to decide whether the
+    /// server's certificate is acceptable?
+    fn awaiting_verdict(&self) -> bool {
+        self.deferred_message.is_some() && self.server_cert_verdict.is_none()
+    }
+
+    /// Process the message which was deferred awaiting a certificate
+    /// verdict, and then any handshake messages which followed it.
+    fn process_deferred_message(&mut self) -> Result<(), TLSError> {
+        let msg = self.deferred_message.take().unwrap();
+        self.process_main_protocol(msg)?;
+        self.process_new_handshake_messages()
+    }
+
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn set_server_cert_verdict(&mut self,
+                                   verdict: Result<verify::ServerCertVerified, TLSError>) {
+        if self.deferred_message.is_none() {
+            warn!("Ignoring server cert verdict: no verification is pending");
+            return;
+        }
+
+        self.server_cert_verdict = Some(verdict);
+    }
+
     fn queue_unexpected_alert(&mut self) {
         self.common.send_fatal_alert(AlertDescription::UnexpectedMessage);
     }
 
     fn reject_renegotiation_attempt(&mut self) -> Result<(), TLSError> {
-        self.common.send_warning_alert(AlertDescription::NoRenegotiation);
-        Ok(())
+        self.common.refuse_renegotiation()
     }
 
     /// Process `msg`.  First, we get the current state.  Then we ask what messages
@@ -359,10 +1139,12 @@ impl ClientSessionImpl {
     /// to handle the message.
     fn process_main_protocol(&mut self, msg: Message) -> Result<(), TLSError> {
         // For TLS1.2, outside of the handshake, send rejection alerts for
-        // renegotation requests.  These can occur any time.
+        // renegotation requests unless we're configured to do them.
+        // These can occur any time.
         if msg.is_handshake_type(HandshakeType::HelloRequest) &&
             !self.common.is_tls13() &&
-            !self.is_handshaking() {
+            !self.is_handshaking() &&
+            !self.config.enable_renegotiation {
             return self.reject_renegotiation_attempt();
         }
 
@@ -387,19 +1169,77 @@ impl ClientSessionImpl {
             return Err(TLSError::CorruptMessage);
         }
 
-        while let Some(msg) = self.common.message_deframer.frames.pop_front() {
-            match self.process_msg(msg) {
-                Ok(_) => {}
-                Err(err) => {
-                    self.error = Some(err.clone());
-                    return Err(err);
+        loop {
+            if self.awaiting_verdict() {
+                break;
+            }
+
+            let result = if self.deferred_message.is_some() {
+                self.process_deferred_message()
+            } else {
+                match self.common.next_message() {
+                    Ok(None) => break,
+                    Ok(Some(Received::Raw(msg))) => self.process_msg(msg),
+                    Ok(Some(Received::Plaintext(msg))) => self.process_plaintext_msg(msg),
+                    Err(err) => Err(err),
                 }
+            };
+
+            if let Err(err) = result {
+                self.error = Some(err.clone());
+                return Err(err);
             }
         }
 
         Ok(())
     }
 
+    pub fn process_unbuffered(&mut self,
+                              input: &mut [u8],
+                              output: &mut [u8],
+                              plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        let mut status = UnbufferedStatus::default();
+
+        loop {
+            status.output_written += self.common.write_tls_into(&mut output[status.output_written..]);
+            self.common.take_plaintext_into(plaintext);
+
+            // Don't take on more work until our output is sent.
+            if !self.common.sendable_tls.is_empty() {
+                status.wants_write = true;
+                break;
+            }
+
+            if self.awaiting_verdict() {
+                break;
+            }
+
+            let result = if self.deferred_message.is_some() {
+                self.process_deferred_message()
+            } else {
+                match self.common.next_message_unbuffered(input,
+                                                          &mut status.input_used,
+                                                          plaintext) {
+                    Ok(None) => break,
+                    Ok(Some(Received::Raw(msg))) => self.process_msg(msg),
+                    Ok(Some(Received::Plaintext(msg))) => self.process_plaintext_msg(msg),
+                    Err(err) => Err(err),
+                }
+            };
+
+            if let Err(err) = result {
+                self.error = Some(err.clone());
+                return Err(err);
+            }
+        }
+
+        Ok(status)
+    }
+
     pub fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         if self.server_cert_chain.is_empty() {
             return None;
@@ -438,7 +1278,108 @@ impl ClientSession {
     /// we behave in the TLS protocol, `hostname` is the
     /// hostname of who we want to talk to.
     pub fn new(config: &Arc<ClientConfig>, hostname: webpki::DNSNameRef) -> ClientSession {
-        ClientSession { imp: ClientSessionImpl::new(config, hostname.into()) }
+        ClientSession { imp: ClientSessionImpl::new(config, hostname.into(), None, Vec::new(),
+                                                   SessionOverrides::default()) }
+    }
+
+    /// Make a new ClientSession, like `new`, but choosing whether
+    /// to send SNI for this session alone.  This overrides
+    /// `enable_sni` and `sni_decider` in `config`.
+    pub fn new_with_sni(config: &Arc<ClientConfig>,
+                        hostname: webpki::DNSNameRef,
+                        enable_sni: bool) -> ClientSession {
+        ClientSession { imp: ClientSessionImpl::new(config, hostname.into(), Some(enable_sni),
+                                                   Vec::new(), SessionOverrides::default()) }
+    }
+
+    /// Make a new ClientSession, like `new`, but with some of
+    /// `config`'s settings replaced by `overrides` for this session
+    /// alone.
+    pub fn new_with_overrides(config: &Arc<ClientConfig>,
+                              hostname: webpki::DNSNameRef,
+                              overrides: SessionOverrides) -> ClientSession {
+        ClientSession { imp: ClientSessionImpl::new(config, hostname.into(), None, Vec::new(),
+                                                   overrides) }
+    }
+
+    /// Make a new ClientSession, like `new`, but also accepting a
+    /// server certificate which is valid for any of `alternate_names`,
+    /// for example a service alias.  SNI uses `hostname` alone.
+    ///
+    /// Sessions made this way are stored apart from those made
+    /// for `hostname` alone, so they are never resumed by a session
+    /// which would not accept the alternate names.
+    pub fn new_with_alternate_names(config: &Arc<ClientConfig>,
+                                    hostname: webpki::DNSNameRef,
+                                    alternate_names: &[webpki::DNSNameRef]) -> ClientSession {
+        let alternate_names = alternate_names.iter()
+            .map(|name| (*name).into())
+            .collect();
+        ClientSession {
+            imp: ClientSessionImpl::new(config, hostname.into(), None, alternate_names,
+                                        SessionOverrides::default())
+        }
+    }
+
+    /// Returns the signed certificate timestamps the server sent
+    /// with its certificate, and which log verified each one, for
+    /// applications doing their own certificate transparency
+    /// auditing.
+    ///
+    /// SCTs are only asked for and checked if `ClientConfig::ct_logs`
+    /// is set.  This is empty until the server's certificate has
+    /// been verified, and for resumed sessions.
+    pub fn get_received_scts(&self) -> &[ReceivedSCT] {
+        &self.imp.received_scts
+    }
+
+    /// Returns how the protocol version was negotiated, including
+    /// whether the server chose a lower version than we offered
+    /// and whether its random carried a downgrade sentinel.
+    ///
+    /// This returns None until the ServerHello has been processed.
+    pub fn get_downgrade_info(&self) -> Option<DowngradeInfo> {
+        self.imp.downgrade_info
+    }
+
+    /// Returns true if the server sent a warning unrecognized_name
+    /// alert: it doesn't know the name we sent in SNI, and may have
+    /// carried on with a certificate for some other name.
+    ///
+    /// Applications can use this to explain a later failure, such
+    /// as a certificate which isn't valid for the name.
+    pub fn server_warned_unrecognized_name(&self) -> bool {
+        self.imp.server_warned_unrecognized_name
+    }
+
+    /// Retrieves the body of the application-defined extension of
+    /// type `typ` which the server sent in reply to ours.
+    ///
+    /// This returns None until the handshake reaches this point, or if
+    /// the server did not reply.
+    #[cfg(feature = "custom_extensions")]
+    pub fn get_received_custom_extension(&self, typ: u16) -> Option<&[u8]> {
+        custom_ext::find_received(&self.imp.received_custom_extensions, typ)
+    }
+
+    /// Returns true if the handshake is suspended because our
+    /// `ServerCertVerifier` deferred its decision.  No further
+    /// messages are processed until `set_server_cert_verdict`
+    /// is called.
+    pub fn is_awaiting_server_cert_verdict(&self) -> bool {
+        self.imp.awaiting_verdict()
+    }
+
+    /// Supply the verdict on the server's certificate, after our
+    /// `ServerCertVerifier` deferred its decision.  The handshake
+    /// resumes at the next call to `process_new_packets`; an `Err`
+    /// verdict aborts it with that error.
+    ///
+    /// This does nothing if no verification is pending.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn set_server_cert_verdict(&mut self,
+                                   verdict: Result<verify::ServerCertVerified, TLSError>) {
+        self.imp.set_server_cert_verdict(verdict)
     }
 }
 
@@ -447,11 +1388,30 @@ impl Session for ClientSession {
         self.imp.common.read_tls(rd)
     }
 
+    fn read_tls_from_buffered(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.imp.common.read_tls_from_buffered(buf)
+    }
+
+    fn read_tls_from_slice(&mut self, buf: &[u8]) -> usize {
+        self.imp.common.read_tls_from_slice(buf)
+    }
+
     /// Writes TLS messages to `wr`.
     fn write_tls(&mut self, wr: &mut io::Write) -> io::Result<usize> {
         self.imp.common.write_tls(wr)
     }
 
+    fn process_unbuffered(&mut self,
+                          input: &mut [u8],
+                          output: &mut [u8],
+                          plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError> {
+        self.imp.process_unbuffered(input, output, plaintext)
+    }
+
+    fn write_unbuffered(&mut self, plaintext: &[u8], output: &mut [u8]) -> (usize, usize) {
+        self.imp.common.write_unbuffered(plaintext, output)
+    }
+
     fn process_new_packets(&mut self) -> Result<(), TLSError> {
         self.imp.process_new_packets()
     }
@@ -468,14 +1428,46 @@ impl Session for ClientSession {
         self.imp.is_handshaking()
     }
 
+    fn get_handshake_phase(&self) -> Option<HandshakePhase> {
+        self.imp.get_handshake_phase()
+    }
+
+    fn take_events(&mut self) -> Vec<SessionEvent> {
+        self.imp.common.take_events()
+    }
+
+    fn is_usable(&self) -> bool {
+        self.imp.is_usable()
+    }
+
     fn set_buffer_limit(&mut self, len: usize) {
         self.imp.set_buffer_limit(len)
     }
 
+    fn set_max_fragment_size(&mut self, size: Option<usize>) -> Result<(), TLSError> {
+        self.imp.common.set_max_fragment_size(size)
+    }
+
+    fn set_write_coalescing(&mut self, threshold: Option<usize>) {
+        self.imp.common.set_write_coalescing(threshold)
+    }
+
     fn send_close_notify(&mut self) {
         self.imp.common.send_close_notify()
     }
 
+    fn peek(&self, buf: &mut [u8]) -> usize {
+        self.imp.common.peek(buf)
+    }
+
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.imp.common.read_uninit(buf)
+    }
+
+    fn peer_has_closed(&self) -> bool {
+        self.imp.common.peer_has_closed()
+    }
+
     fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         self.imp.get_peer_certificates()
     }
@@ -498,6 +1490,18 @@ impl Session for ClientSession {
     fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
         self.imp.get_negotiated_ciphersuite()
     }
+
+    fn get_tls12_flags(&self) -> Option<TLS12Flags> {
+        self.imp.common.tls12_flags
+    }
+
+    fn get_hello_retry_info(&self) -> Option<HelloRetryInfo> {
+        self.imp.common.hello_retry.clone()
+    }
+
+    fn get_connection_id(&self) -> ConnectionId {
+        self.imp.common.connection_id
+    }
 }
 
 impl io::Read for ClientSession {
@@ -506,6 +1510,24 @@ impl io::Read for ClientSession {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.imp.common.read(buf)
     }
+
+    /// Like `read`, but fills each of `bufs` in turn.
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        self.imp.common.read_vectored(bufs)
+    }
+}
+
+impl io::BufRead for ClientSession {
+    /// Obtain plaintext data received from the peer, without
+    /// copying it.  This returns an empty slice if none is
+    /// available yet.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.imp.common.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.imp.common.consume(amt)
+    }
 }
 
 impl io::Write for ClientSession {
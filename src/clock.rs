@@ -0,0 +1,41 @@
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Something which knows the current time.
+///
+/// By default rustls uses `std::time::SystemTime`.  Platforms where
+/// that isn't available can provide the time with `set_time_provider`.
+#[cfg(feature = "dangerous_configuration")]
+pub trait ProvidesTime : Send + Sync {
+    /// Return the time elapsed since the UNIX epoch, or None
+    /// if it isn't known.
+    fn unix_time(&self) -> Option<Duration>;
+}
+
+#[cfg(feature = "dangerous_configuration")]
+lazy_static! {
+    static ref PROVIDER: RwLock<Option<Arc<ProvidesTime>>> = RwLock::new(None);
+}
+
+/// Make rustls get the current time from `provider`, rather than
+/// from the system clock.  This affects the whole process.
+///
+/// A wrong time makes certificate and ticket expiry checks wrong.
+#[cfg(feature = "dangerous_configuration")]
+pub fn set_time_provider(provider: Arc<ProvidesTime>) {
+    *PROVIDER.write().unwrap() = Some(provider);
+}
+
+/// The time elapsed since the UNIX epoch, or None if it
+/// isn't known.
+pub fn unix_time() -> Option<Duration> {
+    #[cfg(feature = "dangerous_configuration")]
+    {
+        if let Some(ref provider) = *PROVIDER.read().unwrap() {
+            return provider.unix_time();
+        }
+    }
+
+    SystemTime::now().duration_since(UNIX_EPOCH).ok()
+}
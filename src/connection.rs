@@ -0,0 +1,224 @@
+use std::io::{self, Read, Write, BufRead};
+use std::mem::MaybeUninit;
+
+use session::{Session, SessionEvent, HandshakePhase, TLS12Flags};
+use session::{HelloRetryInfo, ConnectionId, UnbufferedStatus};
+use client::ClientSession;
+use server::ServerSession;
+use suites::SupportedCipherSuite;
+use msgs::enums::ProtocolVersion;
+use error::TLSError;
+use key;
+
+/// Either a `ClientSession` or a `ServerSession`.
+///
+/// This implements `Session`, `io::Read`, `io::Write` and
+/// `io::BufRead` by passing each call to the session it holds.
+/// It's useful for code like proxies which handle both sides:
+/// such code can hold sessions by value without needing
+/// `Box<Session>`.
+#[derive(Debug)]
+pub enum Connection {
+    /// A client session.
+    Client(ClientSession),
+
+    /// A server session.
+    Server(ServerSession),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $sess:ident => $body:expr) => {
+        match $self {
+            Connection::Client($sess) => $body,
+            Connection::Server($sess) => $body,
+        }
+    }
+}
+
+impl Connection {
+    /// Returns true if this is a client session.
+    pub fn is_client(&self) -> bool {
+        match *self {
+            Connection::Client(_) => true,
+            Connection::Server(_) => false,
+        }
+    }
+
+    /// Returns the client session, if this is one.
+    pub fn as_client(&self) -> Option<&ClientSession> {
+        match *self {
+            Connection::Client(ref sess) => Some(sess),
+            Connection::Server(_) => None,
+        }
+    }
+
+    /// Returns the server session, if this is one.
+    pub fn as_server(&self) -> Option<&ServerSession> {
+        match *self {
+            Connection::Client(_) => None,
+            Connection::Server(ref sess) => Some(sess),
+        }
+    }
+}
+
+impl From<ClientSession> for Connection {
+    fn from(sess: ClientSession) -> Connection {
+        Connection::Client(sess)
+    }
+}
+
+impl From<ServerSession> for Connection {
+    fn from(sess: ServerSession) -> Connection {
+        Connection::Server(sess)
+    }
+}
+
+impl Session for Connection {
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
+        dispatch!(self, sess => sess.read_tls(rd))
+    }
+
+    fn read_tls_from_buffered(&mut self, buf: &[u8]) -> io::Result<()> {
+        dispatch!(self, sess => sess.read_tls_from_buffered(buf))
+    }
+
+    fn read_tls_from_slice(&mut self, buf: &[u8]) -> usize {
+        dispatch!(self, sess => sess.read_tls_from_slice(buf))
+    }
+
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
+        dispatch!(self, sess => sess.write_tls(wr))
+    }
+
+    fn process_unbuffered(&mut self,
+                          input: &mut [u8],
+                          output: &mut [u8],
+                          plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError> {
+        dispatch!(self, sess => sess.process_unbuffered(input, output, plaintext))
+    }
+
+    fn write_unbuffered(&mut self, plaintext: &[u8], output: &mut [u8]) -> (usize, usize) {
+        dispatch!(self, sess => sess.write_unbuffered(plaintext, output))
+    }
+
+    fn process_new_packets(&mut self) -> Result<(), TLSError> {
+        dispatch!(self, sess => sess.process_new_packets())
+    }
+
+    fn wants_read(&self) -> bool {
+        dispatch!(self, sess => sess.wants_read())
+    }
+
+    fn wants_write(&self) -> bool {
+        dispatch!(self, sess => sess.wants_write())
+    }
+
+    fn is_handshaking(&self) -> bool {
+        dispatch!(self, sess => sess.is_handshaking())
+    }
+
+    fn get_handshake_phase(&self) -> Option<HandshakePhase> {
+        dispatch!(self, sess => sess.get_handshake_phase())
+    }
+
+    fn take_events(&mut self) -> Vec<SessionEvent> {
+        dispatch!(self, sess => sess.take_events())
+    }
+
+    fn is_usable(&self) -> bool {
+        dispatch!(self, sess => sess.is_usable())
+    }
+
+    fn set_buffer_limit(&mut self, limit: usize) {
+        dispatch!(self, sess => sess.set_buffer_limit(limit))
+    }
+
+    fn set_max_fragment_size(&mut self, size: Option<usize>) -> Result<(), TLSError> {
+        dispatch!(self, sess => sess.set_max_fragment_size(size))
+    }
+
+    fn set_write_coalescing(&mut self, threshold: Option<usize>) {
+        dispatch!(self, sess => sess.set_write_coalescing(threshold))
+    }
+
+    fn send_close_notify(&mut self) {
+        dispatch!(self, sess => sess.send_close_notify())
+    }
+
+    fn peek(&self, buf: &mut [u8]) -> usize {
+        dispatch!(self, sess => sess.peek(buf))
+    }
+
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        dispatch!(self, sess => sess.read_uninit(buf))
+    }
+
+    fn peer_has_closed(&self) -> bool {
+        dispatch!(self, sess => sess.peer_has_closed())
+    }
+
+    fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
+        dispatch!(self, sess => sess.get_peer_certificates())
+    }
+
+    fn get_alpn_protocol(&self) -> Option<&str> {
+        dispatch!(self, sess => sess.get_alpn_protocol())
+    }
+
+    fn get_protocol_version(&self) -> Option<ProtocolVersion> {
+        dispatch!(self, sess => sess.get_protocol_version())
+    }
+
+    fn export_keying_material(&self,
+                              output: &mut [u8],
+                              label: &[u8],
+                              context: Option<&[u8]>) -> Result<(), TLSError> {
+        dispatch!(self, sess => sess.export_keying_material(output, label, context))
+    }
+
+    fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
+        dispatch!(self, sess => sess.get_negotiated_ciphersuite())
+    }
+
+    fn get_tls12_flags(&self) -> Option<TLS12Flags> {
+        dispatch!(self, sess => sess.get_tls12_flags())
+    }
+
+    fn get_hello_retry_info(&self) -> Option<HelloRetryInfo> {
+        dispatch!(self, sess => sess.get_hello_retry_info())
+    }
+
+    fn get_connection_id(&self) -> ConnectionId {
+        dispatch!(self, sess => sess.get_connection_id())
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, sess => sess.read(buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        dispatch!(self, sess => sess.read_vectored(bufs))
+    }
+}
+
+impl BufRead for Connection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        dispatch!(self, sess => sess.fill_buf())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        dispatch!(self, sess => sess.consume(amt))
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, sess => sess.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(self, sess => sess.flush())
+    }
+}
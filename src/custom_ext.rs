@@ -0,0 +1,142 @@
+use msgs::base::Payload;
+#[cfg(feature = "custom_extensions")]
+use msgs::codec::Codec;
+use msgs::enums::ExtensionType;
+use msgs::handshake::{UnknownExtension, ClientExtension, ServerExtension};
+
+use std::sync::Arc;
+
+#[cfg(feature = "custom_extensions")]
+use error::TLSError;
+
+/// An application-defined TLS extension, which rustls sends
+/// and receives on the application's behalf.
+///
+/// Clients send the extension in their ClientHello.  Servers
+/// reply to it in the ServerHello (for TLS1.2) or in
+/// EncryptedExtensions (for TLS1.3), and only if the client
+/// sent it.
+///
+/// The value sent by the peer is available after the handshake
+/// from `ClientSession::get_received_custom_extension` or
+/// `ServerSession::get_received_custom_extension`.
+pub trait CustomExtension : Send + Sync {
+    /// The extension type.  This must not be an extension
+    /// which rustls knows itself, or the type of another
+    /// custom extension on the same config.
+    fn extension_type(&self) -> u16;
+
+    /// Return the body of the extension to send, or None
+    /// to not send it.
+    ///
+    /// Clients are called with `peer` as None.  Servers are called
+    /// with the body of the client's extension.
+    fn encode(&self, peer: Option<&[u8]>) -> Option<Vec<u8>>;
+}
+
+/// Check `ext` may be added to `handlers`: its type must not be
+/// one rustls knows, or one already in `handlers`.
+#[cfg(feature = "custom_extensions")]
+pub fn check_new(handlers: &[Arc<CustomExtension>],
+                 ext: &CustomExtension) -> Result<(), TLSError> {
+    let typ = ext.extension_type();
+
+    match ExtensionType::read_bytes(&[(typ >> 8) as u8, typ as u8]) {
+        Some(ExtensionType::Unknown(_)) => {}
+        _ => {
+            return Err(TLSError::General(format!("custom extension type {} is built in",
+                                                 typ)));
+        }
+    }
+
+    if handlers.iter().any(|h| h.extension_type() == typ) {
+        return Err(TLSError::General(format!("custom extension type {} already added",
+                                             typ)));
+    }
+
+    Ok(())
+}
+
+/// Extension bodies received from the peer, by extension type.
+pub type ReceivedCustomExtensions = Vec<(u16, Vec<u8>)>;
+
+#[cfg(feature = "custom_extensions")]
+pub fn find_received(received: &ReceivedCustomExtensions, typ: u16) -> Option<&[u8]> {
+    received.iter()
+        .find(|&&(t, _)| t == typ)
+        .map(|&(_, ref body)| body.as_slice())
+}
+
+fn make_unknown(typ: u16, body: Vec<u8>) -> UnknownExtension {
+    UnknownExtension {
+        typ: ExtensionType::Unknown(typ),
+        payload: Payload::new(body),
+    }
+}
+
+fn unknown_body(ext: &UnknownExtension, typ: u16) -> Option<&[u8]> {
+    if ext.typ.get_u16() == typ {
+        Some(&ext.payload.0)
+    } else {
+        None
+    }
+}
+
+/// Make the ClientHello extensions for `handlers`.
+pub fn client_extensions(handlers: &[Arc<CustomExtension>]) -> Vec<ClientExtension> {
+    handlers.iter()
+        .filter_map(|h| {
+            h.encode(None)
+                .map(|body| ClientExtension::Unknown(make_unknown(h.extension_type(), body)))
+        })
+        .collect()
+}
+
+/// Collect the server's replies to our custom extensions.
+pub fn collect_from_server(handlers: &[Arc<CustomExtension>],
+                           exts: &[ServerExtension],
+                           received: &mut ReceivedCustomExtensions) {
+    received.clear();
+
+    for h in handlers {
+        let typ = h.extension_type();
+        let body = exts.iter()
+            .filter_map(|ext| match *ext {
+                ServerExtension::Unknown(ref unk) => unknown_body(unk, typ),
+                _ => None,
+            })
+            .next();
+
+        if let Some(body) = body {
+            received.push((typ, body.to_vec()));
+        }
+    }
+}
+
+/// Collect the client's custom extensions, and make the server's
+/// replies.
+pub fn server_replies(handlers: &[Arc<CustomExtension>],
+                      exts: &[ClientExtension],
+                      received: &mut ReceivedCustomExtensions) -> Vec<ServerExtension> {
+    let mut ret = Vec::new();
+
+    for h in handlers {
+        let typ = h.extension_type();
+        let body = exts.iter()
+            .filter_map(|ext| match *ext {
+                ClientExtension::Unknown(ref unk) => unknown_body(unk, typ),
+                _ => None,
+            })
+            .next();
+
+        if let Some(body) = body {
+            received.push((typ, body.to_vec()));
+
+            if let Some(reply) = h.encode(Some(body)) {
+                ret.push(ServerExtension::Unknown(make_unknown(typ, reply)));
+            }
+        }
+    }
+
+    ret
+}
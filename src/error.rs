@@ -1,5 +1,7 @@
 use std::fmt;
+use std::io;
 use std::error::Error;
+use std::time::{Duration, SystemTime};
 use msgs::enums::{ContentType, HandshakeType, AlertDescription};
 use webpki;
 use sct;
@@ -55,6 +57,22 @@ pub enum TLSError {
     /// The presented certificate chain is invalid.
     WebPKIError(webpki::Error),
 
+    /// A certificate in the presented chain was used outside its
+    /// validity period.  `error` is `CertExpired` or `CertNotValidYet`.
+    ///
+    /// These errors used to be reported as `WebPKIError(error)`, which
+    /// is still used if the certificate can't be found.
+    CertNotValidAtTime {
+        /// What webpki said
+        error: webpki::Error,
+        /// The start of the certificate's validity period
+        not_before: SystemTime,
+        /// The end of the certificate's validity period
+        not_after: SystemTime,
+        /// When we checked the certificate
+        now: SystemTime,
+    },
+
     /// The presented SCT(s) were invalid.
     InvalidSCT(sct::Error),
 
@@ -73,6 +91,15 @@ pub enum TLSError {
 
     /// The peer sent an oversized record/fragment.
     PeerSentOversizedRecord,
+
+    /// The server's random carried the TLS1.3 downgrade sentinel,
+    /// so it supports TLS1.3 but negotiated TLS1.2 when we offered
+    /// TLS1.3.  This suggests an attack.
+    DowngradeDetected,
+
+    /// The peer asked to renegotiate a TLS1.2 session, and we're
+    /// configured to treat that as fatal: see `RenegotiationPolicy`.
+    RenegotiationRefused,
 }
 
 fn join<T: fmt::Debug>(items: &[T]) -> String {
@@ -82,6 +109,19 @@ fn join<T: fmt::Debug>(items: &[T]) -> String {
         .join(" or ")
 }
 
+/// Describe `dur` roughly, in its largest whole unit.
+fn describe_duration(dur: Duration) -> String {
+    let secs = dur.as_secs();
+    let (count, unit) = match secs {
+        s if s >= 86_400 => (s / 86_400, "day"),
+        s if s >= 3_600 => (s / 3_600, "hour"),
+        s if s >= 60 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
 impl fmt::Display for TLSError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -106,10 +146,22 @@ impl fmt::Display for TLSError {
             TLSError::PeerMisbehavedError(ref why) => write!(f, "{}: {}", self.description(), why),
             TLSError::AlertReceived(ref alert) => write!(f, "{}: {:?}", self.description(), alert),
             TLSError::WebPKIError(ref err) => write!(f, "{}: {:?}", self.description(), err),
+            TLSError::CertNotValidAtTime { ref not_before, ref not_after, ref now, .. } => {
+                if let Ok(stale) = now.duration_since(*not_after) {
+                    write!(f, "{}: expired {} ago", self.description(), describe_duration(stale))
+                } else if let Ok(early) = not_before.duration_since(*now) {
+                    write!(f, "{}: not valid for another {}", self.description(),
+                           describe_duration(early))
+                } else {
+                    write!(f, "{}", self.description())
+                }
+            }
             TLSError::CorruptMessage |
             TLSError::NoCertificatesPresented |
             TLSError::DecryptError |
             TLSError::PeerSentOversizedRecord |
+            TLSError::DowngradeDetected |
+            TLSError::RenegotiationRefused |
             TLSError::HandshakeNotComplete => write!(f, "{}", self.description()),
             _ => write!(f, "{}: {:?}", self.description(), self),
         }
@@ -131,16 +183,49 @@ impl Error for TLSError {
             TLSError::PeerMisbehavedError(_) => "peer misbehaved",
             TLSError::AlertReceived(_) => "received fatal alert",
             TLSError::WebPKIError(_) => "invalid certificate",
+            TLSError::CertNotValidAtTime { .. } => "certificate not valid at this time",
             TLSError::InvalidSCT(_) => "invalid certificate timestamp",
             TLSError::General(_) => "unexpected error", // (please file a bug),
             TLSError::FailedToGetCurrentTime => "failed to get current time",
             TLSError::InvalidDNSName(_) => "invalid DNS name",
             TLSError::HandshakeNotComplete => "handshake not complete",
             TLSError::PeerSentOversizedRecord => "peer sent excess record size",
+            TLSError::DowngradeDetected => "protocol version downgrade detected",
+            TLSError::RenegotiationRefused => "peer requested renegotiation",
         }
     }
 }
 
+impl From<TLSError> for io::Error {
+    /// Wraps `err` in an `io::Error`, which can be recovered using
+    /// `io::Error::get_ref` or `io::Error::into_inner`.
+    ///
+    /// The error kind is:
+    ///
+    /// - `ConnectionAborted` if the peer sent a fatal alert,
+    /// - `PermissionDenied` if the peer failed to authenticate,
+    /// - `InvalidInput` for an invalid DNS name,
+    /// - `NotConnected` if the handshake is not complete,
+    /// - `Other` for errors unrelated to the peer,
+    /// - otherwise `InvalidData`, as the peer sent something unacceptable.
+    fn from(err: TLSError) -> io::Error {
+        let kind = match err {
+            TLSError::AlertReceived(_) => io::ErrorKind::ConnectionAborted,
+            TLSError::NoCertificatesPresented |
+            TLSError::WebPKIError(_) |
+            TLSError::CertNotValidAtTime { .. } |
+            TLSError::InvalidSCT(_) => io::ErrorKind::PermissionDenied,
+            TLSError::InvalidDNSName(_) => io::ErrorKind::InvalidInput,
+            TLSError::HandshakeNotComplete => io::ErrorKind::NotConnected,
+            TLSError::General(_) |
+            TLSError::FailedToGetCurrentTime => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        };
+
+        io::Error::new(kind, err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -172,7 +257,9 @@ mod tests {
                        TLSError::FailedToGetCurrentTime,
                        TLSError::InvalidDNSName("dns something".to_string()),
                        TLSError::HandshakeNotComplete,
-                       TLSError::PeerSentOversizedRecord];
+                       TLSError::PeerSentOversizedRecord,
+                       TLSError::DowngradeDetected,
+                       TLSError::RenegotiationRefused];
 
         for err in all {
             println!("{:?}:", err);
@@ -180,4 +267,56 @@ mod tests {
             println!("  fmt '{}'", err);
         }
     }
+
+    #[test]
+    fn validity_display() {
+        use super::TLSError;
+        use webpki;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let not_after = not_before + Duration::from_secs(90 * 86_400);
+
+        let expired = TLSError::CertNotValidAtTime {
+            error: webpki::Error::CertExpired,
+            not_before,
+            not_after,
+            now: not_after + Duration::from_secs(3 * 86_400 + 60),
+        };
+        assert_eq!(expired.to_string(),
+                   "certificate not valid at this time: expired 3 days ago");
+
+        let early = TLSError::CertNotValidAtTime {
+            error: webpki::Error::CertNotValidYet,
+            not_before,
+            not_after,
+            now: not_before - Duration::from_secs(3_600),
+        };
+        assert_eq!(early.to_string(),
+                   "certificate not valid at this time: not valid for another 1 hour");
+    }
+
+    #[test]
+    fn into_io_error() {
+        use super::TLSError;
+        use msgs::enums::AlertDescription;
+        use webpki;
+        use std::io;
+
+        let cases = vec![
+            (TLSError::AlertReceived(AlertDescription::HandshakeFailure),
+             io::ErrorKind::ConnectionAborted),
+            (TLSError::WebPKIError(webpki::Error::UnknownIssuer),
+             io::ErrorKind::PermissionDenied),
+            (TLSError::NoCertificatesPresented, io::ErrorKind::PermissionDenied),
+            (TLSError::HandshakeNotComplete, io::ErrorKind::NotConnected),
+            (TLSError::CorruptMessage, io::ErrorKind::InvalidData),
+        ];
+
+        for (err, kind) in cases {
+            let io_err = io::Error::from(err.clone());
+            assert_eq!(io_err.kind(), kind);
+            assert_eq!(io_err.get_ref().unwrap().downcast_ref::<TLSError>(), Some(&err));
+        }
+    }
 }
@@ -122,13 +122,23 @@ impl HandshakeHash {
 
     /// Take the current hash value, and encapsulate it in a
     /// 'handshake_hash' handshake message.  Start this hash
-    /// again, with that message at the front.
-    pub fn rollup_for_hrr(&mut self) {
+    /// again, with that message at the front.  Returns the
+    /// old hash value.
+    pub fn rollup_for_hrr(&mut self) -> Vec<u8> {
         let old_hash = self.ctx.take().unwrap().finish();
         let old_handshake_hash_msg = HandshakeMessagePayload::build_handshake_hash(old_hash.as_ref());
 
         self.ctx = Some(digest::Context::new(self.alg.unwrap()));
         self.update_raw(&old_handshake_hash_msg.get_encoding());
+        old_hash.as_ref().to_vec()
+    }
+
+    /// Start this hash with a 'handshake_hash' message holding
+    /// `client_hello_hash`, as `rollup_for_hrr` would have.  This
+    /// is for carrying on a handshake after a stateless retry.
+    pub fn restore_for_hrr(&mut self, client_hello_hash: &[u8]) {
+        let handshake_hash_msg = HandshakeMessagePayload::build_handshake_hash(client_hello_hash);
+        self.update_raw(&handshake_hash_msg.get_encoding());
     }
 
     /// Get the current hash value.
@@ -1,4 +1,5 @@
 use std::fmt;
+use util;
 
 /// This type contains a private key by value.
 ///
@@ -7,9 +8,26 @@ use std::fmt;
 ///
 /// `rustls::pemfile::pkcs8_private_keys` or `rustls::pemfile::rsa_private_keys`
 /// could be used to extract private keys from a PEM file in these formats.
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// The key is left out of `Debug` output.  This type implements
+/// `Drop`, so the key can't be moved out of it: clone it instead.
+/// With the `zeroize` feature, the key is overwritten with zeroes
+/// when dropped.
+#[derive(Clone, Eq, PartialEq)]
 pub struct PrivateKey(pub Vec<u8>);
 
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrivateKey(<{} bytes redacted>)", self.0.len())
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        util::wipe(&mut self.0);
+    }
+}
+
 /// This type contains a single certificate by value.
 ///
 /// The certificate must be DER-encoded X.509.
@@ -33,10 +51,16 @@ impl fmt::Debug for Certificate {
 
 #[cfg(test)]
 mod test {
-    use super::Certificate;
+    use super::{Certificate, PrivateKey};
 
     #[test]
     fn certificate_debug() {
         assert_eq!("Certificate(b\"ab\")", format!("{:?}", Certificate(b"ab".to_vec())));
     }
+
+    #[test]
+    fn private_key_debug_is_redacted() {
+        assert_eq!("PrivateKey(<2 bytes redacted>)",
+                   format!("{:?}", PrivateKey(b"ab".to_vec())));
+    }
 }
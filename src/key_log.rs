@@ -0,0 +1,56 @@
+use session::ConnectionId;
+
+/// The label of a TLS1.2 master secret.
+pub const CLIENT_RANDOM: &str = "CLIENT_RANDOM";
+
+/// The label of a TLS1.3 client handshake traffic secret.
+pub const CLIENT_HANDSHAKE_TRAFFIC_SECRET: &str = "CLIENT_HANDSHAKE_TRAFFIC_SECRET";
+
+/// The label of a TLS1.3 server handshake traffic secret.
+pub const SERVER_HANDSHAKE_TRAFFIC_SECRET: &str = "SERVER_HANDSHAKE_TRAFFIC_SECRET";
+
+/// The label of the first TLS1.3 client application traffic secret.
+pub const CLIENT_TRAFFIC_SECRET_0: &str = "CLIENT_TRAFFIC_SECRET_0";
+
+/// The label of the first TLS1.3 server application traffic secret.
+pub const SERVER_TRAFFIC_SECRET_0: &str = "SERVER_TRAFFIC_SECRET_0";
+
+/// The label of a TLS1.3 exporter master secret.
+pub const EXPORTER_SECRET: &str = "EXPORTER_SECRET";
+
+/// Which session a key log entry comes from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyLogContext<'a> {
+    /// The session's `ConnectionId`.
+    pub connection_id: ConnectionId,
+
+    /// Whether the session is a client.
+    pub is_client: bool,
+
+    /// The name of the peer: for a client, the server name it was
+    /// made for; for a server, the SNI the client sent, if any.
+    pub peer_name: Option<&'a str>,
+}
+
+/// Something which is given the secrets of each session, so
+/// traffic can be decrypted later, by tools like Wireshark.
+///
+/// Labels and the client random are as in NSS's key log format,
+/// so an implementation writing `SSLKEYLOGFILE` lines needs only
+/// to hex-encode them.
+///
+/// This sees secret material.  Use `will_log` to log only the
+/// secrets you need, and `KeyLogContext` to choose the sessions.
+pub trait KeyLog : Send + Sync {
+    /// Whether to log secrets with `label`.  If this returns false,
+    /// `log` is not called for them.
+    ///
+    /// The default logs every secret.
+    fn will_log(&self, _label: &str) -> bool {
+        true
+    }
+
+    /// Log `secret`, of kind `label`, from the session with
+    /// `client_random` described by `context`.
+    fn log(&self, label: &str, context: &KeyLogContext, client_random: &[u8], secret: &[u8]);
+}
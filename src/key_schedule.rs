@@ -3,6 +3,8 @@
 use ring::{hmac, digest, hkdf};
 use msgs::codec;
 use error::TLSError;
+use key_log;
+use util;
 
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -30,6 +32,19 @@ impl SecretKind {
             SecretKind::DerivedSecret => b"derived",
         }
     }
+
+    /// The `KeyLog` label of this kind of secret, if it's one
+    /// we log.
+    pub fn log_label(&self) -> Option<&'static str> {
+        match *self {
+            SecretKind::ClientHandshakeTrafficSecret => Some(key_log::CLIENT_HANDSHAKE_TRAFFIC_SECRET),
+            SecretKind::ServerHandshakeTrafficSecret => Some(key_log::SERVER_HANDSHAKE_TRAFFIC_SECRET),
+            SecretKind::ClientApplicationTrafficSecret => Some(key_log::CLIENT_TRAFFIC_SECRET_0),
+            SecretKind::ServerApplicationTrafficSecret => Some(key_log::SERVER_TRAFFIC_SECRET_0),
+            SecretKind::ExporterMasterSecret => Some(key_log::EXPORTER_SECRET),
+            _ => None,
+        }
+    }
 }
 
 /// This is the TLS1.3 key schedule.  It stores the current secret,
@@ -178,6 +193,14 @@ impl KeySchedule {
     }
 }
 
+impl Drop for KeySchedule {
+    fn drop(&mut self) {
+        util::wipe(&mut self.current_client_traffic_secret);
+        util::wipe(&mut self.current_server_traffic_secret);
+        util::wipe(&mut self.current_exporter_secret);
+    }
+}
+
 fn _hkdf_expand_label_vec(secret: &hmac::SigningKey,
                           label: &[u8],
                           context: &[u8],
@@ -208,6 +231,48 @@ fn _hkdf_expand_label(output: &mut [u8],
     hkdf::expand(secret, &hkdflabel, output)
 }
 
+/// Computes HKDF-Expand-Label, as defined in RFC8446 section 7.1,
+/// using `hash`.  The "tls13 " prefix is added to `label`.
+///
+/// This is useful for deriving application-specific keys from
+/// `export_keying_material` output.  It returns an error if `label`,
+/// `context` or `len` are too long to be expressed in an HkdfLabel.
+pub fn hkdf_expand_label(hash: &'static digest::Algorithm,
+                         secret: &[u8],
+                         label: &[u8],
+                         context: &[u8],
+                         len: usize) -> Result<Vec<u8>, TLSError> {
+    if label.len() > 255 - 6 {
+        return Err(TLSError::General("hkdf_expand_label label too long".to_string()));
+    }
+
+    if context.len() > 255 {
+        return Err(TLSError::General("hkdf_expand_label context too long".to_string()));
+    }
+
+    // RFC5869 allows 255 blocks of output, but ring's block counter
+    // overflows after producing the last of them.
+    if len >= 255 * hash.output_len {
+        return Err(TLSError::General("hkdf_expand_label output too long".to_string()));
+    }
+
+    Ok(_hkdf_expand_label_vec(&hmac::SigningKey::new(hash, secret), label, context, len))
+}
+
+/// Computes Derive-Secret, as defined in RFC8446 section 7.1, using
+/// `hash`.  `messages` is the concatenation of the handshake messages
+/// to be hashed into the context.
+///
+/// It returns an error if `label` is too long to be expressed in an
+/// HkdfLabel.
+pub fn derive_secret(hash: &'static digest::Algorithm,
+                     secret: &[u8],
+                     label: &[u8],
+                     messages: &[u8]) -> Result<Vec<u8>, TLSError> {
+    let context = digest::digest(hash, messages);
+    hkdf_expand_label(hash, secret, label, context.as_ref(), hash.output_len)
+}
+
 pub fn derive_traffic_key(hash: &'static digest::Algorithm, secret: &[u8], len: usize) -> Vec<u8> {
     _hkdf_expand_label_vec(&hmac::SigningKey::new(hash, secret), b"key", &[], len)
 }
@@ -219,6 +284,7 @@ pub fn derive_traffic_iv(hash: &'static digest::Algorithm, secret: &[u8], len: u
 #[cfg(test)]
 mod test {
     use super::{KeySchedule, SecretKind, derive_traffic_key, derive_traffic_iv};
+    use super::{hkdf_expand_label, derive_secret};
     use ring::digest;
 
     #[test]
@@ -365,5 +431,43 @@ mod test {
         assert_eq!(derive_traffic_iv(hash, &got_server_ats, server_ats_iv.len()),
                    server_ats_iv.to_vec());
 
+        assert_eq!(hkdf_expand_label(hash, &server_ats, b"key", &[], server_ats_key.len()),
+                   Ok(server_ats_key.to_vec()));
+        assert_eq!(hkdf_expand_label(hash, &server_ats, b"iv", &[], server_ats_iv.len()),
+                   Ok(server_ats_iv.to_vec()));
+    }
+
+    #[test]
+    fn hkdf_expand_label_rejects_bad_lengths() {
+        let hash = &digest::SHA256;
+        let secret = [0u8; 32];
+
+        assert!(hkdf_expand_label(hash, &secret, &[b'a'; 249], &[], 16).is_ok());
+        assert!(hkdf_expand_label(hash, &secret, &[b'a'; 250], &[], 16).is_err());
+        assert!(hkdf_expand_label(hash, &secret, b"key", &[0u8; 255], 16).is_ok());
+        assert!(hkdf_expand_label(hash, &secret, b"key", &[0u8; 256], 16).is_err());
+        assert!(hkdf_expand_label(hash, &secret, b"key", &[], 255 * 32 - 1).is_ok());
+        assert!(hkdf_expand_label(hash, &secret, b"key", &[], 255 * 32).is_err());
+    }
+
+    #[test]
+    fn derive_secret_matches_rfc8448() {
+        // The early secret, and the "derived" secret made from it, for
+        // the simple 1-RTT handshake in RFC8448 section 3.
+        let early_secret = [
+            0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93,
+            0x68, 0x0c, 0xe2, 0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2,
+            0x2e, 0x10, 0xf1, 0x70, 0xf9, 0x2a
+        ];
+
+        let derived = [
+            0x6f, 0x26, 0x15, 0xa1, 0x08, 0xc7, 0x02, 0xc5, 0x67, 0x8f, 0x54, 0xfc, 0x9d,
+            0xba, 0xb6, 0x97, 0x16, 0xc0, 0x76, 0x18, 0x9c, 0x48, 0x25, 0x0c, 0xeb, 0xea,
+            0xc3, 0x57, 0x6c, 0x36, 0x11, 0xba
+        ];
+
+        assert_eq!(derive_secret(&digest::SHA256, &early_secret, b"derived", &[]),
+                   Ok(derived.to_vec()));
+        assert!(derive_secret(&digest::SHA256, &early_secret, &[b'a'; 250], &[]).is_err());
     }
 }
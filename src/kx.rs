@@ -0,0 +1,124 @@
+use msgs::enums::NamedGroup;
+
+use ring;
+use untrusted;
+
+use std::sync::Arc;
+
+/// A key exchange group, such as an elliptic curve, which can be
+/// used for ECDHE.
+///
+/// Implementations other than those provided here allow custom
+/// curves, hardware-backed key agreement or experimental schemes.
+pub trait SupportedKxGroup : Send + Sync {
+    /// The TLS name of this group.
+    fn name(&self) -> NamedGroup;
+
+    /// Start a key exchange by generating an ephemeral key pair.
+    /// Return None if this fails.
+    fn start(&self) -> Option<Box<ActiveKeyExchange>>;
+}
+
+/// An in-progress key exchange, holding our ephemeral private key.
+pub trait ActiveKeyExchange : Send + Sync {
+    /// Our public key, encoded as it is sent in a key share or
+    /// key exchange message.
+    fn pub_key(&self) -> &[u8];
+
+    /// Complete the key exchange with the peer's public key,
+    /// returning the shared secret.  Return None if `peer_pub_key`
+    /// is invalid.
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// A key exchange group implemented by *ring*.
+#[derive(Clone, Copy)]
+pub struct RingKxGroup {
+    name: NamedGroup,
+    agreement_algorithm: &'static ring::agreement::Algorithm,
+}
+
+/// X25519, implemented by *ring*.
+pub static X25519: RingKxGroup = RingKxGroup {
+    name: NamedGroup::X25519,
+    agreement_algorithm: &ring::agreement::X25519,
+};
+
+/// ECDH with the NIST P-256 curve, implemented by *ring*.
+pub static SECP256R1: RingKxGroup = RingKxGroup {
+    name: NamedGroup::secp256r1,
+    agreement_algorithm: &ring::agreement::ECDH_P256,
+};
+
+/// ECDH with the NIST P-384 curve, implemented by *ring*.
+pub static SECP384R1: RingKxGroup = RingKxGroup {
+    name: NamedGroup::secp384r1,
+    agreement_algorithm: &ring::agreement::ECDH_P384,
+};
+
+/// Returns the key exchange groups we support by default, most
+/// preferred first.
+pub fn default_kx_groups() -> Vec<Arc<SupportedKxGroup>> {
+    vec![
+        Arc::new(X25519),
+        Arc::new(SECP384R1),
+        Arc::new(SECP256R1),
+    ]
+}
+
+/// Find the group called `name` in `groups`.
+pub fn find_group(groups: &[Arc<SupportedKxGroup>],
+                  name: NamedGroup) -> Option<&Arc<SupportedKxGroup>> {
+    groups.iter()
+        .find(|group| group.name() == name)
+}
+
+/// Returns the names of `groups`, in the same order.
+pub fn group_names(groups: &[Arc<SupportedKxGroup>]) -> Vec<NamedGroup> {
+    groups.iter()
+        .map(|group| group.name())
+        .collect()
+}
+
+impl SupportedKxGroup for RingKxGroup {
+    fn name(&self) -> NamedGroup {
+        self.name
+    }
+
+    fn start(&self) -> Option<Box<ActiveKeyExchange>> {
+        let rng = ring::rand::SystemRandom::new();
+        let privkey = try_ret!(ring::agreement::EphemeralPrivateKey::generate(self.agreement_algorithm,
+                                                                              &rng).ok());
+
+        let mut pubkey = Vec::new();
+        pubkey.resize(privkey.public_key_len(), 0u8);
+        try_ret!(privkey.compute_public_key(pubkey.as_mut_slice()).ok());
+
+        Some(Box::new(RingKeyExchange {
+            agreement_algorithm: self.agreement_algorithm,
+            privkey,
+            pubkey,
+        }))
+    }
+}
+
+struct RingKeyExchange {
+    agreement_algorithm: &'static ring::agreement::Algorithm,
+    privkey: ring::agreement::EphemeralPrivateKey,
+    pubkey: Vec<u8>,
+}
+
+impl ActiveKeyExchange for RingKeyExchange {
+    fn pub_key(&self) -> &[u8] {
+        &self.pubkey
+    }
+
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Option<Vec<u8>> {
+        ring::agreement::agree_ephemeral(self.privkey,
+                                         self.agreement_algorithm,
+                                         untrusted::Input::from(peer_pub_key),
+                                         (),
+                                         |secret| Ok(secret.to_vec()))
+            .ok()
+    }
+}
@@ -126,8 +126,8 @@
 //! therefore call `client.process_new_packets()` which parses and processes the messages.
 //! Any error returned from `process_new_packets` is fatal to the session, and will tell you
 //! why.  For example, if the server's certificate is expired `process_new_packets` will
-//! return `Err(WebPKIError(CertExpired))`.  From this point on, `process_new_packets` will
-//! not do any new work and will return that error continually.
+//! return `Err(CertNotValidAtTime { error: CertExpired, .. })`.  From this point on,
+//! `process_new_packets` will not do any new work and will return that error continually.
 //!
 //! You can extract newly received data by calling `client.read()` (via the `io::Read`
 //! trait).  You can send data to the peer by calling `client.write()` (via the `io::Write`
@@ -176,9 +176,51 @@
 //!
 //! - `dangerous_configuration`: this feature enables a `dangerous()` method on
 //!   `ClientConfig` and `ServerConfig` that allows setting inadvisable options,
-//!   such as replacing the certificate verification process.  Applications
+//!   such as replacing the certificate verification process.  It also
+//!   provides `danger::NoServerCertVerification`, which turns off server
+//!   authentication entirely for testing, and the `platform` module, which
+//!   replaces rustls's source of time and randomness.  Applications
 //!   requesting this feature should be reviewed carefully.
 //!
+//! - `custom_extensions`: this feature enables sending and receiving
+//!   application-defined TLS extensions, using the `CustomExtension` trait
+//!   and `add_custom_extension` methods on `ClientConfig` and `ServerConfig`.
+//!   This is intended for protocol experimentation.
+//!
+//! - `rfc8448`: this feature enables the `rfc8448` module, containing
+//!   known-answer values from the RFC8448 TLS1.3 traces and checks of
+//!   message encoding, transcript hash and key schedule implementations
+//!   against them.  This is intended for testing.
+//!
+//! - `file_session_cache`: this feature enables `ClientSessionFileCache`,
+//!   a client session store which persists resumption data to a file.
+//!   This lets short-lived processes, like command-line tools, resume
+//!   sessions made by earlier runs.
+//!
+//! - `message_capture`: this feature enables `set_message_capture` on
+//!   `ClientConfig` and `ServerConfig`, which gives a `CapturesMessages`
+//!   implementation every TLS message a session sends and receives, in
+//!   plaintext.  This is intended for protocol debugging.
+//!
+//! - `tls12_prf`: this feature exports `tls12_prf`, the TLS1.2 PRF, for
+//!   protocols such as EAP-TLS which run it with their own labels.
+//!
+//! - `async`: this feature enables `AsyncStream`, which drives a session
+//!   over any transport implementing `AsyncRead` and `AsyncWrite` from
+//!   the `futures-io` crate.  It is not tied to any particular runtime.
+//!
+//! - `pkcs12`: this feature enables `internal::pkcs12::identity`, which
+//!   reads a private key and certificate chain from a password-protected
+//!   PKCS#12 (aka PFX) file, the usual format for client identities
+//!   issued by enterprise PKIs.
+//!
+//! - `zeroize`: this feature overwrites secrets with zeroes once rustls
+//!   is done with them, using the `zeroize` crate so the compiler can't
+//!   optimise the writes away.  This covers private keys, master and
+//!   traffic secrets, key blocks, ticket keys and stored session values.
+//!   Without this feature nothing is wiped, and secrets stay in freed
+//!   memory until it is reused.
+//!
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code,
@@ -212,6 +254,23 @@ extern crate base64;
 #[macro_use]
 extern crate log;
 
+// futures-io for AsyncStream (optional).
+#[cfg(feature = "async")]
+extern crate futures_io;
+
+// zeroize for erasing secrets (optional).
+#[cfg(feature = "zeroize")]
+extern crate zeroize;
+
+// p12-keystore for the pkcs12 module (optional).
+#[cfg(feature = "pkcs12")]
+extern crate p12_keystore;
+
+// lazy_static for the platform hooks (optional).
+#[cfg(feature = "dangerous_configuration")]
+#[macro_use]
+extern crate lazy_static;
+
 #[cfg(not(feature = "logging"))]
 #[macro_use]
 mod compile_out_log {
@@ -228,14 +287,18 @@ mod util;
 mod msgs;
 mod error;
 mod rand;
+mod clock;
 mod hash_hs;
 mod vecbuf;
 mod prf;
 mod cipher;
 mod key_schedule;
 mod session;
+mod connection;
 mod stream;
 mod pemfile;
+#[cfg(feature = "pkcs12")]
+mod pkcs12;
 mod x509;
 mod anchors;
 mod verify;
@@ -248,6 +311,11 @@ mod server;
 mod client;
 mod key;
 mod bs_debug;
+mod custom_ext;
+mod capture;
+mod summary;
+#[cfg(feature = "async")]
+mod async_stream;
 
 /// Internal classes which may be useful outside the library.
 /// The contents of this section DO NOT form part of the stable interface.
@@ -257,6 +325,13 @@ pub mod internal {
         pub use pemfile::{certs, rsa_private_keys, pkcs8_private_keys};
     }
 
+    /// Functions for reading PKCS#12 files containing a key and
+    /// its certificate chain.
+    #[cfg(feature = "pkcs12")]
+    pub mod pkcs12 {
+        pub use pkcs12::identity;
+    }
+
     /// Low-level TLS message parsing and encoding functions.
     pub mod msgs {
         pub use msgs::*;
@@ -267,32 +342,98 @@ pub mod internal {
 pub use msgs::enums::ProtocolVersion;
 pub use msgs::enums::SignatureScheme;
 pub use msgs::enums::CipherSuite;
+pub use msgs::enums::{ExtensionType, NamedGroup, AlertDescription, HashAlgorithm};
+pub use msgs::enums::AlertLevel;
+pub use msgs::enums::SignatureAlgorithm;
+pub use msgs::enums::ContentType;
+pub use msgs::enums::ECPointFormat;
+pub use msgs::handshake::KeyExchangeAlgorithm;
 pub use error::TLSError;
-pub use session::Session;
+pub use session::{Session, TLS12Flags, HelloRetryInfo, ConnectionId, UnbufferedStatus};
+pub use session::{ObservesUncleanClose, UncleanClose, LogUncleanClose};
+pub use session::{ObservesSessionEvents, SessionEvent, HandshakePhase};
+pub use session::{MAX_QUEUED_EVENTS, SessionOverrides, RenegotiationPolicy};
+pub use connection::Connection;
 pub use stream::Stream;
-pub use anchors::{DistinguishedNames, RootCertStore};
-pub use client::StoresClientSessions;
+pub use anchors::{DistinguishedNames, RootCertStore, OwnedTrustAnchor};
+pub use client::{StoresClientSessions, ClientSessionKey};
 pub use client::handy::{NoClientSessionStorage, ClientSessionMemoryCache};
 pub use client::{ClientConfig, ClientSession};
 pub use client::ResolvesClientCert;
+pub use client::{InspectsClientHello, ClientHelloInfo};
+pub use client::{DowngradeInfo, DowngradeProtection, ReceivedSCT};
+pub use client::{ObservesTickets, ReceivedTicket};
+pub use client::DecidesSNI;
 pub use server::StoresServerSessions;
 pub use server::handy::{NoServerSessionStorage, ServerSessionMemoryCache};
 pub use server::{ServerConfig, ServerSession};
 pub use server::handy::ResolvesServerCertUsingSNI;
-pub use server::ResolvesServerCert;
+pub use server::{ResolvesServerCert, ProvidesSCTs};
+pub use server::{ResolvesServerConfig, ClientHelloSummary};
+pub use server::ClientOffer;
+pub use server::ChoosesAlpnProtocol;
 pub use server::ProducesTickets;
-pub use ticketer::Ticketer;
+pub use ticketer::{Ticketer, ObservesTicketRotation};
 pub use verify::{NoClientAuth, AllowAnyAuthenticatedClient,
-                 AllowAnyAnonymousOrAuthenticatedClient};
-pub use suites::{ALL_CIPHERSUITES, SupportedCipherSuite};
+                 AllowAnyAnonymousOrAuthenticatedClient, VerifierPolicy,
+                 VerifiesServerName, VerifiesSignatures};
+pub use suites::{ALL_CIPHERSUITES, SupportedCipherSuite, BulkAlgorithm, SecurityProfile};
 pub use key::{Certificate, PrivateKey};
+pub use key_schedule::hkdf_expand_label;
+pub use summary::{ConfigSummary, ClientAuthMode, ConfigWarning};
 
 /// Message signing interfaces and implementations.
 pub mod sign;
 
+/// Key exchange interfaces and implementations.
+pub mod kx;
+
+/// Logging session secrets, for decrypting traffic when debugging.
+pub mod key_log;
+pub use key_log::{KeyLog, KeyLogContext};
+
+/// Hooks for replacing the system clock and *ring*'s random number
+/// generator, for platforms where they aren't available.
 #[cfg(feature = "dangerous_configuration")]
-pub use verify::{ServerCertVerifier, ServerCertVerified,
+pub mod platform {
+    pub use rand::{ProvidesRandomness, set_randomness_provider};
+    pub use clock::{ProvidesTime, set_time_provider};
+}
+
+/// The TLS1.3 key derivation functions from RFC8446 section 7.1,
+/// for protocols which build on TLS1.3 secrets.
+pub mod hkdf {
+    pub use key_schedule::{hkdf_expand_label, derive_secret};
+}
+
+/// Record protection interfaces, for defining new cipher suites.
+pub mod aead {
+    pub use cipher::{Tls12AeadAlgorithm, Tls13AeadAlgorithm};
+    pub use cipher::{MessageEncrypter, MessageDecrypter, PlainRecord};
+}
+
+#[cfg(feature = "rfc8448")]
+pub mod rfc8448;
+
+#[cfg(feature = "dangerous_configuration")]
+pub use verify::{ServerCertVerifier, ServerCertVerified, ServerCertContext,
     ClientCertVerifier, ClientCertVerified};
 #[cfg(feature = "dangerous_configuration")]
 pub use client::danger::DangerousClientConfig;
 
+/// Dangerous configuration options and implementations.
+#[cfg(feature = "dangerous_configuration")]
+pub mod danger {
+    pub use client::danger::{DangerousClientConfig, NoServerCertVerification};
+}
+#[cfg(feature = "custom_extensions")]
+pub use custom_ext::CustomExtension;
+#[cfg(feature = "file_session_cache")]
+pub use client::filecache::ClientSessionFileCache;
+#[cfg(feature = "message_capture")]
+pub use capture::{CapturesMessages, CapturedMessage, MessageDirection};
+#[cfg(feature = "tls12_prf")]
+pub use prf::prf as tls12_prf;
+#[cfg(feature = "async")]
+pub use async_stream::AsyncStream;
+
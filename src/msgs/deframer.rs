@@ -4,20 +4,62 @@ use std::io;
 
 use msgs::codec;
 use msgs::codec::Codec;
+use msgs::enums::{ContentType, ProtocolVersion};
 use msgs::message::Message;
 
-const HEADER_SIZE: usize = 1 + 2 + 2;
+pub const HEADER_SIZE: usize = 1 + 2 + 2;
 
 /// This is the maximum on-the-wire size of a TLSCiphertext.
 /// That's 2^14 payload bytes, a header, and a 2KB allowance
 /// for ciphertext overheads.
 const MAX_MESSAGE: usize = 16384 + 2048 + HEADER_SIZE;
 
+/// What's at the front of a buffer of received bytes.
+pub enum Frame {
+    /// Not enough bytes for a whole message.
+    Incomplete,
+
+    /// A whole message of this many bytes, including the header.
+    Complete(usize),
+
+    /// Something which isn't a TLS message.
+    Invalid,
+}
+
+/// Does `buf` start with a full message?  It does if it is big enough to
+/// contain a header, and that header has a length which falls within `buf`.
+pub fn check_frame(buf: &[u8]) -> Frame {
+    if buf.len() < HEADER_SIZE {
+        return Frame::Incomplete;
+    }
+
+    let len = match Message::check_header(buf) {
+        Some(len) => len,
+        None => return Frame::Invalid,
+    };
+
+    // This is just too large.
+    if len >= MAX_MESSAGE - HEADER_SIZE {
+        return Frame::Invalid;
+    }
+
+    if buf.len() >= len + HEADER_SIZE {
+        Frame::Complete(len + HEADER_SIZE)
+    } else {
+        Frame::Incomplete
+    }
+}
+
 /// This deframer works to reconstruct TLS messages
 /// from arbitrary-sized reads, buffering as necessary.
-/// The input is `read()`, the output is the `frames` deque.
+///
+/// Data from `read()` is kept in our buffer, so records can be
+/// decrypted in place: see `peek_record`.  Data given to
+/// `deframe()` is decoded straight onto the `frames` deque.
+/// Either way, `pop_message` returns the next message.
 pub struct MessageDeframer {
-    /// Completed frames for output.
+    /// Completed frames for output.  These come before any
+    /// records in our buffer.
     pub frames: VecDeque<Message>,
 
     /// Set to true if the peer is not talking TLS, but some other
@@ -25,9 +67,15 @@ pub struct MessageDeframer {
     /// the deframer cannot recover.
     pub desynced: bool,
 
-    /// A variable-size buffer containing the currently-
-    /// accumulating TLS message.
+    /// A variable-size buffer containing whole TLS records,
+    /// followed by the currently-accumulating TLS record.
     buf: Vec<u8>,
+
+    /// How many bytes at the start of `buf` have been taken.
+    used: usize,
+
+    /// How many bytes at the start of `buf` are whole records.
+    complete: usize,
 }
 
 impl MessageDeframer {
@@ -35,21 +83,25 @@ impl MessageDeframer {
         MessageDeframer {
             frames: VecDeque::new(),
             desynced: false,
-            buf: Vec::with_capacity(MAX_MESSAGE),
+            buf: Vec::new(),
+            used: 0,
+            complete: 0,
         }
     }
 
     /// Read some bytes from `rd`, and add them to our internal
     /// buffer.  If this means our internal buffer contains
-    /// full messages, decode them all.
+    /// full messages, they become available to `pop_message`.
     pub fn read(&mut self, rd: &mut io::Read) -> io::Result<usize> {
-        // Try to do the largest reads possible.  Note that if
-        // we get a message with a length field out of range here,
-        // we do a zero length read.  That looks like an EOF to
-        // the next layer up, which is fine.
+        self.compact();
+
+        // Try to do the largest reads possible: enough to complete
+        // a maximum size message.  A message with a length field out
+        // of range desyncs us before it gets this far.
         let used = self.buf.len();
-        self.buf.resize(MAX_MESSAGE, 0u8);
-        let rc = rd.read(&mut self.buf[used..MAX_MESSAGE]);
+        let space = MAX_MESSAGE - (used - self.complete);
+        self.buf.resize(used + space, 0u8);
+        let rc = rd.read(&mut self.buf[used..]);
 
         if rc.is_err() {
             // Discard indeterminate bytes.
@@ -59,72 +111,148 @@ impl MessageDeframer {
 
         let new_bytes = rc.unwrap();
         self.buf.truncate(used + new_bytes);
+        self.find_records();
+        Ok(new_bytes)
+    }
 
-        loop {
-            match self.buf_contains_message() {
-                None => {
-                    self.desynced = true;
-                    break;
-                }
-                Some(true) => {
-                    self.deframe_one();
-                }
-                Some(false) => break,
-            }
+    /// Decode whole messages from the front of `buf`, without
+    /// copying them into our internal buffer.  Returns how many
+    /// bytes were used: the caller owns the rest, which will be
+    /// a partial message, and should present them again with
+    /// more data appended.
+    ///
+    /// If data from `read` is still buffered, this instead appends
+    /// all of `buf` to it.
+    pub fn deframe(&mut self, buf: &[u8]) -> usize {
+        if self.used < self.buf.len() {
+            self.compact();
+            self.buf.extend_from_slice(buf);
+            self.find_records();
+            return buf.len();
         }
 
-        Ok(new_bytes)
+        self.deframe_from(buf)
     }
 
     /// Returns true if we have messages for the caller
     /// to process, either whole messages in our output
     /// queue or partial messages in our buffer.
     pub fn has_pending(&self) -> bool {
-        !self.frames.is_empty() || !self.buf.is_empty()
+        !self.frames.is_empty() || self.used < self.buf.len()
     }
 
-    /// Does our `buf` contain a full message?  It does if it is big enough to
-    /// contain a header, and that header has a length which falls within `buf`.
-    /// This returns None if it contains a header which is invalid.
-    fn buf_contains_message(&self) -> Option<bool> {
-        if self.buf.len() < HEADER_SIZE {
-            return Some(false);
+    /// Returns true if we have whole messages for the caller
+    /// to process.
+    pub fn has_whole_messages(&self) -> bool {
+        !self.frames.is_empty() || self.used < self.complete
+    }
+
+    /// Returns the content type and version of the next message,
+    /// if it is a whole record in our buffer.  Its payload is
+    /// then available from `record_payload_mut`.
+    pub fn peek_record(&self) -> Option<(ContentType, ProtocolVersion)> {
+        if !self.frames.is_empty() || self.used == self.complete {
+            return None;
         }
 
-        let len_maybe = Message::check_header(&self.buf);
+        let mut rd = codec::Reader::init(&self.buf[self.used..]);
+        let typ = ContentType::read(&mut rd).unwrap();
+        let version = ProtocolVersion::read(&mut rd).unwrap();
+        Some((typ, version))
+    }
+
+    /// The payload of the record described by `peek_record`.
+    pub fn record_payload(&self) -> &[u8] {
+        let start = self.used + HEADER_SIZE;
+        let end = self.used + self.record_len();
+        &self.buf[start..end]
+    }
+
+    /// The payload of the record described by `peek_record`, for
+    /// the caller to decrypt in place.
+    pub fn record_payload_mut(&mut self) -> &mut [u8] {
+        let start = self.used + HEADER_SIZE;
+        let end = self.used + self.record_len();
+        &mut self.buf[start..end]
+    }
+
+    /// Discard the record described by `peek_record`.
+    pub fn skip_record(&mut self) {
+        self.used += self.record_len();
+    }
+
+    /// Take the next whole message.
+    pub fn pop_message(&mut self) -> Option<Message> {
+        if let Some(m) = self.frames.pop_front() {
+            return Some(m);
+        }
 
-        // Header damaged.
-        if len_maybe == None {
+        if self.used == self.complete {
             return None;
         }
 
-        let len = len_maybe.unwrap();
+        let len = self.record_len();
+        let m = {
+            let mut rd = codec::Reader::init(&self.buf[self.used..self.used + len]);
+            Message::read(&mut rd).unwrap()
+        };
+        self.used += len;
+        Some(m)
+    }
 
-        // This is just too large.
-        if len >= MAX_MESSAGE - HEADER_SIZE {
-            return None;
+    /// The length of the whole record at `used`.
+    fn record_len(&self) -> usize {
+        match check_frame(&self.buf[self.used..self.complete]) {
+            Frame::Complete(len) => len,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Forget the bytes we've already taken.
+    fn compact(&mut self) {
+        if self.used > 0 {
+            self.buf.drain(..self.used);
+            self.complete -= self.used;
+            self.used = 0;
         }
+    }
 
-        let full_message = self.buf.len() >= len + HEADER_SIZE;
-        Some(full_message)
+    /// Note any newly-received whole records in our buffer.
+    fn find_records(&mut self) {
+        while !self.desynced {
+            match check_frame(&self.buf[self.complete..]) {
+                Frame::Incomplete => break,
+                Frame::Invalid => self.desynced = true,
+                Frame::Complete(len) => self.complete += len,
+            }
+        }
     }
 
-    /// Take a TLS message off the front of `buf`, and put it onto the back
-    /// of our `frames` deque.
-    fn deframe_one(&mut self) {
-        let used = {
-            let mut rd = codec::Reader::init(&self.buf);
-            let m = Message::read(&mut rd).unwrap();
-            self.frames.push_back(m);
-            rd.used()
-        };
-        self.buf = self.buf.split_off(used);
+    /// Take whole TLS messages off the front of `buf`, and put
+    /// them onto the back of our `frames` deque.  Returns how many
+    /// bytes were used.
+    fn deframe_from(&mut self, buf: &[u8]) -> usize {
+        let mut used = 0;
+
+        while !self.desynced {
+            match check_frame(&buf[used..]) {
+                Frame::Incomplete => break,
+                Frame::Invalid => self.desynced = true,
+                Frame::Complete(len) => {
+                    let mut rd = codec::Reader::init(&buf[used..used + len]);
+                    self.frames.push_back(Message::read(&mut rd).unwrap());
+                    used += len;
+                }
+            }
+        }
+
+        used
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MessageDeframer;
+    use super::{MessageDeframer, Frame, check_frame};
     use std::io;
     use msgs;
 
@@ -160,24 +288,36 @@ mod tests {
         }
     }
 
+    fn message_count(d: &MessageDeframer) -> usize {
+        let mut count = d.frames.len();
+        let mut offs = d.used;
+
+        while let Frame::Complete(len) = check_frame(&d.buf[offs..d.complete]) {
+            offs += len;
+            count += 1;
+        }
+
+        count
+    }
+
     fn input_bytes(d: &mut MessageDeframer, bytes: &[u8]) -> io::Result<usize> {
         let mut rd = ByteRead::new(bytes);
         d.read(&mut rd)
     }
 
     fn input_whole_incremental(d: &mut MessageDeframer, bytes: &[u8]) {
-        let frames_before = d.frames.len();
+        let frames_before = message_count(d);
 
         for i in 0..bytes.len() {
             assert_len(1, input_bytes(d, &bytes[i..i + 1]));
             assert_eq!(d.has_pending(), true);
 
             if i < bytes.len() - 1 {
-                assert_eq!(frames_before, d.frames.len());
+                assert_eq!(frames_before, message_count(d));
             }
         }
 
-        assert_eq!(frames_before + 1, d.frames.len());
+        assert_eq!(frames_before + 1, message_count(d));
     }
 
     fn assert_len(want: usize, got: io::Result<usize>) {
@@ -189,13 +329,13 @@ mod tests {
     }
 
     fn pop_first(d: &mut MessageDeframer) {
-        let mut m = d.frames.pop_front().unwrap();
+        let mut m = d.pop_message().unwrap();
         m.decode_payload();
         assert_eq!(m.typ, msgs::enums::ContentType::Handshake);
     }
 
     fn pop_second(d: &mut MessageDeframer) {
-        let mut m = d.frames.pop_front().unwrap();
+        let mut m = d.pop_message().unwrap();
         m.decode_payload();
         assert_eq!(m.typ, msgs::enums::ContentType::Alert);
     }
@@ -206,7 +346,7 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         input_whole_incremental(&mut d, FIRST_MESSAGE);
         assert_eq!(d.has_pending(), true);
-        assert_eq!(1, d.frames.len());
+        assert_eq!(1, message_count(&d));
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
@@ -219,7 +359,7 @@ mod tests {
         assert_eq!(d.has_pending(), true);
         input_whole_incremental(&mut d, SECOND_MESSAGE);
         assert_eq!(d.has_pending(), true);
-        assert_eq!(2, d.frames.len());
+        assert_eq!(2, message_count(&d));
         pop_first(&mut d);
         assert_eq!(d.has_pending(), true);
         pop_second(&mut d);
@@ -232,7 +372,7 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert_eq!(d.has_pending(), true);
-        assert_eq!(d.frames.len(), 1);
+        assert_eq!(message_count(&d), 1);
         pop_first(&mut d);
         assert_eq!(d.has_pending(), false);
     }
@@ -243,9 +383,73 @@ mod tests {
         assert_eq!(d.has_pending(), false);
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(message_count(&d), 2);
+        pop_first(&mut d);
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_deframe_whole() {
+        let mut d = MessageDeframer::new();
+        let mut both = FIRST_MESSAGE.to_vec();
+        both.extend_from_slice(SECOND_MESSAGE);
+        assert_eq!(d.deframe(&both), both.len());
+        assert_eq!(message_count(&d), 2);
         pop_first(&mut d);
         pop_second(&mut d);
         assert_eq!(d.has_pending(), false);
     }
+
+    #[test]
+    fn check_deframe_leaves_partial() {
+        let mut d = MessageDeframer::new();
+        let mut buf = FIRST_MESSAGE.to_vec();
+        buf.extend_from_slice(&SECOND_MESSAGE[..3]);
+        assert_eq!(d.deframe(&buf), FIRST_MESSAGE.len());
+        assert_eq!(d.deframe(&SECOND_MESSAGE[..3]), 0);
+        assert_eq!(d.deframe(&[]), 0);
+        pop_first(&mut d);
+        assert_eq!(d.has_pending(), false);
+
+        assert_eq!(d.deframe(SECOND_MESSAGE), SECOND_MESSAGE.len());
+        pop_second(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_deframe_after_read() {
+        let mut d = MessageDeframer::new();
+        assert_len(3, input_bytes(&mut d, &FIRST_MESSAGE[..3]));
+        assert_eq!(d.deframe(&FIRST_MESSAGE[3..]), FIRST_MESSAGE.len() - 3);
+        pop_first(&mut d);
+        assert_eq!(d.has_pending(), false);
+    }
+
+    #[test]
+    fn check_deframe_junk() {
+        let mut d = MessageDeframer::new();
+        assert_eq!(d.deframe(b"GET / HTTP/1.1\r\n"), 0);
+        assert_eq!(d.desynced, true);
+        assert_eq!(message_count(&d), 0);
+    }
+
+    #[test]
+    fn check_records_in_place() {
+        let mut d = MessageDeframer::new();
+        let mut both = FIRST_MESSAGE.to_vec();
+        both.extend_from_slice(SECOND_MESSAGE);
+        assert_len(both.len(), input_bytes(&mut d, &both));
+
+        assert_eq!(d.peek_record(),
+                   Some((msgs::enums::ContentType::Handshake, msgs::enums::ProtocolVersion::TLSv1_0)));
+        assert_eq!(d.record_payload(), &FIRST_MESSAGE[5..]);
+        d.skip_record();
+
+        assert_eq!(d.peek_record().map(|(typ, _)| typ),
+                   Some(msgs::enums::ContentType::Alert));
+        pop_second(&mut d);
+        assert_eq!(d.peek_record(), None);
+        assert_eq!(d.has_pending(), false);
+    }
 }
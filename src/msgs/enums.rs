@@ -289,6 +289,10 @@ enum_builder! {@U16
         secp521r1 => 0x0019,
         X25519 => 0x001d,
         X448 => 0x001e,
+        brainpoolP256r1tls13 => 0x001f,
+        brainpoolP384r1tls13 => 0x0020,
+        brainpoolP512r1tls13 => 0x0021,
+        curveSM2 => 0x0029,
         FFDHE2048 => 0x0100,
         FFDHE3072 => 0x0101,
         FFDHE4096 => 0x0102,
@@ -489,6 +493,8 @@ enum_builder! {@U16
         TLS_DHE_DSS_WITH_CAMELLIA_256_CBC_SHA256 => 0x00c3,
         TLS_DHE_RSA_WITH_CAMELLIA_256_CBC_SHA256 => 0x00c4,
         TLS_DH_anon_WITH_CAMELLIA_256_CBC_SHA256 => 0x00c5,
+        TLS13_SM4_GCM_SM3 => 0x00c6,
+        TLS13_SM4_CCM_SM3 => 0x00c7,
         TLS_EMPTY_RENEGOTIATION_INFO_SCSV => 0x00ff,
         TLS13_AES_128_GCM_SHA256 => 0x1301,
         TLS13_AES_256_GCM_SHA384 => 0x1302,
@@ -727,11 +733,18 @@ enum_builder! {@U16
         ECDSA_NISTP384_SHA384 => 0x0503,
         RSA_PKCS1_SHA512 => 0x0601,
         ECDSA_NISTP521_SHA512 => 0x0603,
+        SM2SIG_SM3 => 0x0708,
         RSA_PSS_SHA256 => 0x0804,
         RSA_PSS_SHA384 => 0x0805,
         RSA_PSS_SHA512 => 0x0806,
         ED25519 => 0x0807,
-        ED448 => 0x0808
+        ED448 => 0x0808,
+        ECDSA_BRAINPOOLP256R1TLS13_SHA256 => 0x081a,
+        ECDSA_BRAINPOOLP384R1TLS13_SHA384 => 0x081b,
+        ECDSA_BRAINPOOLP512R1TLS13_SHA512 => 0x081c,
+        ML_DSA_44 => 0x0904,
+        ML_DSA_65 => 0x0905,
+        ML_DSA_87 => 0x0906
     }
 }
 
@@ -64,7 +64,7 @@ fn test_enums() {
     test_enum8::<ECPointFormat>(ECPointFormat::Uncompressed, ECPointFormat::ANSIX962CompressedChar2);
     test_enum8::<HeartbeatMode>(HeartbeatMode::PeerAllowedToSend, HeartbeatMode::PeerNotAllowedToSend);
     test_enum8::<ECCurveType>(ECCurveType::ExplicitPrime, ECCurveType::NamedCurve);
-    test_enum16::<SignatureScheme>(SignatureScheme::RSA_PKCS1_SHA1, SignatureScheme::ED448);
+    test_enum16::<SignatureScheme>(SignatureScheme::RSA_PKCS1_SHA1, SignatureScheme::ML_DSA_87);
     test_enum8::<PSKKeyExchangeMode>(PSKKeyExchangeMode::PSK_KE, PSKKeyExchangeMode::PSK_DHE_KE);
     test_enum8::<KeyUpdateRequest>(KeyUpdateRequest::UpdateNotRequested, KeyUpdateRequest::UpdateRequested);
     test_enum8::<CertificateStatusType>(CertificateStatusType::OCSP, CertificateStatusType::OCSP);
@@ -20,6 +20,11 @@ impl MessageFragmenter {
         MessageFragmenter { max_frag: max_fragment_len }
     }
 
+    /// The largest fragment this will produce.
+    pub fn max_fragment_len(&self) -> usize {
+        self.max_frag
+    }
+
     /// Take the Message `msg` and re-fragment it into new
     /// messages whose fragment is no more than max_frag.
     /// The new messages are appended to the `out` deque.
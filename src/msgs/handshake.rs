@@ -64,6 +64,10 @@ static HELLO_RETRY_REQUEST_RANDOM: Random = Random([
 
 static ZERO_RANDOM: Random = Random([0u8; 32]);
 
+/// The last 8 bytes of a TLS1.3-capable server's random, when it
+/// negotiates TLS1.2.
+pub static TLS12_DOWNGRADE_SENTINEL: [u8; 8] = *b"DOWNGRD\x01";
+
 impl Codec for Random {
     fn encode(&self, bytes: &mut Vec<u8>) {
         bytes.extend_from_slice(&self.0);
@@ -88,6 +92,12 @@ impl Random {
         let buf = self.get_encoding();
         bytes.write_all(&buf).unwrap();
     }
+
+    /// Does this server random say that the server supports
+    /// TLS1.3, but negotiated TLS1.2?
+    pub fn has_tls12_downgrade_sentinel(&self) -> bool {
+        self.0[24..] == TLS12_DOWNGRADE_SENTINEL
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -225,9 +235,20 @@ declare_u16_vec!(SupportedSignatureSchemes, SignatureScheme);
 pub trait DecomposedSignatureScheme {
     fn sign(&self) -> SignatureAlgorithm;
     fn make(alg: SignatureAlgorithm, hash: HashAlgorithm) -> SignatureScheme;
+    fn is_rsa_pkcs1(&self) -> bool;
 }
 
 impl DecomposedSignatureScheme for SignatureScheme {
+    fn is_rsa_pkcs1(&self) -> bool {
+        match *self {
+            SignatureScheme::RSA_PKCS1_SHA1 |
+                SignatureScheme::RSA_PKCS1_SHA256 |
+                SignatureScheme::RSA_PKCS1_SHA384 |
+                SignatureScheme::RSA_PKCS1_SHA512 => true,
+            _ => false,
+        }
+    }
+
     fn sign(&self) -> SignatureAlgorithm {
         match *self {
             SignatureScheme::RSA_PKCS1_SHA1 |
@@ -294,6 +315,11 @@ impl SupportedMandatedSignatureSchemes for SupportedSignatureSchemes {
             SignatureScheme::RSA_PSS_SHA512,
             SignatureScheme::RSA_PSS_SHA384,
             SignatureScheme::RSA_PSS_SHA256,
+
+            /* Only chosen by externally provided signing keys. */
+            SignatureScheme::ML_DSA_87,
+            SignatureScheme::ML_DSA_65,
+            SignatureScheme::ML_DSA_44,
         ]
     }
 }
@@ -602,6 +628,7 @@ pub enum ClientExtension {
     ExtendedMasterSecretRequest,
     CertificateStatusRequest(CertificateStatusRequest),
     SignedCertificateTimestampRequest,
+    RenegotiationInfo(PayloadU8),
     Unknown(UnknownExtension),
 }
 
@@ -623,6 +650,7 @@ impl ClientExtension {
             ClientExtension::ExtendedMasterSecretRequest => ExtensionType::ExtendedMasterSecret,
             ClientExtension::CertificateStatusRequest(_) => ExtensionType::StatusRequest,
             ClientExtension::SignedCertificateTimestampRequest => ExtensionType::SCT,
+            ClientExtension::RenegotiationInfo(_) => ExtensionType::RenegotiationInfo,
             ClientExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -649,6 +677,7 @@ impl Codec for ClientExtension {
             ClientExtension::PresharedKey(ref r) => r.encode(&mut sub),
             ClientExtension::Cookie(ref r) => r.encode(&mut sub),
             ClientExtension::CertificateStatusRequest(ref r) => r.encode(&mut sub),
+            ClientExtension::RenegotiationInfo(ref r) => r.encode(&mut sub),
             ClientExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -708,6 +737,9 @@ impl Codec for ClientExtension {
             ExtensionType::SCT if !sub.any_left() => {
                 ClientExtension::SignedCertificateTimestampRequest
             }
+            ExtensionType::RenegotiationInfo => {
+                ClientExtension::RenegotiationInfo(try_ret!(PayloadU8::read(&mut sub)))
+            }
             _ => ClientExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub))),
         })
     }
@@ -946,6 +978,14 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::SessionTicket)
     }
 
+    pub fn get_cookie(&self) -> Option<&PayloadU16> {
+        let ext = try_ret!(self.find_extension(ExtensionType::Cookie));
+        match *ext {
+            ClientExtension::Cookie(ref ck) => Some(ck),
+            _ => None,
+        }
+    }
+
     pub fn get_versions_extension(&self) -> Option<&ProtocolVersions> {
         let ext = try_ret!(self.find_extension(ExtensionType::SupportedVersions));
         match *ext {
@@ -1257,6 +1297,14 @@ impl ServerHelloPayload {
             .is_some()
     }
 
+    pub fn get_renegotiation_info(&self) -> Option<&PayloadU8> {
+        let ext = try_ret!(self.find_extension(ExtensionType::RenegotiationInfo));
+        match *ext {
+            ServerExtension::RenegotiationInfo(ref info) => Some(info),
+            _ => None,
+        }
+    }
+
     pub fn get_sct_list(&self) -> Option<&SCTList> {
         let ext = try_ret!(self.find_extension(ExtensionType::SCT));
         match *ext {
@@ -1925,12 +1973,14 @@ impl Codec for NewSessionTicketPayload {
 // -- NewSessionTicket electric boogaloo --
 #[derive(Debug)]
 pub enum NewSessionTicketExtension {
+    EarlyData(u32),
     Unknown(UnknownExtension),
 }
 
 impl NewSessionTicketExtension {
     pub fn get_type(&self) -> ExtensionType {
         match *self {
+            NewSessionTicketExtension::EarlyData(_) => ExtensionType::EarlyData,
             NewSessionTicketExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -1942,6 +1992,7 @@ impl Codec for NewSessionTicketExtension {
 
         let mut sub: Vec<u8> = Vec::new();
         match *self {
+            NewSessionTicketExtension::EarlyData(max_size) => codec::encode_u32(max_size, &mut sub),
             NewSessionTicketExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1955,6 +2006,9 @@ impl Codec for NewSessionTicketExtension {
         let mut sub = try_ret!(r.sub(len));
 
         Some(match typ {
+            ExtensionType::EarlyData => {
+                NewSessionTicketExtension::EarlyData(try_ret!(codec::read_u32(&mut sub)))
+            }
             _ => {
                 NewSessionTicketExtension::Unknown(try_ret!(UnknownExtension::read(typ, &mut sub)))
             }
@@ -1986,6 +2040,15 @@ impl NewSessionTicketPayloadTLS13 {
             exts: vec![],
         }
     }
+
+    pub fn get_max_early_data_size(&self) -> Option<u32> {
+        self.exts.iter()
+            .filter_map(|ext| match *ext {
+                NewSessionTicketExtension::EarlyData(max_size) => Some(max_size),
+                _ => None,
+            })
+            .next()
+    }
 }
 
 impl Codec for NewSessionTicketPayloadTLS13 {
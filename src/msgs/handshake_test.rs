@@ -23,6 +23,16 @@ fn reads_random() {
     assert_eq!(rd.any_left(), false);
 }
 
+#[test]
+fn detects_downgrade_sentinel() {
+    let mut bytes = [0x01; 32];
+    assert_eq!(Random::from_slice(&bytes).has_tls12_downgrade_sentinel(), false);
+    bytes[24..].copy_from_slice(b"DOWNGRD\x01");
+    assert_eq!(Random::from_slice(&bytes).has_tls12_downgrade_sentinel(), true);
+    bytes[31] = 0x00;
+    assert_eq!(Random::from_slice(&bytes).has_tls12_downgrade_sentinel(), false);
+}
+
 #[test]
 fn rejects_truncated_sessionid() {
     let bytes = [32; 32];
@@ -345,6 +355,7 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::ExtendedMasterSecretRequest,
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
+            ClientExtension::RenegotiationInfo(PayloadU8(vec![1, 2, 3])),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![ 1, 2, 3 ])
@@ -479,6 +490,7 @@ fn get_sample_newsessionticketpayloadtls13() -> NewSessionTicketPayloadTLS13 {
         nonce: PayloadU8(vec![ 1, 2, 3 ]),
         ticket: PayloadU16(vec![ 4, 5, 6 ]),
         exts: vec![
+            NewSessionTicketExtension::EarlyData(16384),
             NewSessionTicketExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![ 1, 2, 3 ])
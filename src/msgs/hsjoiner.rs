@@ -8,6 +8,12 @@ use msgs::handshake::HandshakeMessagePayload;
 
 const HEADER_SIZE: usize = 1 + 3;
 
+/// The largest handshake message we'll reassemble.  Without a
+/// limit, a peer could make us buffer an endless stream of
+/// fragments.  This is generous: the largest messages are
+/// certificate chains.
+pub const MAX_HANDSHAKE_SIZE: usize = 0xffff;
+
 /// This works to reconstruct TLS handshake messages
 /// from individual TLS messages.  It's guaranteed that
 /// TLS messages output from this layer contain precisely
@@ -43,7 +49,8 @@ impl HandshakeJoiner {
     /// Return the number of new messages added to the
     /// output deque as a result of this message.
     ///
-    /// Returns None if msg or a preceding message was corrupt.
+    /// Returns None if msg or a preceding message was corrupt,
+    /// or if it starts a message longer than `MAX_HANDSHAKE_SIZE`.
     /// You cannot recover from this situation.  Otherwise returns
     /// a count of how many messages we queued.
     pub fn take_message(&mut self, mut msg: Message) -> Option<usize> {
@@ -62,9 +69,20 @@ impl HandshakeJoiner {
             count += 1;
         }
 
+        if self.buf_too_large() {
+            return None;
+        }
+
         Some(count)
     }
 
+    /// Is `buf` accumulating a message which is too large?  We know
+    /// as soon as we have its header.
+    fn buf_too_large(&self) -> bool {
+        self.buf.len() >= HEADER_SIZE &&
+        codec::decode_u24(&self.buf[1..4]).unwrap() as usize > MAX_HANDSHAKE_SIZE
+    }
+
     /// Does our `buf` contain a full handshake payload?  It does if it is big
     /// enough to contain a header, and that header has a length which falls
     /// within `buf`.
@@ -186,6 +204,33 @@ mod tests {
         assert_eq!(hj.take_message(msg), None);
     }
 
+    #[test]
+    fn too_large() {
+        // Check we give up on a message longer than MAX_HANDSHAKE_SIZE
+        // once we see its header, rather than buffering it.
+        let mut hj = HandshakeJoiner::new();
+
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x0b\x01\x00\x00\x00\x00".to_vec()),
+        };
+
+        assert_eq!(hj.take_message(msg), None);
+
+        // But one at the limit is fine.
+        let mut hj = HandshakeJoiner::new();
+
+        let msg = Message {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::new_opaque(b"\x0b\x00\xff\xff\x00\x00".to_vec()),
+        };
+
+        assert_eq!(hj.take_message(msg), Some(0));
+        assert_eq!(hj.is_empty(), false);
+    }
+
     #[test]
     fn join() {
         // Check we join one handshake message split over two PDUs.
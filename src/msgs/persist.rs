@@ -4,6 +4,7 @@ use msgs::codec::{Reader, Codec};
 use msgs::handshake::CertificatePayload;
 use msgs::base::{PayloadU8, PayloadU16};
 use msgs::codec;
+use util;
 
 use webpki;
 use untrusted;
@@ -14,44 +15,6 @@ use std::cmp;
 // These are the keys and values we store in session storage.
 
 // --- Client types ---
-/// Keys for session resumption and tickets.
-/// Matching value is a `ClientSessionValue`.
-#[derive(Debug)]
-pub struct ClientSessionKey {
-    kind: &'static [u8],
-    dns_name: PayloadU8,
-}
-
-impl Codec for ClientSessionKey {
-    fn encode(&self, bytes: &mut Vec<u8>) {
-        bytes.extend_from_slice(self.kind);
-        self.dns_name.encode(bytes);
-    }
-
-    // Don't need to read these.
-    fn read(_r: &mut Reader) -> Option<ClientSessionKey> {
-        None
-    }
-}
-
-impl ClientSessionKey {
-    pub fn session_for_dns_name(dns_name: webpki::DNSNameRef) -> ClientSessionKey {
-        let dns_name_str: &str = dns_name.into();
-        ClientSessionKey {
-            kind: b"session",
-            dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
-        }
-    }
-
-    pub fn hint_for_dns_name(dns_name: webpki::DNSNameRef) -> ClientSessionKey {
-        let dns_name_str: &str = dns_name.into();
-        ClientSessionKey {
-            kind: b"kx-hint",
-            dns_name: PayloadU8::new(dns_name_str.as_bytes().to_vec()),
-        }
-    }
-}
-
 #[derive(Debug)]
 pub struct ClientSessionValue {
     pub version: ProtocolVersion,
@@ -153,6 +116,12 @@ impl ClientSessionValue {
     }
 }
 
+impl Drop for ClientSessionValue {
+    fn drop(&mut self) {
+        util::wipe(&mut self.master_secret.0);
+    }
+}
+
 // --- Server types ---
 pub type ServerSessionKey = SessionID;
 
@@ -236,3 +205,9 @@ impl ServerSessionValue {
         self.extended_ms = true;
     }
 }
+
+impl Drop for ServerSessionValue {
+    fn drop(&mut self) {
+        util::wipe(&mut self.master_secret.0);
+    }
+}
@@ -0,0 +1,27 @@
+use p12_keystore::KeyStore;
+
+use key;
+use error::TLSError;
+
+/// Extract the first private key and its certificate chain from
+/// the PKCS#12 (aka PFX) file `der`, which is encrypted with
+/// `password`.
+///
+/// The results are in the form `ServerConfig::set_single_cert` and
+/// `ClientConfig::set_single_client_cert` expect: the chain starts
+/// with the end-entity certificate, and the key is PKCS#8.
+pub fn identity(der: &[u8],
+                password: &str) -> Result<(Vec<key::Certificate>, key::PrivateKey), TLSError> {
+    let store = KeyStore::from_pkcs12(der, password)
+        .map_err(|err| TLSError::General(format!("cannot read PKCS#12 file: {}", err)))?;
+
+    let (_, chain) = store.private_key_chain()
+        .ok_or_else(|| TLSError::General("PKCS#12 file has no private key".to_string()))?;
+
+    let certs = chain.chain()
+        .iter()
+        .map(|cert| key::Certificate(cert.as_der().to_vec()))
+        .collect();
+
+    Ok((certs, key::PrivateKey(chain.key().to_vec())))
+}
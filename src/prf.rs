@@ -35,6 +35,12 @@ fn concat(a: &[u8], b: &[u8]) -> Vec<u8> {
     ret
 }
 
+/// The TLS1.2 PRF, as defined in RFC5246 section 5, using `hashalg`.
+/// This fills `out` with PRF(`secret`, `label`, `seed`).
+///
+/// This is for protocols, like EAP-TLS, which need to derive keys with
+/// their own labels from secrets they already hold.  Prefer
+/// `Session::export_keying_material` where that is enough.
 pub fn prf(out: &mut [u8],
            hashalg: &'static digest::Algorithm,
            secret: &[u8],
@@ -6,11 +6,47 @@
 use ring::rand::{SystemRandom, SecureRandom};
 use msgs::codec;
 
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::{Arc, RwLock};
+
+/// A source of cryptographically secure random material.
+///
+/// By default rustls uses *ring*'s `SystemRandom`.  Platforms where
+/// that isn't available can provide randomness with
+/// `set_randomness_provider`.  *ring* still uses its own source when
+/// generating ephemeral and signing keys.
+#[cfg(feature = "dangerous_configuration")]
+pub trait ProvidesRandomness : Send + Sync {
+    /// Fill the whole of `bytes` with random material.  This
+    /// should panic if that's impossible: there's no safe way
+    /// to continue.
+    fn fill(&self, bytes: &mut [u8]);
+}
+
+#[cfg(feature = "dangerous_configuration")]
+lazy_static! {
+    static ref PROVIDER: RwLock<Option<Arc<ProvidesRandomness>>> = RwLock::new(None);
+}
+
+/// Make rustls get random material from `provider`, rather than
+/// from *ring*.  This affects the whole process.
+///
+/// A weak `provider` breaks the security of every session.
+#[cfg(feature = "dangerous_configuration")]
+pub fn set_randomness_provider(provider: Arc<ProvidesRandomness>) {
+    *PROVIDER.write().unwrap() = Some(provider);
+}
+
 /// Fill the whole slice with random material.
 pub fn fill_random(bytes: &mut [u8]) {
-    SystemRandom::new()
-        .fill(bytes)
-        .unwrap();
+    #[cfg(feature = "dangerous_configuration")]
+    {
+        if let Some(ref provider) = *PROVIDER.read().unwrap() {
+            return provider.fill(bytes);
+        }
+    }
+
+    SystemRandom::new().fill(bytes).unwrap();
 }
 
 /// Make a Vec<u8> of the given size
@@ -22,6 +58,14 @@ pub fn random_vec(len: usize) -> Vec<u8> {
     v
 }
 
+/// Return a uniformly random u64.
+pub fn random_u64() -> u64 {
+    let mut buf = [0u8; 8];
+    fill_random(&mut buf);
+    codec::decode_u64(&buf)
+        .unwrap()
+}
+
 /// Return a uniformly random u32.
 pub fn random_u32() -> u32 {
     let mut buf = [0u8; 4];
@@ -0,0 +1,281 @@
+//! Known-answer values from the "Simple 1-RTT Handshake" trace in
+//! RFC8448 section 3, and checks of TLS1.3 implementations against
+//! them.
+//!
+//! The trace uses SHA256 throughout.  Its ClientHello and ServerHello
+//! are replayed through our message codec and the transcript hash,
+//! and its key schedule through ours or one supplied by the caller.
+//!
+//! The trace can't be replayed through the state machine: it is of
+//! final RFC8446 TLS1.3, which rustls doesn't yet negotiate, and its
+//! later messages depend on private keys which can't be given to *ring*.
+
+use key_schedule::{KeySchedule, SecretKind, hkdf_expand_label};
+use msgs::codec::Codec;
+use msgs::enums::HandshakeType;
+use msgs::handshake::HandshakeMessagePayload;
+use ring::digest;
+
+/// The client's ClientHello handshake message.
+pub const CLIENT_HELLO: [u8; 196] = [
+    0x01, 0x00, 0x00, 0xc0, 0x03, 0x03, 0xcb, 0x34, 0xec, 0xb1, 0xe7, 0x81, 0x63, 0xba,
+    0x1c, 0x38, 0xc6, 0xda, 0xcb, 0x19, 0x6a, 0x6d, 0xff, 0xa2, 0x1a, 0x8d, 0x99, 0x12,
+    0xec, 0x18, 0xa2, 0xef, 0x62, 0x83, 0x02, 0x4d, 0xec, 0xe7, 0x00, 0x00, 0x06, 0x13,
+    0x01, 0x13, 0x03, 0x13, 0x02, 0x01, 0x00, 0x00, 0x91, 0x00, 0x00, 0x00, 0x0b, 0x00,
+    0x09, 0x00, 0x00, 0x06, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0xff, 0x01, 0x00, 0x01,
+    0x00, 0x00, 0x0a, 0x00, 0x14, 0x00, 0x12, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00,
+    0x19, 0x01, 0x00, 0x01, 0x01, 0x01, 0x02, 0x01, 0x03, 0x01, 0x04, 0x00, 0x23, 0x00,
+    0x00, 0x00, 0x33, 0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x99, 0x38, 0x1d,
+    0xe5, 0x60, 0xe4, 0xbd, 0x43, 0xd2, 0x3d, 0x8e, 0x43, 0x5a, 0x7d, 0xba, 0xfe, 0xb3,
+    0xc0, 0x6e, 0x51, 0xc1, 0x3c, 0xae, 0x4d, 0x54, 0x13, 0x69, 0x1e, 0x52, 0x9a, 0xaf,
+    0x2c, 0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e,
+    0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06,
+    0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x01, 0x04, 0x02, 0x05, 0x02, 0x06, 0x02,
+    0x02, 0x02, 0x00, 0x2d, 0x00, 0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01
+];
+
+/// The server's ServerHello handshake message.
+pub const SERVER_HELLO: [u8; 90] = [
+    0x02, 0x00, 0x00, 0x56, 0x03, 0x03, 0xa6, 0xaf, 0x06, 0xa4, 0x12, 0x18, 0x60, 0xdc,
+    0x5e, 0x6e, 0x60, 0x24, 0x9c, 0xd3, 0x4c, 0x95, 0x93, 0x0c, 0x8a, 0xc5, 0xcb, 0x14,
+    0x34, 0xda, 0xc1, 0x55, 0x77, 0x2e, 0xd3, 0xe2, 0x69, 0x28, 0x00, 0x13, 0x01, 0x00,
+    0x00, 0x2e, 0x00, 0x33, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0xc9, 0x82, 0x88, 0x76,
+    0x11, 0x20, 0x95, 0xfe, 0x66, 0x76, 0x2b, 0xdb, 0xf7, 0xc6, 0x72, 0xe1, 0x56, 0xd6,
+    0xcc, 0x25, 0x3b, 0x83, 0x3d, 0xf1, 0xdd, 0x69, 0xb1, 0xb0, 0x4e, 0x75, 0x1f, 0x0f,
+    0x00, 0x2b, 0x00, 0x02, 0x03, 0x04
+];
+
+/// The (EC)DHE shared secret.
+pub const ECDHE_SHARED_SECRET: [u8; 32] = [
+    0x8b, 0xd4, 0x05, 0x4f, 0xb5, 0x5b, 0x9d, 0x63, 0xfd, 0xfb, 0xac, 0xf9, 0xf0, 0x4b,
+    0x9f, 0x0d, 0x35, 0xe6, 0xd6, 0x3f, 0x53, 0x75, 0x63, 0xef, 0xd4, 0x62, 0x72, 0x90,
+    0x0f, 0x89, 0x49, 0x2d
+];
+
+/// The hash of the ClientHello and ServerHello.
+pub const HELLO_HASH: [u8; 32] = [
+    0x86, 0x0c, 0x06, 0xed, 0xc0, 0x78, 0x58, 0xee, 0x8e, 0x78, 0xf0, 0xe7, 0x42, 0x8c,
+    0x58, 0xed, 0xd6, 0xb4, 0x3f, 0x2c, 0xa3, 0xe6, 0xe9, 0x5f, 0x02, 0xed, 0x06, 0x3c,
+    0xf0, 0xe1, 0xca, 0xd8
+];
+
+/// The early secret, with no PSK.
+pub const EARLY_SECRET: [u8; 32] = [
+    0x33, 0xad, 0x0a, 0x1c, 0x60, 0x7e, 0xc0, 0x3b, 0x09, 0xe6, 0xcd, 0x98, 0x93, 0x68,
+    0x0c, 0xe2, 0x10, 0xad, 0xf3, 0x00, 0xaa, 0x1f, 0x26, 0x60, 0xe1, 0xb2, 0x2e, 0x10,
+    0xf1, 0x70, 0xf9, 0x2a
+];
+
+/// The "derived" secret from the early secret.
+pub const EARLY_DERIVED_SECRET: [u8; 32] = [
+    0x6f, 0x26, 0x15, 0xa1, 0x08, 0xc7, 0x02, 0xc5, 0x67, 0x8f, 0x54, 0xfc, 0x9d, 0xba,
+    0xb6, 0x97, 0x16, 0xc0, 0x76, 0x18, 0x9c, 0x48, 0x25, 0x0c, 0xeb, 0xea, 0xc3, 0x57,
+    0x6c, 0x36, 0x11, 0xba
+];
+
+/// The handshake secret.
+pub const HANDSHAKE_SECRET: [u8; 32] = [
+    0x1d, 0xc8, 0x26, 0xe9, 0x36, 0x06, 0xaa, 0x6f, 0xdc, 0x0a, 0xad, 0xc1, 0x2f, 0x74,
+    0x1b, 0x01, 0x04, 0x6a, 0xa6, 0xb9, 0x9f, 0x69, 0x1e, 0xd2, 0x21, 0xa9, 0xf0, 0xca,
+    0x04, 0x3f, 0xbe, 0xac
+];
+
+/// The client handshake traffic secret.
+pub const CLIENT_HANDSHAKE_TRAFFIC_SECRET: [u8; 32] = [
+    0xb3, 0xed, 0xdb, 0x12, 0x6e, 0x06, 0x7f, 0x35, 0xa7, 0x80, 0xb3, 0xab, 0xf4, 0x5e,
+    0x2d, 0x8f, 0x3b, 0x1a, 0x95, 0x07, 0x38, 0xf5, 0x2e, 0x96, 0x00, 0x74, 0x6a, 0x0e,
+    0x27, 0xa5, 0x5a, 0x21
+];
+
+/// The server handshake traffic secret.
+pub const SERVER_HANDSHAKE_TRAFFIC_SECRET: [u8; 32] = [
+    0xb6, 0x7b, 0x7d, 0x69, 0x0c, 0xc1, 0x6c, 0x4e, 0x75, 0xe5, 0x42, 0x13, 0xcb, 0x2d,
+    0x37, 0xb4, 0xe9, 0xc9, 0x12, 0xbc, 0xde, 0xd9, 0x10, 0x5d, 0x42, 0xbe, 0xfd, 0x59,
+    0xd3, 0x91, 0xad, 0x38
+];
+
+/// The client handshake write key, for TLS_AES_128_GCM_SHA256.
+pub const CLIENT_HANDSHAKE_KEY: [u8; 16] = [
+    0xdb, 0xfa, 0xa6, 0x93, 0xd1, 0x76, 0x2c, 0x5b, 0x66, 0x6a, 0xf5, 0xd9, 0x50, 0x25,
+    0x8d, 0x01
+];
+
+/// The client handshake write IV.
+pub const CLIENT_HANDSHAKE_IV: [u8; 12] = [
+    0x5b, 0xd3, 0xc7, 0x1b, 0x83, 0x6e, 0x0b, 0x76, 0xbb, 0x73, 0x26, 0x5f
+];
+
+/// The server handshake write key, for TLS_AES_128_GCM_SHA256.
+pub const SERVER_HANDSHAKE_KEY: [u8; 16] = [
+    0x3f, 0xce, 0x51, 0x60, 0x09, 0xc2, 0x17, 0x27, 0xd0, 0xf2, 0xe4, 0xe8, 0x6e, 0xe4,
+    0x03, 0xbc
+];
+
+/// The server handshake write IV.
+pub const SERVER_HANDSHAKE_IV: [u8; 12] = [
+    0x5d, 0x31, 0x3e, 0xb2, 0x67, 0x12, 0x76, 0xee, 0x13, 0x00, 0x0b, 0x30
+];
+
+/// The "derived" secret from the handshake secret.
+pub const HANDSHAKE_DERIVED_SECRET: [u8; 32] = [
+    0x43, 0xde, 0x77, 0xe0, 0xc7, 0x77, 0x13, 0x85, 0x9a, 0x94, 0x4d, 0xb9, 0xdb, 0x25,
+    0x90, 0xb5, 0x31, 0x90, 0xa6, 0x5b, 0x3e, 0xe2, 0xe4, 0xf1, 0x2d, 0xd7, 0xa0, 0xbb,
+    0x7c, 0xe2, 0x54, 0xb4
+];
+
+/// The master secret.
+pub const MASTER_SECRET: [u8; 32] = [
+    0x18, 0xdf, 0x06, 0x84, 0x3d, 0x13, 0xa0, 0x8b, 0xf2, 0xa4, 0x49, 0x84, 0x4c, 0x5f,
+    0x8a, 0x47, 0x80, 0x01, 0xbc, 0x4d, 0x4c, 0x62, 0x79, 0x84, 0xd5, 0xa4, 0x1d, 0xa8,
+    0xd0, 0x40, 0x29, 0x19
+];
+
+/// The server application traffic secret.
+pub const SERVER_APPLICATION_TRAFFIC_SECRET: [u8; 32] = [
+    0xa1, 0x1a, 0xf9, 0xf0, 0x55, 0x31, 0xf8, 0x56, 0xad, 0x47, 0x11, 0x6b, 0x45, 0xa9,
+    0x50, 0x32, 0x82, 0x04, 0xb4, 0xf4, 0x4b, 0xfb, 0x6b, 0x3a, 0x4b, 0x4f, 0x1f, 0x3f,
+    0xcb, 0x63, 0x16, 0x43
+];
+
+/// The server application write key, for TLS_AES_128_GCM_SHA256.
+pub const SERVER_APPLICATION_KEY: [u8; 16] = [
+    0x9f, 0x02, 0x28, 0x3b, 0x6c, 0x9c, 0x07, 0xef, 0xc2, 0x6b, 0xb9, 0xf2, 0xac, 0x92,
+    0xe3, 0x56
+];
+
+/// The server application write IV.
+pub const SERVER_APPLICATION_IV: [u8; 12] = [
+    0xcf, 0x78, 0x2b, 0x88, 0xdd, 0x83, 0x54, 0x9a, 0xad, 0xf1, 0xe9, 0x84
+];
+
+fn check(what: &str, got: &[u8], expected: &[u8]) -> Result<(), String> {
+    if got == expected {
+        Ok(())
+    } else {
+        Err(format!("{} was {:?}, expected {:?}", what, got, expected))
+    }
+}
+
+/// Check a SHA256 implementation, as used for the transcript hash,
+/// against the trace.
+///
+/// `hash` is called with the handshake messages so far, and must
+/// return their SHA256 hash.
+pub fn check_transcript_hash<F>(hash: F) -> Result<(), String>
+    where F: Fn(&[u8]) -> Vec<u8>
+{
+    let mut transcript = CLIENT_HELLO.to_vec();
+    transcript.extend_from_slice(&SERVER_HELLO);
+    check("hello hash", &hash(&transcript), &HELLO_HASH)
+}
+
+/// Check our message codec decodes the trace's ClientHello and
+/// ServerHello, and encodes them again unchanged.
+pub fn check_hello_codec() -> Result<(), String> {
+    let messages: [(&str, &[u8], HandshakeType); 2] = [
+        ("ClientHello", &CLIENT_HELLO, HandshakeType::ClientHello),
+        ("ServerHello", &SERVER_HELLO, HandshakeType::ServerHello),
+    ];
+
+    for &(name, encoding, typ) in messages.iter() {
+        let hmp = HandshakeMessagePayload::read_bytes(encoding)
+            .ok_or_else(|| format!("{} could not be decoded", name))?;
+
+        if hmp.typ != typ {
+            return Err(format!("{} was decoded as {:?}", name, hmp.typ));
+        }
+
+        check(&format!("{} encoding", name), &hmp.get_encoding(), encoding)?;
+    }
+
+    check_transcript_hash(|data| digest::digest(&digest::SHA256, data).as_ref().to_vec())
+}
+
+/// Check an HKDF-Expand-Label implementation against the trace.
+///
+/// `expand_label` is called with a secret, a label (without the
+/// "tls13 " prefix), a context and an output length, and must
+/// return the HKDF-Expand-Label output using SHA256.
+pub fn check_expand_label<F>(expand_label: F) -> Result<(), String>
+    where F: Fn(&[u8], &[u8], &[u8], usize) -> Vec<u8>
+{
+    let empty_hash = digest::digest(&digest::SHA256, &[]);
+
+    check("early derived secret",
+          &expand_label(&EARLY_SECRET, b"derived", empty_hash.as_ref(), 32),
+          &EARLY_DERIVED_SECRET)?;
+    check("client handshake traffic secret",
+          &expand_label(&HANDSHAKE_SECRET, b"c hs traffic", &HELLO_HASH, 32),
+          &CLIENT_HANDSHAKE_TRAFFIC_SECRET)?;
+    check("server handshake traffic secret",
+          &expand_label(&HANDSHAKE_SECRET, b"s hs traffic", &HELLO_HASH, 32),
+          &SERVER_HANDSHAKE_TRAFFIC_SECRET)?;
+    check("handshake derived secret",
+          &expand_label(&HANDSHAKE_SECRET, b"derived", empty_hash.as_ref(), 32),
+          &HANDSHAKE_DERIVED_SECRET)?;
+
+    let keys: [(&str, &[u8], &[u8], &[u8]); 3] = [
+        ("client handshake", &CLIENT_HANDSHAKE_TRAFFIC_SECRET,
+         &CLIENT_HANDSHAKE_KEY, &CLIENT_HANDSHAKE_IV),
+        ("server handshake", &SERVER_HANDSHAKE_TRAFFIC_SECRET,
+         &SERVER_HANDSHAKE_KEY, &SERVER_HANDSHAKE_IV),
+        ("server application", &SERVER_APPLICATION_TRAFFIC_SECRET,
+         &SERVER_APPLICATION_KEY, &SERVER_APPLICATION_IV),
+    ];
+
+    for &(name, secret, key, iv) in keys.iter() {
+        check(&format!("{} key", name),
+              &expand_label(secret, b"key", &[], key.len()),
+              key)?;
+        check(&format!("{} iv", name),
+              &expand_label(secret, b"iv", &[], iv.len()),
+              iv)?;
+    }
+
+    Ok(())
+}
+
+/// Check our own TLS1.3 key schedule against the trace.
+pub fn check_key_schedule() -> Result<(), String> {
+    let mut ks = KeySchedule::new(&digest::SHA256);
+    ks.input_empty();
+    ks.input_secret(&ECDHE_SHARED_SECRET);
+
+    check("client handshake traffic secret",
+          &ks.derive(SecretKind::ClientHandshakeTrafficSecret, &HELLO_HASH),
+          &CLIENT_HANDSHAKE_TRAFFIC_SECRET)?;
+    check("server handshake traffic secret",
+          &ks.derive(SecretKind::ServerHandshakeTrafficSecret, &HELLO_HASH),
+          &SERVER_HANDSHAKE_TRAFFIC_SECRET)?;
+
+    check_expand_label(|secret, label, context, len| {
+        hkdf_expand_label(&digest::SHA256, secret, label, context, len).unwrap()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_key_schedule, check_expand_label, check_hello_codec,
+                check_transcript_hash};
+
+    #[test]
+    fn rfc8448_key_schedule() {
+        assert_eq!(check_key_schedule(), Ok(()));
+    }
+
+    #[test]
+    fn rfc8448_hello_codec() {
+        assert_eq!(check_hello_codec(), Ok(()));
+    }
+
+    #[test]
+    fn rfc8448_detects_wrong_transcript_hash() {
+        assert!(check_transcript_hash(|_data| vec![0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn rfc8448_detects_wrong_expand_label() {
+        let result = check_expand_label(|_secret, _label, _context, len| vec![0u8; len]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use server::ClientOffer;
+use msgs::enums::ExtensionType;
+use ring::digest;
+
+/// GREASE values (RFC8701) are random, so fingerprints ignore them.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join<T, F>(items: &[T], sep: &str, f: F) -> String
+    where F: Fn(&T) -> String
+{
+    items.iter()
+        .map(f)
+        .collect::<Vec<String>>()
+        .join(sep)
+}
+
+fn decimal_list(values: &[u16]) -> String {
+    join(values, "-", |v| v.to_string())
+}
+
+fn hex_list(values: &[u16]) -> String {
+    join(values, ",", |v| format!("{:04x}", v))
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter()
+        .cloned()
+        .filter(|v| !is_grease(*v))
+        .collect()
+}
+
+/// The first 12 hex digits of the SHA256 of `input`, or zeroes if
+/// there's nothing to hash.
+fn truncated_hash(input: &str) -> String {
+    if input.is_empty() {
+        return "000000000000".to_string();
+    }
+
+    let hash = digest::digest(&digest::SHA256, input.as_bytes());
+    join(&hash.as_ref()[..6], "", |b| format!("{:02x}", b))
+}
+
+fn is_tls13_draft(version: u16) -> bool {
+    version >> 8 == 0x7f
+}
+
+fn ja4_version(version: u16) -> &'static str {
+    if is_tls13_draft(version) {
+        return "13";
+    }
+
+    match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    }
+}
+
+fn ja4_alpn(alpn: Option<&Vec<u8>>) -> String {
+    let proto = match alpn {
+        Some(proto) if !proto.is_empty() => proto,
+        _ => return "00".to_string(),
+    };
+
+    let first = proto[0];
+    let last = proto[proto.len() - 1];
+
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first as char, last as char)
+    } else {
+        format!("{:x}{:x}", first >> 4, last & 0x0f)
+    }
+}
+
+impl ClientOffer {
+    /// Returns the JA3 fingerprint of this ClientHello, before
+    /// hashing.  The JA3 hash is the MD5 of this string: rustls
+    /// doesn't provide MD5, so that is left to the caller.
+    ///
+    /// GREASE values are left out, as JA3 requires.
+    pub fn ja3_string(&self) -> String {
+        let cipher_suites = self.cipher_suites.iter()
+            .map(|cs| cs.get_u16())
+            .collect::<Vec<u16>>();
+        let extensions = self.extensions.iter()
+            .map(|ext| ext.get_u16())
+            .collect::<Vec<u16>>();
+        let named_groups = self.named_groups.iter()
+            .map(|group| group.get_u16())
+            .collect::<Vec<u16>>();
+        let point_formats = self.ec_point_formats.iter()
+            .map(|fmt| u16::from(fmt.get_u8()))
+            .collect::<Vec<u16>>();
+
+        format!("{},{},{},{},{}",
+                self.client_version.get_u16(),
+                decimal_list(&without_grease(&cipher_suites)),
+                decimal_list(&without_grease(&extensions)),
+                decimal_list(&without_grease(&named_groups)),
+                decimal_list(&point_formats))
+    }
+
+    /// Returns the JA4 fingerprint of this ClientHello, like
+    /// `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    pub fn ja4(&self) -> String {
+        let cipher_suites = without_grease(&self.cipher_suites.iter()
+            .map(|cs| cs.get_u16())
+            .collect::<Vec<u16>>());
+        let extensions = without_grease(&self.extensions.iter()
+            .map(|ext| ext.get_u16())
+            .collect::<Vec<u16>>());
+        let signature_schemes = without_grease(&self.signature_schemes.iter()
+            .map(|scheme| scheme.get_u16())
+            .collect::<Vec<u16>>());
+
+        let version = without_grease(&self.supported_versions.iter()
+                .map(|v| v.get_u16())
+                .collect::<Vec<u16>>())
+            .into_iter()
+            .max_by_key(|v| if is_tls13_draft(*v) { 0x0304 } else { *v })
+            .unwrap_or_else(|| self.client_version.get_u16());
+
+        let sni = if self.extensions.contains(&ExtensionType::ServerName) {
+            'd'
+        } else {
+            'i'
+        };
+
+        let mut sorted_suites = cipher_suites.clone();
+        sorted_suites.sort();
+
+        // SNI and ALPN are already described in the first part.
+        let mut sorted_exts = extensions.iter()
+            .cloned()
+            .filter(|ext| *ext != ExtensionType::ServerName.get_u16() &&
+                          *ext != ExtensionType::ALProtocolNegotiation.get_u16())
+            .collect::<Vec<u16>>();
+        sorted_exts.sort();
+
+        let mut ext_string = hex_list(&sorted_exts);
+        if !signature_schemes.is_empty() {
+            ext_string.push('_');
+            ext_string.push_str(&hex_list(&signature_schemes));
+        }
+
+        format!("t{}{}{:02}{:02}{}_{}_{}",
+                ja4_version(version),
+                sni,
+                cipher_suites.len().min(99),
+                extensions.len().min(99),
+                ja4_alpn(self.alpn_protocols.first()),
+                truncated_hash(&hex_list(&sorted_suites)),
+                truncated_hash(&ext_string))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use msgs::enums::{NamedGroup, ECPointFormat};
+    use msgs::enums::ProtocolVersion;
+    use msgs::codec::Codec;
+
+    fn decode<T: Codec>(value: u16) -> T {
+        T::read_bytes(&[(value >> 8) as u8, value as u8]).unwrap()
+    }
+
+    fn example_offer() -> ClientOffer {
+        let suites: &[u16] = &[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9,
+                      0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035];
+        let exts: &[u16] = &[0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005,
+                    0x000d, 0x0012, 0x0033, 0x002d, 0x002b, 0x001b, 0x0015, 0x4469];
+        let schemes: &[u16] = &[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601];
+
+        ClientOffer {
+            client_version: ProtocolVersion::TLSv1_2,
+            cipher_suites: suites.iter().map(|v| decode(*v)).collect(),
+            extensions: exts.iter().map(|v| decode(*v)).collect(),
+            supported_versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            signature_schemes: schemes.iter().map(|v| decode(*v)).collect(),
+            named_groups: vec![NamedGroup::X25519, NamedGroup::secp256r1],
+            ec_point_formats: vec![ECPointFormat::Uncompressed],
+            alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+
+    fn add_grease(offer: &mut ClientOffer) {
+        offer.cipher_suites.insert(0, decode(0x0a0a));
+        offer.extensions.insert(0, decode(0x1a1a));
+        offer.extensions.push(decode(0xfafa));
+        offer.supported_versions.insert(0, decode(0x2a2a));
+        offer.named_groups.insert(0, decode(0x3a3a));
+    }
+
+    #[test]
+    fn grease_values() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+
+    #[test]
+    fn ja4_matches_reference() {
+        assert_eq!("t13d1516h2_8daaf6152771_e5627efa2ab1", example_offer().ja4());
+    }
+
+    #[test]
+    fn ja3_string_is_decimal() {
+        let offer = example_offer();
+        let ja3 = offer.ja3_string();
+        assert!(ja3.starts_with("771,4865-4866-4867-49195-"));
+        assert!(ja3.ends_with(",0-23-65281-10-11-35-16-5-13-18-51-45-43-27-21-17513,29-23,0"));
+    }
+
+    #[test]
+    fn fingerprints_ignore_grease() {
+        let mut offer = example_offer();
+        let ja3 = offer.ja3_string();
+        let ja4 = offer.ja4();
+
+        add_grease(&mut offer);
+        assert_eq!(ja3, offer.ja3_string());
+        assert_eq!(ja4, offer.ja4());
+    }
+
+    #[test]
+    fn ja4_without_extensions() {
+        let offer = ClientOffer {
+            client_version: ProtocolVersion::TLSv1_2,
+            cipher_suites: Vec::new(),
+            extensions: Vec::new(),
+            supported_versions: Vec::new(),
+            signature_schemes: Vec::new(),
+            named_groups: Vec::new(),
+            ec_point_formats: Vec::new(),
+            alpn_protocols: Vec::new(),
+        };
+
+        assert_eq!("t12i000000_000000000000_000000000000", offer.ja4());
+    }
+
+    #[test]
+    fn ja4_alpn_not_alphanumeric() {
+        assert_eq!("00", ja4_alpn(None));
+        assert_eq!("h2", ja4_alpn(Some(&b"h2".to_vec())));
+        assert_eq!("a1", ja4_alpn(Some(&vec![0xab, 0x01])));
+    }
+}
@@ -23,6 +23,9 @@ impl server::StoresServerSessions for NoServerSessionStorage {
     fn get(&self, _id: &[u8]) -> Option<Vec<u8>> {
         None
     }
+    fn enabled(&self) -> bool {
+        false
+    }
 }
 
 /// An implementor of `StoresServerSessions` that stores everything
@@ -145,12 +148,28 @@ impl server::ResolvesServerCert for AlwaysResolvesChain {
 /// on client-supplied server name (via SNI).
 pub struct ResolvesServerCertUsingSNI {
     by_name: collections::HashMap<String, sign::CertifiedKey>,
+    default: Option<sign::CertifiedKey>,
 }
 
 impl ResolvesServerCertUsingSNI {
     /// Create a new and empty (ie, knows no certificates) resolver.
     pub fn new() -> ResolvesServerCertUsingSNI {
-        ResolvesServerCertUsingSNI { by_name: collections::HashMap::new() }
+        ResolvesServerCertUsingSNI {
+            by_name: collections::HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Set the `sign::CertifiedKey` to use when the client sends no
+    /// SNI, or a name we have no certificate for.  Without one of
+    /// these, such handshakes fail.
+    ///
+    /// This function fails if the certificate chain is syntactically
+    /// faulty.  The certificate needn't be valid for any name.
+    pub fn set_default(&mut self, ck: sign::CertifiedKey) -> Result<(), TLSError> {
+        ck.cross_check_end_entity_cert(None)?;
+        self.default = Some(ck);
+        Ok(())
     }
 
     /// Add a new `sign::CertifiedKey` to be used for the given SNI `name`.
@@ -173,13 +192,9 @@ impl server::ResolvesServerCert for ResolvesServerCertUsingSNI {
                server_name: Option<webpki::DNSNameRef>,
                _sigschemes: &[SignatureScheme])
                -> Option<sign::CertifiedKey> {
-        if let Some(name) = server_name {
-            self.by_name.get(name.into())
-                .map(|ck| ck.clone())
-        } else {
-            // This kind of resolver requires SNI
-            None
-        }
+        server_name.and_then(|name| self.by_name.get(name.into()))
+            .or(self.default.as_ref())
+            .cloned()
     }
 }
 
@@ -1,43 +1,50 @@
 use msgs::enums::{ContentType, HandshakeType, ProtocolVersion};
 use msgs::enums::{Compression, NamedGroup, ECPointFormat, CipherSuite};
-use msgs::enums::{ExtensionType, AlertDescription};
+use msgs::enums::{ExtensionType, AlertDescription, AlertLevel};
 use msgs::enums::{ClientCertificateType, SignatureScheme, PSKKeyExchangeMode};
 use msgs::message::{Message, MessagePayload};
-use msgs::base::{Payload, PayloadU8};
+use msgs::base::{Payload, PayloadU8, PayloadU16};
 use msgs::handshake::{HandshakePayload, SupportedSignatureSchemes};
 use msgs::handshake::{HandshakeMessagePayload, ServerHelloPayload, Random};
 use msgs::handshake::{ClientHelloPayload, ServerExtension, SessionID};
 use msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList};
-use msgs::handshake::{NamedGroups, SupportedGroups, ClientExtension};
+use msgs::handshake::DecomposedSignatureScheme;
+use msgs::handshake::ClientExtension;
 use msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use msgs::handshake::{ServerECDHParams, DigitallySignedStruct};
 use msgs::handshake::{ServerKeyExchangePayload, ECDHEServerKeyExchange};
 use msgs::handshake::{CertificateRequestPayload, NewSessionTicketPayload};
+use msgs::handshake::DistinguishedNames;
 use msgs::handshake::{CertificateRequestPayloadTLS13, NewSessionTicketPayloadTLS13};
 use msgs::handshake::{HelloRetryRequest, HelloRetryExtension, KeyShareEntry};
 use msgs::handshake::{CertificatePayloadTLS13, CertificateEntry};
 use msgs::handshake::{CertificateStatus, CertificateExtension};
 use msgs::handshake::{CertReqExtension, SupportedMandatedSignatureSchemes};
+use msgs::handshake::SCTList;
 use msgs::ccs::ChangeCipherSpecPayload;
-use msgs::codec::Codec;
+use msgs::codec::{Codec, Reader};
 use msgs::persist;
-use session::SessionSecrets;
+use session::{SessionSecrets, TLS12Flags, HelloRetryInfo, SessionEvent, HandshakePhase};
 use cipher;
-use server::ServerSessionImpl;
+use server::{ServerSessionImpl, ClientHelloSummary, ClientOffer};
 use key_schedule::{KeySchedule, SecretKind};
 use suites;
+use kx;
 use verify;
 use util;
 use rand;
 use sign;
 use error::TLSError;
 use handshake::{check_handshake_message, check_message};
+use custom_ext;
 use webpki;
 
 use server::common::{HandshakeDetails, ServerKXDetails, ClientCertDetails};
 
 use ring::constant_time;
 
+use std::cmp;
+
 const TLS13_DRAFT: u16 = 0x7f17;
 
 macro_rules! extract_handshake(
@@ -57,6 +64,7 @@ type NextState = Box<State + Send + Sync>;
 type NextStateOrError = Result<NextState, TLSError>;
 
 pub trait State {
+    fn phase(&self) -> HandshakePhase;
     fn check_message(&self, m: &Message) -> CheckResult;
     fn handle(self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError;
 }
@@ -76,6 +84,124 @@ fn decode_error(sess: &mut ServerSessionImpl, why: &str) -> TLSError {
     TLSError::PeerMisbehavedError(why.to_string())
 }
 
+/// Decode a stored session or ticket, then erase the encoding: it
+/// contains the session's secret.
+fn decode_session(mut plain: Vec<u8>) -> Option<persist::ServerSessionValue> {
+    let result = persist::ServerSessionValue::read_bytes(&plain);
+    util::wipe(&mut plain);
+    result
+}
+
+/// What we put in a HelloRetryRequest cookie: enough to carry on
+/// the handshake without remembering anything about the first
+/// ClientHello.
+#[derive(Debug)]
+struct RetryCookie {
+    client_hello_hash: PayloadU8,
+    group: NamedGroup,
+    suite: CipherSuite,
+}
+
+impl Codec for RetryCookie {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.client_hello_hash.encode(bytes);
+        self.group.encode(bytes);
+        self.suite.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<RetryCookie> {
+        Some(RetryCookie {
+            client_hello_hash: try_ret!(PayloadU8::read(r)),
+            group: try_ret!(NamedGroup::read(r)),
+            suite: try_ret!(CipherSuite::read(r)),
+        })
+    }
+}
+
+fn build_hello_retry_request(sess: &ServerSessionImpl,
+                             session_id: &SessionID,
+                             group: NamedGroup,
+                             cookie: Option<PayloadU16>) -> Message {
+    let mut req = HelloRetryRequest {
+        legacy_version: ProtocolVersion::TLSv1_2,
+        session_id: *session_id,
+        cipher_suite: sess.common.get_suite_assert().suite,
+        extensions: Vec::new(),
+    };
+
+    req.extensions.push(HelloRetryExtension::KeyShare(group));
+    req.extensions.push(HelloRetryExtension::SupportedVersions(ProtocolVersion::Unknown(TLS13_DRAFT)));
+    if let Some(cookie) = cookie {
+        req.extensions.push(HelloRetryExtension::Cookie(cookie));
+    }
+
+    Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_2,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::HelloRetryRequest,
+            payload: HandshakePayload::HelloRetryRequest(req),
+        }),
+    }
+}
+
+/// Ask `sct_provider` for SCTs if `certkey` came without any, and
+/// drop an SCT list we can't send.
+fn prepare_sct_list(sess: &ServerSessionImpl, certkey: &mut sign::CertifiedKey) {
+    if !certkey.has_sct_list() {
+        if let Some(ref provider) = sess.config.sct_provider {
+            certkey.sct_list = provider.sct_list(&certkey.cert);
+        }
+    }
+
+    // Clients reject empty lists, and empty SCTs.
+    let valid = certkey.sct_list
+        .as_ref()
+        .map_or(true, |list| match SCTList::read_bytes(list) {
+            Some(scts) => !scts.is_empty() && scts.iter().all(|sct| !sct.0.is_empty()),
+            None => false,
+        });
+    if !valid {
+        warn!("Not sending invalid SCT list");
+        certkey.sct_list = None;
+    }
+}
+
+/// The client asked for a name we have no certificate for: alert
+/// as `unrecognized_name_alert` says, perhaps falling back to the
+/// certificate for no name.
+fn unrecognized_name(sess: &mut ServerSessionImpl,
+                     sigschemes: &[SignatureScheme]) -> Result<sign::CertifiedKey, TLSError> {
+    let fallback = match sess.config.unrecognized_name_alert {
+        None => {
+            sess.common.send_fatal_alert(AlertDescription::AccessDenied);
+            None
+        }
+        Some(AlertLevel::Warning) => {
+            if !sess.common.is_tls13() {
+                sess.common.send_warning_alert(AlertDescription::UnrecognisedName);
+            }
+            sess.config.cert_resolver.resolve(None, sigschemes)
+        }
+        Some(_) => None,
+    };
+
+    fallback.ok_or_else(|| {
+        if sess.config.unrecognized_name_alert.is_some() {
+            sess.common.send_fatal_alert(AlertDescription::UnrecognisedName);
+        }
+        TLSError::General("no server certificate chain resolved".to_string())
+    })
+}
+
+/// The CA names to send in our CertificateRequest.
+fn client_auth_ca_names(sess: &ServerSessionImpl) -> DistinguishedNames {
+    match sess.config.client_auth_ca_names {
+        Some(ref names) => names.clone(),
+        None => sess.config.verifier.client_auth_root_subjects(),
+    }
+}
+
 fn can_resume(sess: &ServerSessionImpl,
               handshake: &HandshakeDetails,
               resumedata: &Option<persist::ServerSessionValue>) -> bool {
@@ -87,10 +213,16 @@ fn can_resume(sess: &ServerSessionImpl,
     // a different name. Instead, it proceeds with a full handshake to
     // establish a new session."
 
+    if sess.overrides.disable_resumption {
+        return false;
+    }
+
     if let Some(ref resume) = *resumedata {
         resume.cipher_suite == sess.common.get_suite_assert().suite &&
             (resume.extended_ms == handshake.using_ems ||
              (resume.extended_ms && !handshake.using_ems)) &&
+            ((resume.extended_ms && handshake.using_ems) ||
+             !sess.config.require_ems_for_resumption) &&
             same_dns_name_or_both_none(resume.sni.as_ref(), sess.sni.as_ref())
     } else {
         false
@@ -207,7 +339,7 @@ impl ExpectClientHello {
         let mut ret = Vec::new();
 
         // ALPN
-        let our_protocols = &sess.config.alpn_protocols;
+        let our_protocols = sess.alpn_protocols().to_vec();
         let maybe_their_protocols = hello.get_alpn_extension();
         if let Some(their_protocols) = maybe_their_protocols {
             let their_proto_strings = their_protocols.to_strings();
@@ -217,7 +349,29 @@ impl ExpectClientHello {
                     .to_string()));
             }
 
-            sess.alpn_protocol = util::first_in_both(our_protocols, &their_proto_strings);
+            sess.alpn_protocol = match sess.config.alpn_chooser.clone() {
+                Some(chooser) => {
+                    let sni = hello.get_sni_extension()
+                        .and_then(|sni| sni.get_hostname());
+                    match chooser.choose(sni, &their_proto_strings) {
+                        Ok(chosen) => chosen,
+                        Err(err) => {
+                            sess.common.send_fatal_alert(AlertDescription::NoApplicationProtocol);
+                            return Err(err);
+                        }
+                    }
+                }
+                None => util::first_in_both(&our_protocols, &their_proto_strings),
+            };
+
+            if let Some(ref chosen) = sess.alpn_protocol {
+                if !their_proto_strings.contains(chosen) {
+                    sess.common.send_fatal_alert(AlertDescription::InternalError);
+                    return Err(TLSError::General("chose an ALPN protocol the client did not offer"
+                        .to_string()));
+                }
+            }
+
             if let Some(ref selected_protocol) = sess.alpn_protocol {
                 debug!("Chosen ALPN protocol {:?}", selected_protocol);
                 ret.push(ServerExtension::make_alpn(selected_protocol.clone()));
@@ -274,7 +428,7 @@ impl ExpectClientHello {
             // If we get any SessionTicket extension and have tickets enabled,
             // we send an ack.
             if hello.find_extension(ExtensionType::SessionTicket).is_some() &&
-               sess.config.ticketer.enabled() {
+               sess.enables_tickets() {
                 self.send_ticket = true;
                 ret.push(ServerExtension::SessionTicketAck);
             }
@@ -284,8 +438,20 @@ impl ExpectClientHello {
                 ret.push(ServerExtension::ExtendedMasterSecretAck);
             }
 
+            sess.common.tls12_flags = Some(TLS12Flags {
+                extended_master_secret: self.handshake.using_ems,
+                secure_renegotiation: secure_reneg_offered,
+                session_ticket: self.send_ticket,
+            });
+
         }
 
+        // Application-defined extensions.
+        let custom_replies = custom_ext::server_replies(&sess.config.custom_extensions,
+                                                        &hello.extensions,
+                                                        &mut sess.received_custom_extensions);
+        ret.extend(custom_replies);
+
         Ok(ret)
     }
 
@@ -322,7 +488,8 @@ impl ExpectClientHello {
         let mut extensions = Vec::new();
 
         // Do key exchange
-        let kxr = suites::KeyExchange::start_ecdhe(share.group)
+        let kxr = kx::find_group(&sess.config.kx_groups, share.group)
+            .and_then(|group| suites::KeyExchange::start(group.as_ref()))
             .and_then(|kx| kx.complete(&share.payload.0))
             .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
 
@@ -359,8 +526,9 @@ impl ExpectClientHello {
         // Start key schedule
         let suite = sess.common.get_suite_assert();
         let mut key_schedule = KeySchedule::new(suite.get_hash());
-        if let Some(psk) = resuming_psk {
+        if let Some(mut psk) = resuming_psk {
             key_schedule.input_secret(&psk);
+            util::wipe(&mut psk);
         } else {
             key_schedule.input_empty();
         }
@@ -371,6 +539,9 @@ impl ExpectClientHello {
         let read_key = key_schedule.derive(SecretKind::ClientHandshakeTrafficSecret, &handshake_hash);
         sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
         sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+        let client_random = &self.handshake.randoms.client;
+        sess.common.log_tls13_secret(SecretKind::ClientHandshakeTrafficSecret, client_random, &read_key);
+        sess.common.log_tls13_secret(SecretKind::ServerHandshakeTrafficSecret, client_random, &write_key);
         key_schedule.current_client_traffic_secret = read_key;
         key_schedule.current_server_traffic_secret = write_key;
         sess.common.set_key_schedule(key_schedule);
@@ -390,30 +561,67 @@ impl ExpectClientHello {
 
     fn emit_hello_retry_request(&mut self,
                                 sess: &mut ServerSessionImpl,
+                                session_id: &SessionID,
                                 group: NamedGroup) {
-        let mut req = HelloRetryRequest {
-            legacy_version: ProtocolVersion::TLSv1_2,
-            session_id: SessionID::empty(),
-            cipher_suite: sess.common.get_suite_assert().suite,
-            extensions: Vec::new(),
-        };
+        let client_hello_hash = self.handshake.transcript.rollup_for_hrr();
 
-        req.extensions.push(HelloRetryExtension::KeyShare(group));
-        req.extensions.push(HelloRetryExtension::SupportedVersions(ProtocolVersion::Unknown(TLS13_DRAFT)));
+        let cookie = sess.config.hello_retry_cookies
+            .as_ref()
+            .and_then(|cookies| {
+                let value = RetryCookie {
+                    client_hello_hash: PayloadU8::new(client_hello_hash.clone()),
+                    group,
+                    suite: sess.common.get_suite_assert().suite,
+                };
+                cookies.encrypt(&value.get_encoding())
+            })
+            .map(PayloadU16::new);
+        let has_cookie = cookie.is_some();
+
+        let m = build_hello_retry_request(sess, session_id, group, cookie);
+        trace!("Requesting retry {:?}", m);
+        self.handshake.transcript.add_message(&m);
+        sess.common.send_msg(m, false);
 
-        let m = Message {
-            typ: ContentType::Handshake,
-            version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::HelloRetryRequest,
-                payload: HandshakePayload::HelloRetryRequest(req),
-            }),
+        sess.common.hello_retry = Some(HelloRetryInfo {
+            requested_group: Some(group),
+            has_cookie,
+            client_hello_hash,
+        });
+    }
+
+    /// The client sent a cookie from a HelloRetryRequest which another
+    /// session sent.  If it's ours, carry on as if this session had
+    /// sent that HelloRetryRequest.
+    fn resume_after_stateless_retry(&mut self,
+                                    sess: &mut ServerSessionImpl,
+                                    client_hello: &ClientHelloPayload,
+                                    cookie: &PayloadU16) -> Result<(), TLSError> {
+        let cookies = match sess.config.hello_retry_cookies {
+            Some(ref cookies) => cookies.clone(),
+            None => return Ok(()),
         };
 
-        trace!("Requesting retry {:?}", m);
-        self.handshake.transcript.rollup_for_hrr();
+        let retry = cookies.decrypt(&cookie.0)
+            .and_then(|plain| RetryCookie::read_bytes(&plain))
+            .ok_or_else(|| illegal_param(sess, "client sent invalid cookie"))?;
+
+        if retry.suite != sess.common.get_suite_assert().suite {
+            return Err(illegal_param(sess, "did not follow retry request"));
+        }
+
+        let m = build_hello_retry_request(sess, &client_hello.session_id,
+                                          retry.group, Some(cookie.clone()));
+        self.handshake.transcript.restore_for_hrr(&retry.client_hello_hash.0);
         self.handshake.transcript.add_message(&m);
-        sess.common.send_msg(m, false);
+        self.done_retry = true;
+
+        sess.common.hello_retry = Some(HelloRetryInfo {
+            requested_group: Some(retry.group),
+            has_cookie: true,
+            client_hello_hash: retry.client_hello_hash.0,
+        });
+        Ok(())
     }
 
     fn emit_encrypted_extensions(&mut self,
@@ -451,7 +659,7 @@ impl ExpectClientHello {
         let schemes = SupportedSignatureSchemes::supported_verify();
         cr.extensions.push(CertReqExtension::SignatureAlgorithms(schemes));
 
-        let names = sess.config.verifier.client_auth_root_subjects();
+        let names = client_auth_ca_names(sess);
         if !names.is_empty() {
             cr.extensions.push(CertReqExtension::AuthorityNames(names));
         }
@@ -585,6 +793,8 @@ impl ExpectClientHello {
                     &self.handshake.hash_at_server_fin);
         let suite = sess.common.get_suite_assert();
         sess.common.set_message_encrypter(cipher::new_tls13_write(suite, &write_key));
+        sess.common.log_tls13_secret(SecretKind::ServerApplicationTrafficSecret,
+                                     &self.handshake.randoms.client, &write_key);
         sess.common
             .get_mut_key_schedule()
             .current_server_traffic_secret = write_key;
@@ -593,6 +803,8 @@ impl ExpectClientHello {
             .get_key_schedule()
             .derive(SecretKind::ExporterMasterSecret,
                     &self.handshake.hash_at_server_fin);
+        sess.common.log_tls13_secret(SecretKind::ExporterMasterSecret,
+                                     &self.handshake.randoms.client, &exporter_secret);
         sess.common
             .get_mut_key_schedule()
             .current_exporter_secret = exporter_secret;
@@ -676,8 +888,8 @@ impl ExpectClientHello {
                       group: &NamedGroup,
                       server_certkey: &mut sign::CertifiedKey)
                       -> Result<suites::KeyExchange, TLSError> {
-        let kx = sess.common.get_suite_assert()
-            .start_server_kx(*group)
+        let kx = kx::find_group(&sess.config.kx_groups, *group)
+            .and_then(|kx_group| sess.common.get_suite_assert().start_server_kx(kx_group.as_ref()))
             .ok_or_else(|| TLSError::PeerMisbehavedError("key exchange failed".to_string()))?;
         let secdh = ServerECDHParams::new(group, &kx.pubkey);
 
@@ -717,12 +929,17 @@ impl ExpectClientHello {
             return false;
         }
 
-        let names = client_auth.client_auth_root_subjects();
+        let names = client_auth_ca_names(sess);
+
+        let mut sigschemes = SupportedSignatureSchemes::supported_verify();
+        if !sess.config.enable_rsa_pkcs1 {
+            sigschemes.retain(|scheme| !scheme.is_rsa_pkcs1());
+        }
 
         let cr = CertificateRequestPayload {
             certtypes: vec![ ClientCertificateType::RSASign,
                          ClientCertificateType::ECDSASign ],
-            sigschemes: SupportedSignatureSchemes::supported_verify(),
+            sigschemes,
             canames: names,
         };
 
@@ -760,7 +977,7 @@ impl ExpectClientHello {
                         client_hello: &ClientHelloPayload,
                         sni: Option<&webpki::DNSName>,
                         id: &SessionID,
-                        resumedata: persist::ServerSessionValue)
+                        mut resumedata: persist::ServerSessionValue)
                         -> NextStateOrError {
         debug!("Resuming session");
 
@@ -768,6 +985,8 @@ impl ExpectClientHello {
             return Err(illegal_param(sess, "refusing to resume without ems"));
         }
 
+        sess.common.emit_event(SessionEvent::ResumptionAccepted);
+
         self.handshake.session_id = *id;
         self.emit_server_hello(sess, None, client_hello, true)?;
 
@@ -777,7 +996,7 @@ impl ExpectClientHello {
                                        hashalg,
                                        &resumedata.master_secret.0)
         );
-        sess.client_cert_chain = resumedata.client_cert_chain;
+        sess.client_cert_chain = resumedata.client_cert_chain.take();
 
         if self.send_ticket {
             emit_ticket(&mut self.handshake, sess);
@@ -802,6 +1021,12 @@ impl ExpectClientHello {
             return Err(illegal_param(sess, "client offered wrong compressions"));
         }
 
+        if !self.done_retry {
+            if let Some(cookie) = client_hello.get_cookie() {
+                self.resume_after_stateless_retry(sess, client_hello, cookie)?;
+            }
+        }
+
         let groups_ext = client_hello.get_namedgroups_extension()
             .ok_or_else(|| incompatible(sess, "client didn't describe groups"))?;
 
@@ -823,11 +1048,12 @@ impl ExpectClientHello {
             .map(|share| share.group)
             .collect();
 
-        let chosen_group = util::first_in_both(&NamedGroups::supported(), &share_groups);
+        let our_groups = sess.config.get_kx_groups();
+        let chosen_group = util::first_in_both(&our_groups, &share_groups);
         if chosen_group.is_none() {
             // We don't have a suitable key share.  Choose a suitable group and
             // send a HelloRetryRequest.
-            let retry_group_maybe = util::first_in_both(&NamedGroups::supported(), groups_ext);
+            let retry_group_maybe = util::first_in_both(&our_groups, groups_ext);
             self.handshake.transcript.add_message(chm);
 
             if let Some(group) = retry_group_maybe {
@@ -835,7 +1061,7 @@ impl ExpectClientHello {
                     return Err(illegal_param(sess, "did not follow retry request"));
                 }
 
-                self.emit_hello_retry_request(sess, group);
+                self.emit_hello_retry_request(sess, &client_hello.session_id, group);
                 self.emit_fake_ccs(sess);
                 return Ok(self.into_expect_retried_client_hello());
             }
@@ -869,7 +1095,7 @@ impl ExpectClientHello {
                 let maybe_resume = sess.config
                     .ticketer
                     .decrypt(&psk_id.identity.0)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain));
+                    .and_then(decode_session);
 
                 if !can_resume(sess, &self.handshake, &maybe_resume) {
                     continue;
@@ -883,7 +1109,7 @@ impl ExpectClientHello {
                 }
 
                 chosen_psk_index = Some(i);
-                resuming_psk = Some(resume.master_secret.0);
+                resuming_psk = Some(resume.master_secret.0.clone());
                 break;
             }
         }
@@ -897,7 +1123,16 @@ impl ExpectClientHello {
             self.send_ticket = true;
         }
 
+        if client_hello.get_psk().is_some() {
+            sess.common.emit_event(if resuming_psk.is_some() {
+                SessionEvent::ResumptionAccepted
+            } else {
+                SessionEvent::ResumptionRejected
+            });
+        }
+
         let full_handshake = resuming_psk.is_none();
+        client_hello.random.write_slice(&mut self.handshake.randoms.client);
         self.handshake.transcript.add_message(chm);
         self.emit_server_hello_tls13(sess, &client_hello.session_id,
                                      chosen_share, chosen_psk_index, resuming_psk)?;
@@ -925,6 +1160,37 @@ impl ExpectClientHello {
         }
     }
 
+    /// Ask the config resolver, if any, for the config to use for
+    /// the rest of the handshake.
+    fn resolve_config(&mut self,
+                      sess: &mut ServerSessionImpl,
+                      client_hello: &ClientHelloPayload) {
+        let resolver = match sess.config.config_resolver {
+            Some(ref resolver) => resolver.clone(),
+            None => return,
+        };
+
+        let default_sigschemes_ext = SupportedSignatureSchemes::default();
+        let summary = ClientHelloSummary {
+            server_name: client_hello.get_sni_extension()
+                .and_then(|sni| sni.get_hostname()),
+            signature_schemes: client_hello.get_sigalgs_extension()
+                .unwrap_or(&default_sigschemes_ext),
+            alpn_protocols: client_hello.get_alpn_extension()
+                .map(|protos| protos.to_strings())
+                .unwrap_or_else(Vec::new),
+            cipher_suites: &client_hello.cipher_suites,
+        };
+
+        if let Some(config) = resolver.resolve(&summary) {
+            debug!("Config resolver chose a new config");
+            if config.verifier.offer_client_auth() {
+                self.handshake.transcript.set_client_auth_enabled();
+            }
+            sess.config = config;
+        }
+    }
+
     fn save_sni(&self,
                 sess: &mut ServerSessionImpl,
                 sni: Option<webpki::DNSName>) {
@@ -935,16 +1201,49 @@ impl ExpectClientHello {
     }
 }
 
+fn describe_offer(client_hello: &ClientHelloPayload) -> ClientOffer {
+    ClientOffer {
+        client_version: client_hello.client_version,
+        cipher_suites: client_hello.cipher_suites.clone(),
+        extensions: client_hello.extensions
+            .iter()
+            .map(|ext| ext.get_type())
+            .collect(),
+        supported_versions: client_hello.get_versions_extension()
+            .cloned()
+            .unwrap_or_else(Vec::new),
+        signature_schemes: client_hello.get_sigalgs_extension()
+            .cloned()
+            .unwrap_or_else(Vec::new),
+        named_groups: client_hello.get_namedgroups_extension()
+            .cloned()
+            .unwrap_or_else(Vec::new),
+        ec_point_formats: client_hello.get_ecpoints_extension()
+            .cloned()
+            .unwrap_or_else(Vec::new),
+        alpn_protocols: client_hello.get_alpn_extension()
+            .map(|protos| protos.iter().map(|proto| proto.0.clone()).collect())
+            .unwrap_or_else(Vec::new),
+    }
+}
+
 impl State for ExpectClientHello {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingHello
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::ClientHello])
     }
 
     fn handle(mut self: Box<Self>, sess: &mut ServerSessionImpl, m: Message) -> NextStateOrError {
+        if !self.done_retry {
+            sess.common.emit_event(SessionEvent::HandshakeStarted);
+        }
+
         let client_hello = extract_handshake!(m, HandshakePayload::ClientHello).unwrap();
-        let tls13_enabled = sess.config.versions.contains(&ProtocolVersion::TLSv1_3);
-        let tls12_enabled = sess.config.versions.contains(&ProtocolVersion::TLSv1_2);
         trace!("we got a clienthello {:?}", client_hello);
+        sess.client_offer = Some(describe_offer(client_hello));
 
         if !client_hello.compression_methods.contains(&Compression::Null) {
             sess.common.send_fatal_alert(AlertDescription::IllegalParameter);
@@ -956,6 +1255,14 @@ impl State for ExpectClientHello {
             return Err(decode_error(sess, "client sent duplicate extensions"));
         }
 
+        // A retried ClientHello keeps the config chosen for the first.
+        if !self.done_retry {
+            self.resolve_config(sess, client_hello);
+        }
+
+        let tls13_enabled = sess.enables_version(ProtocolVersion::TLSv1_3);
+        let tls12_enabled = sess.enables_version(ProtocolVersion::TLSv1_2);
+
         // Are we doing TLS1.3?
         let maybe_versions_ext = client_hello.get_versions_extension();
         if let Some(versions) = maybe_versions_ext {
@@ -975,6 +1282,11 @@ impl State for ExpectClientHello {
 
         if sess.common.negotiated_version == None {
             sess.common.negotiated_version = Some(ProtocolVersion::TLSv1_2);
+
+            // We don't set TLS12_DOWNGRADE_SENTINEL: we only speak a
+            // TLS1.3 draft, so a client offering final TLS1.3 rightly
+            // falls back to TLS1.2 with us, and would abort if it saw
+            // the sentinel.  Set it once we speak final TLS1.3.
         }
 
         // Common to TLS1.2 and TLS1.3: ciphersuite and certificate selection.
@@ -1007,12 +1319,20 @@ impl State for ExpectClientHello {
             trace!("sni {:?}", sni_ref);
             trace!("sig schemes {:?}", sigschemes_ext);
             let certkey = sess.config.cert_resolver.resolve(sni_ref, sigschemes_ext);
-            certkey.ok_or_else(|| {
-                sess.common.send_fatal_alert(AlertDescription::AccessDenied);
-                TLSError::General("no server certificate chain resolved".to_string())
-            })?
+            match (certkey, sni_ref) {
+                (Some(certkey), _) => certkey,
+                (None, Some(_)) => unrecognized_name(sess, sigschemes_ext)?,
+                (None, None) => {
+                    sess.common.send_fatal_alert(AlertDescription::AccessDenied);
+                    return Err(TLSError::General("no server certificate chain resolved".to_string()));
+                }
+            }
         };
 
+        if client_hello.find_extension(ExtensionType::SCT).is_some() {
+            prepare_sct_list(sess, &mut certkey);
+        }
+
         // Reduce our supported ciphersuites by the certificate.
         // (no-op for TLS1.3)
         let suitable_suites = suites::reduce_given_sigalg(&sess.config.ciphersuites,
@@ -1084,16 +1404,18 @@ impl State for ExpectClientHello {
         // our handling of the ClientHello.
         //
         let mut ticket_received = false;
+        let mut resumption_offered = false;
 
         if let Some(ticket_ext) = client_hello.get_ticket_extension() {
             if let ClientExtension::SessionTicketOffer(ref ticket) = *ticket_ext {
                 ticket_received = true;
+                resumption_offered = !ticket.0.is_empty();
                 debug!("Ticket received");
 
                 let maybe_resume = sess.config
                     .ticketer
                     .decrypt(&ticket.0)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain));
+                    .and_then(decode_session);
 
                 if can_resume(sess, &self.handshake, &maybe_resume) {
                     return self.start_resumption(sess,
@@ -1107,8 +1429,10 @@ impl State for ExpectClientHello {
         }
 
         // If we're not offered a ticket or a potential session ID,
-        // allocate a session ID.
-        if self.handshake.session_id.is_empty() && !ticket_received {
+        // allocate a session ID.  There's no point if we won't store
+        // the session.
+        if self.handshake.session_id.is_empty() && !ticket_received &&
+           !sess.overrides.disable_resumption {
             let sessid = sess.config
                 .session_storage
                 .generate();
@@ -1118,9 +1442,10 @@ impl State for ExpectClientHello {
         // Perhaps resume?  If we received a ticket, the sessionid
         // does not correspond to a real session.
         if !client_hello.session_id.is_empty() && !ticket_received {
+            resumption_offered = true;
             let maybe_resume = sess.config.session_storage
                 .get(&client_hello.session_id.get_encoding())
-                .and_then(|x| persist::ServerSessionValue::read_bytes(&x));
+                .and_then(decode_session);
 
             if can_resume(sess, &self.handshake, &maybe_resume) {
                 return self.start_resumption(sess,
@@ -1130,12 +1455,21 @@ impl State for ExpectClientHello {
             }
         }
 
+        if resumption_offered {
+            sess.common.emit_event(SessionEvent::ResumptionRejected);
+        }
+
         // Now we have chosen a ciphersuite, we can make kx decisions.
+        let mut sigschemes_ext = sigschemes_ext.clone();
+        if !sess.config.enable_rsa_pkcs1 {
+            sigschemes_ext.retain(|scheme| !scheme.is_rsa_pkcs1());
+        }
+
         let sigscheme = sess.common.get_suite_assert()
-            .resolve_sig_scheme(sigschemes_ext)
+            .resolve_sig_scheme(&sigschemes_ext)
             .ok_or_else(|| incompatible(sess, "no supported sig scheme"))?;
 
-        let group = util::first_in_both(NamedGroups::supported().as_slice(),
+        let group = util::first_in_both(sess.config.get_kx_groups().as_slice(),
                                         groups_ext.as_slice())
             .ok_or_else(|| incompatible(sess, "no supported group"))?;
 
@@ -1179,6 +1513,10 @@ impl ExpectTLS12Certificate {
 }
 
 impl State for ExpectTLS12Certificate {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::Certificate])
     }
@@ -1231,6 +1569,10 @@ impl ExpectTLS13Certificate {
 }
 
 impl State for ExpectTLS13Certificate {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::Certificate])
     }
@@ -1297,6 +1639,10 @@ impl ExpectTLS12ClientKX {
 }
 
 impl State for ExpectTLS12ClientKX {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::ClientKeyExchange])
     }
@@ -1357,6 +1703,10 @@ impl ExpectTLS12CertificateVerify {
 }
 
 impl State for ExpectTLS12CertificateVerify {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::CertificateVerify])
     }
@@ -1367,7 +1717,11 @@ impl State for ExpectTLS12CertificateVerify {
             let handshake_msgs = self.handshake.transcript.take_handshake_buf();
             let certs = &self.client_cert.cert_chain;
 
-            verify::verify_signed_struct(&handshake_msgs, &certs[0], sig)
+            if !sess.config.enable_rsa_pkcs1 && sig.scheme.is_rsa_pkcs1() {
+                Err(TLSError::PeerMisbehavedError("client signed with RSA PKCS#1".to_string()))
+            } else {
+                verify::verify_signed_struct(&handshake_msgs, &certs[0], sig)
+            }
         };
 
         if let Err(e) = rc {
@@ -1399,6 +1753,10 @@ impl ExpectTLS13CertificateVerify {
 }
 
 impl State for ExpectTLS13CertificateVerify {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingCertificates
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::CertificateVerify])
     }
@@ -1413,7 +1771,8 @@ impl State for ExpectTLS13CertificateVerify {
             verify::verify_tls13(&certs[0],
                                  sig,
                                  &handshake_hash,
-                                 b"TLS 1.3, client CertificateVerify\x00")
+                                 b"TLS 1.3, client CertificateVerify\x00",
+                                 None)
         };
 
         if let Err(e) = rc {
@@ -1447,6 +1806,10 @@ impl ExpectTLS12CCS {
 }
 
 impl State for ExpectTLS12CCS {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_message(m, &[ContentType::ChangeCipherSpec], &[])
     }
@@ -1497,29 +1860,43 @@ fn get_server_session_value_tls13(handshake: &HandshakeDetails,
     let handshake_hash = handshake
         .transcript
         .get_current_hash();
-    let resumption_master_secret = sess.common
+    let mut resumption_master_secret = sess.common
         .get_key_schedule()
         .derive(SecretKind::ResumptionMasterSecret, &handshake_hash);
     let secret = sess.common
         .get_key_schedule()
         .derive_ticket_psk(&resumption_master_secret, nonce);
+    util::wipe(&mut resumption_master_secret);
 
     persist::ServerSessionValue::new(sess.get_sni(), version,
                                      scs.suite, secret,
                                      &sess.client_cert_chain)
 }
 
+// RFC8446 section 4.6.1.
+const MAX_TICKET_LIFETIME_TLS13: u32 = 7 * 24 * 60 * 60;
+
+fn get_ticket_lifetime(sess: &ServerSessionImpl) -> u32 {
+    sess.config.ticket_lifetime
+        .unwrap_or_else(|| sess.config.ticketer.get_lifetime())
+}
+
 fn emit_ticket(handshake: &mut HandshakeDetails,
                sess: &mut ServerSessionImpl) {
     // If we can't produce a ticket for some reason, we can't
     // report an error. Send an empty one.
-    let plain = get_server_session_value_tls12(handshake, sess)
+    let mut plain = get_server_session_value_tls12(handshake, sess)
         .get_encoding();
     let ticket = sess.config
         .ticketer
         .encrypt(&plain)
         .unwrap_or_else(Vec::new);
-    let ticket_lifetime = sess.config.ticketer.get_lifetime();
+    util::wipe(&mut plain);
+    let ticket_lifetime = get_ticket_lifetime(sess);
+
+    if !ticket.is_empty() {
+        sess.common.emit_event(SessionEvent::TicketIssued);
+    }
 
     let m = Message {
         typ: ContentType::Handshake,
@@ -1583,6 +1960,10 @@ impl ExpectTLS12Finished {
 }
 
 impl State for ExpectTLS12Finished {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::Finished])
     }
@@ -1604,7 +1985,8 @@ impl State for ExpectTLS12Finished {
             .map(|_| verify::FinishedMessageVerified::assertion())?;
 
         // Save session, perhaps
-        if !self.resuming && !self.handshake.session_id.is_empty() {
+        if !self.resuming && !self.handshake.session_id.is_empty() &&
+           !sess.overrides.disable_resumption {
             let value = get_server_session_value_tls12(&self.handshake, sess);
 
             let worked = sess.config.session_storage
@@ -1652,12 +2034,13 @@ impl ExpectTLS13Finished {
         }
 
         let nonce = rand::random_vec(32);
-        let plain = get_server_session_value_tls13(&self.handshake, sess, &nonce)
+        let mut plain = get_server_session_value_tls13(&self.handshake, sess, &nonce)
             .get_encoding();
         let maybe_ticket = sess.config
             .ticketer
             .encrypt(&plain);
-        let ticket_lifetime = sess.config.ticketer.get_lifetime();
+        util::wipe(&mut plain);
+        let ticket_lifetime = cmp::min(get_ticket_lifetime(sess), MAX_TICKET_LIFETIME_TLS13);
 
         if maybe_ticket.is_none() {
             return;
@@ -1678,10 +2061,15 @@ impl ExpectTLS13Finished {
         trace!("sending new ticket {:?}", m);
         self.handshake.transcript.add_message(&m);
         sess.common.send_msg(m, true);
+        sess.common.emit_event(SessionEvent::TicketIssued);
     }
 }
 
 impl State for ExpectTLS13Finished {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::AwaitingFinished
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_handshake_message(m, &[HandshakeType::Finished])
     }
@@ -1716,12 +2104,16 @@ impl State for ExpectTLS13Finished {
         let suite = sess.common.get_suite_assert();
         check_aligned_handshake(sess)?;
         sess.common.set_message_decrypter(cipher::new_tls13_read(suite, &read_key));
+        sess.common.log_tls13_secret(SecretKind::ClientApplicationTrafficSecret,
+                                     &self.handshake.randoms.client, &read_key);
         sess.common
             .get_mut_key_schedule()
             .current_client_traffic_secret = read_key;
 
-        if sess.config.ticketer.enabled() {
-            self.emit_ticket_tls13(sess);
+        if sess.enables_tickets() {
+            for _ in 0..sess.config.tls13_ticket_count {
+                self.emit_ticket_tls13(sess);
+            }
         }
 
         sess.common.we_now_encrypting();
@@ -1739,6 +2131,10 @@ impl ExpectTLS12Traffic {
 }
 
 impl State for ExpectTLS12Traffic {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::Traffic
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_message(m, &[ContentType::ApplicationData], &[])
     }
@@ -1766,6 +2162,10 @@ impl ExpectTLS13Traffic {
 }
 
 impl State for ExpectTLS13Traffic {
+    fn phase(&self) -> HandshakePhase {
+        HandshakePhase::Traffic
+    }
+
     fn check_message(&self, m: &Message) -> CheckResult {
         check_message(m,
                       &[ContentType::ApplicationData, ContentType::Handshake],
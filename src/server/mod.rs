@@ -1,21 +1,34 @@
-use session::{Session, SessionCommon};
-use suites::{SupportedCipherSuite, ALL_CIPHERSUITES};
-use msgs::enums::{ContentType, SignatureScheme};
-use msgs::enums::{AlertDescription, HandshakeType, ProtocolVersion};
-use msgs::handshake::SessionID;
+use session::{Session, SessionCommon, TLS12Flags, ObservesUncleanClose};
+use session::{HelloRetryInfo, ObservesSessionEvents, Received, ConnectionId};
+use session::{UnbufferedStatus, HandshakePhase, SessionEvent, SessionOverrides};
+use session::{WEB_ALPN_PROTOCOLS, WEB_BUFFER_LIMIT, RenegotiationPolicy};
+use key_log::KeyLog;
+use suites::{self, SupportedCipherSuite, SecurityProfile, ALL_CIPHERSUITES};
+use msgs::enums::{ContentType, SignatureScheme, NamedGroup, CipherSuite, ECPointFormat};
+use msgs::enums::{AlertDescription, HandshakeType, ProtocolVersion, ExtensionType};
+use msgs::enums::AlertLevel;
+use msgs::handshake::{SessionID, DistinguishedNames};
 use msgs::message::Message;
 use error::TLSError;
 use sign;
+use kx;
 use verify;
+use ticketer::Ticketer;
 use key;
+use custom_ext;
+use capture;
+use summary::{ConfigSummary, ClientAuthMode, ConfigWarning};
 use webpki;
 
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
 use std::io;
 use std::fmt;
+use std::mem::MaybeUninit;
 
 mod hs;
 mod common;
+mod fingerprint;
 pub mod handy;
 
 /// A trait for the ability to generate Session IDs, and store
@@ -41,6 +54,12 @@ pub trait StoresServerSessions : Send + Sync {
     /// Find a session with the given `id`.  Return it, or None
     /// if it doesn't exist.
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Returns false if this implementation never stores anything.
+    /// This is only used to describe configs.
+    fn enabled(&self) -> bool {
+        true
+    }
 }
 
 /// A trait for the ability to encrypt and decrypt tickets.
@@ -90,6 +109,102 @@ pub trait ResolvesServerCert : Send + Sync {
                -> Option<sign::CertifiedKey>;
 }
 
+/// Something which supplies signed certificate timestamps (RFC6962)
+/// for the server's certificate, to send to clients asking for them.
+/// This suits certificates without embedded SCTs, when the
+/// `CertifiedKey` from `ResolvesServerCert` has no `sct_list`.
+pub trait ProvidesSCTs : Send + Sync {
+    /// Return a `SignedCertificateTimestampList` encoding of SCTs
+    /// for `cert_chain`, whose first entry is the end-entity
+    /// certificate, or None to send none.
+    fn sct_list(&self, cert_chain: &[key::Certificate]) -> Option<Vec<u8>>;
+}
+
+/// How to choose an ALPN protocol for a session, as an alternative
+/// to a fixed `ServerConfig::alpn_protocols` list.
+pub trait ChoosesAlpnProtocol : Send + Sync {
+    /// Choose one of the `offered` protocols, which are the client's,
+    /// most preferred first.  `server_name` is the DNS name the client
+    /// sent with SNI, if any.
+    ///
+    /// Return `Ok(None)` to continue without ALPN.  Returning an error
+    /// rejects the handshake with a `no_application_protocol` alert.
+    fn choose(&self,
+              server_name: Option<webpki::DNSNameRef>,
+              offered: &[String])
+              -> Result<Option<String>, TLSError>;
+}
+
+/// What a `ResolvesServerConfig` knows about the client, from
+/// its ClientHello.
+#[derive(Debug)]
+pub struct ClientHelloSummary<'a> {
+    /// The DNS name the client sent with SNI, if any.
+    pub server_name: Option<webpki::DNSNameRef<'a>>,
+
+    /// The signature schemes the client supports.
+    pub signature_schemes: &'a [SignatureScheme],
+
+    /// The ALPN protocols the client offered, most preferred first.
+    /// This is empty if the client didn't do ALPN.
+    pub alpn_protocols: Vec<String>,
+
+    /// The ciphersuites the client offered.
+    pub cipher_suites: &'a [CipherSuite],
+}
+
+/// What the client offered in its ClientHello, kept for the life
+/// of the session.  This is for logging, anomaly detection and
+/// the like: it has no effect on the handshake.
+///
+/// If the server sent a HelloRetryRequest this describes the
+/// second ClientHello.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientOffer {
+    /// The `client_version` field of the ClientHello.
+    pub client_version: ProtocolVersion,
+
+    /// The offered ciphersuites, in the client's preference order.
+    pub cipher_suites: Vec<CipherSuite>,
+
+    /// The types of the extensions, in the order they were sent.
+    pub extensions: Vec<ExtensionType>,
+
+    /// The versions from the supported_versions extension.  This is
+    /// empty if the client didn't send it.
+    pub supported_versions: Vec<ProtocolVersion>,
+
+    /// The schemes from the signature_algorithms extension.  This
+    /// is empty if the client didn't send it.
+    pub signature_schemes: Vec<SignatureScheme>,
+
+    /// The groups from the supported_groups extension.  This is
+    /// empty if the client didn't send it.
+    pub named_groups: Vec<NamedGroup>,
+
+    /// The formats from the ec_point_formats extension.  This is
+    /// empty if the client didn't send it.
+    pub ec_point_formats: Vec<ECPointFormat>,
+
+    /// The protocols from the ALPN extension, most preferred first.
+    /// This is empty if the client didn't do ALPN.
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// How to choose a complete `ServerConfig` for a session once
+/// its ClientHello arrives.  This allows different policy per
+/// virtual host on one listener: different versions, ALPN
+/// protocols, certificates or client authentication.
+pub trait ResolvesServerConfig : Send + Sync {
+    /// Return the config to use for the rest of the handshake, or
+    /// None to carry on with the config the session was made with.
+    ///
+    /// Settings which are fixed when the session is made --
+    /// `mtu`, `buffer_limit`, `max_appdata_record_len`, the observers
+    /// and message capture -- still come from the original config.
+    fn resolve(&self, hello: &ClientHelloSummary) -> Option<Arc<ServerConfig>>;
+}
+
 /// Common configuration for a set of server sessions.
 ///
 /// Making one of these can be expensive, and should be
@@ -104,28 +219,191 @@ pub struct ServerConfig {
     /// which is supported by the client.
     pub ignore_client_order: bool,
 
+    /// Whether to use and accept RSA PKCS#1 v1.5 signature schemes
+    /// in TLS1.2.  If false, we only sign our key exchange with
+    /// RSA-PSS or ECDSA, we leave them out of our CertificateRequest,
+    /// and we reject a client CertificateVerify which uses them.
+    ///
+    /// The default is true.
+    pub enable_rsa_pkcs1: bool,
+
+    /// Whether to refuse to resume TLS1.2 sessions which were
+    /// established without the extended master secret extension
+    /// (RFC7627), or where the resuming ClientHello doesn't offer
+    /// it.  Such sessions are vulnerable to the triple handshake
+    /// attack; if they're refused, the client gets a full handshake
+    /// instead.
+    ///
+    /// The default is false.
+    pub require_ems_for_resumption: bool,
+
+    /// How to turn down a TLS1.2 client's attempt to renegotiate.
+    /// rustls servers never renegotiate.
+    ///
+    /// The default is `RenegotiationPolicy::Refuse`.
+    pub renegotiation_policy: RenegotiationPolicy,
+
     /// Our MTU.  If None, we don't limit TLS message sizes.
     pub mtu: Option<usize>,
 
+    /// The initial buffer limit for sessions made from this config:
+    /// see `Session::set_buffer_limit`.  If None, there is no limit.
+    pub buffer_limit: Option<usize>,
+
+    /// The most plaintext to put in each application data record
+    /// sent by sessions made from this config.  Smaller records let
+    /// the peer decrypt and act on data sooner, at the cost of more
+    /// per-record overhead.  This only affects what we send; unlike
+    /// `mtu` it isn't about what the network can carry, and
+    /// handshake messages are unaffected.
+    ///
+    /// If None or zero, records are as large as `mtu` allows.
+    /// The default is None.
+    pub max_appdata_record_len: Option<usize>,
+
+    /// Whether to treat TLS1.2 warning alerts, other than
+    /// close_notify, as fatal.  Normally rustls logs these and
+    /// carries on; if this is true, the session fails with
+    /// `TLSError::AlertReceived` instead.  TLS1.3 forbids
+    /// warning alerts, so they are always fatal there.
+    ///
+    /// The default is false.
+    pub fatal_warning_alerts: bool,
+
+    /// Whether reading plaintext fails with `io::ErrorKind::UnexpectedEof`
+    /// once `read_tls` has seen the end of the underlying transport
+    /// but no close_notify alert was received.  Without this, reads
+    /// then return no data, and the caller must use
+    /// `Session::peer_has_closed` to tell a truncated session from
+    /// a complete one.
+    ///
+    /// The default is false.
+    pub report_truncation: bool,
+
     /// How to store client sessions.
     pub session_storage: Arc<StoresServerSessions + Send + Sync>,
 
     /// How to produce tickets.
     pub ticketer: Arc<ProducesTickets>,
 
+    /// The ticket lifetime, in seconds, which we tell clients.
+    /// If None, the ticketer's lifetime is used.  For TLS1.3 this
+    /// is limited to seven days.
+    pub ticket_lifetime: Option<u32>,
+
+    /// How many tickets to send after a TLS1.3 handshake.  Clients
+    /// should use each ticket once, so more tickets allow more
+    /// resumptions.
+    ///
+    /// The default is 1.
+    pub tls13_ticket_count: usize,
+
+    /// How to protect the cookies we put in TLS1.3 HelloRetryRequests.
+    /// If this is set, the cookie carries everything we need to carry
+    /// on the handshake: a fresh `ServerSession` made from this config
+    /// can accept the client's second ClientHello.  That means a
+    /// server under load can drop sessions after sending a
+    /// HelloRetryRequest, instead of keeping their state.
+    ///
+    /// A `Ticketer` works well here, but it should be a different
+    /// one from `ticketer`.
+    ///
+    /// The default is None: we send no cookie.
+    pub hello_retry_cookies: Option<Arc<ProducesTickets>>,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Arc<ResolvesServerCert>,
 
+    /// Where to get SCTs for a certificate from `cert_resolver`
+    /// which came without any, when the client asks for them.
+    /// An SCT list which isn't validly encoded is not sent.
+    ///
+    /// The default is None.
+    pub sct_provider: Option<Arc<ProvidesSCTs>>,
+
+    /// What to do when a client asks for a name `cert_resolver`
+    /// has no certificate for.
+    ///
+    /// - If None, we send a fatal access_denied alert.
+    /// - If `AlertLevel::Fatal`, we send a fatal unrecognized_name
+    ///   alert, so the client can tell what went wrong.
+    /// - If `AlertLevel::Warning`, we send a warning unrecognized_name
+    ///   alert and carry on with the certificate `cert_resolver` gives
+    ///   for no name.  TLS1.3 has no warning alerts, so there we carry
+    ///   on without one.  If there's no such certificate, we send a
+    ///   fatal unrecognized_name alert.
+    ///
+    /// The default is None.
+    pub unrecognized_name_alert: Option<AlertLevel>,
+
+    /// How to choose a different config for a session, given its
+    /// ClientHello.  The config this returns is used for the rest
+    /// of the handshake; its own `config_resolver` is ignored.
+    ///
+    /// The default is None.
+    pub config_resolver: Option<Arc<ResolvesServerConfig>>,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<String>,
 
+    /// Chooses an ALPN protocol for each session.  If this is set,
+    /// `alpn_protocols` is ignored.
+    ///
+    /// The default is None.
+    pub alpn_chooser: Option<Arc<ChoosesAlpnProtocol>>,
+
     /// Supported protocol versions, in no particular order.
     /// The default is all supported versions.
     pub versions: Vec<ProtocolVersion>,
 
+    /// Key exchange groups we support, most preferred first.
+    /// The default is X25519, then NIST P-384 and P-256.
+    pub kx_groups: Vec<Arc<kx::SupportedKxGroup>>,
+
+    /// Counts sessions made from this config, for their
+    /// `ConnectionId`.
+    connection_counter: Arc<AtomicUsize>,
+
+    /// Something to tell about sessions which are dropped without
+    /// sending or receiving close_notify.  This is a debugging aid.
+    ///
+    /// The default is None.
+    pub unclean_close_observer: Option<Arc<ObservesUncleanClose>>,
+
+    /// Something to tell about events in each session, such as
+    /// handshakes finishing and alerts.
+    ///
+    /// The default is None.
+    pub event_observer: Option<Arc<ObservesSessionEvents>>,
+
+    /// Something to give each session's secrets to, for decrypting
+    /// its traffic later.  This is a debugging aid.
+    ///
+    /// The default is None.
+    pub key_log: Option<Arc<KeyLog>>,
+
+    /// The names of the CAs we tell clients we accept client
+    /// certificates from, so a client with several identities
+    /// can choose one.  These go in the CertificateRequest: in
+    /// its list of names for TLS1.2, and in a certificate_authorities
+    /// extension for TLS1.3.
+    ///
+    /// If None, we send the subjects of our client certificate
+    /// verifier's trust roots.  Use `RootCertStore::get_subjects` to
+    /// send a different list.  An empty list sends no names.
+    ///
+    /// The default is None.
+    pub client_auth_ca_names: Option<DistinguishedNames>,
+
     /// How to verify client certificates.
     verifier: Arc<verify::ClientCertVerifier>,
+
+    /// Application-defined extensions we reply to.
+    custom_extensions: Vec<Arc<custom_ext::CustomExtension>>,
+
+    /// Sees every message sent and received, for debugging.
+    message_capture: Option<Arc<capture::CapturesMessages>>,
 }
 
 impl ServerConfig {
@@ -146,21 +424,161 @@ impl ServerConfig {
         ServerConfig {
             ciphersuites: ALL_CIPHERSUITES.to_vec(),
             ignore_client_order: false,
+            enable_rsa_pkcs1: true,
+            require_ems_for_resumption: false,
+            renegotiation_policy: RenegotiationPolicy::Refuse,
             mtu: None,
+            buffer_limit: None,
+            max_appdata_record_len: None,
+            fatal_warning_alerts: false,
+            report_truncation: false,
             session_storage: handy::ServerSessionMemoryCache::new(256),
             ticketer: Arc::new(handy::NeverProducesTickets {}),
+            ticket_lifetime: None,
+            tls13_ticket_count: 1,
+            hello_retry_cookies: None,
             alpn_protocols: Vec::new(),
+            alpn_chooser: None,
             cert_resolver: Arc::new(handy::FailResolveChain {}),
+            sct_provider: None,
+            unrecognized_name_alert: None,
+            config_resolver: None,
             versions: vec![ ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2 ],
+            kx_groups: kx::default_kx_groups(),
+            connection_counter: Arc::new(AtomicUsize::new(0)),
+            unclean_close_observer: None,
+            event_observer: None,
+            key_log: None,
+            client_auth_ca_names: None,
             verifier: client_cert_verifier,
+            custom_extensions: Vec::new(),
+            message_capture: None,
         }
     }
 
+    /// Make a `ServerConfig` suitable for a typical web server:
+    /// the defaults of `new`, without client authentication, using
+    /// `cert_chain` and `key_der` for every connection, offering
+    /// HTTP/2 and HTTP/1.1 with ALPN, issuing tickets with regularly
+    /// rotated keys, and with a buffer limit of 64KB.
+    ///
+    /// This panics if `key_der` is not a valid RSA private key, like
+    /// `set_single_cert`.  The result can be customised further like
+    /// any other config.
+    pub fn for_web_server(cert_chain: Vec<key::Certificate>,
+                          key_der: key::PrivateKey) -> ServerConfig {
+        let mut config = ServerConfig::new(verify::NoClientAuth::new());
+        config.set_single_cert(cert_chain, key_der);
+        config.ticketer = Ticketer::new();
+        config.alpn_protocols = WEB_ALPN_PROTOCOLS.iter()
+            .map(|proto| proto.to_string())
+            .collect();
+        config.buffer_limit = Some(WEB_BUFFER_LIMIT);
+        config
+    }
+
     #[doc(hidden)]
     pub fn get_verifier(&self) -> &verify::ClientCertVerifier {
         self.verifier.as_ref()
     }
 
+    /// Use the protocol versions, ciphersuites and key exchange
+    /// groups of `profile`.  This replaces `versions`, `ciphersuites`
+    /// and `kx_groups`; other settings are unchanged.
+    pub fn set_security_profile(&mut self, profile: SecurityProfile) {
+        self.versions = profile.versions();
+        self.ciphersuites = profile.ciphersuites();
+        self.kx_groups = profile.kx_groups();
+    }
+
+    /// Returns the protocol versions this config can use, most
+    /// preferred first.  Versions without any usable ciphersuite
+    /// in `ciphersuites` are left out.
+    pub fn get_effective_versions(&self) -> Vec<ProtocolVersion> {
+        suites::effective_versions(&self.versions, &self.ciphersuites)
+    }
+
+    /// Returns the ciphersuites this config can use, most preferred
+    /// first.  Suites for versions not in `versions` are left out.
+    pub fn get_effective_ciphersuites(&self) -> Vec<&'static SupportedCipherSuite> {
+        suites::effective_suites(&self.versions, &self.ciphersuites)
+    }
+
+    /// Returns the key exchange groups this config can use, most
+    /// preferred first.
+    pub fn get_kx_groups(&self) -> Vec<NamedGroup> {
+        kx::group_names(&self.kx_groups)
+    }
+
+    /// Returns the ALPN protocols, most preferred first.
+    pub fn get_alpn_protocols(&self) -> &[String] {
+        &self.alpn_protocols
+    }
+
+    /// Returns a summary of the security-relevant settings
+    /// sessions made from this config will use.  If
+    /// `config_resolver` is set, sessions may use other configs.
+    pub fn get_summary(&self) -> ConfigSummary {
+        let tickets = self.ticketer.enabled();
+
+        ConfigSummary {
+            is_client: false,
+            versions: self.get_effective_versions(),
+            ciphersuites: self.get_effective_ciphersuites()
+                .iter()
+                .map(|scs| scs.suite)
+                .collect(),
+            kx_groups: self.get_kx_groups(),
+            alpn_protocols: self.alpn_protocols.clone(),
+            client_auth: if !self.verifier.offer_client_auth() {
+                ClientAuthMode::Disabled
+            } else if self.verifier.client_auth_mandatory() {
+                ClientAuthMode::Mandatory
+            } else {
+                ClientAuthMode::Optional
+            },
+            custom_verifier: false,
+            enable_rsa_pkcs1: self.enable_rsa_pkcs1,
+            enable_renegotiation: false,
+            renegotiation_policy: self.renegotiation_policy,
+            downgrade_protection: None,
+            require_ems_for_resumption: self.require_ems_for_resumption,
+            session_storage: self.session_storage.enabled(),
+            config_resolver: self.config_resolver.is_some(),
+            tickets: tickets,
+            ticket_lifetime: if tickets {
+                Some(self.ticket_lifetime.unwrap_or_else(|| self.ticketer.get_lifetime()))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the settings in this config which may be mistakes.
+    /// None of these stop the config being used.
+    pub fn get_warnings(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+        let versions = self.get_effective_versions();
+
+        if versions.is_empty() {
+            warnings.push(ConfigWarning::NoUsableVersions);
+        }
+
+        if versions.contains(&ProtocolVersion::TLSv1_2) && !self.require_ems_for_resumption {
+            warnings.push(ConfigWarning::Tls12WithoutEmsRequirement);
+        }
+
+        if self.verifier.offer_client_auth() && !self.verifier.client_auth_mandatory() {
+            warnings.push(ConfigWarning::OptionalClientAuth);
+        }
+
+        if self.alpn_protocols.is_empty() && self.alpn_chooser.is_none() {
+            warnings.push(ConfigWarning::NoAlpnProtocols);
+        }
+
+        warnings
+    }
+
     /// Sets the session persistence layer to `persist`.
     pub fn set_persistence(&mut self, persist: Arc<StoresServerSessions + Send + Sync>) {
         self.session_storage = persist;
@@ -213,6 +631,27 @@ impl ServerConfig {
         self.alpn_protocols.clear();
         self.alpn_protocols.extend_from_slice(protocols);
     }
+
+    /// Adds an application-defined extension.  We reply to
+    /// this extension if a client sends it.
+    ///
+    /// This fails if the extension's type is one rustls knows,
+    /// or is the type of an extension already added.
+    #[cfg(feature = "custom_extensions")]
+    pub fn add_custom_extension(&mut self, ext: Arc<custom_ext::CustomExtension>)
+                                -> Result<(), TLSError> {
+        custom_ext::check_new(&self.custom_extensions, ext.as_ref())?;
+        self.custom_extensions.push(ext);
+        Ok(())
+    }
+
+    /// Captures every TLS message sent and received by sessions
+    /// made from this config, in plaintext.  This exposes secrets,
+    /// so is only for debugging and testing.
+    #[cfg(feature = "message_capture")]
+    pub fn set_message_capture(&mut self, capture: Arc<capture::CapturesMessages>) {
+        self.message_capture = Some(capture);
+    }
 }
 
 pub struct ServerSessionImpl {
@@ -223,6 +662,10 @@ pub struct ServerSessionImpl {
     pub error: Option<TLSError>,
     pub state: Option<Box<hs::State + Send + Sync>>,
     pub client_cert_chain: Option<Vec<key::Certificate>>,
+    pub received_custom_extensions: custom_ext::ReceivedCustomExtensions,
+    pub send_tickets: bool,
+    pub client_offer: Option<ClientOffer>,
+    pub overrides: SessionOverrides,
 }
 
 impl fmt::Debug for ServerSessionImpl {
@@ -232,20 +675,55 @@ impl fmt::Debug for ServerSessionImpl {
 }
 
 impl ServerSessionImpl {
-    pub fn new(server_config: &Arc<ServerConfig>) -> ServerSessionImpl {
+    pub fn new(server_config: &Arc<ServerConfig>,
+               overrides: SessionOverrides) -> ServerSessionImpl {
         let perhaps_client_auth = server_config.verifier.offer_client_auth();
+        let mut common = SessionCommon::new(server_config.mtu,
+                                            false,
+                                            ConnectionId::next(&server_config.connection_counter));
+        if let Some(limit) = server_config.buffer_limit {
+            common.set_buffer_limit(limit);
+        }
+        common.max_appdata_record_len = server_config.max_appdata_record_len;
+        common.fatal_warning_alerts = server_config.fatal_warning_alerts;
+        common.report_truncation = server_config.report_truncation;
+        common.renegotiation_policy = server_config.renegotiation_policy;
+        common.unclean_close_observer = server_config.unclean_close_observer.clone();
+        common.event_observer = server_config.event_observer.clone();
+        common.key_log = server_config.key_log.clone();
+        common.message_capture = server_config.message_capture.clone();
 
         ServerSessionImpl {
             config: server_config.clone(),
-            common: SessionCommon::new(server_config.mtu, false),
+            common,
             sni: None,
             alpn_protocol: None,
             error: None,
             state: Some(Box::new(hs::ExpectClientHello::new(perhaps_client_auth))),
             client_cert_chain: None,
+            received_custom_extensions: Vec::new(),
+            send_tickets: true,
+            client_offer: None,
+            overrides,
         }
     }
 
+    /// The ALPN protocols we accept, perhaps overridden for this session.
+    pub fn alpn_protocols(&self) -> &[String] {
+        self.overrides.alpn_protocols(&self.config.alpn_protocols)
+    }
+
+    /// Is `version` enabled for this session?
+    pub fn enables_version(&self, version: ProtocolVersion) -> bool {
+        self.overrides.enables_version(&self.config.versions, version)
+    }
+
+    /// Do we issue tickets in this session?
+    pub fn enables_tickets(&self) -> bool {
+        self.config.ticketer.enabled() && self.send_tickets &&
+            !self.overrides.disable_resumption
+    }
+
     pub fn wants_read(&self) -> bool {
         // We want to read more data all the time, except when we
         // have unprocessed plaintext.  This provides back-pressure
@@ -264,15 +742,32 @@ impl ServerSessionImpl {
         !self.common.traffic
     }
 
+    pub fn get_handshake_phase(&self) -> Option<HandshakePhase> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        self.state.as_ref().map(|st| st.phase())
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.error.is_none() && self.common.is_usable()
+    }
+
     pub fn set_buffer_limit(&mut self, len: usize) {
         self.common.set_buffer_limit(len)
     }
 
     pub fn process_msg(&mut self, mut msg: Message) -> Result<(), TLSError> {
-        // TLS1.3: drop CCS at any time during handshaking
-        if self.common.is_tls13()
+        // TLS1.3: drop CCS at any time during handshaking.  If we do
+        // stateless retries, a client's CCS can come before the first
+        // ClientHello we see: it followed one another session saw.
+        let may_be_retrying = self.config.hello_retry_cookies.is_some()
+            && self.common.negotiated_version.is_none();
+        if (self.common.is_tls13() || may_be_retrying)
             && msg.is_content_type(ContentType::ChangeCipherSpec)
             && self.is_handshaking() {
+            self.common.capture_received(&msg);
             trace!("Dropping CCS");
             return Ok(());
         }
@@ -283,6 +778,13 @@ impl ServerSessionImpl {
             msg = dm;
         }
 
+        self.process_plaintext_msg(msg)
+    }
+
+    /// Process `msg`, which has been decrypted if necessary.
+    fn process_plaintext_msg(&mut self, mut msg: Message) -> Result<(), TLSError> {
+        self.common.capture_received(&msg);
+
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self.common.handshake_joiner.want_message(&msg) {
@@ -319,8 +821,7 @@ impl ServerSessionImpl {
     pub fn process_main_protocol(&mut self, msg: Message) -> Result<(), TLSError> {
         if self.common.traffic && !self.common.is_tls13() &&
            msg.is_handshake_type(HandshakeType::ClientHello) {
-            self.common.send_warning_alert(AlertDescription::NoRenegotiation);
-            return Ok(());
+            return self.common.refuse_renegotiation();
         }
 
         let st = self.state.take().unwrap();
@@ -341,20 +842,61 @@ impl ServerSessionImpl {
             return Err(TLSError::CorruptMessage);
         }
 
-        while let Some(msg) = self.common.message_deframer.frames.pop_front() {
-            match self.process_msg(msg) {
-                Ok(_) => {}
-                Err(err) => {
-                    self.error = Some(err.clone());
-                    return Err(err);
-                }
+        loop {
+            let result = match self.common.next_message() {
+                Ok(None) => break,
+                Ok(Some(Received::Raw(msg))) => self.process_msg(msg),
+                Ok(Some(Received::Plaintext(msg))) => self.process_plaintext_msg(msg),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                self.error = Some(err.clone());
+                return Err(err);
             }
-
         }
 
         Ok(())
     }
 
+    pub fn process_unbuffered(&mut self,
+                              input: &mut [u8],
+                              output: &mut [u8],
+                              plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError> {
+        if let Some(ref err) = self.error {
+            return Err(err.clone());
+        }
+
+        let mut status = UnbufferedStatus::default();
+
+        loop {
+            status.output_written += self.common.write_tls_into(&mut output[status.output_written..]);
+            self.common.take_plaintext_into(plaintext);
+
+            // Don't take on more work until our output is sent.
+            if !self.common.sendable_tls.is_empty() {
+                status.wants_write = true;
+                break;
+            }
+
+            let result = match self.common.next_message_unbuffered(input,
+                                                                   &mut status.input_used,
+                                                                   plaintext) {
+                Ok(None) => break,
+                Ok(Some(Received::Raw(msg))) => self.process_msg(msg),
+                Ok(Some(Received::Plaintext(msg))) => self.process_plaintext_msg(msg),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                self.error = Some(err.clone());
+                return Err(err);
+            }
+        }
+
+        Ok(status)
+    }
+
     pub fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         if self.client_cert_chain.is_none() {
             return None;
@@ -388,6 +930,8 @@ impl ServerSessionImpl {
     pub fn set_sni(&mut self, value: webpki::DNSName) {
         // The SNI hostname is immutable once set.
         assert!(self.sni.is_none());
+        let name: &str = value.as_ref().into();
+        self.common.peer_name = Some(name.to_string());
         self.sni = Some(value)
     }
 }
@@ -406,7 +950,15 @@ impl ServerSession {
     /// Make a new ServerSession.  `config` controls how
     /// we behave in the TLS protocol.
     pub fn new(config: &Arc<ServerConfig>) -> ServerSession {
-        ServerSession { imp: ServerSessionImpl::new(config) }
+        ServerSession { imp: ServerSessionImpl::new(config, SessionOverrides::default()) }
+    }
+
+    /// Make a new ServerSession, like `new`, but with some of
+    /// `config`'s settings replaced by `overrides` for this session
+    /// alone.
+    pub fn new_with_overrides(config: &Arc<ServerConfig>,
+                              overrides: SessionOverrides) -> ServerSession {
+        ServerSession { imp: ServerSessionImpl::new(config, overrides) }
     }
 
     /// Retrieves the SNI hostname, if any, used to select the certificate and
@@ -428,6 +980,45 @@ impl ServerSession {
     pub fn get_sni_hostname(&self)-> Option<&str> {
         self.imp.get_sni().map(|s| s.as_ref().into())
     }
+
+    /// Sets whether we send tickets to this client, if our
+    /// configuration allows tickets at all.  This must be called
+    /// before the handshake starts.
+    ///
+    /// The default is true.
+    pub fn set_send_tickets(&mut self, send_tickets: bool) {
+        self.imp.send_tickets = send_tickets;
+    }
+
+    /// Returns what the client offered in its ClientHello.
+    ///
+    /// This returns None until a ClientHello has been received.
+    /// It is still available if the handshake then failed.
+    pub fn get_client_offer(&self) -> Option<&ClientOffer> {
+        self.imp.client_offer.as_ref()
+    }
+
+    /// Returns every ALPN protocol the client offered, most preferred
+    /// first, whichever one was chosen -- or if none was.
+    ///
+    /// This is empty until a ClientHello has been received, and if
+    /// the client didn't do ALPN.
+    pub fn get_offered_alpn_protocols(&self) -> &[Vec<u8>] {
+        match self.imp.client_offer {
+            Some(ref offer) => &offer.alpn_protocols,
+            None => &[],
+        }
+    }
+
+    /// Retrieves the body of the application-defined extension of
+    /// type `typ` which the client sent.
+    ///
+    /// This returns None until the ClientHello has been processed, or
+    /// if the client did not send the extension.
+    #[cfg(feature = "custom_extensions")]
+    pub fn get_received_custom_extension(&self, typ: u16) -> Option<&[u8]> {
+        custom_ext::find_received(&self.imp.received_custom_extensions, typ)
+    }
 }
 
 impl Session for ServerSession {
@@ -435,11 +1026,30 @@ impl Session for ServerSession {
         self.imp.common.read_tls(rd)
     }
 
+    fn read_tls_from_buffered(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.imp.common.read_tls_from_buffered(buf)
+    }
+
+    fn read_tls_from_slice(&mut self, buf: &[u8]) -> usize {
+        self.imp.common.read_tls_from_slice(buf)
+    }
+
     /// Writes TLS messages to `wr`.
     fn write_tls(&mut self, wr: &mut io::Write) -> io::Result<usize> {
         self.imp.common.write_tls(wr)
     }
 
+    fn process_unbuffered(&mut self,
+                          input: &mut [u8],
+                          output: &mut [u8],
+                          plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError> {
+        self.imp.process_unbuffered(input, output, plaintext)
+    }
+
+    fn write_unbuffered(&mut self, plaintext: &[u8], output: &mut [u8]) -> (usize, usize) {
+        self.imp.common.write_unbuffered(plaintext, output)
+    }
+
     fn process_new_packets(&mut self) -> Result<(), TLSError> {
         self.imp.process_new_packets()
     }
@@ -456,14 +1066,46 @@ impl Session for ServerSession {
         self.imp.is_handshaking()
     }
 
+    fn get_handshake_phase(&self) -> Option<HandshakePhase> {
+        self.imp.get_handshake_phase()
+    }
+
+    fn take_events(&mut self) -> Vec<SessionEvent> {
+        self.imp.common.take_events()
+    }
+
+    fn is_usable(&self) -> bool {
+        self.imp.is_usable()
+    }
+
     fn set_buffer_limit(&mut self, len: usize) {
         self.imp.set_buffer_limit(len)
     }
 
+    fn set_max_fragment_size(&mut self, size: Option<usize>) -> Result<(), TLSError> {
+        self.imp.common.set_max_fragment_size(size)
+    }
+
+    fn set_write_coalescing(&mut self, threshold: Option<usize>) {
+        self.imp.common.set_write_coalescing(threshold)
+    }
+
     fn send_close_notify(&mut self) {
         self.imp.common.send_close_notify()
     }
 
+    fn peek(&self, buf: &mut [u8]) -> usize {
+        self.imp.common.peek(buf)
+    }
+
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        self.imp.common.read_uninit(buf)
+    }
+
+    fn peer_has_closed(&self) -> bool {
+        self.imp.common.peer_has_closed()
+    }
+
     fn get_peer_certificates(&self) -> Option<Vec<key::Certificate>> {
         self.imp.get_peer_certificates()
     }
@@ -486,6 +1128,18 @@ impl Session for ServerSession {
     fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite> {
         self.imp.get_negotiated_ciphersuite()
     }
+
+    fn get_tls12_flags(&self) -> Option<TLS12Flags> {
+        self.imp.common.tls12_flags
+    }
+
+    fn get_hello_retry_info(&self) -> Option<HelloRetryInfo> {
+        self.imp.common.hello_retry.clone()
+    }
+
+    fn get_connection_id(&self) -> ConnectionId {
+        self.imp.common.connection_id
+    }
 }
 
 impl io::Read for ServerSession {
@@ -494,6 +1148,24 @@ impl io::Read for ServerSession {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.imp.common.read(buf)
     }
+
+    /// Like `read`, but fills each of `bufs` in turn.
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        self.imp.common.read_vectored(bufs)
+    }
+}
+
+impl io::BufRead for ServerSession {
+    /// Obtain plaintext data received from the peer, without
+    /// copying it.  This returns an empty slice if none is
+    /// available yet.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.imp.common.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.imp.common.consume(amt)
+    }
 }
 
 impl io::Write for ServerSession {
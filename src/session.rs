@@ -1,12 +1,13 @@
 use ring;
 use std::io::{Read, Write};
 use msgs::message::{BorrowMessage, Message, MessagePayload};
-use msgs::deframer::MessageDeframer;
-use msgs::fragmenter::{MessageFragmenter, MAX_FRAGMENT_LEN};
+use msgs::deframer::{self, Frame, MessageDeframer};
+use msgs::fragmenter::{MessageFragmenter, MAX_FRAGMENT_LEN, PACKET_OVERHEAD};
 use msgs::hsjoiner::HandshakeJoiner;
 use msgs::base::Payload;
-use msgs::codec::{Codec, encode_u16};
+use msgs::codec::{self, Codec, encode_u16};
 use msgs::enums::{ContentType, ProtocolVersion, AlertDescription, AlertLevel};
+use msgs::enums::NamedGroup;
 use msgs::enums::KeyUpdateRequest;
 use error::TLSError;
 use suites::SupportedCipherSuite;
@@ -15,10 +16,18 @@ use vecbuf::ChunkVecBuffer;
 use key;
 use key_schedule::{SecretKind, KeySchedule};
 use prf;
+use util;
 use rand;
+use capture::{CapturesMessages, CapturedMessage, MessageDirection};
+use key_log::{self, KeyLog, KeyLogContext};
 
 use std::io;
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt;
+use std::cmp;
+use std::mem::MaybeUninit;
 
 /// Generalises `ClientSession` and `ServerSession`
 pub trait Session: Read + Write + Send + Sync {
@@ -37,6 +46,31 @@ pub trait Session: Read + Write + Send + Sync {
     /// or a file is at EOF.
     fn read_tls(&mut self, rd: &mut Read) -> Result<usize, io::Error>;
 
+    /// Read TLS content from `buf`, which holds bytes the caller
+    /// already took off the transport -- for example, while sniffing
+    /// the protocol or parsing a PROXY header.
+    ///
+    /// Unlike `read_tls`, this consumes all of `buf`.  You should call
+    /// `process_new_packets` after this function succeeds.
+    ///
+    /// An `io::ErrorKind::InvalidData` error is returned if `buf`
+    /// cannot be accepted because it does not contain TLS messages.
+    fn read_tls_from_buffered(&mut self, buf: &[u8]) -> Result<(), io::Error>;
+
+    /// Read TLS content from `buf`, a buffer owned by the caller,
+    /// without copying it into an internal buffer first.  This suits
+    /// designs where the application owns all I/O buffers.
+    ///
+    /// This uses only whole TLS messages from the front of `buf`, and
+    /// returns how many bytes it used.  The remaining bytes are the
+    /// start of a message: the caller should keep them, and pass them
+    /// again once more data has arrived.  You should call
+    /// `process_new_packets` after this function.
+    ///
+    /// If `buf` does not contain TLS messages, the error is reported
+    /// by `process_new_packets`.
+    fn read_tls_from_slice(&mut self, buf: &[u8]) -> usize;
+
     /// Writes TLS messages to `wr`.
     ///
     /// On success the function returns `Ok(n)` where `n` is a number
@@ -50,6 +84,40 @@ pub trait Session: Read + Write + Send + Sync {
     /// [`wants_write`]: #tymethod.wants_write
     fn write_tls(&mut self, wr: &mut Write) -> Result<usize, io::Error>;
 
+    /// Processes TLS messages from the front of `input`, a buffer
+    /// owned by the caller, and writes any TLS data to send to the
+    /// front of `output`.  This, with `write_unbuffered`, is an
+    /// alternative to `read_tls`, `process_new_packets`, `read`,
+    /// `write` and `write_tls` for callers which want to own every
+    /// buffer.  Don't mix the two styles on one session.
+    ///
+    /// Application data records are decrypted in place in `input`,
+    /// and each piece of plaintext is passed to `plaintext` as it is
+    /// found.  Bytes of `input` which were used are overwritten, and
+    /// should be discarded; the remaining bytes are the start of a
+    /// message, and should be passed again once more data arrives.
+    ///
+    /// Processing stops early if `output` fills up.  In that case
+    /// the returned status has `wants_write` set: send what was
+    /// written, and call this again with a fresh `output`.
+    ///
+    /// Errors are fatal to the session, as for `process_new_packets`.
+    /// There may be an alert to send afterwards: collect it by
+    /// calling `write_unbuffered` with no plaintext.
+    fn process_unbuffered(&mut self,
+                          input: &mut [u8],
+                          output: &mut [u8],
+                          plaintext: &mut FnMut(&[u8])) -> Result<UnbufferedStatus, TLSError>;
+
+    /// Encrypts application data from `plaintext` directly into
+    /// `output`, after any other TLS data waiting to be sent.
+    /// Returns how many bytes of `plaintext` were used, and how
+    /// many bytes at the front of `output` should be sent.
+    ///
+    /// Records are made small enough to fit in `output`.  No
+    /// plaintext is used until the handshake is complete.
+    fn write_unbuffered(&mut self, plaintext: &[u8], output: &mut [u8]) -> (usize, usize);
+
     /// Processes any new packets read by a previous call to `read_tls`.
     /// Errors from this function relate to TLS protocol errors, and
     /// are fatal to the session.  Future calls after an error will do
@@ -72,6 +140,34 @@ pub trait Session: Read + Write + Send + Sync {
     /// session is buffered in memory.
     fn is_handshaking(&self) -> bool;
 
+    /// Returns which part of the handshake the session is waiting
+    /// for, or `Traffic` once it is complete.  This is finer-grained
+    /// than `is_handshaking`, for example for applying a timeout
+    /// to each phase.
+    ///
+    /// Returns None if the session has failed.
+    fn get_handshake_phase(&self) -> Option<HandshakePhase>;
+
+    /// Returns the events which have happened since this was last
+    /// called, oldest first: for example the handshake finishing,
+    /// a ticket arriving or an alert being received.  Calling this
+    /// after `process_new_packets` means no event is missed, unlike
+    /// polling accessors such as `is_handshaking`.
+    ///
+    /// At most `MAX_QUEUED_EVENTS` are kept; if more happen between
+    /// calls, the oldest are dropped.
+    fn take_events(&mut self) -> Vec<SessionEvent>;
+
+    /// Returns true if the session can still carry application
+    /// data: no error has occurred, no close_notify has been sent
+    /// or received, and the session has not run out of sequence
+    /// numbers.
+    ///
+    /// This is cheap, and intended for connection pools deciding
+    /// whether to reuse an idle session.  A true result doesn't
+    /// mean the peer hasn't closed the underlying transport.
+    fn is_usable(&self) -> bool;
+
     /// Sets a limit on the internal buffers used to buffer
     /// unsent plaintext (prior to completing the TLS handshake)
     /// and unsent TLS records.
@@ -80,11 +176,59 @@ pub trait Session: Read + Write + Send + Sync {
     /// at any time, even if the current buffer use is higher.
     fn set_buffer_limit(&mut self, limit: usize);
 
+    /// Sets the largest record payload this session will send,
+    /// for example after a path MTU change.  This takes effect for
+    /// the next message sent.  If None, the TLS maximum of 16384
+    /// bytes is used.
+    ///
+    /// Unlike the configuration's `mtu`, `size` does not include
+    /// the record header.  An error is returned if `size` is zero
+    /// or more than the TLS maximum, and the previous size is kept.
+    fn set_max_fragment_size(&mut self, size: Option<usize>) -> Result<(), TLSError>;
+
+    /// Sets whether small plaintext writes are gathered into fewer
+    /// records.  If `threshold` is Some, plaintext written after the
+    /// handshake is held back until at least `threshold` bytes are
+    /// waiting, or until `flush` or `send_close_notify` is called.
+    /// This saves the per-record overhead of many small writes, at
+    /// the cost of latency: callers must call `flush` once they have
+    /// written a whole message.
+    ///
+    /// Setting None sends anything held back.  The default is None.
+    fn set_write_coalescing(&mut self, threshold: Option<usize>);
+
     /// Queues a close_notify fatal alert to be sent in the next
     /// `write_tls` call.  This informs the peer that the
     /// connection is being closed.
+    ///
+    /// This closes only our side of the session: afterwards, writes
+    /// fail with `io::ErrorKind::BrokenPipe`, but data from the peer
+    /// can still be read until it sends its own close_notify.
+    /// Calling this more than once has no further effect.
     fn send_close_notify(&mut self);
 
+    /// Copies received plaintext into `buf` without consuming it, so
+    /// a later `read` returns the same bytes.  This is intended for
+    /// sniffing the start of the application protocol.  Returns how
+    /// many bytes were copied, which is fewer than `buf.len()` if
+    /// less plaintext is available.
+    fn peek(&self, buf: &mut [u8]) -> usize;
+
+    /// Reads received plaintext into `buf`, like `io::Read::read`,
+    /// but without needing `buf` to be zero-filled first.  This
+    /// saves clearing large buffers which are about to be
+    /// overwritten anyway.
+    ///
+    /// Returns how many bytes were read: that many bytes at the
+    /// start of `buf` are then initialised.  Errors are as for
+    /// `read`.
+    fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize>;
+
+    /// Returns true once the peer has sent a close_notify alert,
+    /// meaning it will send no more data.  Data it sent before
+    /// that may still be waiting to be read.
+    fn peer_has_closed(&self) -> bool;
+
     /// Retrieves the certificate chain used by the peer to authenticate.
     ///
     /// For clients, this is the certificate chain of the server.
@@ -130,6 +274,49 @@ pub trait Session: Read + Write + Send + Sync {
     /// This returns None until the ciphersuite is agreed.
     fn get_negotiated_ciphersuite(&self) -> Option<&'static SupportedCipherSuite>;
 
+    /// Retrieves which TLS1.2 extensions were negotiated with the peer.
+    ///
+    /// This returns None until the ServerHello has been processed,
+    /// and for TLS1.3 sessions (where these extensions don't exist).
+    fn get_tls12_flags(&self) -> Option<TLS12Flags>;
+
+    /// Returns details of the HelloRetryRequest in this handshake,
+    /// if there was one.
+    ///
+    /// This is available as soon as the HelloRetryRequest has been
+    /// sent or processed.
+    fn get_hello_retry_info(&self) -> Option<HelloRetryInfo>;
+
+    /// Returns a non-secret identifier for this session, made when
+    /// it was constructed.  This is intended for correlating log
+    /// lines and metrics about the same connection.
+    fn get_connection_id(&self) -> ConnectionId;
+
+    /// Returns true if the master secret is bound to the handshake
+    /// transcript, as the extended master secret extension (RFC7627)
+    /// does for TLS1.2.  TLS1.3 always does this.
+    ///
+    /// This returns false until the ServerHello has been processed.
+    fn uses_extended_master_secret(&self) -> bool {
+        match self.get_protocol_version() {
+            Some(ProtocolVersion::TLSv1_3) => true,
+            _ => self.get_tls12_flags()
+                .map(|flags| flags.extended_master_secret)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Returns true if the renegotiation_info extension (RFC5746)
+    /// was negotiated in a TLS1.2 session.
+    ///
+    /// This returns false until the ServerHello has been processed,
+    /// and for TLS1.3 sessions, which cannot be renegotiated.
+    fn uses_secure_renegotiation(&self) -> bool {
+        self.get_tls12_flags()
+            .map(|flags| flags.secure_renegotiation)
+            .unwrap_or(false)
+    }
+
     /// This function uses `io` to complete any outstanding IO for
     /// this session.
     ///
@@ -151,7 +338,8 @@ pub trait Session: Read + Write + Send + Sync {
     /// This function will block if `io` blocks.
     ///
     /// Errors from TLS record handling (ie, from `process_new_packets()`)
-    /// are wrapped in an `io::ErrorKind::InvalidData`-kind error.
+    /// are converted to an `io::Error` wrapping the `TLSError`; see
+    /// `From<TLSError> for io::Error` for the error kinds used.
     fn complete_io<T>(&mut self, io: &mut T) -> Result<(usize, usize), io::Error>
         where Self: Sized, T: Read + Write
     {
@@ -162,7 +350,10 @@ pub trait Session: Read + Write + Send + Sync {
 
         loop {
             while self.wants_write() {
-                wrlen += self.write_tls(io)?;
+                match self.write_tls(io)? {
+                    0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    n => wrlen += n,
+                }
             }
 
             if !until_handshaked && wrlen > 0 {
@@ -184,7 +375,7 @@ pub trait Session: Read + Write + Send + Sync {
                     // error.
                     let _ignored = self.write_tls(io);
 
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                    return Err(io::Error::from(e));
                 },
             };
 
@@ -196,6 +387,274 @@ pub trait Session: Read + Write + Send + Sync {
             }
         }
     }
+
+    /// Closes the session cleanly over `io`: sends our close_notify
+    /// if we haven't already, then reads until the peer's
+    /// close_notify arrives.  This lets both sides tell a deliberate
+    /// close from a truncation attack.
+    ///
+    /// Data the peer sends before its close_notify is kept, and can
+    /// be read afterwards in the usual way.  This returns an
+    /// `UnexpectedEof` error if `io` reaches EOF first, and a
+    /// `WriteZero` error if `io` accepts no data.
+    ///
+    /// This function will block if `io` blocks.
+    fn complete_shutdown<T>(&mut self, io: &mut T) -> Result<(), io::Error>
+        where Self: Sized, T: Read + Write
+    {
+        self.send_close_notify();
+
+        loop {
+            while self.wants_write() {
+                if self.write_tls(io)? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero));
+                }
+            }
+
+            if self.peer_has_closed() {
+                return Ok(());
+            }
+
+            if self.read_tls(io)? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            if let Err(e) = self.process_new_packets() {
+                let _ignored = self.write_tls(io);
+                return Err(io::Error::from(e));
+            }
+        }
+    }
+}
+
+/// Security-relevant TLS1.2 extensions, and whether they
+/// were negotiated with the peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TLS12Flags {
+    /// The extended master secret extension (RFC7627).
+    pub extended_master_secret: bool,
+
+    /// The renegotiation_info extension (RFC5746).
+    pub secure_renegotiation: bool,
+
+    /// The SessionTicket extension (RFC5077).  This means the
+    /// server will issue a ticket during the handshake.
+    pub session_ticket: bool,
+}
+
+/// Settings for one session which shadow those in its shared
+/// `ClientConfig` or `ServerConfig`.  This saves keeping a config
+/// for every combination of these settings.
+///
+/// The default overrides nothing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionOverrides {
+    /// If not None, the ALPN protocols to offer (for clients)
+    /// or accept (for servers), instead of the config's
+    /// `alpn_protocols`.
+    pub alpn_protocols: Option<Vec<String>>,
+
+    /// If true, this session neither resumes an earlier session
+    /// nor stores itself or any tickets for later resumption.
+    pub disable_resumption: bool,
+
+    /// If not None, the highest protocol version this session
+    /// may use.  Versions the config doesn't enable stay disabled.
+    pub max_version: Option<ProtocolVersion>,
+}
+
+impl SessionOverrides {
+    /// Returns the ALPN protocols to use, given the config's.
+    pub fn alpn_protocols<'a>(&'a self, config: &'a [String]) -> &'a [String] {
+        match self.alpn_protocols {
+            Some(ref protocols) => protocols,
+            None => config,
+        }
+    }
+
+    /// Returns true if `version` is enabled, given the config's
+    /// list of enabled versions.
+    pub fn enables_version(&self, config: &[ProtocolVersion], version: ProtocolVersion) -> bool {
+        config.contains(&version) &&
+            self.max_version.map_or(true, |max| version.get_u16() <= max.get_u16())
+    }
+}
+
+/// Describes a session which was dropped without sending or
+/// receiving a close_notify alert.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UncleanClose {
+    /// True if we were the client.
+    pub is_client: bool,
+
+    /// True if the handshake had completed.
+    pub handshake_complete: bool,
+
+    /// The negotiated protocol version, if any.
+    pub protocol_version: Option<ProtocolVersion>,
+
+    /// How many bytes of TLS data were waiting to be written.
+    pub unsent_tls_bytes: usize,
+}
+
+/// Something which is told about sessions which are dropped
+/// without an orderly close.
+///
+/// An application which treats the peer closing the underlying
+/// connection as the end of the data, without checking for a
+/// close_notify alert, is open to truncation attacks.  This is
+/// a debugging aid for finding such bugs during testing; it
+/// isn't needed in production.
+pub trait ObservesUncleanClose : Send + Sync {
+    /// Called while dropping a session which neither sent nor
+    /// received close_notify.
+    fn unclean_close(&self, info: &UncleanClose);
+}
+
+/// An implementor of `ObservesUncleanClose` which logs a warning.
+pub struct LogUncleanClose {}
+
+impl ObservesUncleanClose for LogUncleanClose {
+    fn unclean_close(&self, info: &UncleanClose) {
+        warn!("Session dropped without close_notify: {:?}", info);
+    }
+}
+
+/// What to do when the peer asks to renegotiate a TLS1.2 session
+/// and we won't.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenegotiationPolicy {
+    /// Ignore the request silently.
+    Ignore,
+
+    /// Refuse with a no_renegotiation warning alert, and carry on.
+    Refuse,
+
+    /// End the session with a fatal no_renegotiation alert, failing
+    /// with `TLSError::RenegotiationRefused`.
+    Abort,
+}
+
+/// Something that happened during a session, reported to
+/// an `ObservesSessionEvents`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionEvent {
+    /// A handshake started.  For clients this is when the first
+    /// ClientHello is made; for servers, when it is received.
+    HandshakeStarted,
+
+    /// A handshake finished, so application data can be exchanged.
+    HandshakeFinished,
+
+    /// The server gave us a ticket.
+    TicketReceived,
+
+    /// We gave the client a ticket.
+    TicketIssued,
+
+    /// We updated our TLS1.3 write keys and sent a KeyUpdate.
+    KeyUpdateSent,
+
+    /// We received a TLS1.3 KeyUpdate and updated our read keys.
+    KeyUpdateReceived,
+
+    /// We sent an alert.
+    AlertSent(AlertDescription),
+
+    /// We received an alert.
+    AlertReceived(AlertDescription),
+
+    /// A session offered by the client was resumed.
+    ResumptionAccepted,
+
+    /// The client offered a session to resume, but a full
+    /// handshake was done instead.
+    ResumptionRejected,
+}
+
+/// How many events `Session::take_events` can return.
+pub const MAX_QUEUED_EVENTS: usize = 64;
+
+/// Something which is told about events in sessions, for example
+/// to collect metrics.
+///
+/// This is called synchronously during session processing, so
+/// should be quick.
+pub trait ObservesSessionEvents : Send + Sync {
+    /// Called with each `event` as it happens.  `is_client` says
+    /// which side of the session we are.
+    fn event(&self, is_client: bool, event: &SessionEvent);
+}
+
+/// Which part of the handshake a session is waiting for, as
+/// reported by `Session::get_handshake_phase`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakePhase {
+    /// Waiting for the peer's hello: a ServerHello (or
+    /// HelloRetryRequest) for clients, a ClientHello for servers.
+    AwaitingHello,
+
+    /// Waiting for the rest of the peer's first flight: its
+    /// certificates, their verification, and key exchange messages.
+    AwaitingCertificates,
+
+    /// Waiting for the peer's Finished message, and anything which
+    /// comes just before it, such as a ChangeCipherSpec or a TLS1.2
+    /// NewSessionTicket.
+    AwaitingFinished,
+
+    /// The handshake is complete, and application data can be
+    /// exchanged.
+    Traffic,
+}
+
+/// A non-secret identifier for a session, for correlating log
+/// lines, key log entries and metrics about the same connection.
+///
+/// This is fixed when the session is made, and has no meaning
+/// to the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId {
+    /// How many sessions were made from the same config before
+    /// this one.  Clones of a config share this counter.
+    pub seq: usize,
+
+    /// A random value chosen when the session was made.  This
+    /// tells apart sessions made from different configs or
+    /// processes.
+    pub nonce: u64,
+}
+
+impl ConnectionId {
+    /// Make a new ID, taking the next sequence number from `counter`.
+    pub fn next(counter: &AtomicUsize) -> ConnectionId {
+        ConnectionId {
+            seq: counter.fetch_add(1, Ordering::Relaxed),
+            nonce: rand::random_u64(),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}-{}", self.nonce, self.seq)
+    }
+}
+
+/// Details of a TLS1.3 HelloRetryRequest, sent or received
+/// during the handshake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelloRetryInfo {
+    /// The key exchange group the server asked for, if any.
+    pub requested_group: Option<NamedGroup>,
+
+    /// Whether the server sent a cookie.
+    pub has_cookie: bool,
+
+    /// The transcript hash of the first ClientHello.  This replaces
+    /// that message in the handshake transcript, as the body of a
+    /// synthetic `message_hash` handshake message.
+    pub client_hello_hash: Vec<u8>,
 }
 
 #[derive(Clone, Debug)]
@@ -356,6 +815,42 @@ impl SessionSecrets {
     }
 }
 
+impl Drop for SessionSecrets {
+    fn drop(&mut self) {
+        util::wipe(&mut self.master_secret);
+    }
+}
+
+/// A message from `SessionCommon::next_message`.
+pub enum Received {
+    /// A message as received, which may need decrypting.
+    Raw(Message),
+
+    /// A message which has already been decrypted.
+    Plaintext(Message),
+}
+
+/// What a call to `Session::process_unbuffered` did.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UnbufferedStatus {
+    /// How many bytes from the front of `input` were used.
+    pub input_used: usize,
+
+    /// How many bytes at the front of `output` should be sent
+    /// to the peer.
+    pub output_written: usize,
+
+    /// Whether there is more TLS data to send, which did not fit
+    /// in `output`.
+    pub wants_write: bool,
+}
+
+/// The ALPN protocols offered by the web presets, most preferred first.
+pub static WEB_ALPN_PROTOCOLS: &'static [&'static str] = &["h2", "http/1.1"];
+
+/// The buffer limit used by the web presets.
+pub const WEB_BUFFER_LIMIT: usize = 64 * 1024;
+
 // --- Common (to client and server) session functions ---
 static SEQ_SOFT_LIMIT: u64 = 0xffff_ffff_ffff_0000u64;
 static SEQ_HARD_LIMIT: u64 = 0xffff_ffff_ffff_fffeu64;
@@ -368,52 +863,155 @@ enum Limit {
 pub struct SessionCommon {
     pub negotiated_version: Option<ProtocolVersion>,
     pub is_client: bool,
+    pub connection_id: ConnectionId,
     message_encrypter: Box<MessageEncrypter>,
     message_decrypter: Box<MessageDecrypter>,
+    pending_message_encrypter: Option<Box<MessageEncrypter>>,
+    pending_message_decrypter: Option<Box<MessageDecrypter>>,
     pub secrets: Option<SessionSecrets>,
+    pub tls12_flags: Option<TLS12Flags>,
+    pub hello_retry: Option<HelloRetryInfo>,
     key_schedule: Option<KeySchedule>,
     suite: Option<&'static SupportedCipherSuite>,
     write_seq: u64,
     read_seq: u64,
     peer_eof: bool,
+    transport_eof: bool,
+    sent_close_notify: bool,
+    pub unclean_close_observer: Option<Arc<ObservesUncleanClose>>,
+    pub event_observer: Option<Arc<ObservesSessionEvents>>,
+    events: VecDeque<SessionEvent>,
+    pub message_capture: Option<Arc<CapturesMessages>>,
+    pub key_log: Option<Arc<KeyLog>>,
+    pub peer_name: Option<String>,
     pub peer_encrypting: bool,
     pub we_encrypting: bool,
     pub traffic: bool,
+    pub renegotiating: bool,
     pub want_write_key_update: bool,
+    coalesce_threshold: Option<usize>,
     pub message_deframer: MessageDeframer,
     pub handshake_joiner: HandshakeJoiner,
     pub message_fragmenter: MessageFragmenter,
+    pub max_appdata_record_len: Option<usize>,
+    pub fatal_warning_alerts: bool,
+    pub report_truncation: bool,
+    pub renegotiation_policy: RenegotiationPolicy,
     received_plaintext: ChunkVecBuffer,
     sendable_plaintext: ChunkVecBuffer,
     pub sendable_tls: ChunkVecBuffer,
 }
 
 impl SessionCommon {
-    pub fn new(mtu: Option<usize>, client: bool) -> SessionCommon {
+    pub fn new(mtu: Option<usize>, client: bool, connection_id: ConnectionId) -> SessionCommon {
         SessionCommon {
             negotiated_version: None,
             is_client: client,
+            connection_id,
             suite: None,
             message_encrypter: MessageEncrypter::invalid(),
             message_decrypter: MessageDecrypter::invalid(),
+            pending_message_encrypter: None,
+            pending_message_decrypter: None,
             secrets: None,
+            tls12_flags: None,
+            hello_retry: None,
             key_schedule: None,
             write_seq: 0,
             read_seq: 0,
             peer_eof: false,
+            transport_eof: false,
+            sent_close_notify: false,
+            unclean_close_observer: None,
+            event_observer: None,
+            events: VecDeque::new(),
+            message_capture: None,
+            key_log: None,
+            peer_name: None,
             peer_encrypting: false,
             we_encrypting: false,
             traffic: false,
+            renegotiating: false,
             want_write_key_update: false,
+            coalesce_threshold: None,
             message_deframer: MessageDeframer::new(),
             handshake_joiner: HandshakeJoiner::new(),
             message_fragmenter: MessageFragmenter::new(mtu.unwrap_or(MAX_FRAGMENT_LEN)),
+            max_appdata_record_len: None,
+            fatal_warning_alerts: false,
+            report_truncation: false,
+            renegotiation_policy: RenegotiationPolicy::Refuse,
             received_plaintext: ChunkVecBuffer::new(),
             sendable_plaintext: ChunkVecBuffer::new(),
             sendable_tls: ChunkVecBuffer::new(),
         }
     }
 
+    pub fn emit_event(&mut self, event: SessionEvent) {
+        if let Some(ref observer) = self.event_observer {
+            observer.event(self.is_client, &event);
+        }
+
+        if self.events.len() == MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub fn take_events(&mut self) -> Vec<SessionEvent> {
+        self.events.drain(..).collect()
+    }
+
+    /// Give `secret` to the key log, if it wants secrets labelled
+    /// `label`.
+    pub fn log_secret(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let key_log = match self.key_log {
+            Some(ref key_log) if key_log.will_log(label) => key_log,
+            _ => return,
+        };
+
+        let context = KeyLogContext {
+            connection_id: self.connection_id,
+            is_client: self.is_client,
+            peer_name: self.peer_name.as_ref().map(|name| name.as_str()),
+        };
+        key_log.log(label, &context, client_random, secret);
+    }
+
+    /// Log a TLS1.3 `secret` of kind `kind`, if it's one we log.
+    pub fn log_tls13_secret(&self, kind: SecretKind, client_random: &[u8], secret: &[u8]) {
+        if let Some(label) = kind.log_label() {
+            self.log_secret(label, client_random, secret);
+        }
+    }
+
+    fn capture(&self,
+               direction: MessageDirection,
+               typ: ContentType,
+               version: ProtocolVersion,
+               payload: &[u8]) {
+        if let Some(ref capture) = self.message_capture {
+            let msg = CapturedMessage { direction, typ, version, payload };
+            capture.message(self.is_client, &msg);
+        }
+    }
+
+    fn capture_message(&self, direction: MessageDirection, m: &Message) {
+        if self.message_capture.is_none() {
+            return;
+        }
+
+        let mut payload = Vec::new();
+        m.payload.encode(&mut payload);
+        self.capture(direction, m.typ, m.version, &payload);
+    }
+
+    /// Tell any message capture about `m`, which we just
+    /// received and decrypted.
+    pub fn capture_received(&self, m: &Message) {
+        self.capture_message(MessageDirection::Received, m);
+    }
+
     pub fn is_tls13(&self) -> bool {
       match self.negotiated_version {
         Some(ProtocolVersion::TLSv1_3) => true,
@@ -443,6 +1041,12 @@ impl SessionCommon {
         }
     }
 
+    /// Allow a later `set_suite` to choose any suite.  This is
+    /// for TLS1.2 renegotiation.
+    pub fn forget_suite(&mut self) {
+        self.suite = None;
+    }
+
     pub fn get_mut_key_schedule(&mut self) -> &mut KeySchedule {
         self.key_schedule.as_mut().unwrap()
     }
@@ -469,6 +1073,10 @@ impl SessionCommon {
         self.peer_encrypting = true;
     }
 
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        self.received_plaintext.peek(buf)
+    }
+
     pub fn has_readable_plaintext(&self) -> bool {
         !self.received_plaintext.is_empty()
     }
@@ -478,13 +1086,68 @@ impl SessionCommon {
         self.sendable_tls.set_limit(limit);
     }
 
-    pub fn encrypt_outgoing(&mut self, plain: BorrowMessage) -> Message {
+    pub fn set_max_fragment_size(&mut self, size: Option<usize>) -> Result<(), TLSError> {
+        let size = size.unwrap_or(MAX_FRAGMENT_LEN);
+        if size == 0 || size > MAX_FRAGMENT_LEN {
+            return Err(TLSError::General(format!("bad max fragment size {}", size)));
+        }
+
+        self.message_fragmenter = MessageFragmenter::new(size);
+        Ok(())
+    }
+
+    pub fn set_write_coalescing(&mut self, threshold: Option<usize>) {
+        self.coalesce_threshold = threshold;
+        self.flush_plaintext();
+    }
+
+    /// The most application data we put in one record.
+    fn appdata_fragment_len(&self) -> usize {
+        let max_frag = self.message_fragmenter.max_fragment_len();
+
+        match self.max_appdata_record_len {
+            Some(len) if len > 0 => cmp::min(len, max_frag),
+            _ => max_frag,
+        }
+    }
+
+    /// The length of the record `encrypt_record` makes from
+    /// `plain_len` bytes of plaintext.
+    fn encrypted_record_len(&self, plain_len: usize) -> usize {
+        PACKET_OVERHEAD + self.message_encrypter.encrypted_len(plain_len)
+    }
+
+    /// Encrypt `plain` into a new record, ready for sending.
+    fn encrypt_record(&mut self, plain: BorrowMessage) -> Vec<u8> {
+        let mut record = vec![0u8; self.encrypted_record_len(plain.payload.len())];
+        self.encrypt_record_into(plain, &mut record);
+        record
+    }
+
+    /// Encrypt `plain` into `record`, which must be exactly
+    /// `encrypted_record_len` bytes long.  The plaintext is copied
+    /// into place once, and then encrypted there.
+    fn encrypt_record_into(&mut self, plain: BorrowMessage, record: &mut [u8]) {
+        let offset = PACKET_OVERHEAD + self.message_encrypter.payload_offset();
+        record[offset..offset + plain.payload.len()].copy_from_slice(plain.payload);
+
         let seq = self.write_seq;
         self.write_seq += 1;
-        self.message_encrypter.encrypt(plain, seq).unwrap()
+        let (typ, version) = self.message_encrypter
+            .encrypt_in_place(plain.typ,
+                              plain.version,
+                              &mut record[PACKET_OVERHEAD..],
+                              plain.payload.len(),
+                              seq)
+            .unwrap();
+
+        let payload_len = record.len() - PACKET_OVERHEAD;
+        record[0] = typ.get_u8();
+        codec::put_u16(version.get_u16(), &mut record[1..3]);
+        codec::put_u16(payload_len as u16, &mut record[3..5]);
     }
 
-    pub fn decrypt_incoming(&mut self, encr: Message) -> Result<Message, TLSError> {
+    fn take_read_seq(&mut self) -> u64 {
         // Perhaps if we send an alert well before their counter wraps, a
         // buggy peer won't make a terrible mistake here?
         // Note that there's no reason to refuse to decrypt: the security
@@ -495,7 +1158,10 @@ impl SessionCommon {
 
         let seq = self.read_seq;
         self.read_seq += 1;
-        let ret = self.message_decrypter.decrypt(encr, seq);
+        seq
+    }
+
+    fn check_decrypt_error<T>(&mut self, ret: Result<T, TLSError>) -> Result<T, TLSError> {
         if let Err(TLSError::PeerSentOversizedRecord) = ret {
             self.send_fatal_alert(AlertDescription::RecordOverflow);
         }
@@ -503,8 +1169,183 @@ impl SessionCommon {
         ret
     }
 
+    pub fn decrypt_incoming(&mut self, encr: Message) -> Result<Message, TLSError> {
+        let seq = self.take_read_seq();
+        let ret = self.message_decrypter.decrypt(encr, seq);
+        self.check_decrypt_error(ret)
+    }
+
+    /// Returns the next received message for the state machine.
+    ///
+    /// Once traffic is flowing, records are decrypted in place in the
+    /// deframer's buffer, and application data goes straight to
+    /// `received_plaintext` without passing through the state
+    /// machine.  Other messages decrypted this way are returned
+    /// as `Received::Plaintext`.
+    pub fn next_message(&mut self) -> Result<Option<Received>, TLSError> {
+        loop {
+            let (typ, version) = match self.message_deframer.peek_record() {
+                Some(header) if self.can_decrypt_in_place(header.0) => header,
+                _ => return Ok(self.message_deframer.pop_message().map(Received::Raw)),
+            };
+
+            let seq = self.take_read_seq();
+            let ret = self.message_decrypter
+                .decrypt_in_place(typ, version, self.message_deframer.record_payload_mut(), seq);
+            let plain = match self.check_decrypt_error(ret) {
+                Ok(plain) => plain,
+                Err(err) => {
+                    self.message_deframer.skip_record();
+                    return Err(err);
+                }
+            };
+
+            let msg = {
+                let payload = &self.message_deframer.record_payload()[..plain.len];
+                if plain.typ == ContentType::ApplicationData {
+                    self.capture(MessageDirection::Received, plain.typ, plain.version, payload);
+                    self.received_plaintext.append_copy(payload);
+                    None
+                } else {
+                    Some(Message {
+                        typ: plain.typ,
+                        version: plain.version,
+                        payload: MessagePayload::new_opaque(payload.to_vec()),
+                    })
+                }
+            };
+
+            self.message_deframer.skip_record();
+
+            if let Some(msg) = msg {
+                return Ok(Some(Received::Plaintext(msg)));
+            }
+        }
+    }
+
+    /// Like `next_message`, but takes records from the front of
+    /// `input[*used..]`, a buffer owned by the caller, advancing
+    /// `used` past each record taken.  Application data is decrypted
+    /// in place and passed to `plaintext` rather than buffered.
+    pub fn next_message_unbuffered(&mut self,
+                                   input: &mut [u8],
+                                   used: &mut usize,
+                                   plaintext: &mut FnMut(&[u8]))
+                                   -> Result<Option<Received>, TLSError> {
+        loop {
+            let record = &mut input[*used..];
+            let len = match deframer::check_frame(record) {
+                Frame::Incomplete => return Ok(None),
+                Frame::Complete(len) => len,
+                Frame::Invalid => {
+                    self.send_fatal_alert(AlertDescription::DecodeError);
+                    return Err(TLSError::CorruptMessage);
+                }
+            };
+            let record = &mut record[..len];
+            *used += len;
+
+            let (typ, version) = {
+                let mut rd = codec::Reader::init(record);
+                (ContentType::read(&mut rd).unwrap(), ProtocolVersion::read(&mut rd).unwrap())
+            };
+
+            if !self.can_decrypt_in_place(typ) {
+                let mut rd = codec::Reader::init(record);
+                return Ok(Some(Received::Raw(Message::read(&mut rd).unwrap())));
+            }
+
+            let payload = &mut record[deframer::HEADER_SIZE..];
+            let seq = self.take_read_seq();
+            let ret = self.message_decrypter.decrypt_in_place(typ, version, payload, seq);
+            let plain = self.check_decrypt_error(ret)?;
+            let payload = &payload[..plain.len];
+            self.capture(MessageDirection::Received, plain.typ, plain.version, payload);
+
+            if plain.typ != ContentType::ApplicationData {
+                return Ok(Some(Received::Plaintext(Message {
+                    typ: plain.typ,
+                    version: plain.version,
+                    payload: MessagePayload::new_opaque(payload.to_vec()),
+                })));
+            }
+
+            if !payload.is_empty() {
+                plaintext(payload);
+            }
+        }
+    }
+
+    /// Pass plaintext the state machine has received to `plaintext`,
+    /// leaving none buffered.
+    pub fn take_plaintext_into(&mut self, plaintext: &mut FnMut(&[u8])) {
+        while !self.received_plaintext.is_empty() {
+            plaintext(&self.received_plaintext.take_one());
+        }
+    }
+
+    /// Move as much pending TLS data as fits into `output`,
+    /// returning how many bytes were written.
+    pub fn write_tls_into(&mut self, output: &mut [u8]) -> usize {
+        self.sendable_tls.read(output).unwrap()
+    }
+
+    /// See `Session::write_unbuffered`.
+    pub fn write_unbuffered(&mut self, plaintext: &[u8], output: &mut [u8]) -> (usize, usize) {
+        if self.traffic && (self.want_write_key_update || self.write_key_exhausted()) {
+            self.do_write_key_update();
+        }
+
+        let mut written = self.write_tls_into(output);
+        if !self.traffic || self.sent_close_notify || !self.sendable_tls.is_empty() {
+            return (0, written);
+        }
+
+        debug_assert!(self.we_encrypting);
+
+        let mut used = 0;
+        while used < plaintext.len() && self.write_seq < SEQ_SOFT_LIMIT &&
+                !self.write_key_exhausted() {
+            let overhead = self.encrypted_record_len(0);
+            let space = output.len() - written;
+            if space <= overhead {
+                break;
+            }
+
+            let take = cmp::min(plaintext.len() - used,
+                                self.appdata_fragment_len());
+            let take = cmp::min(take, space - overhead);
+            let record_len = overhead + take;
+
+            let m = BorrowMessage {
+                typ: ContentType::ApplicationData,
+                version: ProtocolVersion::TLSv1_2,
+                payload: &plaintext[used..used + take],
+            };
+            self.capture(MessageDirection::Sent, m.typ, m.version, m.payload);
+            self.encrypt_record_into(m, &mut output[written..written + record_len]);
+
+            used += take;
+            written += record_len;
+        }
+
+        (used, written)
+    }
+
+    /// Can a record of type `typ` take the fast path in
+    /// `next_message`?  Only once the state machine is passing
+    /// application data straight through.
+    fn can_decrypt_in_place(&self, typ: ContentType) -> bool {
+        typ == ContentType::ApplicationData &&
+            self.peer_encrypting &&
+            self.traffic &&
+            !self.renegotiating
+    }
+
     pub fn process_alert(&mut self, msg: Message) -> Result<(), TLSError> {
         if let MessagePayload::Alert(ref alert) = msg.payload {
+            self.emit_event(SessionEvent::AlertReceived(alert.description));
+
             // Reject unknown AlertLevels.
             match alert.level {
                 AlertLevel::Unknown(_) => {
@@ -524,7 +1365,7 @@ impl SessionCommon {
             if alert.level == AlertLevel::Warning {
                 if self.is_tls13() {
                     self.send_fatal_alert(AlertDescription::DecodeError);
-                } else {
+                } else if !self.fatal_warning_alerts {
                     warn!("TLS alert warning received: {:#?}", msg);
                     return Ok(());
                 }
@@ -547,7 +1388,12 @@ impl SessionCommon {
         };
 
         self.want_write_key_update = false;
-        self.send_msg_encrypt(Message::build_key_update_notify());
+        let mut plain_messages = VecDeque::new();
+        self.message_fragmenter.fragment(Message::build_key_update_notify(), &mut plain_messages);
+        for m in plain_messages {
+            self.send_single_fragment(m.to_borrowed());
+        }
+        self.emit_event(SessionEvent::KeyUpdateSent);
 
         let write_key = self.get_key_schedule().derive_next(kind);
         let scs = self.get_suite_assert();
@@ -560,17 +1406,35 @@ impl SessionCommon {
         }
     }
 
-    /// Fragment `m`, encrypt the fragments, and then queue
-    /// the encrypted fragments for sending.
-    pub fn send_msg_encrypt(&mut self, m: Message) {
-        if self.want_write_key_update {
+    /// Have we nearly reached the suite's limit on records encrypted
+    /// with our TLS1.3 write key?  We keep one record back for the
+    /// KeyUpdate.
+    fn write_key_exhausted(&self) -> bool {
+        if !self.is_tls13() || !self.traffic {
+            return false;
+        }
+
+        let limit = cmp::min(self.get_suite_assert().confidentiality_limit(),
+                             SEQ_SOFT_LIMIT);
+        self.write_seq + 1 >= limit
+    }
+
+    /// Send a KeyUpdate now if we've been asked to, or our write
+    /// key is nearly used up.
+    fn update_write_key_if_due(&mut self) {
+        if self.want_write_key_update || self.write_key_exhausted() {
             self.do_write_key_update();
         }
+    }
 
+    /// Fragment `m`, encrypt the fragments, and then queue
+    /// the encrypted fragments for sending.
+    pub fn send_msg_encrypt(&mut self, m: Message) {
         let mut plain_messages = VecDeque::new();
         self.message_fragmenter.fragment(m, &mut plain_messages);
 
         for m in plain_messages {
+            self.update_write_key_if_due();
             self.send_single_fragment(m.to_borrowed());
         }
     }
@@ -579,9 +1443,6 @@ impl SessionCommon {
     fn send_appdata_encrypt(&mut self,
                             payload: &[u8],
                             limit: Limit) -> usize {
-        if self.want_write_key_update {
-            self.do_write_key_update();
-        }
 
         // Here, the limit on sendable_tls applies to encrypted data,
         // but we're respecting it for plaintext data -- so we'll
@@ -593,12 +1454,14 @@ impl SessionCommon {
         };
 
         let mut plain_messages = VecDeque::new();
-        self.message_fragmenter.fragment_borrow(ContentType::ApplicationData,
-                                                ProtocolVersion::TLSv1_2,
-                                                &payload[..len],
-                                                &mut plain_messages);
+        MessageFragmenter::new(self.appdata_fragment_len())
+            .fragment_borrow(ContentType::ApplicationData,
+                             ProtocolVersion::TLSv1_2,
+                             &payload[..len],
+                             &mut plain_messages);
 
         for m in plain_messages {
+            self.update_write_key_if_due();
             self.send_single_fragment(m);
         }
 
@@ -618,8 +1481,22 @@ impl SessionCommon {
             return;
         }
 
-        let em = self.encrypt_outgoing(m);
-        self.queue_tls_message(em);
+        self.capture(MessageDirection::Sent, m.typ, m.version, m.payload);
+        let record = self.encrypt_record(m);
+        self.sendable_tls.append(record);
+    }
+
+    /// Can this session still send and receive data?  See
+    /// `Session::is_usable`.
+    pub fn is_usable(&self) -> bool {
+        !self.peer_eof &&
+            !self.sent_close_notify &&
+            self.write_seq < SEQ_SOFT_LIMIT &&
+            self.read_seq < SEQ_SOFT_LIMIT
+    }
+
+    pub fn peer_has_closed(&self) -> bool {
+        self.peer_eof
     }
 
     /// Are we done? ie, have we processed all received messages,
@@ -633,7 +1510,33 @@ impl SessionCommon {
     /// buffering, so `rd` can supply TLS messages in arbitrary-
     /// sized chunks (like a socket or pipe might).
     pub fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize> {
-        self.message_deframer.read(rd)
+        let len = self.message_deframer.read(rd)?;
+        if len == 0 {
+            self.transport_eof = true;
+        }
+        Ok(len)
+    }
+
+    /// Take all of `buf` as if it had been read from the transport,
+    /// failing as soon as it is clearly not TLS.
+    pub fn read_tls_from_buffered(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut buf = buf;
+
+        while !buf.is_empty() {
+            let len = self.message_deframer.read(&mut buf)?;
+            if len == 0 || self.message_deframer.desynced {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "buffered data is not TLS"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode whole TLS messages from the front of `buf`,
+    /// returning how many bytes were used.
+    pub fn read_tls_from_slice(&mut self, buf: &[u8]) -> usize {
+        self.message_deframer.deframe(buf)
     }
 
     pub fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize> {
@@ -646,6 +1549,11 @@ impl SessionCommon {
     /// If internal buffers are too small, this function will not accept
     /// all the data.
     pub fn send_some_plaintext(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.sent_close_notify {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe,
+                                      "close_notify already sent"));
+        }
+
         self.send_plain(data, Limit::Yes)
     }
 
@@ -668,16 +1576,31 @@ impl SessionCommon {
             return Ok(0);
         }
 
+        if let Some(threshold) = self.coalesce_threshold {
+            let len = match limit {
+                Limit::Yes => self.sendable_plaintext.apply_limit(data.len()),
+                Limit::No => data.len()
+            };
+            self.sendable_plaintext.append_copy(&data[..len]);
+
+            if self.sendable_plaintext.len() >= threshold {
+                self.flush_plaintext();
+            }
+            return Ok(len);
+        }
+
         Ok(self.send_appdata_encrypt(data, limit))
     }
 
     pub fn start_traffic(&mut self) {
         self.traffic = true;
+        self.renegotiating = false;
+        self.emit_event(SessionEvent::HandshakeFinished);
         self.flush_plaintext();
     }
 
     /// Send any buffered plaintext.  Plaintext is buffered if
-    /// written during handshake.
+    /// written during handshake, or while coalescing writes.
     pub fn flush_plaintext(&mut self) {
         if !self.traffic {
             return;
@@ -685,8 +1608,7 @@ impl SessionCommon {
 
         while !self.sendable_plaintext.is_empty() {
             let buf = self.sendable_plaintext.take_one();
-            self.send_plain(&buf, Limit::No)
-                .unwrap();
+            self.send_appdata_encrypt(&buf, Limit::No);
         }
     }
 
@@ -697,10 +1619,15 @@ impl SessionCommon {
 
     /// Send a raw TLS message, fragmenting it if needed.
     pub fn send_msg(&mut self, m: Message, must_encrypt: bool) {
+        // In TLS1.2, everything is encrypted once we start encrypting.
+        // This covers handshake messages sent during a renegotiation.
+        let must_encrypt = must_encrypt || (self.we_encrypting && !self.is_tls13());
+
         if !must_encrypt {
             let mut to_send = VecDeque::new();
             self.message_fragmenter.fragment(m, &mut to_send);
             for mm in to_send {
+                self.capture_message(MessageDirection::Sent, &mm);
                 self.queue_tls_message(mm);
             }
         } else {
@@ -712,34 +1639,111 @@ impl SessionCommon {
         self.received_plaintext.append(bytes.0);
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = self.received_plaintext.read(buf)?;
+    /// If there's no plaintext to read, and there never will be,
+    /// return the error reads should give.
+    fn check_plaintext_eof(&self) -> io::Result<()> {
+        if !self.received_plaintext.is_empty() {
+            return Ok(());
+        }
 
-        if len == 0 && self.connection_at_eof() && self.received_plaintext.is_empty() {
+        if self.connection_at_eof() {
             return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
                                       "CloseNotify alert received"));
         }
 
+        if self.report_truncation && self.transport_eof && !self.peer_eof &&
+            !self.message_deframer.has_whole_messages() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "peer closed connection without sending close_notify"));
+        }
+
+        Ok(())
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.received_plaintext.read(buf)?;
+
+        if len == 0 {
+            self.check_plaintext_eof()?;
+        }
+
+        Ok(len)
+    }
+
+    pub fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> io::Result<usize> {
+        let len = self.received_plaintext.read_vectored(bufs);
+
+        if len == 0 {
+            self.check_plaintext_eof()?;
+        }
+
+        Ok(len)
+    }
+
+    pub fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> io::Result<usize> {
+        let len = self.received_plaintext.read_uninit(buf);
+
+        if len == 0 {
+            self.check_plaintext_eof()?;
+        }
+
         Ok(len)
     }
 
+    pub fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.check_plaintext_eof()?;
+        Ok(self.received_plaintext.chunk())
+    }
+
+    pub fn consume(&mut self, amt: usize) {
+        self.received_plaintext.consume(amt);
+    }
+
+    /// Derive TLS1.2 record protection from `secrets`.  The new keys
+    /// take effect in each direction at that side's ChangeCipherSpec.
     pub fn start_encryption_tls12(&mut self, secrets: SessionSecrets) {
+        self.log_secret(key_log::CLIENT_RANDOM, &secrets.randoms.client, &secrets.master_secret);
         let (dec, enc) = cipher::new_tls12(self.get_suite_assert(), &secrets);
-        self.message_encrypter = enc;
-        self.message_decrypter = dec;
+        self.pending_message_encrypter = Some(enc);
+        self.pending_message_decrypter = Some(dec);
         self.secrets = Some(secrets);
     }
 
     pub fn peer_now_encrypting(&mut self) {
+        if let Some(dec) = self.pending_message_decrypter.take() {
+            self.message_decrypter = dec;
+            self.read_seq = 0;
+        }
         self.peer_encrypting = true;
     }
 
     pub fn we_now_encrypting(&mut self) {
+        if let Some(enc) = self.pending_message_encrypter.take() {
+            self.message_encrypter = enc;
+            self.write_seq = 0;
+        }
         self.we_encrypting = true;
     }
 
+    /// Turn down a TLS1.2 renegotiation request, as our
+    /// `renegotiation_policy` says.
+    pub fn refuse_renegotiation(&mut self) -> Result<(), TLSError> {
+        match self.renegotiation_policy {
+            RenegotiationPolicy::Ignore => Ok(()),
+            RenegotiationPolicy::Refuse => {
+                self.send_warning_alert(AlertDescription::NoRenegotiation);
+                Ok(())
+            }
+            RenegotiationPolicy::Abort => {
+                self.send_fatal_alert(AlertDescription::NoRenegotiation);
+                Err(TLSError::RenegotiationRefused)
+            }
+        }
+    }
+
     pub fn send_warning_alert(&mut self, desc: AlertDescription) {
         warn!("Sending warning alert {:?}", desc);
+        self.emit_event(SessionEvent::AlertSent(desc));
         let m = Message::build_alert(AlertLevel::Warning, desc);
         let enc = self.we_encrypting;
         self.send_msg(m, enc);
@@ -747,12 +1751,20 @@ impl SessionCommon {
 
     pub fn send_fatal_alert(&mut self, desc: AlertDescription) {
         warn!("Sending fatal alert {:?}", desc);
+        self.emit_event(SessionEvent::AlertSent(desc));
         let m = Message::build_alert(AlertLevel::Fatal, desc);
         let enc = self.we_encrypting;
         self.send_msg(m, enc);
     }
 
     pub fn send_close_notify(&mut self) {
+        if self.sent_close_notify {
+            return;
+        }
+
+        // Data held back for coalescing must go before the alert.
+        self.flush_plaintext();
+        self.sent_close_notify = true;
         self.send_warning_alert(AlertDescription::CloseNotify)
     }
 
@@ -790,6 +1802,8 @@ impl SessionCommon {
             self.get_mut_key_schedule().current_client_traffic_secret = new_read_key;
         }
 
+        self.emit_event(SessionEvent::KeyUpdateReceived);
+
         Ok(())
     }
 
@@ -814,3 +1828,20 @@ impl SessionCommon {
         }
     }
 }
+
+impl Drop for SessionCommon {
+    fn drop(&mut self) {
+        if self.sent_close_notify || self.peer_eof {
+            return;
+        }
+
+        if let Some(ref observer) = self.unclean_close_observer {
+            observer.unclean_close(&UncleanClose {
+                is_client: self.is_client,
+                handshake_complete: self.traffic,
+                protocol_version: self.negotiated_version,
+                unsent_tls_bytes: self.sendable_tls.len(),
+            });
+        }
+    }
+}
@@ -2,6 +2,7 @@ use msgs::enums::{SignatureAlgorithm, SignatureScheme};
 use util;
 use key;
 use error::TLSError;
+use x509;
 
 use untrusted;
 
@@ -12,6 +13,7 @@ use webpki;
 
 use std::sync::Arc;
 use std::mem;
+use std::time::{Duration, SystemTime};
 
 /// An abstract signing key.
 pub trait SigningKey : Send + Sync {
@@ -34,6 +36,36 @@ pub trait Signer : Send + Sync {
     fn get_scheme(&self) -> SignatureScheme;
 }
 
+/// A problem with a certificate chain, found by
+/// `CertifiedKey::check_chain`.  Indices are positions in the
+/// chain, after any reordering.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertChainWarning {
+    /// The certificate at this index couldn't be parsed.
+    Unparseable(usize),
+
+    /// The chain wasn't in order, with each certificate followed
+    /// by its issuer.  It has been reordered.
+    Reordered,
+
+    /// The chain isn't in order, with each certificate followed
+    /// by its issuer, and reordering was not requested.
+    Misordered,
+
+    /// The certificate at this index isn't followed by its issuer,
+    /// for example because an intermediate certificate is missing.
+    IssuerMissing(usize),
+
+    /// The certificate at this index has expired.
+    Expired(usize),
+
+    /// The certificate at this index is not valid yet.
+    NotYetValid(usize),
+
+    /// The end-entity certificate expires within the given time.
+    ExpiresSoon(Duration),
+}
+
 /// A packaged together certificate chain, matching `SigningKey` and
 /// optional stapled OCSP response and/or SCT.
 #[derive(Clone)]
@@ -98,6 +130,83 @@ impl CertifiedKey {
         mem::replace(&mut self.sct_list, None)
     }
 
+    /// Check the certificate chain for common deployment problems,
+    /// calling `report` with each one found.
+    ///
+    /// If `fix_order` is true, a chain which is out of order is
+    /// sorted so each certificate is followed by its issuer.  The
+    /// end-entity certificate must be first either way.  Unrelated
+    /// certificates are moved to the end.
+    ///
+    /// Certificates which aren't valid at `now` are reported, as
+    /// is an end-entity certificate which expires within
+    /// `expiry_warning`.
+    ///
+    /// Like `cross_check_end_entity_cert`, this is not
+    /// security-sensitive: it helps detect misconfiguration.
+    pub fn check_chain(&mut self,
+                       now: SystemTime,
+                       expiry_warning: Duration,
+                       fix_order: bool,
+                       report: &mut FnMut(CertChainWarning)) {
+        let order = {
+            let summaries = self.cert.iter()
+                .map(|cert| x509::summarise_cert(cert.as_ref()))
+                .collect::<Vec<_>>();
+            for (i, summary) in summaries.iter().enumerate() {
+                if summary.is_none() {
+                    report(CertChainWarning::Unparseable(i));
+                }
+            }
+            if summaries.iter().any(|summary| summary.is_none()) {
+                return;
+            }
+
+            let summaries = summaries.into_iter()
+                .map(|summary| summary.unwrap())
+                .collect::<Vec<_>>();
+            chain_order(&summaries)
+        };
+
+        if order.iter().enumerate().any(|(i, &j)| i != j) {
+            if fix_order {
+                let mut old = mem::replace(&mut self.cert, Vec::new())
+                    .into_iter()
+                    .map(Some)
+                    .collect::<Vec<_>>();
+                self.cert = order.iter()
+                    .map(|&i| old[i].take().unwrap())
+                    .collect();
+                report(CertChainWarning::Reordered);
+            } else {
+                report(CertChainWarning::Misordered);
+            }
+        }
+
+        let summaries = self.cert.iter()
+            .map(|cert| x509::summarise_cert(cert.as_ref()).unwrap())
+            .collect::<Vec<_>>();
+
+        for (i, pair) in summaries.windows(2).enumerate() {
+            if pair[0].issuer != pair[1].subject {
+                report(CertChainWarning::IssuerMissing(i));
+            }
+        }
+
+        for (i, summary) in summaries.iter().enumerate() {
+            if now > summary.not_after {
+                report(CertChainWarning::Expired(i));
+            } else if now < summary.not_before {
+                report(CertChainWarning::NotYetValid(i));
+            } else if i == 0 {
+                let remaining = summary.not_after.duration_since(now).unwrap();
+                if remaining < expiry_warning {
+                    report(CertChainWarning::ExpiresSoon(remaining));
+                }
+            }
+        }
+    }
+
     /// Check the certificate chain for validity:
     /// - it should be non-empty list
     /// - the first certificate should be parsable as a x509v3,
@@ -135,6 +244,35 @@ impl CertifiedKey {
     }
 }
 
+/// Work out the best order for a certificate chain: the
+/// end-entity certificate, then its issuer, then that
+/// certificate's issuer, and so on.  Certificates which don't
+/// fit go at the end, in their original order.  Returns
+/// indices into `summaries`.
+fn chain_order(summaries: &[x509::CertSummary]) -> Vec<usize> {
+    if summaries.is_empty() {
+        return Vec::new();
+    }
+
+    let mut order = vec![0];
+    let mut remaining = (1..summaries.len()).collect::<Vec<_>>();
+
+    loop {
+        let last = &summaries[*order.last().unwrap()];
+        if last.issuer == last.subject {
+            break;
+        }
+
+        match remaining.iter().position(|&i| summaries[i].subject == last.issuer) {
+            Some(pos) => order.push(remaining.remove(pos)),
+            None => break,
+        }
+    }
+
+    order.extend(remaining);
+    order
+}
+
 /// A `SigningKey` for RSA-PKCS1 or RSA-PSS
 pub struct RSASigningKey {
     key: Arc<RSAKeyPair>,
@@ -214,3 +352,13 @@ impl Signer for RSASigner {
         self.scheme
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_order_of_empty_chain_is_empty() {
+        assert_eq!(chain_order(&[]), Vec::<usize>::new());
+    }
+}
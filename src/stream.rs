@@ -1,4 +1,4 @@
-use std::io::{Read, Write, Result};
+use std::io::{Read, Write, BufRead, Result, Error, ErrorKind};
 use session::Session;
 
 /// This type implements `io::Read` and `io::Write`, encapsulating
@@ -6,6 +6,12 @@ use session::Session;
 /// a socket.
 ///
 /// This allows you to use a rustls Session like a normal stream.
+/// It also implements `io::BufRead` if `S` does, as `ClientSession`
+/// and `ServerSession` do.
+///
+/// If `T` has a read or write timeout, or is non-blocking, errors
+/// like `WouldBlock` and `TimedOut` are returned to the caller.  No
+/// data is lost when this happens, and the operation can be retried.
 pub struct Stream<'a, S: 'a + Session + ?Sized, T: 'a + Read + Write + ?Sized> {
     /// Our session
     pub sess: &'a mut S,
@@ -21,6 +27,43 @@ impl<'a, S, T> Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + Write {
         Stream { sess, sock }
     }
 
+    /// Make a new Stream for a connection which is being upgraded to
+    /// TLS part-way through, as in STARTTLS-style protocols (SMTP, IMAP,
+    /// LDAP, etc.).
+    ///
+    /// Call this once the plaintext part of the protocol has concluded.
+    /// `sess` should be freshly made: rustls has not read anything from
+    /// `sock` at this point, so any plaintext the caller has buffered
+    /// is left alone.  The handshake is completed before this function
+    /// returns.
+    ///
+    /// If the caller read some bytes from `sock` beyond the end of the
+    /// plaintext exchange, use `starttls_with_buffered` instead.
+    pub fn starttls(sess: &'a mut S, sock: &'a mut T) -> Result<Stream<'a, S, T>> {
+        Stream::starttls_with_buffered(sess, sock, &[])
+    }
+
+    /// Like `starttls`, but first feeds `buffered` to `sess` as if it
+    /// had been read from `sock`.  `buffered` should contain the bytes
+    /// the caller read from `sock` past the end of the plaintext
+    /// exchange, which would otherwise be lost.
+    pub fn starttls_with_buffered(sess: &'a mut S,
+                                  sock: &'a mut T,
+                                  buffered: &[u8]) -> Result<Stream<'a, S, T>> {
+        sess.read_tls_from_buffered(buffered)?;
+
+        // Process what we were given before touching `sock`: the peer may
+        // be waiting for our reply to it.
+        if let Err(e) = sess.process_new_packets() {
+            let _ignored = sess.write_tls(sock);
+            return Err(Error::from(e));
+        }
+
+        let mut stream = Stream::new(sess, sock);
+        stream.complete_prior_io()?;
+        Ok(stream)
+    }
+
     /// If we're handshaking, complete all the IO for that.
     /// If we have data to write, write it all.
     fn complete_prior_io(&mut self) -> Result<()> {
@@ -34,10 +77,10 @@ impl<'a, S, T> Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + Write {
 
         Ok(())
     }
-}
 
-impl<'a, S, T> Read for Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + Write {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    /// Read from `sock` until `sess` has plaintext for us, or `sock`
+    /// reaches EOF.
+    fn read_until_plaintext(&mut self) -> Result<()> {
         self.complete_prior_io()?;
 
         // We call complete_io() in a loop since a single call may read only
@@ -51,16 +94,52 @@ impl<'a, S, T> Read for Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + W
             self.sess.complete_io(self.sock)?.0 != 0
         { }
 
+        Ok(())
+    }
+}
+
+/// Is `err` something a transport with a timeout, or a non-blocking
+/// one, returns when it can't make progress right now?
+fn is_transient(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => true,
+        _ => false,
+    }
+}
+
+impl<'a, S, T> Read for Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + Write {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read_until_plaintext()?;
         self.sess.read(buf)
     }
 }
 
+impl<'a, S, T> BufRead for Stream<'a, S, T> where S: 'a + Session + BufRead, T: 'a + Read + Write {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        self.read_until_plaintext()?;
+        self.sess.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.sess.consume(amt)
+    }
+}
+
 impl<'a, S, T> Write for Stream<'a, S, T> where S: 'a + Session, T: 'a + Read + Write {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         self.complete_prior_io()?;
 
         let len = self.sess.write(buf)?;
-        self.sess.complete_io(self.sock)?;
+
+        // `sess` has taken `len` bytes, so we must report them as
+        // written even if `sock` can't take them yet: they're sent
+        // by the next call which does IO.
+        match self.sess.complete_io(self.sock) {
+            Err(ref e) if is_transient(e) => {}
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+
         Ok(len)
     }
 
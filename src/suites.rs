@@ -5,16 +5,43 @@ use msgs::handshake::DecomposedSignatureScheme;
 use msgs::handshake::{ClientECDHParams, ServerECDHParams};
 use msgs::codec::{Reader, Codec};
 use util;
+use kx;
+use cipher;
 
 use ring;
-use untrusted;
 
+use std::sync::Arc;
+use std::fmt;
+
+/// How a cipher suite does bulk encryption.
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
 pub enum BulkAlgorithm {
+    /// AES-128 in GCM mode.
     AES_128_GCM,
+
+    /// AES-256 in GCM mode.
     AES_256_GCM,
+
+    /// The RFC7539 ChaCha20Poly1305 AEAD.
     CHACHA20_POLY1305,
+
+    /// An AEAD provided by the application, for TLS1.2 suites.
+    Tls12Custom(&'static cipher::Tls12AeadAlgorithm),
+
+    /// An AEAD provided by the application, for TLS1.3 suites.
+    Tls13Custom(&'static cipher::Tls13AeadAlgorithm),
+}
+
+impl fmt::Debug for BulkAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BulkAlgorithm::AES_128_GCM => write!(f, "AES_128_GCM"),
+            BulkAlgorithm::AES_256_GCM => write!(f, "AES_256_GCM"),
+            BulkAlgorithm::CHACHA20_POLY1305 => write!(f, "CHACHA20_POLY1305"),
+            BulkAlgorithm::Tls12Custom(_) => write!(f, "Tls12Custom"),
+            BulkAlgorithm::Tls13Custom(_) => write!(f, "Tls13Custom"),
+        }
+    }
 }
 
 /// The result of a key exchange.  This has our public key,
@@ -24,48 +51,33 @@ pub struct KeyExchangeResult {
     pub premaster_secret: Vec<u8>,
 }
 
-/// An in-progress key exchange.  This has the algorithm,
+/// An in-progress key exchange.  This has the group,
 /// our private key, and our public key.
 pub struct KeyExchange {
     pub group: NamedGroup,
-    alg: &'static ring::agreement::Algorithm,
-    privkey: ring::agreement::EphemeralPrivateKey,
+    active: Box<kx::ActiveKeyExchange>,
     pub pubkey: Vec<u8>,
 }
 
 impl KeyExchange {
-    pub fn named_group_to_ecdh_alg(group: NamedGroup)
-                                   -> Option<&'static ring::agreement::Algorithm> {
-        match group {
-            NamedGroup::X25519 => Some(&ring::agreement::X25519),
-            NamedGroup::secp256r1 => Some(&ring::agreement::ECDH_P256),
-            NamedGroup::secp384r1 => Some(&ring::agreement::ECDH_P384),
-            _ => None,
-        }
-    }
-
-    pub fn client_ecdhe(kx_params: &[u8]) -> Option<KeyExchangeResult> {
+    pub fn client_ecdhe(kx_params: &[u8],
+                        groups: &[Arc<kx::SupportedKxGroup>]) -> Option<KeyExchangeResult> {
         let mut rd = Reader::init(kx_params);
         let ecdh_params = try_ret!(ServerECDHParams::read(&mut rd));
+        let group = try_ret!(kx::find_group(groups, ecdh_params.curve_params.named_group));
 
-        try_ret!(KeyExchange::start_ecdhe(ecdh_params.curve_params.named_group))
+        try_ret!(KeyExchange::start(group.as_ref()))
             .complete(&ecdh_params.public.0)
     }
 
-    pub fn start_ecdhe(named_group: NamedGroup) -> Option<KeyExchange> {
-        let alg = try_ret!(KeyExchange::named_group_to_ecdh_alg(named_group));
-        let rng = ring::rand::SystemRandom::new();
-        let ours = ring::agreement::EphemeralPrivateKey::generate(alg, &rng).unwrap();
-
-        let mut pubkey = Vec::new();
-        pubkey.resize(ours.public_key_len(), 0u8);
-        ours.compute_public_key(pubkey.as_mut_slice()).unwrap();
+    pub fn start(group: &kx::SupportedKxGroup) -> Option<KeyExchange> {
+        let active = try_ret!(group.start());
+        let pubkey = active.pub_key().to_vec();
 
         Some(KeyExchange {
-            group: named_group,
-            alg: alg,
-            privkey: ours,
-            pubkey: pubkey,
+            group: group.name(),
+            active,
+            pubkey,
         })
     }
 
@@ -89,31 +101,20 @@ impl KeyExchange {
     }
 
     pub fn complete(self, peer: &[u8]) -> Option<KeyExchangeResult> {
-        let secret = ring::agreement::agree_ephemeral(self.privkey,
-                                                      self.alg,
-                                                      untrusted::Input::from(peer),
-                                                      (),
-                                                      |v| {
-                                                          let mut r = Vec::new();
-                                                          r.extend_from_slice(v);
-                                                          Ok(r)
-                                                      });
-
-        if secret.is_err() {
-            return None;
-        }
+        let secret = try_ret!(self.active.complete(peer));
 
         Some(KeyExchangeResult {
             pubkey: self.pubkey,
-            premaster_secret: secret.unwrap(),
+            premaster_secret: secret,
         })
     }
 }
 
 /// A cipher suite supported by rustls.
 ///
-/// All possible instances of this class are provided by the library in
-/// the `ALL_CIPHERSUITES` array.
+/// The instances provided by the library are in the `ALL_CIPHERSUITES`
+/// array.  Others can be defined using `BulkAlgorithm::Tls12Custom` or
+/// `BulkAlgorithm::Tls13Custom`.
 #[derive(Debug)]
 pub struct SupportedCipherSuite {
     /// The TLS enumeration naming this cipher suite.
@@ -153,7 +154,23 @@ impl PartialEq for SupportedCipherSuite {
     }
 }
 
+/// How many records AES-GCM may encrypt under one key, per
+/// RFC8446 section 5.5: 2^24.5, rounded down.
+const AES_GCM_RECORD_LIMIT: u64 = 23_726_566;
+
 impl SupportedCipherSuite {
+    /// How many records one TLS1.3 traffic key may encrypt before
+    /// it must be updated.
+    pub fn confidentiality_limit(&self) -> u64 {
+        match self.bulk {
+            BulkAlgorithm::AES_128_GCM |
+            BulkAlgorithm::AES_256_GCM => AES_GCM_RECORD_LIMIT,
+            BulkAlgorithm::Tls13Custom(aead) => aead.confidentiality_limit(),
+            BulkAlgorithm::CHACHA20_POLY1305 |
+            BulkAlgorithm::Tls12Custom(_) => u64::max_value(),
+        }
+    }
+
     /// Which hash function to use with this suite.
     pub fn get_hash(&self) -> &'static ring::digest::Algorithm {
         match self.hash {
@@ -168,18 +185,21 @@ impl SupportedCipherSuite {
     /// We have parameters and a verified public key in `kx_params`.
     /// Generate an ephemeral key, generate the shared secret, and
     /// return it and the public half in a `KeyExchangeResult`.
-    pub fn do_client_kx(&self, kx_params: &[u8]) -> Option<KeyExchangeResult> {
+    /// `groups` are the groups we're willing to use.
+    pub fn do_client_kx(&self,
+                        kx_params: &[u8],
+                        groups: &[Arc<kx::SupportedKxGroup>]) -> Option<KeyExchangeResult> {
         match self.kx {
-            KeyExchangeAlgorithm::ECDHE => KeyExchange::client_ecdhe(kx_params),
+            KeyExchangeAlgorithm::ECDHE => KeyExchange::client_ecdhe(kx_params, groups),
             _ => None,
         }
     }
 
     /// Start the KX process with the given group.  This generates
     /// the server's share, but we don't yet have the client's share.
-    pub fn start_server_kx(&self, named_group: NamedGroup) -> Option<KeyExchange> {
+    pub fn start_server_kx(&self, group: &kx::SupportedKxGroup) -> Option<KeyExchange> {
         match self.kx {
-            KeyExchangeAlgorithm::ECDHE => KeyExchange::start_ecdhe(named_group),
+            KeyExchangeAlgorithm::ECDHE => KeyExchange::start(group),
             _ => None,
         }
     }
@@ -212,15 +232,6 @@ impl SupportedCipherSuite {
         util::first_in_both(our_preference.as_slice(), offered)
     }
 
-    /// Which AEAD algorithm to use for this suite.
-    pub fn get_aead_alg(&self) -> &'static ring::aead::Algorithm {
-        match self.bulk {
-            BulkAlgorithm::AES_128_GCM => &ring::aead::AES_128_GCM,
-            BulkAlgorithm::AES_256_GCM => &ring::aead::AES_256_GCM,
-            BulkAlgorithm::CHACHA20_POLY1305 => &ring::aead::CHACHA20_POLY1305,
-        }
-    }
-
     /// Length of key block that needs to be output by the key
     /// derivation phase for this suite.
     pub fn key_block_len(&self) -> usize {
@@ -229,6 +240,12 @@ impl SupportedCipherSuite {
 
     /// Return true if this suite is usable for TLS `version`.
     pub fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        match self.bulk {
+            BulkAlgorithm::Tls12Custom(_) if version != ProtocolVersion::TLSv1_2 => return false,
+            BulkAlgorithm::Tls13Custom(_) if version != ProtocolVersion::TLSv1_3 => return false,
+            _ => {}
+        }
+
         match version {
             ProtocolVersion::TLSv1_3 => self.sign == SignatureAlgorithm::Anonymous,
             ProtocolVersion::TLSv1_2 => self.sign != SignatureAlgorithm::Anonymous,
@@ -371,6 +388,62 @@ pub static ALL_CIPHERSUITES: [&'static SupportedCipherSuite; 9] =
      &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
      &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256];
 
+/// A named choice of protocol versions, ciphersuites and key
+/// exchange groups, following Mozilla's server side TLS
+/// recommendations.  Use `ClientConfig::set_security_profile` or
+/// `ServerConfig::set_security_profile` to apply one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityProfile {
+    /// TLS1.3 only, for peers which are all up to date.
+    Modern,
+
+    /// TLS1.3 and TLS1.2 with forward-secret AEAD suites, for
+    /// general-purpose servers and clients.
+    Intermediate,
+}
+
+impl SecurityProfile {
+    /// The protocol versions, most preferred first.
+    pub fn versions(&self) -> Vec<ProtocolVersion> {
+        match *self {
+            SecurityProfile::Modern => vec![ProtocolVersion::TLSv1_3],
+            SecurityProfile::Intermediate => vec![ProtocolVersion::TLSv1_3,
+                                                  ProtocolVersion::TLSv1_2],
+        }
+    }
+
+    /// The ciphersuites, most preferred first.
+    pub fn ciphersuites(&self) -> Vec<&'static SupportedCipherSuite> {
+        let mut suites: Vec<&'static SupportedCipherSuite> = vec![
+            &TLS13_AES_128_GCM_SHA256,
+            &TLS13_AES_256_GCM_SHA384,
+            &TLS13_CHACHA20_POLY1305_SHA256,
+        ];
+
+        if *self == SecurityProfile::Intermediate {
+            suites.extend_from_slice(&[
+                &TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                &TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                &TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                &TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                &TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ]);
+        }
+
+        suites
+    }
+
+    /// The key exchange groups, most preferred first.
+    pub fn kx_groups(&self) -> Vec<Arc<kx::SupportedKxGroup>> {
+        vec![
+            Arc::new(kx::X25519),
+            Arc::new(kx::SECP256R1),
+            Arc::new(kx::SECP384R1),
+        ]
+    }
+}
+
 // These both O(N^2)!
 pub fn choose_ciphersuite_preferring_client(client_suites: &[CipherSuite],
                                             server_suites: &[&'static SupportedCipherSuite])
@@ -416,9 +489,50 @@ pub fn reduce_given_version(all: &[&'static SupportedCipherSuite],
         .collect()
 }
 
+/// Return the versions in `versions` which have a usable
+/// ciphersuite in `suites`, most preferred first.
+pub fn effective_versions(versions: &[ProtocolVersion],
+                          suites: &[&'static SupportedCipherSuite])
+                          -> Vec<ProtocolVersion> {
+    [ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2].iter()
+        .filter(|version| versions.contains(version))
+        .filter(|&&version| suites.iter().any(|suite| suite.usable_for_version(version)))
+        .cloned()
+        .collect()
+}
+
+/// Return the ciphersuites in `suites` which are usable with
+/// one of `versions`, keeping their order.
+pub fn effective_suites(versions: &[ProtocolVersion],
+                        suites: &[&'static SupportedCipherSuite])
+                        -> Vec<&'static SupportedCipherSuite> {
+    suites.iter()
+        .filter(|&&suite| versions.iter().any(|&version| suite.usable_for_version(version)))
+        .cloned()
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
-    use msgs::enums::CipherSuite;
+    use msgs::enums::{CipherSuite, ProtocolVersion};
+
+    #[test]
+    fn test_effective_versions_and_suites() {
+        let suites = vec![&super::TLS13_AES_128_GCM_SHA256,
+                          &super::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256];
+        let both = [ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3];
+
+        assert_eq!(super::effective_versions(&both, &suites),
+                   vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]);
+        assert_eq!(super::effective_versions(&both, &suites[1..]),
+                   vec![ProtocolVersion::TLSv1_2]);
+        assert_eq!(super::effective_versions(&[ProtocolVersion::TLSv1_3], &suites[1..]),
+                   vec![]);
+
+        assert_eq!(super::effective_suites(&both, &suites), suites);
+        assert_eq!(super::effective_suites(&[ProtocolVersion::TLSv1_2], &suites),
+                   vec![&super::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]);
+    }
 
     #[test]
     fn test_client_pref() {
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use msgs::enums::{ProtocolVersion, CipherSuite, NamedGroup};
+use session::RenegotiationPolicy;
+use client::DowngradeProtection;
+
+/// Whether a config does client authentication.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuthMode {
+    /// A server doesn't ask for client certificates, or a client
+    /// has none to send.
+    Disabled,
+
+    /// A server asks for a client certificate but accepts clients
+    /// without one, or a client has a certificate to send when asked.
+    Optional,
+
+    /// A server refuses clients without a valid certificate.
+    Mandatory,
+}
+
+/// The security-relevant settings of a `ClientConfig` or
+/// `ServerConfig`, as sessions made from it will use them.  This
+/// is for auditing configurations, and for noticing when they
+/// differ across a fleet of hosts.
+///
+/// The `Display` form has one `name: value` line per field, in a
+/// fixed order, with lists separated by commas.  ALPN protocols
+/// are quoted and escaped like Rust strings, so a comma in one
+/// can't be mistaken for a separator.  Summaries of equal configs
+/// are equal, so they can be compared with `diff` or hashed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigSummary {
+    /// True for a `ClientConfig`.
+    pub is_client: bool,
+
+    /// The usable protocol versions, most preferred first.
+    pub versions: Vec<ProtocolVersion>,
+
+    /// The usable ciphersuites, most preferred first.
+    pub ciphersuites: Vec<CipherSuite>,
+
+    /// The key exchange groups, most preferred first.
+    pub kx_groups: Vec<NamedGroup>,
+
+    /// The ALPN protocols, most preferred first.
+    pub alpn_protocols: Vec<String>,
+
+    /// How client authentication is done.
+    pub client_auth: ClientAuthMode,
+
+    /// For a client, whether server certificates are checked by a
+    /// verifier set with `dangerous()`, or by webpki with a name
+    /// verifier from `set_server_name_verifier`.  Always false for
+    /// a server.
+    pub custom_verifier: bool,
+
+    /// Whether RSA PKCS#1 v1.5 signatures are allowed in TLS1.2.
+    pub enable_rsa_pkcs1: bool,
+
+    /// For a client, whether it renegotiates when the server asks.
+    /// Always false for a server.
+    pub enable_renegotiation: bool,
+
+    /// What happens when the peer asks to renegotiate.
+    pub renegotiation_policy: RenegotiationPolicy,
+
+    /// For a client, what it does when it sees the TLS1.3 downgrade
+    /// sentinel.  None for a server.
+    pub downgrade_protection: Option<DowngradeProtection>,
+
+    /// For a server, whether it refuses to resume TLS1.2 sessions
+    /// without extended master secret.  Always false for a client.
+    pub require_ems_for_resumption: bool,
+
+    /// Whether sessions are stored for resumption by session ID
+    /// (for a server) or for any later resumption (for a client).
+    pub session_storage: bool,
+
+    /// For a server, whether `config_resolver` is set, so sessions
+    /// may use other settings than these.  Always false for a
+    /// client.
+    pub config_resolver: bool,
+
+    /// Whether resumption with tickets is offered (for a client)
+    /// or tickets are issued (for a server).
+    pub tickets: bool,
+
+    /// For a server issuing tickets, the lifetime in seconds it
+    /// tells clients.  Otherwise None.
+    pub ticket_lifetime: Option<u32>,
+}
+
+fn join<T: fmt::Debug>(items: &[T]) -> String {
+    items.iter()
+        .map(|item| format!("{:?}", item))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "side: {}", if self.is_client { "client" } else { "server" })?;
+        writeln!(f, "versions: {}", join(&self.versions))?;
+        writeln!(f, "ciphersuites: {}", join(&self.ciphersuites))?;
+        writeln!(f, "kx_groups: {}", join(&self.kx_groups))?;
+        writeln!(f, "alpn_protocols: {}", join(&self.alpn_protocols))?;
+        writeln!(f, "client_auth: {:?}", self.client_auth)?;
+        writeln!(f, "custom_verifier: {}", self.custom_verifier)?;
+        writeln!(f, "enable_rsa_pkcs1: {}", self.enable_rsa_pkcs1)?;
+        writeln!(f, "enable_renegotiation: {}", self.enable_renegotiation)?;
+        writeln!(f, "renegotiation_policy: {:?}", self.renegotiation_policy)?;
+        match self.downgrade_protection {
+            Some(protection) => writeln!(f, "downgrade_protection: {:?}", protection)?,
+            None => writeln!(f, "downgrade_protection: none")?,
+        }
+        writeln!(f, "require_ems_for_resumption: {}", self.require_ems_for_resumption)?;
+        writeln!(f, "session_storage: {}", self.session_storage)?;
+        writeln!(f, "config_resolver: {}", self.config_resolver)?;
+        writeln!(f, "tickets: {}", self.tickets)?;
+        match self.ticket_lifetime {
+            Some(lifetime) => writeln!(f, "ticket_lifetime: {}", lifetime),
+            None => writeln!(f, "ticket_lifetime: none"),
+        }
+    }
+}
+
+/// A setting which is allowed, but may be a mistake, found by
+/// `ClientConfig::get_warnings` or `ServerConfig::get_warnings`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigWarning {
+    /// No protocol version has a usable ciphersuite, so every
+    /// handshake will fail.
+    NoUsableVersions,
+
+    /// TLS1.2 is enabled, but resumption of TLS1.2 sessions made
+    /// without the extended master secret extension is allowed.
+    /// See `ServerConfig::require_ems_for_resumption`.
+    Tls12WithoutEmsRequirement,
+
+    /// Client certificates are asked for, but clients which
+    /// don't send one are accepted anonymously.
+    OptionalClientAuth,
+
+    /// A server has no ALPN protocols and no `alpn_chooser`, so
+    /// it can't agree an application protocol with clients which
+    /// ask for one, like those speaking HTTP/2.  This only matters
+    /// if the protocol served is chosen with ALPN.
+    NoAlpnProtocols,
+}
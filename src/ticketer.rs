@@ -2,24 +2,33 @@
 use server::ProducesTickets;
 use rand;
 
+use std::cmp;
 use std::mem;
+use std::fmt;
 use std::sync::{Mutex, Arc};
-use std::time;
+use std::collections::VecDeque;
+use clock;
+use util;
 use ring::aead;
 
+/// The longest ticket lifetime RFC8446 allows us to tell clients.
+const MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
+
 /// The timebase for expiring and rolling tickets and ticketing
-/// keys.  This is UNIX wall time in seconds.
+/// keys.  This is UNIX wall time in seconds, or zero if the
+/// time isn't known.
 pub fn timebase() -> u64 {
-    time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+    clock::unix_time()
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0)
 }
 
 /// This is a `ProducesTickets` implementation which uses
 /// any *ring* `aead::Algorithm` to encrypt and authentication
 /// the ticket payload.  It does not enforce any lifetime
 /// constraint.
+///
+/// The key is left out of `Debug` output.
 pub struct AEADTicketer {
     alg: &'static aead::Algorithm,
     enc: aead::SealingKey,
@@ -48,7 +57,19 @@ impl AEADTicketer {
     pub fn new() -> AEADTicketer {
         let mut key = [0u8; 32];
         rand::fill_random(&mut key);
-        AEADTicketer::new_custom(&aead::CHACHA20_POLY1305, &key, 60 * 60 * 12)
+        let ticketer = AEADTicketer::new_custom(&aead::CHACHA20_POLY1305, &key, 60 * 60 * 12);
+        util::wipe(&mut key);
+        ticketer
+    }
+}
+
+impl fmt::Debug for AEADTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AEADTicketer")
+            .field("alg", self.alg)
+            .field("key", &"<redacted>")
+            .field("lifetime", &self.lifetime)
+            .finish()
     }
 }
 
@@ -102,18 +123,32 @@ impl ProducesTickets for AEADTicketer {
     }
 }
 
+/// A trait for being told when a `Ticketer` rotates its key.
+///
+/// Keys are rotated lazily, when tickets are produced or checked.
+/// If no rotation is reported for much longer than the ticketer's
+/// rotation interval, it is not being used.
+pub trait ObservesTicketRotation : Send + Sync {
+    /// Called after each rotation.  `epoch` counts rotations
+    /// since the ticketer was made, starting at 1.
+    fn rotated(&self, epoch: u64);
+}
+
 struct TicketSwitcherState {
     current: Box<ProducesTickets>,
-    previous: Option<Box<ProducesTickets>>,
+    previous: VecDeque<Box<ProducesTickets>>,
     next_switch_time: u64,
+    epoch: u64,
 }
 
-/// A ticketer that has a 'current' sub-ticketer and a single
-/// 'previous' ticketer.  It creates a new ticketer every so
+/// A ticketer that has a 'current' sub-ticketer and a number of
+/// 'previous' ticketers.  It creates a new ticketer every so
 /// often, demoting the current ticketer.
 pub struct TicketSwitcher {
     generator: fn() -> Box<ProducesTickets>,
     lifetime: u32,
+    previous_epochs: usize,
+    observer: Option<Arc<ObservesTicketRotation>>,
     state: Mutex<TicketSwitcherState>,
 }
 
@@ -125,37 +160,72 @@ impl TicketSwitcher {
     pub fn new(lifetime: u32,
                generator: fn() -> Box<ProducesTickets>)
                -> TicketSwitcher {
+        TicketSwitcher::new_custom(lifetime, 1, None, generator)
+    }
+
+    /// Like `new`, but tickets from `previous_epochs` previous ticketers
+    /// are accepted, so tickets are accepted for no longer than
+    /// `previous_epochs + 1` times `lifetime`.  `observer` is told
+    /// about each rotation.
+    ///
+    /// The lifetime told to clients is that, but at most seven days:
+    /// the most RFC8446 allows.
+    pub fn new_custom(lifetime: u32,
+                      previous_epochs: usize,
+                      observer: Option<Arc<ObservesTicketRotation>>,
+                      generator: fn() -> Box<ProducesTickets>)
+                      -> TicketSwitcher {
         TicketSwitcher {
             generator: generator,
             lifetime: lifetime,
+            previous_epochs: previous_epochs,
+            observer: observer,
             state: Mutex::new(TicketSwitcherState {
                 current: generator(),
-                previous: None,
+                previous: VecDeque::new(),
                 next_switch_time: timebase() + lifetime as u64,
+                epoch: 0,
             }),
         }
     }
 
     /// If it's time, demote the `current` ticketer to `previous` (so it
     /// does no new encryptions but can do decryptions) and make a fresh
-    /// `current` ticketer.
+    /// `current` ticketer.  The oldest `previous` ticketer is erased
+    /// if there are too many.
     ///
     /// Calling this regularly will ensure timely key erasure.  Otherwise,
     /// key erasure will be delayed until the next encrypt/decrypt call.
     pub fn maybe_roll(&self) {
-        let mut state = self.state.lock().unwrap();
-        let now = timebase();
+        self.maybe_roll_at(timebase());
+    }
+
+    fn maybe_roll_at(&self, now: u64) {
+        let rotated = {
+            let mut state = self.state.lock().unwrap();
 
-        if now > state.next_switch_time {
-            state.previous = Some(mem::replace(&mut state.current, (self.generator)()));
-            state.next_switch_time = now + self.lifetime as u64;
+            if now > state.next_switch_time {
+                let old = mem::replace(&mut state.current, (self.generator)());
+                state.previous.push_front(old);
+                state.previous.truncate(self.previous_epochs);
+                state.next_switch_time = now + self.lifetime as u64;
+                state.epoch += 1;
+                Some(state.epoch)
+            } else {
+                None
+            }
+        };
+
+        if let (Some(epoch), Some(observer)) = (rotated, self.observer.as_ref()) {
+            observer.rotated(epoch);
         }
     }
 }
 
 impl ProducesTickets for TicketSwitcher {
     fn get_lifetime(&self) -> u32 {
-        self.lifetime * 2
+        let epochs = cmp::min(self.previous_epochs, u32::max_value() as usize - 1) as u32 + 1;
+        cmp::min(self.lifetime.saturating_mul(epochs), MAX_TICKET_LIFETIME)
     }
     fn enabled(&self) -> bool {
         true
@@ -175,13 +245,14 @@ impl ProducesTickets for TicketSwitcher {
         self.maybe_roll();
 
         let state = self.state.lock().unwrap();
-        let rc = state.current.decrypt(ciphertext);
-
-        if rc.is_none() && state.previous.is_some() {
-            state.previous.as_ref().unwrap().decrypt(ciphertext)
-        } else {
-            rc
-        }
+        state.current
+            .decrypt(ciphertext)
+            .or_else(|| {
+                state.previous
+                    .iter()
+                    .filter_map(|prev| prev.decrypt(ciphertext))
+                    .next()
+            })
     }
 }
 
@@ -200,4 +271,80 @@ impl Ticketer {
     pub fn new() -> Arc<ProducesTickets> {
         Arc::new(TicketSwitcher::new(6 * 60 * 60, generate_inner))
     }
+
+    /// Make a Ticketer which uses a new key every `lifetime` seconds,
+    /// and also accepts tickets made with the `previous_epochs`
+    /// keys before that.  More previous epochs means more successful
+    /// resumptions, but keys are erased later.
+    ///
+    /// `observer` is told each time the key is rotated.
+    pub fn new_custom(lifetime: u32,
+                      previous_epochs: usize,
+                      observer: Option<Arc<ObservesTicketRotation>>)
+                      -> Arc<ProducesTickets> {
+        Arc::new(TicketSwitcher::new_custom(lifetime, previous_epochs, observer, generate_inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TicketSwitcher, ObservesTicketRotation, generate_inner, timebase};
+    use super::AEADTicketer;
+    use server::ProducesTickets;
+    use ring::aead;
+    use std::sync::{Arc, Mutex};
+
+    struct RecordRotations(Mutex<Vec<u64>>);
+
+    impl ObservesTicketRotation for RecordRotations {
+        fn rotated(&self, epoch: u64) {
+            self.0.lock().unwrap().push(epoch);
+        }
+    }
+
+    #[test]
+    fn accepts_previous_epochs() {
+        let observer = Arc::new(RecordRotations(Mutex::new(Vec::new())));
+        let switcher = TicketSwitcher::new_custom(10, 2, Some(observer.clone()), generate_inner);
+        assert_eq!(switcher.get_lifetime(), 30);
+
+        let ticket = switcher.encrypt(b"hello").unwrap();
+        let now = timebase();
+
+        switcher.maybe_roll_at(now + 11);
+        assert_eq!(switcher.decrypt(&ticket), Some(b"hello".to_vec()));
+        switcher.maybe_roll_at(now + 22);
+        assert_eq!(switcher.decrypt(&ticket), Some(b"hello".to_vec()));
+        switcher.maybe_roll_at(now + 33);
+        assert_eq!(switcher.decrypt(&ticket), None);
+
+        assert_eq!(*observer.0.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn lifetime_is_capped() {
+        let switcher = TicketSwitcher::new_custom(3 * 24 * 60 * 60, 2, None, generate_inner);
+        assert_eq!(switcher.get_lifetime(), 7 * 24 * 60 * 60);
+
+        let switcher = TicketSwitcher::new_custom(u32::max_value(), usize::max_value(),
+                                                  None, generate_inner);
+        assert_eq!(switcher.get_lifetime(), 7 * 24 * 60 * 60);
+    }
+
+    #[test]
+    fn no_previous_epochs() {
+        let switcher = TicketSwitcher::new_custom(10, 0, None, generate_inner);
+        assert_eq!(switcher.get_lifetime(), 10);
+
+        let ticket = switcher.encrypt(b"hello").unwrap();
+        switcher.maybe_roll_at(timebase() + 11);
+        assert_eq!(switcher.decrypt(&ticket), None);
+    }
+
+    #[test]
+    fn aead_ticketer_debug_omits_key() {
+        let ticketer = AEADTicketer::new_custom(&aead::CHACHA20_POLY1305, &[0x5a; 32], 60);
+        assert_eq!("AEADTicketer { alg: CHACHA20_POLY1305, key: \"<redacted>\", lifetime: 60 }",
+                   format!("{:?}", ticketer));
+    }
 }
@@ -8,3 +8,33 @@ pub fn first_in_both<T: Clone + PartialEq>(prefs: &[T], avail: &[T]) -> Option<T
 
     None
 }
+
+/// Overwrite `secret` with zeroes.
+///
+/// With the `zeroize` feature this is done in a way the compiler
+/// can't optimise away.  Without it, this does nothing.
+#[cfg(feature = "zeroize")]
+pub fn wipe(secret: &mut [u8]) {
+    use zeroize::Zeroize;
+    secret.zeroize();
+}
+
+/// Overwrite `secret` with zeroes.
+///
+/// With the `zeroize` feature this is done in a way the compiler
+/// can't optimise away.  Without it, this does nothing.
+#[cfg(not(feature = "zeroize"))]
+#[inline(always)]
+pub fn wipe(_secret: &mut [u8]) {}
+
+#[cfg(all(test, feature = "zeroize"))]
+mod test {
+    use super::wipe;
+
+    #[test]
+    fn wipe_zeroes_secret() {
+        let mut secret = vec![0x5au8; 48];
+        wipe(&mut secret);
+        assert_eq!(secret, vec![0u8; 48]);
+    }
+}
@@ -2,6 +2,10 @@ use std::io::Read;
 use std::io;
 use std::cmp;
 use std::collections::VecDeque;
+use std::mem::MaybeUninit;
+
+/// `append_copy` doesn't grow chunks beyond this size.
+const COALESCE_LIMIT: usize = 64 * 1024;
 
 /// This is a byte buffer that is built from a vector
 /// of byte vectors.  This avoids extra copies when
@@ -73,12 +77,70 @@ impl ChunkVecBuffer {
         len
     }
 
+    /// Append a copy of `bytes`.  Small appends are coalesced into
+    /// the last chunk, to avoid allocating for each one.
+    pub fn append_copy(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        if let Some(last) = self.chunks.back_mut() {
+            if last.len() + bytes.len() <= COALESCE_LIMIT {
+                last.extend_from_slice(bytes);
+                return;
+            }
+        }
+
+        self.chunks.push_back(bytes.to_vec());
+    }
+
     /// Take one of the chunks from this object.  This
     /// function panics if the object `is_empty`.
     pub fn take_one(&mut self) -> Vec<u8> {
         self.chunks.pop_front().unwrap()
     }
 
+    /// Copy data from the front of this object into `buf`,
+    /// without consuming it.  Returns how many bytes were copied.
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let mut offs = 0;
+
+        for chunk in &self.chunks {
+            if offs == buf.len() {
+                break;
+            }
+
+            let take = cmp::min(chunk.len(), buf.len() - offs);
+            buf[offs..offs + take].copy_from_slice(&chunk[..take]);
+            offs += take;
+        }
+
+        offs
+    }
+
+    /// The data at the front of this object, without consuming it.
+    /// This may be less than everything we're storing.
+    pub fn chunk(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &[],
+        }
+    }
+
+    /// Discard `used` bytes from the front of this object, which
+    /// must not be more than `chunk()` returned.
+    pub fn consume(&mut self, used: usize) {
+        if used == 0 {
+            return;
+        }
+
+        if used == self.chunks[0].len() {
+            self.take_one();
+        } else {
+            self.chunks[0].drain(..used);
+        }
+    }
+
     /// Read data out of this object, writing it into `buf`
     /// and returning how many bytes were written there.
     pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
@@ -90,7 +152,7 @@ impl ChunkVecBuffer {
             if used == self.chunks[0].len() {
                 self.take_one();
             } else {
-                self.chunks[0] = self.chunks[0].split_off(used);
+                self.chunks[0].drain(..used);
             }
 
             offs += used;
@@ -99,6 +161,51 @@ impl ChunkVecBuffer {
         Ok(offs)
     }
 
+    /// Read data out of this object into each of `bufs` in turn,
+    /// returning how many bytes were written in total.
+    pub fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut]) -> usize {
+        let mut total = 0;
+
+        for buf in bufs.iter_mut() {
+            let mut offs = 0;
+
+            while offs < buf.len() && !self.is_empty() {
+                let used = cmp::min(buf.len() - offs, self.chunks[0].len());
+                buf[offs..offs + used].copy_from_slice(&self.chunks[0][..used]);
+                self.consume(used);
+                offs += used;
+            }
+
+            total += offs;
+
+            if self.is_empty() {
+                break;
+            }
+        }
+
+        total
+    }
+
+    /// Like `read`, but `buf` needn't be initialised first.  The
+    /// first bytes of `buf`, as many as are returned, are
+    /// initialised afterwards.
+    pub fn read_uninit(&mut self, buf: &mut [MaybeUninit<u8>]) -> usize {
+        let mut offs = 0;
+
+        while offs < buf.len() && !self.is_empty() {
+            let used = cmp::min(buf.len() - offs, self.chunks[0].len());
+
+            for (dst, src) in buf[offs..offs + used].iter_mut().zip(&self.chunks[0]) {
+                *dst = MaybeUninit::new(*src);
+            }
+
+            self.consume(used);
+            offs += used;
+        }
+
+        offs
+    }
+
     /// Read data of this object, passing it `wr`
     pub fn write_to(&mut self, wr: &mut io::Write) -> io::Result<usize> {
         // would desperately like writev support here!
@@ -111,7 +218,7 @@ impl ChunkVecBuffer {
         if used == self.chunks[0].len() {
             self.take_one();
         } else {
-            self.chunks[0] = self.chunks[0].split_off(used);
+            self.chunks[0].drain(..used);
         }
 
         Ok(used)
@@ -122,6 +229,31 @@ impl ChunkVecBuffer {
 mod test {
     use super::ChunkVecBuffer;
 
+    #[test]
+    fn read_vectored_spans_chunks() {
+        use std::io::IoSliceMut;
+
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        let mut a = [0u8; 3];
+        let mut b = [0u8; 4];
+        let mut c = [0u8; 8];
+        let got = {
+            let mut bufs = [IoSliceMut::new(&mut a),
+                            IoSliceMut::new(&mut b),
+                            IoSliceMut::new(&mut c)];
+            cvb.read_vectored(&mut bufs)
+        };
+
+        assert_eq!(got, 10);
+        assert_eq!(&a, b"hel");
+        assert_eq!(&b, b"lowo");
+        assert_eq!(&c[..3], b"rld");
+        assert!(cvb.is_empty());
+    }
+
     #[test]
     fn short_append_copy_with_limit()
     {
@@ -137,4 +269,34 @@ mod test {
         assert_eq!(buf.to_vec(),
                    b"helloworldhe".to_vec());
     }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append(b"GE".to_vec());
+        cvb.append(b"T /".to_vec());
+
+        let mut buf = [0u8; 4];
+        assert_eq!(cvb.peek(&mut buf), 4);
+        assert_eq!(&buf, b"GET ");
+        assert_eq!(cvb.peek(&mut [0u8; 10]), 5);
+        assert_eq!(cvb.len(), 5);
+    }
+
+    #[test]
+    fn append_copy_coalesces() {
+        let mut cvb = ChunkVecBuffer::new();
+        cvb.append_copy(b"hello");
+        cvb.append_copy(b"");
+        cvb.append_copy(b"world");
+        assert_eq!(cvb.chunks.len(), 1);
+
+        cvb.append_copy(&[0u8; super::COALESCE_LIMIT]);
+        assert_eq!(cvb.chunks.len(), 2);
+
+        let mut buf = [0u8; 7];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 7);
+        assert_eq!(&buf, b"hellowo");
+        assert_eq!(cvb.len(), 3 + super::COALESCE_LIMIT);
+    }
 }
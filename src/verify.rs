@@ -1,15 +1,19 @@
 use webpki;
 use untrusted;
 use sct;
-use std;
 use std::sync::Arc;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use key::Certificate;
 use msgs::handshake::DigitallySignedStruct;
 use msgs::handshake::SCTList;
-use msgs::enums::SignatureScheme;
+use msgs::enums::{SignatureScheme, HashAlgorithm, NamedGroup, ProtocolVersion};
 use error::TLSError;
-use anchors::{DistinguishedNames, RootCertStore};
+use clock;
+use anchors::{DistinguishedNames, RootCertStore, OwnedTrustAnchor};
+use x509::{self, KeySummary};
+use suites::SupportedCipherSuite;
 
 type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
 
@@ -28,6 +32,88 @@ static SUPPORTED_SIG_ALGS: SignatureAlgorithms = &[&webpki::ECDSA_P256_SHA256,
                                                    &webpki::RSA_PKCS1_2048_8192_SHA512,
                                                    &webpki::RSA_PKCS1_3072_8192_SHA384];
 
+/// `SUPPORTED_SIG_ALGS` with the hash each uses.
+static SIG_ALG_HASHES: &'static [(&'static webpki::SignatureAlgorithm, HashAlgorithm)] = &[
+    (&webpki::ECDSA_P256_SHA256, HashAlgorithm::SHA256),
+    (&webpki::ECDSA_P256_SHA384, HashAlgorithm::SHA384),
+    (&webpki::ECDSA_P384_SHA256, HashAlgorithm::SHA256),
+    (&webpki::ECDSA_P384_SHA384, HashAlgorithm::SHA384),
+    (&webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY, HashAlgorithm::SHA256),
+    (&webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY, HashAlgorithm::SHA384),
+    (&webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY, HashAlgorithm::SHA512),
+    (&webpki::RSA_PKCS1_2048_8192_SHA1, HashAlgorithm::SHA1),
+    (&webpki::RSA_PKCS1_2048_8192_SHA256, HashAlgorithm::SHA256),
+    (&webpki::RSA_PKCS1_2048_8192_SHA384, HashAlgorithm::SHA384),
+    (&webpki::RSA_PKCS1_2048_8192_SHA512, HashAlgorithm::SHA512),
+    (&webpki::RSA_PKCS1_3072_8192_SHA384, HashAlgorithm::SHA384),
+];
+
+/// Security requirements which `WebPKIVerifier` applies to the
+/// certificates a server presents, on top of webpki's own checks.
+///
+/// The default policy is no stricter than webpki: RSA keys of at
+/// least 2048 bits, P-256 or P-384 EC keys, and chain signatures
+/// using SHA-1 or any SHA-2 hash.  Trust anchors are not checked
+/// against the policy.
+#[derive(Clone, Debug)]
+pub struct VerifierPolicy {
+    /// The smallest RSA modulus allowed in a presented certificate,
+    /// in bits.  webpki never accepts less than 2048 bits.
+    pub min_rsa_bits: usize,
+
+    /// The curves allowed for EC keys in presented certificates.
+    pub allowed_curves: Vec<NamedGroup>,
+
+    /// The hashes allowed in the signatures which make up the
+    /// certificate chain.  This doesn't affect handshake signatures.
+    pub allowed_hashes: Vec<HashAlgorithm>,
+}
+
+impl Default for VerifierPolicy {
+    fn default() -> VerifierPolicy {
+        VerifierPolicy {
+            min_rsa_bits: 2048,
+            allowed_curves: vec![NamedGroup::secp256r1, NamedGroup::secp384r1],
+            allowed_hashes: vec![HashAlgorithm::SHA1,
+                                 HashAlgorithm::SHA256,
+                                 HashAlgorithm::SHA384,
+                                 HashAlgorithm::SHA512],
+        }
+    }
+}
+
+impl VerifierPolicy {
+    /// The chain signature algorithms this policy allows.
+    fn sig_algs(&self) -> Vec<&'static webpki::SignatureAlgorithm> {
+        SIG_ALG_HASHES.iter()
+            .filter(|&&(_, ref hash)| self.allowed_hashes.contains(hash))
+            .map(|&(alg, _)| alg)
+            .collect()
+    }
+
+    /// Check the public key of each of `certs`.
+    fn check_keys(&self, certs: &[Certificate]) -> Result<(), TLSError> {
+        for (i, cert) in certs.iter().enumerate() {
+            let summary = x509::summarise_cert(&cert.0)
+                .ok_or(TLSError::WebPKIError(webpki::Error::BadDER))?;
+
+            match summary.key {
+                KeySummary::Rsa(bits) if bits < self.min_rsa_bits => {
+                    let why = format!("certificate {} has a {}-bit RSA key", i, bits);
+                    return Err(TLSError::PeerIncompatibleError(why));
+                }
+                KeySummary::Ec(curve) if !self.allowed_curves.contains(&curve) => {
+                    let why = format!("certificate {} has a {:?} key", i, curve);
+                    return Err(TLSError::PeerIncompatibleError(why));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Marker types.  These are used to bind the fact some verification
 /// (certificate chain or handshake signature) has taken place into
 /// protocol states.  We use this to have the compiler check that there
@@ -57,16 +143,61 @@ impl ClientCertVerified {
     pub fn assertion() -> Self { Self { 0: () } }
 }
 
+/// What has been negotiated in the handshake at the point the
+/// server's certificate is verified.
+#[derive(Debug)]
+pub struct ServerCertContext<'a> {
+    /// The negotiated protocol version.
+    pub version: ProtocolVersion,
+
+    /// The negotiated ciphersuite.
+    pub suite: &'static SupportedCipherSuite,
+
+    /// The ALPN protocols we offered, in order of preference.
+    pub offered_alpn: &'a [String],
+
+    /// The ALPN protocol the server selected, if any.
+    pub alpn_protocol: Option<&'a str>,
+
+    /// Other names the certificate may be valid for, from
+    /// `ClientSession::new_with_alternate_names`.
+    pub alternate_names: &'a [webpki::DNSName],
+}
+
 /// Something that can verify a server certificate chain
 pub trait ServerCertVerifier : Send + Sync {
     /// Verify a the certificate chain `presented_certs` against the roots
     /// configured in `roots`.  Make sure that `dns_name` is quoted by
-    /// the top certificate in the chain.
+    /// the top certificate in the chain.  `context` describes the
+    /// rest of the handshake, for verifiers whose policy depends on it.
     fn verify_server_cert(&self,
                           roots: &RootCertStore,
                           presented_certs: &[Certificate],
                           dns_name: webpki::DNSNameRef,
-                          ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError>;
+                          ocsp_response: &[u8],
+                          context: &ServerCertContext) -> Result<ServerCertVerified, TLSError>;
+
+    /// Like `verify_server_cert`, but may instead return `Ok(None)`
+    /// to suspend the handshake until the application supplies a
+    /// verdict with `ClientSession::set_server_cert_verdict`.  This
+    /// allows verification which needs network requests, such as
+    /// OCSP fetching or asking a policy service, without blocking
+    /// inside `process_new_packets`.
+    ///
+    /// A deferred verdict is final: it should accept a certificate
+    /// valid for `dns_name` or any of `context.alternate_names`.
+    ///
+    /// The default implementation calls `verify_server_cert`.
+    fn verify_server_cert_or_defer(&self,
+                                   roots: &RootCertStore,
+                                   presented_certs: &[Certificate],
+                                   dns_name: webpki::DNSNameRef,
+                                   ocsp_response: &[u8],
+                                   context: &ServerCertContext)
+                                   -> Result<Option<ServerCertVerified>, TLSError> {
+        self.verify_server_cert(roots, presented_certs, dns_name, ocsp_response, context)
+            .map(Some)
+    }
 }
 
 /// Something that can verify a client certificate chain
@@ -89,8 +220,50 @@ pub trait ClientCertVerifier : Send + Sync {
                           presented_certs: &[Certificate]) -> Result<ClientCertVerified, TLSError>;
 }
 
+/// Something which makes the final decision about whether a
+/// server certificate is valid for the name we connected to.  This
+/// allows custom name matching, such as accepting a known alternate
+/// name or RFC6125 SRV-ID and URI-ID matching, while webpki still
+/// verifies the certificate chain.
+pub trait VerifiesServerName : Send + Sync {
+    /// Decide whether `cert`, the server's end-entity certificate,
+    /// is valid for `dns_name`.  The certificate chain has already
+    /// been verified.  `webpki_result` is the result of webpki's own
+    /// check of `dns_name` against the certificate's subjectAltName
+    /// extension; returning it unchanged gives the default behaviour.
+    fn verify_server_name(&self,
+                          cert: &Certificate,
+                          dns_name: webpki::DNSNameRef,
+                          webpki_result: Result<(), webpki::Error>) -> Result<(), TLSError>;
+}
+
+/// Something which verifies TLS1.3 handshake signatures made with
+/// schemes that webpki doesn't support, such as ML-DSA.  This lets
+/// an external provider supply the signature algorithm.
+///
+/// This only checks the CertificateVerify signature.  The
+/// certificate chain is checked as usual, so a `ServerCertVerifier`
+/// which understands the chain's keys is needed too.
+pub trait VerifiesSignatures : Send + Sync {
+    /// The schemes this can verify.  These are offered in our
+    /// signature_algorithms extension, after the built-in ones.
+    fn supported_schemes(&self) -> Vec<SignatureScheme>;
+
+    /// Verify that `signature` is a valid signature over `message`
+    /// using `scheme` and the public key in `cert`, the peer's
+    /// end-entity certificate.
+    fn verify_signature(&self,
+                        scheme: SignatureScheme,
+                        cert: &Certificate,
+                        message: &[u8],
+                        signature: &[u8]) -> Result<(), TLSError>;
+}
+
 pub struct WebPKIVerifier {
-    pub time: fn() -> Result<webpki::Time, TLSError>,
+    pub time: fn() -> Result<SystemTime, TLSError>,
+    pub policy: VerifierPolicy,
+    pub name_verifier: Option<Arc<VerifiesServerName>>,
+    pub extra_intermediates: Vec<Certificate>,
 }
 
 impl ServerCertVerifier for WebPKIVerifier {
@@ -98,33 +271,89 @@ impl ServerCertVerifier for WebPKIVerifier {
                           roots: &RootCertStore,
                           presented_certs: &[Certificate],
                           dns_name: webpki::DNSNameRef,
-                          ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
-        let (cert, chain, trustroots) = prepare(roots, presented_certs)?;
+                          ocsp_response: &[u8],
+                          _context: &ServerCertContext) -> Result<ServerCertVerified, TLSError> {
+        let (cert, chain, trustroots) = prepare(roots, presented_certs, &self.extra_intermediates)?;
+        self.policy.check_keys(presented_certs)?;
         let now = (self.time)()?;
-        let cert = cert.verify_is_valid_tls_server_cert(SUPPORTED_SIG_ALGS,
-                &webpki::TLSServerTrustAnchors(&trustroots), &chain, now)
-            .map_err(TLSError::WebPKIError)
+
+        // Servers sometimes leave out intermediates; these are only
+        // candidates for path building, so need not be trusted.
+        let chain: Vec<untrusted::Input> = chain.into_iter()
+            .chain(self.extra_intermediates
+                   .iter()
+                   .map(|cert| untrusted::Input::from(&cert.0)))
+            .collect();
+        let cert = cert.verify_is_valid_tls_server_cert(&self.policy.sig_algs(),
+                &webpki::TLSServerTrustAnchors(&trustroots), &chain, webpki_time(now)?)
+            .map_err(|err| {
+                let certs = presented_certs.iter().chain(self.extra_intermediates.iter());
+                describe_error(err, certs, now)
+            })
             .map(|_| cert)?;
 
         if !ocsp_response.is_empty() {
             debug!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        cert.verify_is_valid_for_dns_name(dns_name)
-            .map_err(TLSError::WebPKIError)
-            .map(|_| ServerCertVerified::assertion())
+        let name_result = cert.verify_is_valid_for_dns_name(dns_name);
+        match self.name_verifier {
+            Some(ref name_verifier) => {
+                name_verifier.verify_server_name(&presented_certs[0], dns_name, name_result)
+            }
+            None => name_result.map_err(TLSError::WebPKIError),
+        }.map(|_| ServerCertVerified::assertion())
     }
 }
 
 impl WebPKIVerifier {
     pub fn new() -> WebPKIVerifier {
+        WebPKIVerifier::with_policy(VerifierPolicy::default())
+    }
+
+    pub fn with_policy(policy: VerifierPolicy) -> WebPKIVerifier {
         WebPKIVerifier {
             time: try_now,
+            policy,
+            name_verifier: None,
+            extra_intermediates: Vec::new(),
         }
     }
 }
 
-fn prepare<'a, 'b>(roots: &'b RootCertStore, presented_certs: &'a [Certificate])
+/// The roots which could have issued one of `certs`, or all of them
+/// if none could.  webpki only tries a root whose subject is the
+/// issuer of a certificate in the chain, so the rest can be skipped.
+fn candidate_roots<'b, 'c, I>(roots: &'b RootCertStore, certs: I) -> Vec<webpki::TrustAnchor<'b>>
+    where I: Iterator<Item = &'c Certificate>
+{
+    let mut found: Vec<&OwnedTrustAnchor> = Vec::new();
+
+    for cert in certs {
+        let issuer = match x509::summarise_cert(&cert.0) {
+            Some(summary) => summary.issuer,
+            None => continue,
+        };
+
+        for ota in roots.find_by_subject(issuer) {
+            if !found.iter().any(|f| ptr::eq(*f, ota)) {
+                found.push(ota);
+            }
+        }
+    }
+
+    if found.is_empty() {
+        found = roots.roots.iter().collect();
+    }
+
+    found.into_iter()
+        .map(|ota| ota.to_trust_anchor())
+        .collect()
+}
+
+fn prepare<'a, 'b>(roots: &'b RootCertStore,
+                   presented_certs: &'a [Certificate],
+                   extra_intermediates: &[Certificate])
                    -> Result<(webpki::EndEntityCert<'a>,
                               Vec<untrusted::Input<'a>>,
                               Vec<webpki::TrustAnchor<'b>>), TLSError> {
@@ -142,17 +371,46 @@ fn prepare<'a, 'b>(roots: &'b RootCertStore, presented_certs: &'a [Certificate])
         .map(|cert| untrusted::Input::from(&cert.0))
         .collect();
 
-    let trustroots: Vec<webpki::TrustAnchor> = roots.roots
-        .iter()
-        .map(|x| x.to_trust_anchor())
-        .collect();
+    let trustroots = candidate_roots(roots,
+                                     presented_certs.iter().chain(extra_intermediates.iter()));
 
     Ok((cert, chain, trustroots))
 }
 
-fn try_now() -> Result<webpki::Time, TLSError> {
-    webpki::Time::try_from(std::time::SystemTime::now())
-        .map_err( |_ | TLSError::FailedToGetCurrentTime)
+fn try_now() -> Result<SystemTime, TLSError> {
+    clock::unix_time()
+        .map(|dur| UNIX_EPOCH + dur)
+        .ok_or(TLSError::FailedToGetCurrentTime)
+}
+
+fn webpki_time(now: SystemTime) -> Result<webpki::Time, TLSError> {
+    webpki::Time::try_from(now)
+        .map_err(|_| TLSError::FailedToGetCurrentTime)
+}
+
+/// Turn webpki's `err` from verifying a chain of `certs` at `now`
+/// into a `TLSError`.  webpki doesn't say which certificate has
+/// expired or isn't yet valid, so we find it: the first in `certs`
+/// which is outside its validity period.
+fn describe_error<'a, I>(err: webpki::Error, certs: I, now: SystemTime) -> TLSError
+    where I: Iterator<Item = &'a Certificate>
+{
+    let outside_validity = |summary: &x509::CertSummary| match err {
+        webpki::Error::CertExpired => now > summary.not_after,
+        webpki::Error::CertNotValidYet => now < summary.not_before,
+        _ => false,
+    };
+
+    certs
+        .filter_map(|cert| x509::summarise_cert(&cert.0))
+        .find(|summary| outside_validity(summary))
+        .map(|summary| TLSError::CertNotValidAtTime {
+            error: err,
+            not_before: summary.not_before,
+            not_after: summary.not_after,
+            now,
+        })
+        .unwrap_or(TLSError::WebPKIError(err))
 }
 
 /// A `ClientCertVerifier` that will ensure that every client provides a trusted
@@ -181,12 +439,12 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
 
     fn verify_client_cert(&self, presented_certs: &[Certificate])
                           -> Result<ClientCertVerified, TLSError> {
-        let (cert, chain, trustroots) = prepare(&self.roots, presented_certs)?;
+        let (cert, chain, trustroots) = prepare(&self.roots, presented_certs, &[])?;
         let now = try_now()?;
         cert.verify_is_valid_tls_client_cert(
                 SUPPORTED_SIG_ALGS, &webpki::TLSClientTrustAnchors(&trustroots),
-                &chain, now)
-            .map_err(TLSError::WebPKIError)
+                &chain, webpki_time(now)?)
+            .map_err(|err| describe_error(err, presented_certs.iter(), now))
             .map(|_| ClientCertVerified::assertion())
     }
 }
@@ -342,15 +600,23 @@ fn convert_alg_tls13(scheme: SignatureScheme)
 pub fn verify_tls13(cert: &Certificate,
                     dss: &DigitallySignedStruct,
                     handshake_hash: &[u8],
-                    context_string_with_0: &[u8])
+                    context_string_with_0: &[u8],
+                    extra: Option<&VerifiesSignatures>)
                     -> Result<HandshakeSignatureValid, TLSError> {
-    let alg = convert_alg_tls13(dss.scheme)?;
-
     let mut msg = Vec::new();
     msg.resize(64, 0x20u8);
     msg.extend_from_slice(context_string_with_0);
     msg.extend_from_slice(handshake_hash);
 
+    if let Some(extra) = extra {
+        if extra.supported_schemes().contains(&dss.scheme) {
+            return extra.verify_signature(dss.scheme, cert, &msg, &dss.sig.0)
+                .map(|_| HandshakeSignatureValid::assertion());
+        }
+    }
+
+    let alg = convert_alg_tls13(dss.scheme)?;
+
     let cert_in = untrusted::Input::from(&cert.0);
     let cert = webpki::EndEntityCert::from(cert_in)
         .map_err(TLSError::WebPKIError)?;
@@ -363,20 +629,23 @@ pub fn verify_tls13(cert: &Certificate,
 }
 
 fn unix_time_millis() -> Result<u64, TLSError> {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
+    clock::unix_time()
         .map(|dur| dur.as_secs())
-        .map_err(|_| TLSError::FailedToGetCurrentTime)
+        .ok_or(TLSError::FailedToGetCurrentTime)
         .and_then(|secs| secs.checked_mul(1000)
                   .ok_or(TLSError::FailedToGetCurrentTime))
 }
 
+/// Verify each of `scts` against `logs`, returning the log which
+/// signed each one, or why it couldn't be verified.
 pub fn verify_scts(cert: &Certificate,
                    scts: &SCTList,
-                   logs: &[&sct::Log]) -> Result<(), TLSError> {
+                   logs: &'static [&'static sct::Log<'static>])
+                   -> Result<Vec<Result<&'static sct::Log<'static>, sct::Error>>, TLSError> {
     let mut valid_scts = 0;
     let now = unix_time_millis()?;
     let mut last_sct_error = None;
+    let mut verdicts = Vec::new();
 
     for sct in scts {
         match sct::verify_sct(&cert.0, &sct.0, now, logs) {
@@ -384,6 +653,7 @@ pub fn verify_scts(cert: &Certificate,
                 debug!("Valid SCT signed by {} on {}",
                       logs[index].operated_by, logs[index].description);
                 valid_scts += 1;
+                verdicts.push(Ok(logs[index]));
             }
             Err(e) => {
                 if e.should_be_fatal() {
@@ -391,6 +661,7 @@ pub fn verify_scts(cert: &Certificate,
                 }
                 debug!("SCT ignored because {:?}", e);
                 last_sct_error = Some(e);
+                verdicts.push(Err(e));
             }
         }
     }
@@ -402,5 +673,53 @@ pub fn verify_scts(cert: &Certificate,
         return Err(TLSError::InvalidSCT(last_sct_error.unwrap()));
     }
 
-    Ok(())
+    Ok(verdicts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn describe_error_finds_expired_cert() {
+        let certs = vec![
+            Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec()),
+            Certificate(include_bytes!("testdata/cert-reddit.1.der").to_vec()),
+        ];
+        let summary = x509::summarise_cert(&certs[0].0).unwrap();
+
+        let now = at(1_600_000_000);
+        match describe_error(webpki::Error::CertExpired, certs.iter(), now) {
+            TLSError::CertNotValidAtTime { error, not_before, not_after, now: when } => {
+                assert_eq!(error, webpki::Error::CertExpired);
+                assert_eq!(not_before, summary.not_before);
+                assert_eq!(not_after, summary.not_after);
+                assert_eq!(when, now);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        match describe_error(webpki::Error::CertNotValidYet, certs.iter(), at(1_000_000_000)) {
+            TLSError::CertNotValidAtTime { error: webpki::Error::CertNotValidYet, .. } => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn describe_error_passes_through_others() {
+        let certs = vec![
+            Certificate(include_bytes!("testdata/cert-reddit.0.der").to_vec()),
+        ];
+
+        // No certificate is outside its validity period then.
+        assert_eq!(describe_error(webpki::Error::CertExpired, certs.iter(), at(1_500_000_000)),
+                   TLSError::WebPKIError(webpki::Error::CertExpired));
+        assert_eq!(describe_error(webpki::Error::UnknownIssuer, certs.iter(), at(1_600_000_000)),
+                   TLSError::WebPKIError(webpki::Error::UnknownIssuer));
+    }
 }
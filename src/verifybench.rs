@@ -4,13 +4,15 @@
 // Note: we don't use any of the standard 'cargo bench', 'test::Bencher',
 // etc. because it's unstable at the time of writing.
 
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anchors;
 use error::TLSError;
 use verify;
 use verify::ServerCertVerifier;
 use key;
+use suites;
+use msgs::enums::ProtocolVersion;
 use webpki;
 
 extern crate webpki_roots;
@@ -37,13 +39,25 @@ fn bench<Fsetup, Ftest, S>(count: usize, name: &'static str, f_setup: Fsetup, f_
              times.iter().min().unwrap() / 1000);
 }
 
-fn fixed_time() -> Result<webpki::Time, TLSError> {
-    Ok(webpki::Time::from_seconds_since_unix_epoch(1500000000))
+fn fixed_time() -> Result<SystemTime, TLSError> {
+    Ok(UNIX_EPOCH + Duration::from_secs(1500000000))
 }
 
-static V: &'static verify::WebPKIVerifier = &verify::WebPKIVerifier {
-    time: fixed_time,
-};
+fn context() -> verify::ServerCertContext<'static> {
+    verify::ServerCertContext {
+        version: ProtocolVersion::TLSv1_3,
+        suite: &suites::TLS13_AES_128_GCM_SHA256,
+        offered_alpn: &[],
+        alpn_protocol: None,
+        alternate_names: &[],
+    }
+}
+
+fn verifier() -> verify::WebPKIVerifier {
+    let mut v = verify::WebPKIVerifier::new();
+    v.time = fixed_time;
+    v
+}
 
 #[test]
 fn test_reddit_cert() {
@@ -52,12 +66,13 @@ fn test_reddit_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(reddit)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("reddit.com")
           .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -68,12 +83,13 @@ fn test_github_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(github)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("github.com")
           .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -85,12 +101,13 @@ fn test_arstechnica_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(arstechnica)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("arstechnica.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -102,12 +119,13 @@ fn test_servo_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(servo)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("servo.org")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -118,12 +136,13 @@ fn test_twitter_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(twitter)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("twitter.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap(); });
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap(); });
 }
 
 #[test]
@@ -133,12 +152,13 @@ fn test_wikipedia_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(wikipedia)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("wikipedia.org")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -150,12 +170,13 @@ fn test_google_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(google)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("www.google.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -167,12 +188,13 @@ fn test_hn_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(hn)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("news.ycombinator.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -183,12 +205,13 @@ fn test_stackoverflow_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(stackoverflow)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("stackoverflow.com")
           .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -199,12 +222,13 @@ fn test_duckduckgo_cert() {
     let chain = [ cert0, cert1 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(duckduckgo)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("duckduckgo.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -216,12 +240,13 @@ fn test_rustlang_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(rustlang)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("www.rust-lang.org")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -233,12 +258,13 @@ fn test_wapo_cert() {
     let chain = [ cert0, cert1, cert2 ];
     let mut anchors = anchors::RootCertStore::empty();
     anchors.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let v = verifier();
     bench(100, "verify_server_cert(wapo)", 
           || (),
           |_| {
         let dns_name = webpki::DNSNameRef::try_from_ascii_str("www.washingtonpost.com")
             .unwrap();
-        V.verify_server_cert(&anchors, &chain[..], dns_name, &[]).unwrap();
+        v.verify_server_cert(&anchors, &chain[..], dns_name, &[], &context()).unwrap();
     });
 }
 
@@ -1,6 +1,11 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
 use ring::der;
+use ring::error::Unspecified;
+use untrusted;
+use msgs::enums::NamedGroup;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
@@ -23,6 +28,178 @@ pub fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// The parts of a certificate which `CertifiedKey::check_chain`
+/// and `VerifierPolicy` look at.
+#[derive(Debug)]
+pub struct CertSummary<'a> {
+    pub issuer: &'a [u8],
+    pub subject: &'a [u8],
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub key: KeySummary,
+}
+
+/// The type and size of a certificate's public key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeySummary {
+    /// An RSA key with a modulus of this many bits.
+    Rsa(usize),
+
+    /// An EC key on this curve.
+    Ec(NamedGroup),
+
+    /// Any other kind of key, or an EC key on an unknown curve.
+    Other,
+}
+
+// OIDs, as the contents of their DER encoding.
+static OID_RSA_ENCRYPTION: &'static [u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+static OID_EC_PUBLIC_KEY: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+static OID_SECP256R1: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+static OID_SECP384R1: &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+static OID_SECP521R1: &'static [u8] = &[0x2b, 0x81, 0x04, 0x00, 0x23];
+
+fn digits(bytes: &[u8]) -> Result<u64, Unspecified> {
+    if bytes.is_empty() || !bytes.iter().all(|b| b.is_ascii_digit()) {
+        return Err(Unspecified);
+    }
+
+    Ok(bytes.iter().fold(0u64, |acc, &b| acc * 10 + u64::from(b - b'0')))
+}
+
+/// Days from 1970-01-01 to the given date, or None if it
+/// is earlier than that.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> Option<u64> {
+    // Count years from March, so any leap day is last.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe).checked_sub(719_468)
+}
+
+/// Parse the contents of a UTCTime or GeneralizedTime.  Only
+/// the forms allowed by RFC5280 are accepted.
+fn parse_time(tag: u8, value: &[u8]) -> Result<SystemTime, Unspecified> {
+    let (year, value) = if tag == der::Tag::UTCTime as u8 && value.len() == 13 {
+        let yy = digits(&value[..2])?;
+        (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &value[2..])
+    } else if tag == der::Tag::GeneralizedTime as u8 && value.len() == 15 {
+        (digits(&value[..4])?, &value[4..])
+    } else {
+        return Err(Unspecified);
+    };
+
+    if value[10] != b'Z' {
+        return Err(Unspecified);
+    }
+
+    let month = digits(&value[0..2])?;
+    let day = digits(&value[2..4])?;
+    let hour = digits(&value[4..6])?;
+    let minute = digits(&value[6..8])?;
+    let second = digits(&value[8..10])?;
+    if year < 1 || month < 1 || month > 12 || day < 1 || day > 31 ||
+        hour > 23 || minute > 59 || second > 59 {
+        return Err(Unspecified);
+    }
+
+    // Times before 1970 are all in the past, which is all we care about.
+    let secs = days_since_epoch(year, month, day)
+        .map(|days| days * 86_400 + hour * 3600 + minute * 60 + second)
+        .unwrap_or(0);
+
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn read_time(rd: &mut untrusted::Reader) -> Result<SystemTime, Unspecified> {
+    let (tag, value) = der::read_tag_and_get_value(rd)?;
+    parse_time(tag, value.as_slice_less_safe())
+}
+
+fn read_sequence<'a>(rd: &mut untrusted::Reader<'a>) -> Result<&'a [u8], Unspecified> {
+    der::expect_tag_and_get_value(rd, der::Tag::Sequence)
+        .map(|value| value.as_slice_less_safe())
+}
+
+/// The length in bits of the big-endian integer `n`, which has
+/// no leading zero bytes.
+fn bit_len(n: &[u8]) -> usize {
+    match n.first() {
+        Some(&top) => n.len() * 8 - top.leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+fn read_rsa_key(key: untrusted::Input) -> Result<KeySummary, Unspecified> {
+    key.read_all(Unspecified, |rd| {
+        der::nested(rd, der::Tag::Sequence, Unspecified, |rsa| {
+            let modulus = der::positive_integer(rsa)?;
+            der::positive_integer(rsa)?;
+            Ok(KeySummary::Rsa(bit_len(modulus.as_slice_less_safe())))
+        })
+    })
+}
+
+fn summarise_spki(rd: &mut untrusted::Reader) -> Result<KeySummary, Unspecified> {
+    let (oid, params) = der::nested(rd, der::Tag::Sequence, Unspecified, |alg| {
+        let oid = der::expect_tag_and_get_value(alg, der::Tag::OID)?;
+        Ok((oid.as_slice_less_safe(), alg.skip_to_end().as_slice_less_safe()))
+    })?;
+    let key = der::bit_string_with_no_unused_bits(rd)?;
+
+    if oid == OID_RSA_ENCRYPTION {
+        return read_rsa_key(key);
+    }
+
+    if oid != OID_EC_PUBLIC_KEY {
+        return Ok(KeySummary::Other);
+    }
+
+    let curve = untrusted::Input::from(params)
+        .read_all(Unspecified, |rd| der::expect_tag_and_get_value(rd, der::Tag::OID))?;
+    Ok(match curve.as_slice_less_safe() {
+        c if c == OID_SECP256R1 => KeySummary::Ec(NamedGroup::secp256r1),
+        c if c == OID_SECP384R1 => KeySummary::Ec(NamedGroup::secp384r1),
+        c if c == OID_SECP521R1 => KeySummary::Ec(NamedGroup::secp521r1),
+        _ => KeySummary::Other,
+    })
+}
+
+fn summarise_tbs<'a>(rd: &mut untrusted::Reader<'a>) -> Result<CertSummary<'a>, Unspecified> {
+    if rd.peek(der::Tag::ContextSpecificConstructed0 as u8) {
+        der::expect_tag_and_get_value(rd, der::Tag::ContextSpecificConstructed0)?;
+    }
+
+    der::expect_tag_and_get_value(rd, der::Tag::Integer)?;
+    read_sequence(rd)?;
+    let issuer = read_sequence(rd)?;
+    let (not_before, not_after) = der::nested(rd, der::Tag::Sequence, Unspecified, |validity| {
+        Ok((read_time(validity)?, read_time(validity)?))
+    })?;
+    let subject = read_sequence(rd)?;
+    let key = der::nested(rd, der::Tag::Sequence, Unspecified, summarise_spki)?;
+
+    rd.skip_to_end();
+    Ok(CertSummary { issuer, subject, not_before, not_after, key })
+}
+
+/// Extract the issuer, subject, validity period and key type from
+/// the DER-encoded certificate `cert`.  This does not check anything
+/// else about the certificate.
+pub fn summarise_cert(cert: &[u8]) -> Option<CertSummary> {
+    untrusted::Input::from(cert)
+        .read_all(Unspecified, |rd| {
+            der::nested(rd, der::Tag::Sequence, Unspecified, |cert| {
+                let tbs = der::nested(cert, der::Tag::Sequence, Unspecified, summarise_tbs)?;
+                cert.skip_to_end();
+                Ok(tbs)
+            })
+        })
+        .ok()
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
@@ -60,3 +237,67 @@ fn test_large() {
     assert_eq!(vec![0x30, 0x82, 0x12, 0x34, 0x12, 0x12],
                val[..6].to_vec());
 }
+
+#[cfg(test)]
+fn utc_time(s: &str) -> SystemTime {
+    parse_time(der::Tag::UTCTime as u8, s.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+fn since_epoch(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[test]
+fn test_parse_time() {
+    assert_eq!(since_epoch(utc_time("700101000000Z")), 0);
+    assert_eq!(since_epoch(utc_time("171227171945Z")), 1_514_395_185);
+    assert_eq!(since_epoch(utc_time("000229000000Z")), 951_782_400);
+    assert_eq!(since_epoch(utc_time("691231235959Z")), 0);
+    assert_eq!(since_epoch(parse_time(der::Tag::GeneralizedTime as u8,
+                                      b"20500101000000Z").unwrap()),
+               2_524_608_000);
+}
+
+#[test]
+fn test_parse_time_rejects_junk() {
+    let utc = der::Tag::UTCTime as u8;
+    assert!(parse_time(utc, b"1712271719Z").is_err());
+    assert!(parse_time(utc, b"17122717194xZ").is_err());
+    assert!(parse_time(utc, b"171227171945+").is_err());
+    assert!(parse_time(utc, b"171327171945Z").is_err());
+    assert!(parse_time(der::Tag::GeneralizedTime as u8, b"171227171945Z").is_err());
+}
+
+#[test]
+fn test_summarise_cert_rejects_junk() {
+    assert!(summarise_cert(&[]).is_none());
+    assert!(summarise_cert(&[0x30, 0x00]).is_none());
+}
+
+#[cfg(test)]
+fn test_cert(pem: &[u8]) -> Vec<u8> {
+    use pemfile;
+    pemfile::certs(&mut &pem[..]).unwrap().remove(0).0
+}
+
+#[test]
+fn test_summarise_keys() {
+    let rsa_end = test_cert(include_bytes!("../test-ca/rsa/end.cert"));
+    let rsa_ca = test_cert(include_bytes!("../test-ca/rsa/ca.cert"));
+    let ec_end = test_cert(include_bytes!("../test-ca/ecdsa/end.cert"));
+    let ec_ca = test_cert(include_bytes!("../test-ca/ecdsa/ca.cert"));
+
+    assert_eq!(summarise_cert(&rsa_end).unwrap().key, KeySummary::Rsa(2048));
+    assert_eq!(summarise_cert(&rsa_ca).unwrap().key, KeySummary::Rsa(8192));
+    assert_eq!(summarise_cert(&ec_end).unwrap().key, KeySummary::Ec(NamedGroup::secp256r1));
+    assert_eq!(summarise_cert(&ec_ca).unwrap().key, KeySummary::Ec(NamedGroup::secp384r1));
+}
+
+#[test]
+fn test_bit_len() {
+    assert_eq!(bit_len(&[]), 0);
+    assert_eq!(bit_len(&[0x01]), 1);
+    assert_eq!(bit_len(&[0x80, 0x00]), 16);
+    assert_eq!(bit_len(&[0x7f, 0xff, 0xff]), 23);
+}
@@ -125,5 +125,11 @@ for kt in rsa ecdsa ; do
   cat $kt/inter.cert $kt/ca.cert > $kt/client.chain
   cat $kt/client.cert $kt/inter.cert $kt/ca.cert > $kt/client.fullchain
 
+  openssl pkcs12 -export \
+            -inkey $kt/client.key \
+            -in $kt/client.fullchain \
+            -passout pass:rustls \
+            -out $kt/client.p12
+
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
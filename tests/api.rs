@@ -1,24 +1,51 @@
 // Assorted public API tests.
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic;
 use std::fs;
-use std::io::{self, Write, Read};
+use std::io::{self, Write, Read, BufRead};
+use std::time;
+use std::mem;
 
 extern crate rustls;
+extern crate sct;
 
 use rustls::{ClientConfig, ClientSession, ResolvesClientCert};
+use rustls::{InspectsClientHello, ClientHelloInfo, ExtensionType, NamedGroup};
+use rustls::DecidesSNI;
+use rustls::{ObservesTickets, ReceivedTicket};
+use rustls::{StoresClientSessions, ClientSessionKey};
 use rustls::{ServerConfig, ServerSession, ResolvesServerCert};
-use rustls::Session;
+use rustls::{ResolvesServerConfig, ClientHelloSummary};
+use rustls::ChoosesAlpnProtocol;
+use rustls::{Session, TLS12Flags};
+use rustls::{ObservesUncleanClose, UncleanClose};
+use rustls::{ObservesSessionEvents, SessionEvent, AlertDescription, AlertLevel};
+use rustls::HandshakePhase;
 use rustls::Stream;
+use rustls::Connection;
+use rustls::SessionOverrides;
+use rustls::{KeyLog, KeyLogContext};
+#[cfg(feature = "async")]
+use rustls::AsyncStream;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Wake, Waker};
 use rustls::{ProtocolVersion, SignatureScheme, CipherSuite};
 use rustls::TLSError;
 use rustls::sign;
-use rustls::{ALL_CIPHERSUITES, SupportedCipherSuite};
+use rustls::{ALL_CIPHERSUITES, SupportedCipherSuite, BulkAlgorithm, KeyExchangeAlgorithm};
+use rustls::{ContentType, HashAlgorithm, SignatureAlgorithm};
+use rustls::aead::{Tls13AeadAlgorithm, MessageEncrypter, MessageDecrypter, PlainRecord};
+use rustls::internal::msgs::codec::Codec;
 use rustls::{Certificate, PrivateKey};
 use rustls::internal::pemfile;
 use rustls::{RootCertStore, NoClientAuth, AllowAnyAuthenticatedClient};
 
 extern crate webpki;
+extern crate ring;
+#[cfg(feature = "async")]
+extern crate futures_io;
 
 fn transfer(left: &mut Session, right: &mut Session) {
     let mut buf = [0u8; 262144];
@@ -115,6 +142,9 @@ fn alpn_test(server_protos: Vec<String>, client_protos: Vec<String>, agreed: Opt
     let mut client_config = make_client_config();
     let mut server_config = make_server_config();
 
+    let offered = client_protos.iter()
+        .map(|proto| proto.as_bytes().to_vec())
+        .collect::<Vec<_>>();
     client_config.alpn_protocols = client_protos;
     server_config.alpn_protocols = server_protos;
 
@@ -123,9 +153,11 @@ fn alpn_test(server_protos: Vec<String>, client_protos: Vec<String>, agreed: Opt
 
     assert_eq!(client.get_alpn_protocol(), None);
     assert_eq!(server.get_alpn_protocol(), None);
+    assert!(server.get_offered_alpn_protocols().is_empty());
     do_handshake(&mut client, &mut server);
     assert_eq!(client.get_alpn_protocol(), agreed);
     assert_eq!(server.get_alpn_protocol(), agreed);
+    assert_eq!(server.get_offered_alpn_protocols(), &offered[..]);
 }
 
 #[test]
@@ -295,6 +327,120 @@ fn client_can_get_server_cert() {
     assert_eq!(certs, Some(get_chain()));
 }
 
+fn sct_from_unknown_log() -> Vec<u8> {
+    let mut sct = vec![0u8];
+    sct.extend_from_slice(&[0x11; 32]);
+    sct.extend_from_slice(&[0; 8]);
+    sct.extend_from_slice(&[0x00, 0x00, 0x04, 0x03, 0x00, 0x04]);
+    sct.extend_from_slice(b"sign");
+    sct
+}
+
+#[test]
+fn client_can_get_received_scts() {
+    let sct = sct_from_unknown_log();
+    let mut sct_list = vec![0, sct.len() as u8 + 2, 0, sct.len() as u8];
+    sct_list.extend_from_slice(&sct);
+
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.ct_logs = Some(&[]);
+        let mut server_config = make_server_config();
+        server_config.set_single_cert_with_ocsp_and_sct(get_chain(), get_key(),
+                                                        vec![], sct_list.clone());
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        assert!(client.get_received_scts().is_empty());
+        do_handshake(&mut client, &mut server);
+
+        let scts = client.get_received_scts();
+        assert_eq!(scts.len(), 1);
+        assert_eq!(scts[0].sct, sct);
+        assert_eq!(scts[0].verdict.as_ref().unwrap_err(), &sct::Error::UnknownLog);
+    }
+}
+
+struct FixedSCTs(Vec<u8>);
+
+impl rustls::ProvidesSCTs for FixedSCTs {
+    fn sct_list(&self, cert_chain: &[Certificate]) -> Option<Vec<u8>> {
+        assert_eq!(cert_chain, &get_chain()[..]);
+        Some(self.0.clone())
+    }
+}
+
+#[test]
+fn server_sends_scts_from_provider() {
+    let sct = sct_from_unknown_log();
+    let mut sct_list = vec![0, sct.len() as u8 + 2, 0, sct.len() as u8];
+    sct_list.extend_from_slice(&sct);
+
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.ct_logs = Some(&[]);
+        let mut server_config = make_server_config();
+        server_config.sct_provider = Some(Arc::new(FixedSCTs(sct_list.clone())));
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+
+        let scts = client.get_received_scts();
+        assert_eq!(scts.len(), 1);
+        assert_eq!(scts[0].sct, sct);
+    }
+}
+
+#[test]
+fn server_does_not_send_invalid_scts() {
+    for sct_list in &[vec![0x00, 0x05, 0x01], vec![0x00, 0x00], vec![0x00, 0x02, 0x00, 0x00]] {
+        let mut client_config = make_client_config();
+        client_config.ct_logs = Some(&[]);
+        let mut server_config = make_server_config();
+        server_config.sct_provider = Some(Arc::new(FixedSCTs(sct_list.clone())));
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        assert!(client.get_received_scts().is_empty());
+    }
+}
+
+#[test]
+fn root_store_finds_roots_by_subject() {
+    let mut roots = RootCertStore::empty();
+    for ca in &["test-ca/rsa/ca.cert", "test-ca/ecdsa/ca.cert"] {
+        let mut rootbuf = io::BufReader::new(fs::File::open(ca).unwrap());
+        roots.add_pem_file(&mut rootbuf).unwrap();
+    }
+
+    let subject = roots.roots[1].subject().to_vec();
+    let found = roots.find_by_subject(&subject);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].subject(), &subject[..]);
+    assert!(roots.find_by_subject(b"nobody").is_empty());
+
+    // Changing `roots` directly is noticed, and fixed by `reindex`.
+    let first = roots.roots[0].clone();
+    roots.roots.push(first.clone());
+    assert_eq!(roots.find_by_subject(first.subject()).len(), 2);
+    roots.reindex();
+    assert_eq!(roots.find_by_subject(first.subject()).len(), 2);
+}
+
+#[test]
+fn client_verifies_with_many_roots() {
+    let mut client_config = make_client_config();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/ecdsa/ca.cert").unwrap());
+    client_config.root_store.add_pem_file(&mut rootbuf).unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+}
+
 #[test]
 fn server_can_get_client_cert() {
     let mut client_config = make_client_config();
@@ -310,6 +456,33 @@ fn server_can_get_client_cert() {
     assert_eq!(certs, Some(get_chain()));
 }
 
+#[cfg(feature = "pkcs12")]
+#[test]
+fn client_identity_from_pkcs12() {
+    let der = fs::read("test-ca/rsa/client.p12").unwrap();
+    let (chain, key) = rustls::internal::pkcs12::identity(&der, "rustls").unwrap();
+    let expect_chain = pemfile::certs(&mut io::BufReader::new(
+            fs::File::open("test-ca/rsa/client.fullchain").unwrap())).unwrap();
+    assert_eq!(chain, expect_chain);
+
+    let mut client_config = make_client_config();
+    client_config.set_single_client_cert(chain, key);
+    let server_config = make_server_config_with_mandatory_client_auth();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_peer_certificates(), Some(expect_chain));
+}
+
+#[cfg(feature = "pkcs12")]
+#[test]
+fn pkcs12_needs_correct_password() {
+    let der = fs::read("test-ca/rsa/client.p12").unwrap();
+    assert!(rustls::internal::pkcs12::identity(&der, "wrong").is_err());
+    assert!(rustls::internal::pkcs12::identity(&der[..100], "rustls").is_err());
+}
+
 fn check_read_and_close(reader: &mut io::Read, expect: &[u8]) {
     let mut buf = Vec::new();
     buf.resize(expect.len(), 0u8);
@@ -373,472 +546,3665 @@ fn client_close_notify() {
     check_read(&mut client, b"from-server!");
 }
 
-struct ServerCheckCertResolve {
-    expected: String
-}
+#[test]
+fn hello_retry_is_reported() {
+    let mut client = client_needing_retry();
+    let mut server = ServerSession::new(&Arc::new(server_config_forcing_retry()));
+    do_handshake(&mut client, &mut server);
 
-impl ServerCheckCertResolve {
-    fn new(expect: &str) -> ServerCheckCertResolve {
-        ServerCheckCertResolve {
-            expected: expect.to_string()
-        }
-    }
+    let info = client.get_hello_retry_info().unwrap();
+    assert_eq!(info.requested_group, Some(NamedGroup::X25519));
+    assert!(!info.has_cookie);
+    assert!(!info.client_hello_hash.is_empty());
+    assert_eq!(server.get_hello_retry_info(), Some(info));
 }
 
-impl ResolvesServerCert for ServerCheckCertResolve {
-    fn resolve(&self,
-               server_name: Option<webpki::DNSNameRef>,
-               sigschemes: &[SignatureScheme])
-        -> Option<sign::CertifiedKey> {
-        if let Some(got_dns_name) = server_name {
-            let got: &str = got_dns_name.into();
-            if got != self.expected {
-                panic!("unexpected dns name (wanted '{}' got '{:?}')", &self.expected, got_dns_name);
-            }
-        } else {
-            panic!("dns name not provided (wanted '{}')", &self.expected);
-        }
+/// A client which sends only a secp384r1 key share.  With a server
+/// from `server_config_forcing_retry`, which doesn't support that
+/// group, the server must ask for another.
+fn client_needing_retry() -> ClientSession {
+    let storage = rustls::ClientSessionMemoryCache::new(32);
+    let mut client_config = make_client_config();
+    let key = ClientSessionKey::new(dns_name("localhost"), &client_config);
+    storage.set_kx_hint(&key, NamedGroup::secp384r1);
+    client_config.set_persistence(storage);
 
-        if sigschemes.len() == 0 {
-            panic!("no signature schemes shared by client");
-        }
+    ClientSession::new(&Arc::new(client_config), dns_name("localhost"))
+}
 
-        None
-    }
+fn server_config_forcing_retry() -> ServerConfig {
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![Arc::new(rustls::kx::X25519)];
+    server_config
 }
 
 #[test]
-fn server_cert_resolve_with_sni() {
-    let client_config = make_client_config();
-    let mut server_config = make_server_config();
+fn hello_retry_cookie_allows_stateless_server() {
+    let mut server_config = server_config_forcing_retry();
+    server_config.hello_retry_cookies = Some(rustls::Ticketer::new());
+    let server_config = Arc::new(server_config);
 
-    server_config.cert_resolver = Arc::new(ServerCheckCertResolve::new("the-value-from-sni"));
+    let mut client = client_needing_retry();
 
-    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("the-value-from-sni"));
-    let mut server = ServerSession::new(&Arc::new(server_config));
+    // This server sends a HelloRetryRequest, then goes away.
+    let mut first_server = ServerSession::new(&server_config);
+    transfer(&mut client, &mut first_server);
+    first_server.process_new_packets().unwrap();
+    transfer(&mut first_server, &mut client);
+    client.process_new_packets().unwrap();
+    let sent_info = first_server.get_hello_retry_info().unwrap();
+    assert!(sent_info.has_cookie);
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert_eq!(err.is_err(), true);
+    // A new one carries on with the second ClientHello.
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+
+    let info = client.get_hello_retry_info().unwrap();
+    assert_eq!(info.requested_group, Some(NamedGroup::X25519));
+    assert!(info.has_cookie);
+    assert_eq!(info, sent_info);
+    assert_eq!(server.get_hello_retry_info(), Some(info));
+
+    server.write(b"hello").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"hello");
 }
 
-struct ServerCheckNoSNI {}
+#[test]
+fn hello_retry_cookie_from_other_server_is_rejected() {
+    let mut server_config = server_config_forcing_retry();
+    server_config.hello_retry_cookies = Some(rustls::Ticketer::new());
+    let mut other_server_config = server_config.clone();
+    other_server_config.hello_retry_cookies = Some(rustls::Ticketer::new());
+
+    let mut client = client_needing_retry();
+    let mut first_server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut first_server);
+    first_server.process_new_packets().unwrap();
+    transfer(&mut first_server, &mut client);
+    client.process_new_packets().unwrap();
 
-impl ResolvesServerCert for ServerCheckNoSNI {
-    fn resolve(&self,
-               server_name: Option<webpki::DNSNameRef>,
-               _sigschemes: &[SignatureScheme])
-        -> Option<sign::CertifiedKey> {
-        assert!(server_name.is_none());
+    let mut server = ServerSession::new(&Arc::new(other_server_config));
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("client sent invalid cookie".to_string())));
+}
 
-        None
-    }
+#[test]
+fn hello_retry_is_not_reported_without_retry() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.get_hello_retry_info(), None);
+    assert_eq!(server.get_hello_retry_info(), None);
 }
 
 #[test]
-fn client_with_sni_disabled_does_not_send_sni() {
+fn client_rejects_tls13_server_hello_without_session_id_echo() {
+    // Get a TLS1.2 session, so the next ClientHello has a session_id.
+    let storage = rustls::ClientSessionMemoryCache::new(32);
     let mut client_config = make_client_config();
-    client_config.enable_sni = false;
+    client_config.set_persistence(storage.clone());
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let server_config = Arc::new(make_server_config());
 
-    let mut server_config = make_server_config();
-    server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+    let mut client = ClientSession::new(&Arc::new(client_config.clone()), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
 
-    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("value-not-sent"));
-    let mut server = ServerSession::new(&Arc::new(server_config));
+    client_config.versions = vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert_eq!(err.is_err(), true);
+    // Corrupt the session_id in the ServerHello: it follows the record
+    // and handshake headers, legacy_version, random and its length.
+    let mut buf = Vec::new();
+    server.write_tls(&mut buf).unwrap();
+    assert_eq!(buf[5 + 4 + 2 + 32], 32);
+    buf[5 + 4 + 2 + 32 + 1] ^= 0x01;
+    client.read_tls(&mut buf.as_slice()).unwrap();
+
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("server did not echo our session_id"
+                                                 .to_string())));
 }
 
 #[test]
-fn client_checks_server_certificate_with_given_name() {
-    let client_config = make_client_config();
-    let server_config = make_server_config();
-
-    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("not-the-right-hostname.com"));
-    let mut server = ServerSession::new(&Arc::new(server_config));
+fn config_reports_effective_policy() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3];
+    client_config.ciphersuites = vec![&rustls::ALL_CIPHERSUITES[0]];
+    client_config.set_protocols(&["h2".to_string()]);
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert_eq!(err,
-               Err(TLSErrorFromPeer::Client(
-                       TLSError::WebPKIError(webpki::Error::CertNotValidForName))
-                   )
-               );
-}
+    assert_eq!(client_config.get_effective_versions(), vec![ProtocolVersion::TLSv1_3]);
+    assert_eq!(client_config.get_effective_ciphersuites(), client_config.ciphersuites);
+    assert_eq!(client_config.get_alpn_protocols(), &["h2".to_string()]);
+    assert!(client_config.get_kx_groups().contains(&NamedGroup::X25519));
 
-struct ClientCheckCertResolve {
-    query_count: atomic::AtomicUsize,
-    expect_queries: usize
+    let mut server_config = make_server_config();
+    server_config.versions = vec![ProtocolVersion::TLSv1_2];
+    assert_eq!(server_config.get_effective_versions(), vec![ProtocolVersion::TLSv1_2]);
+    assert!(!server_config.get_effective_ciphersuites().is_empty());
+    assert!(server_config.get_effective_ciphersuites()
+            .iter()
+            .all(|suite| suite.usable_for_version(ProtocolVersion::TLSv1_2)));
+    assert!(server_config.get_alpn_protocols().is_empty());
+    assert_eq!(server_config.get_kx_groups(), client_config.get_kx_groups());
 }
 
-impl ClientCheckCertResolve {
-    fn new(expect_queries: usize) -> ClientCheckCertResolve {
-        ClientCheckCertResolve {
-            query_count: atomic::AtomicUsize::new(0),
-            expect_queries: expect_queries
-        }
-    }
-}
+#[test]
+fn config_summary_describes_settings() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.ciphersuites = vec![&rustls::ALL_CIPHERSUITES[0]];
+    client_config.set_protocols(&["h2".to_string(), "a,b".to_string()]);
+
+    let summary = client_config.get_summary();
+    assert!(summary.is_client);
+    assert_eq!(summary.versions, vec![ProtocolVersion::TLSv1_3]);
+    assert_eq!(summary.ciphersuites, vec![rustls::ALL_CIPHERSUITES[0].suite]);
+    assert_eq!(summary.kx_groups, client_config.get_kx_groups());
+    assert_eq!(summary.client_auth, rustls::ClientAuthMode::Disabled);
+    assert!(!summary.custom_verifier);
+    assert_eq!(summary.downgrade_protection, Some(rustls::DowngradeProtection::LogOnly));
+    assert!(summary.session_storage);
+    assert!(summary.tickets);
+    assert_eq!(summary.ticket_lifetime, None);
+    assert_eq!(summary, client_config.clone().get_summary());
+
+    let text = summary.to_string();
+    assert!(text.starts_with("side: client\nversions: TLSv1_3\nciphersuites: "));
+    assert!(text.contains("\nalpn_protocols: \"h2\",\"a,b\"\n"));
+    assert!(text.contains("\ndowngrade_protection: LogOnly\n"));
+    assert!(text.ends_with("\ntickets: true\nticket_lifetime: none\n"));
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        let count = self.query_count.load(atomic::Ordering::SeqCst);
-        assert_eq!(count, self.expect_queries);
-    }
+    client_config.set_single_client_cert(get_chain(), get_key());
+    client_config.set_persistence(Arc::new(rustls::NoClientSessionStorage {}));
+    client_config.set_server_name_verifier(Arc::new(AlternateName("alternate.example")));
+    let summary = client_config.get_summary();
+    assert_eq!(summary.client_auth, rustls::ClientAuthMode::Optional);
+    assert!(!summary.session_storage);
+    assert!(summary.custom_verifier);
+
+    let mut server_config = make_server_config_with_mandatory_client_auth();
+    server_config.ticketer = rustls::Ticketer::new();
+    server_config.ticket_lifetime = Some(3600);
+    server_config.require_ems_for_resumption = true;
+    server_config.session_storage = Arc::new(rustls::NoServerSessionStorage {});
+    let summary = server_config.get_summary();
+    assert!(!summary.is_client);
+    assert_eq!(summary.client_auth, rustls::ClientAuthMode::Mandatory);
+    assert_eq!(summary.downgrade_protection, None);
+    assert!(summary.require_ems_for_resumption);
+    assert!(!summary.session_storage);
+    assert!(!summary.config_resolver);
+    assert!(summary.tickets);
+    assert_eq!(summary.ticket_lifetime, Some(3600));
+
+    assert_eq!(make_server_config().get_summary().client_auth,
+               rustls::ClientAuthMode::Disabled);
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(&self,
-               acceptable_issuers: &[&[u8]],
-               sigschemes: &[SignatureScheme])
-        -> Option<sign::CertifiedKey> {
-        self.query_count.fetch_add(1, atomic::Ordering::SeqCst);
-
-        if acceptable_issuers.len() == 0 {
-            panic!("no issuers offered by server");
-        }
+#[test]
+fn config_warnings_report_likely_mistakes() {
+    use rustls::ConfigWarning;
 
-        if sigschemes.len() == 0 {
-            panic!("no signature schemes shared by server");
-        }
+    let mut client_config = make_client_config();
+    assert_eq!(client_config.get_warnings(), vec![]);
+    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.ciphersuites = rustls::ALL_CIPHERSUITES.iter()
+        .filter(|suite| !suite.usable_for_version(ProtocolVersion::TLSv1_3))
+        .cloned()
+        .collect();
+    assert_eq!(client_config.get_warnings(), vec![ConfigWarning::NoUsableVersions]);
 
-        None
-    }
+    let mut server_config = make_server_config();
+    assert_eq!(server_config.get_warnings(),
+               vec![ConfigWarning::Tls12WithoutEmsRequirement, ConfigWarning::NoAlpnProtocols]);
+    server_config.require_ems_for_resumption = true;
+    server_config.set_protocols(&["h2".to_string()]);
+    assert_eq!(server_config.get_warnings(), vec![]);
 
-    fn has_certs(&self) -> bool {
-        true
-    }
+    let mut client_auth_roots = RootCertStore::empty();
+    client_auth_roots.add(&get_chain()[2]).unwrap();
+    let mut server_config = ServerConfig::new(
+        rustls::AllowAnyAnonymousOrAuthenticatedClient::new(client_auth_roots));
+    server_config.versions = vec![ProtocolVersion::TLSv1_3];
+    server_config.set_protocols(&["h2".to_string()]);
+    assert_eq!(server_config.get_warnings(), vec![ConfigWarning::OptionalClientAuth]);
 }
 
 #[test]
-fn client_cert_resolve() {
+fn security_profiles_select_versions_and_suites() {
+    use rustls::SecurityProfile;
+
     let mut client_config = make_client_config();
-    let server_config = make_server_config_with_mandatory_client_auth();
+    client_config.set_security_profile(SecurityProfile::Modern);
+    assert_eq!(client_config.get_effective_versions(), vec![ProtocolVersion::TLSv1_3]);
+    assert_eq!(client_config.ciphersuites.len(), 3);
+    assert_eq!(client_config.get_kx_groups(),
+               vec![NamedGroup::X25519, NamedGroup::secp256r1, NamedGroup::secp384r1]);
 
-    client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+    let mut server_config = make_server_config();
+    server_config.set_security_profile(SecurityProfile::Intermediate);
+    assert_eq!(server_config.get_effective_versions(),
+               vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]);
+    assert_eq!(server_config.ciphersuites.len(), 9);
+
+    let mut client = ClientSession::new(&Arc::new(client_config.clone()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config.clone()));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
 
+    // A modern client can't talk to a server which only does TLS1.2.
+    server_config.versions = vec![ProtocolVersion::TLSv1_2];
     let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(server_config));
-
-    assert_eq!(
-        do_handshake_until_error(&mut client, &mut server),
-        Err(TLSErrorFromPeer::Server(TLSError::NoCertificatesPresented)));
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
 }
 
-#[test]
-fn client_error_is_sticky() {
-    let client_config = make_client_config();
-    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
-    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
-    let mut err = client.process_new_packets();
-    assert_eq!(err.is_err(), true);
-    err = client.process_new_packets();
-    assert_eq!(err.is_err(), true);
+struct RecordsSessionEvents {
+    client: Mutex<Vec<SessionEvent>>,
+    server: Mutex<Vec<SessionEvent>>,
 }
 
-#[test]
-fn server_error_is_sticky() {
-    let server_config = make_server_config();
-    let mut server = ServerSession::new(&Arc::new(server_config));
-    server.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
-    let mut err = server.process_new_packets();
-    assert_eq!(err.is_err(), true);
-    err = server.process_new_packets();
-    assert_eq!(err.is_err(), true);
-}
+impl RecordsSessionEvents {
+    fn new() -> Arc<RecordsSessionEvents> {
+        Arc::new(RecordsSessionEvents {
+            client: Mutex::new(Vec::new()),
+            server: Mutex::new(Vec::new()),
+        })
+    }
 
-#[test]
-fn server_is_send_and_sync() {
-    let server_config = make_server_config();
-    let server = ServerSession::new(&Arc::new(server_config));
-    &server as &Send;
-    &server as &Sync;
+    fn take(&self) -> (Vec<SessionEvent>, Vec<SessionEvent>) {
+        (self.client.lock().unwrap().drain(..).collect(),
+         self.server.lock().unwrap().drain(..).collect())
+    }
 }
 
-#[test]
-fn client_is_send_and_sync() {
-    let client_config = make_client_config();
-    let client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
-    &client as &Send;
-    &client as &Sync;
+impl ObservesSessionEvents for RecordsSessionEvents {
+    fn event(&self, is_client: bool, event: &SessionEvent) {
+        let events = if is_client { &self.client } else { &self.server };
+        events.lock().unwrap().push(event.clone());
+    }
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
-
-    server.set_buffer_limit(32);
+fn session_events_are_reported() {
+    let observer = RecordsSessionEvents::new();
+    let mut client_config = make_client_config();
+    client_config.event_observer = Some(observer.clone());
+    let client_config = Arc::new(client_config);
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    server_config.event_observer = Some(observer.clone());
+    let server_config = Arc::new(server_config);
 
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 12);
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
+    assert_eq!(observer.take(),
+               (vec![SessionEvent::HandshakeStarted,
+                     SessionEvent::HandshakeFinished,
+                     SessionEvent::TicketReceived,
+                     SessionEvent::AlertSent(AlertDescription::CloseNotify)],
+                vec![SessionEvent::HandshakeStarted,
+                     SessionEvent::TicketIssued,
+                     SessionEvent::HandshakeFinished,
+                     SessionEvent::AlertReceived(AlertDescription::CloseNotify)]));
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
     do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
 
-    check_read(&mut client, b"01234567890123456789012345678901");
+    let (client_events, server_events) = observer.take();
+    assert!(client_events.contains(&SessionEvent::ResumptionAccepted));
+    assert!(server_events.contains(&SessionEvent::ResumptionAccepted));
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
+fn session_events_are_queued() {
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut server = ServerSession::new(&Arc::new(server_config));
 
-    // this test will vary in behaviour depending on the default suites
     do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(48);
+    assert_eq!(client.take_events(),
+               vec![SessionEvent::HandshakeStarted,
+                    SessionEvent::HandshakeFinished,
+                    SessionEvent::TicketReceived]);
+    assert_eq!(server.take_events(),
+               vec![SessionEvent::HandshakeStarted,
+                    SessionEvent::TicketIssued,
+                    SessionEvent::HandshakeFinished]);
+    assert_eq!(client.take_events(), vec![]);
 
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(server.write(b"01234567890123456789").unwrap(), 6);
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(client.take_events(),
+               vec![SessionEvent::AlertSent(AlertDescription::CloseNotify)]);
+    assert_eq!(server.take_events(),
+               vec![SessionEvent::AlertReceived(AlertDescription::CloseNotify)]);
+}
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+#[derive(Clone, Debug, PartialEq)]
+struct KeyLogEntry {
+    label: String,
+    is_client: bool,
+    connection_id: rustls::ConnectionId,
+    peer_name: Option<String>,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
 
-    check_read(&mut client, b"01234567890123456789012345");
+struct RecordsKeyLog {
+    labels: Option<Vec<&'static str>>,
+    entries: Mutex<Vec<KeyLogEntry>>,
+}
+
+impl RecordsKeyLog {
+    fn new(labels: Option<Vec<&'static str>>) -> Arc<RecordsKeyLog> {
+        Arc::new(RecordsKeyLog { labels, entries: Mutex::new(Vec::new()) })
+    }
+
+    fn take(&self, is_client: bool) -> Vec<KeyLogEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let (ours, theirs) = entries.drain(..)
+            .partition(|entry| entry.is_client == is_client);
+        *entries = theirs;
+        ours
+    }
+}
+
+impl KeyLog for RecordsKeyLog {
+    fn will_log(&self, label: &str) -> bool {
+        self.labels.as_ref()
+            .map(|labels| labels.contains(&label))
+            .unwrap_or(true)
+    }
+
+    fn log(&self, label: &str, context: &KeyLogContext, client_random: &[u8], secret: &[u8]) {
+        self.entries.lock().unwrap().push(KeyLogEntry {
+            label: label.to_string(),
+            is_client: context.is_client,
+            connection_id: context.connection_id,
+            peer_name: context.peer_name.map(|name| name.to_string()),
+            client_random: client_random.to_vec(),
+            secret: secret.to_vec(),
+        });
+    }
+}
+
+fn key_log_handshake(version: ProtocolVersion,
+                     key_log: &Arc<RecordsKeyLog>) -> (ClientSession, ServerSession) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![version];
+    client_config.key_log = Some(key_log.clone());
+    let mut server_config = make_server_config();
+    server_config.key_log = Some(key_log.clone());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    (client, server)
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn key_log_sees_tls13_secrets() {
+    let key_log = RecordsKeyLog::new(None);
+    let (client, server) = key_log_handshake(ProtocolVersion::TLSv1_3, &key_log);
+
+    let client_entries = key_log.take(true);
+    let mut server_entries = key_log.take(false);
+    let labels = client_entries.iter()
+        .map(|entry| entry.label.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(labels, vec![rustls::key_log::CLIENT_HANDSHAKE_TRAFFIC_SECRET,
+                            rustls::key_log::SERVER_HANDSHAKE_TRAFFIC_SECRET,
+                            rustls::key_log::SERVER_TRAFFIC_SECRET_0,
+                            rustls::key_log::EXPORTER_SECRET,
+                            rustls::key_log::CLIENT_TRAFFIC_SECRET_0]);
+
+    for entry in &client_entries {
+        assert_eq!(entry.connection_id, client.get_connection_id());
+        assert_eq!(entry.peer_name, Some("localhost".to_string()));
+        assert_eq!(entry.client_random.len(), 32);
+        assert_eq!(entry.client_random, client_entries[0].client_random);
+
+        // Both ends derive the same secrets.
+        let pos = server_entries.iter()
+            .position(|theirs| theirs.label == entry.label)
+            .unwrap();
+        let theirs = server_entries.remove(pos);
+        assert_eq!(theirs.connection_id, server.get_connection_id());
+        assert_eq!(theirs.peer_name, Some("localhost".to_string()));
+        assert_eq!(theirs.client_random, entry.client_random);
+        assert_eq!(theirs.secret, entry.secret);
+    }
+    assert_eq!(server_entries, vec![]);
+}
 
-    client.set_buffer_limit(32);
+#[test]
+fn key_log_sees_tls12_master_secret() {
+    let key_log = RecordsKeyLog::new(None);
+    key_log_handshake(ProtocolVersion::TLSv1_2, &key_log);
+
+    let client_entries = key_log.take(true);
+    let server_entries = key_log.take(false);
+    assert_eq!(client_entries.len(), 1);
+    assert_eq!(server_entries.len(), 1);
+    assert_eq!(client_entries[0].label, rustls::key_log::CLIENT_RANDOM);
+    assert_eq!(client_entries[0].secret.len(), 48);
+    assert_eq!(client_entries[0].client_random, server_entries[0].client_random);
+    assert_eq!(client_entries[0].secret, server_entries[0].secret);
+}
 
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 12);
+#[test]
+fn key_log_can_choose_labels() {
+    let key_log = RecordsKeyLog::new(Some(vec![rustls::key_log::CLIENT_TRAFFIC_SECRET_0]));
+    key_log_handshake(ProtocolVersion::TLSv1_3, &key_log);
+    key_log_handshake(ProtocolVersion::TLSv1_2, &key_log);
+
+    for is_client in &[true, false] {
+        let labels = key_log.take(*is_client)
+            .into_iter()
+            .map(|entry| entry.label)
+            .collect::<Vec<String>>();
+        assert_eq!(labels, vec![rustls::key_log::CLIENT_TRAFFIC_SECRET_0.to_string()]);
+    }
+}
+
+#[test]
+fn rejected_resumption_is_reported() {
+    let observer = RecordsSessionEvents::new();
+    let mut client_config = make_client_config();
+    client_config.event_observer = Some(observer.clone());
+    let client_config = Arc::new(client_config);
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    server_config.event_observer = Some(observer.clone());
 
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config.clone()));
     do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    observer.take();
 
-    check_read(&mut server, b"01234567890123456789012345678901");
+    // A new ticketer can't decrypt the old tickets.
+    server_config.ticketer = rustls::Ticketer::new();
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    let (client_events, server_events) = observer.take();
+    assert!(client_events.contains(&SessionEvent::ResumptionRejected));
+    assert!(server_events.contains(&SessionEvent::ResumptionRejected));
 }
 
 #[test]
-fn client_respects_buffer_limit_post_handshake() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn session_store_is_shared_between_configs() {
+    let observer = RecordsSessionEvents::new();
+    let store = rustls::ClientSessionMemoryCache::new(32);
+    let make_config = |alpn: &str| {
+        let mut client_config = make_client_config();
+        client_config.alpn_protocols = vec![alpn.to_string()];
+        client_config.set_persistence(store.clone());
+        client_config.event_observer = Some(observer.clone());
+        Arc::new(client_config)
+    };
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    let server_config = Arc::new(server_config);
 
+    let mut client = ClientSession::new(&make_config("h2"), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
     do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(48);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    observer.take();
 
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
-    assert_eq!(client.write(b"01234567890123456789").unwrap(), 6);
+    // A config with other ALPN protocols doesn't use the h2 session.
+    let mut client = ClientSession::new(&make_config("http/1.1"), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    let (client_events, _) = observer.take();
+    assert!(!client_events.contains(&SessionEvent::ResumptionAccepted));
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    // But another config with the same settings does.
+    let mut client = ClientSession::new(&make_config("h2"), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    let (client_events, _) = observer.take();
+    assert!(client_events.contains(&SessionEvent::ResumptionAccepted));
+}
 
-    check_read(&mut server, b"01234567890123456789012345");
+#[test]
+fn session_key_depends_on_config() {
+    let name = dns_name("localhost");
+    let client_config = make_client_config();
+    let key = ClientSessionKey::new(name, &client_config);
+    assert_eq!(key, ClientSessionKey::new(name, &make_client_config()));
+    assert_eq!(key, ClientSessionKey::from_parts(name, key.get_config_digest()));
+
+    let mut other = make_client_config();
+    other.alpn_protocols = vec!["h2".to_string()];
+    assert_ne!(key, ClientSessionKey::new(name, &other));
+
+    let mut other = make_client_config();
+    other.root_store = RootCertStore::empty();
+    assert_ne!(key, ClientSessionKey::new(name, &other));
+
+    let mut other = make_client_config();
+    other.set_verifier_policy(rustls::VerifierPolicy {
+        min_rsa_bits: 3072,
+        ..Default::default()
+    });
+    assert_ne!(key, ClientSessionKey::new(name, &other));
+
+    // Changing the roots directly is noticed, too.
+    let mut other = make_client_config();
+    other.root_store.roots.clear();
+    assert_ne!(key, ClientSessionKey::new(name, &other));
+    other.root_store.reindex();
+    assert_eq!(ClientSessionKey::new(name, &other),
+               ClientSessionKey::new(name, &{
+                   let mut config = make_client_config();
+                   config.root_store = RootCertStore::empty();
+                   config
+               }));
 }
 
-struct OtherSession<'a> {
-    sess: &'a mut Session,
-    pub reads: usize,
-    pub writes: usize,
-    fail_ok: bool,
-    pub last_error: Option<rustls::TLSError>,
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn sessions_are_kept_apart_by_verifier() {
+    let store = rustls::ClientSessionMemoryCache::new(32);
+    let make_config = |dangerous: bool| {
+        let mut client_config = make_client_config();
+        client_config.set_persistence(store.clone());
+        if dangerous {
+            client_config.dangerous()
+                .set_certificate_verifier(Arc::new(rustls::danger::NoServerCertVerification));
+        }
+        Arc::new(client_config)
+    };
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    let server_config = Arc::new(server_config);
+
+    let mut client = ClientSession::new(&make_config(true), dns_name("other.example"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // The certificate isn't valid for other.example, so a config
+    // which verifies it must do a full handshake and fail.
+    let mut client = ClientSession::new(&make_config(false), dns_name("other.example"));
+    let mut server = ServerSession::new(&server_config);
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(
+                       TLSError::WebPKIError(webpki::Error::CertNotValidForName))));
 }
 
-impl<'a> OtherSession<'a> {
-    fn new(sess: &'a mut Session) -> OtherSession<'a> {
-        OtherSession { sess, reads: 0, writes: 0, fail_ok: false, last_error: None, }
-    }
+struct RecordsUncleanClose {
+    seen: Mutex<Vec<UncleanClose>>,
+}
 
-    fn new_fails(sess: &'a mut Session) -> OtherSession<'a> {
-        OtherSession { sess, reads: 0, writes: 0, fail_ok: true, last_error: None, }
+impl RecordsUncleanClose {
+    fn new() -> Arc<RecordsUncleanClose> {
+        Arc::new(RecordsUncleanClose { seen: Mutex::new(Vec::new()) })
     }
 }
 
-impl<'a> io::Read for OtherSession<'a> {
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
+impl ObservesUncleanClose for RecordsUncleanClose {
+    fn unclean_close(&self, info: &UncleanClose) {
+        self.seen.lock().unwrap().push(info.clone());
     }
 }
 
-impl<'a> io::Write for OtherSession<'a> {
-    fn write(&mut self, mut b: &[u8]) -> io::Result<usize> {
-        self.writes += 1;
-        let l = self.sess.read_tls(b.by_ref())?;
-        let rc = self.sess.process_new_packets();
-
-        if !self.fail_ok {
-            rc.unwrap();
-        } else if rc.is_err() {
-            self.last_error = rc.err();
-        }
+#[test]
+fn unclean_close_is_reported() {
+    let observer = RecordsUncleanClose::new();
+    let mut client_config = make_client_config();
+    client_config.unclean_close_observer = Some(observer.clone());
+    let mut server_config = make_server_config();
+    server_config.unclean_close_observer = Some(observer.clone());
 
-        Ok(l)
+    {
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(12, client.write(b"from-client!").unwrap());
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    // The server is dropped first.
+    let seen = observer.seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert!(!seen[0].is_client);
+    assert_eq!(seen[0].unsent_tls_bytes, 0);
+    assert_eq!(seen[1], UncleanClose {
+        is_client: true,
+        handshake_complete: true,
+        protocol_version: Some(ProtocolVersion::TLSv1_3),
+        unsent_tls_bytes: seen[1].unsent_tls_bytes,
+    });
+    assert!(seen[1].unsent_tls_bytes > 0);
+}
+
+#[test]
+fn clean_close_is_not_reported() {
+    let observer = RecordsUncleanClose::new();
+    let mut client_config = make_client_config();
+    client_config.unclean_close_observer = Some(observer.clone());
+    let mut server_config = make_server_config();
+    server_config.unclean_close_observer = Some(observer.clone());
+
+    {
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        client.send_close_notify();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
     }
+
+    assert!(observer.seen.lock().unwrap().is_empty());
 }
 
 #[test]
-fn client_complete_io_for_handshake() {
+fn sessions_unusable_after_close_notify() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert!(client.is_usable());
+    assert!(server.is_usable());
 
-    assert_eq!(true, client.is_handshaking());
-    let (rdlen, wrlen) = client.complete_io(&mut OtherSession::new(&mut server)).unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert_eq!(false, client.is_handshaking());
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_usable());
+    assert!(server.is_usable());
+
+    client.send_close_notify();
+    assert!(!client.is_usable());
+    assert!(server.is_usable());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(!server.is_usable());
 }
 
 #[test]
-fn client_complete_io_for_handshake_eof() {
+fn writes_refused_after_close_notify() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut input = io::Cursor::new(Vec::new());
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
 
-    assert_eq!(true, client.is_handshaking());
-    let err = client.complete_io(&mut input).unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    client.send_close_notify();
+    client.send_close_notify();
+    let err = client.write(b"more").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert!(!client.peer_has_closed());
+
+    // the read side is still open
+    server.write_all(b"still here").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"still here");
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(server.peer_has_closed());
 }
 
 #[test]
-fn client_complete_io_for_write() {
+fn complete_shutdown_exchanges_close_notify() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
-
     do_handshake(&mut client, &mut server);
 
-    client.write(b"01234567890123456789").unwrap();
-    client.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-        assert!(rdlen == 0 && wrlen > 0);
-        assert_eq!(pipe.writes, 2);
-    }
-    check_read(&mut server, b"0123456789012345678901234567890123456789");
+    server.write_all(b"goodbye").unwrap();
+    server.send_close_notify();
+    client.complete_shutdown(&mut OtherSession::new(&mut server)).unwrap();
+
+    assert!(client.peer_has_closed());
+    assert!(server.peer_has_closed());
+    check_read_and_close(&mut client, b"goodbye");
 }
 
 #[test]
-fn client_complete_io_for_read() {
+fn complete_shutdown_reports_truncation() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let mut pipe = io::Cursor::new(Vec::new());
+    let err = client.complete_shutdown(&mut pipe).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
 
+#[test]
+fn read_reports_truncation_if_configured() {
+    let mut client_config = make_client_config();
+    client_config.report_truncation = true;
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
     do_handshake(&mut client, &mut server);
 
-    server.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-        assert!(rdlen > 0 && wrlen == 0);
-        assert_eq!(pipe.reads, 1);
-    }
-    check_read(&mut client, b"01234567890123456789");
+    server.write_all(b"partial").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // The transport closes without a close_notify.
+    assert_eq!(client.read_tls(&mut io::empty()).unwrap(), 0);
+    let mut buf = [0u8; 8];
+    assert_eq!(client.read(&mut buf).unwrap(), 7);
+    assert_eq!(&buf[..7], b"partial");
+
+    let err = client.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(client.fill_buf().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
 }
 
 #[test]
-fn server_complete_io_for_handshake() {
+fn read_ignores_truncation_by_default() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
 
-    assert_eq!(true, server.is_handshaking());
-    let (rdlen, wrlen) = server.complete_io(&mut OtherSession::new(&mut client)).unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert_eq!(false, server.is_handshaking());
+    assert_eq!(client.read_tls(&mut io::empty()).unwrap(), 0);
+    let mut buf = [0u8; 8];
+    assert_eq!(client.read(&mut buf).unwrap(), 0);
 }
 
 #[test]
-fn server_complete_io_for_handshake_eof() {
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
-    let mut input = io::Cursor::new(Vec::new());
+fn sessions_unusable_after_error() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    assert!(client.process_new_packets().is_err());
+    assert!(!client.is_usable());
 
-    assert_eq!(true, server.is_handshaking());
-    let err = server.complete_io(&mut input).unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    server.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    assert!(server.process_new_packets().is_err());
+    assert!(!server.is_usable());
 }
 
 #[test]
-fn server_complete_io_for_write() {
+fn handshake_phases_are_reported_for_tls13() {
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
     let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(Some(HandshakePhase::AwaitingHello), client.get_handshake_phase());
+    assert_eq!(Some(HandshakePhase::AwaitingHello), server.get_handshake_phase());
 
-    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::AwaitingFinished), server.get_handshake_phase());
 
-    server.write(b"01234567890123456789").unwrap();
-    server.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-        assert!(rdlen == 0 && wrlen > 0);
-        assert_eq!(pipe.writes, 2);
-    }
-    check_read(&mut client, b"0123456789012345678901234567890123456789");
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::Traffic), client.get_handshake_phase());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::Traffic), server.get_handshake_phase());
+}
+
+#[test]
+fn handshake_phases_are_reported_for_tls12() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::AwaitingCertificates), server.get_handshake_phase());
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::AwaitingFinished), client.get_handshake_phase());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::Traffic), server.get_handshake_phase());
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(Some(HandshakePhase::Traffic), client.get_handshake_phase());
+}
+
+#[test]
+fn handshake_phase_is_absent_after_error() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    assert!(client.process_new_packets().is_err());
+    assert_eq!(None, client.get_handshake_phase());
+}
+
+struct ServerCheckCertResolve {
+    expected: String
+}
+
+impl ServerCheckCertResolve {
+    fn new(expect: &str) -> ServerCheckCertResolve {
+        ServerCheckCertResolve {
+            expected: expect.to_string()
+        }
+    }
+}
+
+impl ResolvesServerCert for ServerCheckCertResolve {
+    fn resolve(&self,
+               server_name: Option<webpki::DNSNameRef>,
+               sigschemes: &[SignatureScheme])
+        -> Option<sign::CertifiedKey> {
+        if let Some(got_dns_name) = server_name {
+            let got: &str = got_dns_name.into();
+            if got != self.expected {
+                panic!("unexpected dns name (wanted '{}' got '{:?}')", &self.expected, got_dns_name);
+            }
+        } else {
+            panic!("dns name not provided (wanted '{}')", &self.expected);
+        }
+
+        if sigschemes.len() == 0 {
+            panic!("no signature schemes shared by client");
+        }
+
+        None
+    }
+}
+
+#[test]
+fn server_cert_resolve_with_sni() {
+    let client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve::new("the-value-from-sni"));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("the-value-from-sni"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+struct ServerCheckNoSNI {}
+
+impl ResolvesServerCert for ServerCheckNoSNI {
+    fn resolve(&self,
+               server_name: Option<webpki::DNSNameRef>,
+               _sigschemes: &[SignatureScheme])
+        -> Option<sign::CertifiedKey> {
+        assert!(server_name.is_none());
+
+        None
+    }
+}
+
+#[test]
+fn client_with_sni_disabled_does_not_send_sni() {
+    let mut client_config = make_client_config();
+    client_config.enable_sni = false;
+
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("value-not-sent"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn client_with_sni_disabled_per_session_does_not_send_sni() {
+    let client_config = make_client_config();
+
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+
+    let mut client = ClientSession::new_with_sni(&Arc::new(client_config),
+                                                 dns_name("value-not-sent"),
+                                                 false);
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn client_with_sni_enabled_per_session_sends_sni() {
+    let mut client_config = make_client_config();
+    client_config.enable_sni = false;
+
+    let mut client = ClientSession::new_with_sni(&Arc::new(client_config),
+                                                 dns_name("localhost"),
+                                                 true);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(server.get_sni_hostname(), Some("localhost"));
+}
+
+struct SNIOnlyFor(&'static str);
+
+impl DecidesSNI for SNIOnlyFor {
+    fn should_send_sni(&self, dns_name: webpki::DNSNameRef) -> bool {
+        let name: &str = dns_name.into();
+        name == self.0
+    }
+}
+
+#[test]
+fn client_sni_decider_chooses_per_server() {
+    let mut client_config = make_client_config();
+    client_config.sni_decider = Some(Arc::new(SNIOnlyFor("localhost")));
+    let client_config = Arc::new(client_config);
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_sni_hostname(), Some("localhost"));
+
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+
+    let mut client = ClientSession::new(&client_config, dns_name("value-not-sent"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn client_checks_server_certificate_with_given_name() {
+    let client_config = make_client_config();
+    let server_config = make_server_config();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("not-the-right-hostname.com"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSErrorFromPeer::Client(
+                       TLSError::WebPKIError(webpki::Error::CertNotValidForName))
+                   )
+               );
+}
+
+struct ClientCheckCertResolve {
+    query_count: atomic::AtomicUsize,
+    expect_queries: usize
+}
+
+impl ClientCheckCertResolve {
+    fn new(expect_queries: usize) -> ClientCheckCertResolve {
+        ClientCheckCertResolve {
+            query_count: atomic::AtomicUsize::new(0),
+            expect_queries: expect_queries
+        }
+    }
+}
+
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        let count = self.query_count.load(atomic::Ordering::SeqCst);
+        assert_eq!(count, self.expect_queries);
+    }
+}
+
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(&self,
+               acceptable_issuers: &[&[u8]],
+               sigschemes: &[SignatureScheme])
+        -> Option<sign::CertifiedKey> {
+        self.query_count.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if acceptable_issuers.len() == 0 {
+            panic!("no issuers offered by server");
+        }
+
+        if sigschemes.len() == 0 {
+            panic!("no signature schemes shared by server");
+        }
+
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// Records the CA names the server sends, and declines to
+/// authenticate.
+struct RecordsAcceptableIssuers(Mutex<Vec<Vec<u8>>>);
+
+impl ResolvesClientCert for RecordsAcceptableIssuers {
+    fn resolve(&self,
+               acceptable_issuers: &[&[u8]],
+               _sigschemes: &[SignatureScheme])
+        -> Option<sign::CertifiedKey> {
+        *self.0.lock().unwrap() = acceptable_issuers.iter()
+            .map(|issuer| issuer.to_vec())
+            .collect();
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn issuers_sent_by_server(server_config: ServerConfig,
+                          version: ProtocolVersion) -> Vec<Vec<u8>> {
+    let recorder = Arc::new(RecordsAcceptableIssuers(Mutex::new(Vec::new())));
+    let mut client_config = make_client_config();
+    client_config.versions = vec![version];
+    client_config.client_auth_cert_resolver = recorder.clone();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    let _ = do_handshake_until_error(&mut client, &mut server);
+
+    let issuers = recorder.0.lock().unwrap().clone();
+    issuers
+}
+
+#[test]
+fn server_sends_configured_client_auth_ca_names() {
+    let mut roots = RootCertStore::empty();
+    roots.add(&load_cert("test-ca/ecdsa/ca.cert")).unwrap();
+    let names = roots.get_subjects();
+    let expected = names.iter()
+        .map(|name| name.0.clone())
+        .collect::<Vec<_>>();
+
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let default_issuers = issuers_sent_by_server(make_server_config_with_mandatory_client_auth(),
+                                                     *version);
+        assert!(!default_issuers.is_empty());
+        assert_ne!(default_issuers, expected);
+
+        let mut server_config = make_server_config_with_mandatory_client_auth();
+        server_config.client_auth_ca_names = Some(names.clone());
+        assert_eq!(issuers_sent_by_server(server_config, *version), expected);
+    }
+}
+
+#[test]
+fn client_cert_resolve() {
+    let mut client_config = make_client_config();
+    let server_config = make_server_config_with_mandatory_client_auth();
+
+    client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(TLSErrorFromPeer::Server(TLSError::NoCertificatesPresented)));
+}
+
+#[test]
+fn client_with_renegotiation_enabled_works_with_tls12() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.enable_renegotiation = true;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn tls12_flags_are_reported() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(client.get_tls12_flags(), None);
+    assert_eq!(server.get_tls12_flags(), None);
+    assert!(!client.uses_extended_master_secret());
+    assert!(!server.uses_secure_renegotiation());
+    do_handshake(&mut client, &mut server);
+
+    let expected = TLS12Flags {
+        extended_master_secret: true,
+        secure_renegotiation: true,
+        session_ticket: true,
+    };
+    assert_eq!(client.get_tls12_flags(), Some(expected));
+    assert_eq!(server.get_tls12_flags(), Some(expected));
+    assert!(client.uses_extended_master_secret());
+    assert!(server.uses_extended_master_secret());
+    assert!(client.uses_secure_renegotiation());
+    assert!(server.uses_secure_renegotiation());
+}
+
+#[test]
+fn tls12_flags_are_absent_for_tls13() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(client.get_tls12_flags(), None);
+    assert_eq!(server.get_tls12_flags(), None);
+    assert!(client.uses_extended_master_secret());
+    assert!(server.uses_extended_master_secret());
+    assert!(!client.uses_secure_renegotiation());
+    assert!(!server.uses_secure_renegotiation());
+}
+
+struct RecordingInspector {
+    reverse: bool,
+    seen: Mutex<Vec<(Vec<CipherSuite>, Vec<ExtensionType>)>>,
+}
+
+impl RecordingInspector {
+    fn new(reverse: bool) -> RecordingInspector {
+        RecordingInspector { reverse, seen: Mutex::new(Vec::new()) }
+    }
+}
+
+impl InspectsClientHello for RecordingInspector {
+    fn inspect(&self, hello: &ClientHelloInfo) {
+        assert!(!hello.named_groups.is_empty());
+        assert!(!hello.signature_schemes.is_empty());
+        self.seen.lock().unwrap()
+            .push((hello.cipher_suites.to_vec(), hello.extensions.to_vec()));
+    }
+
+    fn order_extensions(&self, hello: &ClientHelloInfo) -> Option<Vec<ExtensionType>> {
+        if self.reverse {
+            Some(hello.extensions.iter().rev().cloned().collect())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn client_hello_inspector_sees_hello() {
+    let inspector = Arc::new(RecordingInspector::new(false));
+    let mut client_config = make_client_config();
+    client_config.hello_inspector = Some(inspector.clone());
+    let expected_suites = client_config.ciphersuites
+        .iter()
+        .map(|scs| scs.suite)
+        .collect::<Vec<_>>();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let seen = inspector.seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert!(expected_suites.iter().all(|cs| seen[0].0.contains(cs)));
+    assert!(seen[0].1.contains(&ExtensionType::ServerName));
+    assert!(seen[0].1.contains(&ExtensionType::KeyShare));
+}
+
+#[test]
+fn client_hello_inspector_can_reorder_extensions() {
+    let inspector = Arc::new(RecordingInspector::new(true));
+    let mut client_config = make_client_config();
+    client_config.hello_inspector = Some(inspector.clone());
+    let client_config = Arc::new(client_config);
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    let server_config = Arc::new(server_config);
+
+    // The second handshake resumes, and so sends a pre_shared_key
+    // extension which must still come last.
+    for _ in 0..2 {
+        let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+    }
+
+    let seen = inspector.seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert!(seen[1].1.contains(&ExtensionType::PreSharedKey));
+}
+
+struct ClientStorageCountingTickets {
+    storage: Arc<StoresClientSessions>,
+    tickets: atomic::AtomicUsize,
+}
+
+impl ClientStorageCountingTickets {
+    fn new() -> ClientStorageCountingTickets {
+        ClientStorageCountingTickets {
+            storage: rustls::ClientSessionMemoryCache::new(32),
+            tickets: atomic::AtomicUsize::new(0),
+        }
+    }
+}
+
+impl StoresClientSessions for ClientStorageCountingTickets {
+    fn set_kx_hint(&self, key: &ClientSessionKey, group: NamedGroup) {
+        self.storage.set_kx_hint(key, group)
+    }
+
+    fn kx_hint(&self, key: &ClientSessionKey) -> Option<NamedGroup> {
+        self.storage.kx_hint(key)
+    }
+
+    fn set_tls12_session(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.storage.set_tls12_session(key, value)
+    }
+
+    fn tls12_session(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        self.storage.tls12_session(key)
+    }
+
+    fn remove_tls12_session(&self, key: &ClientSessionKey) {
+        self.storage.remove_tls12_session(key)
+    }
+
+    fn insert_tls13_ticket(&self, key: &ClientSessionKey, value: Vec<u8>) {
+        self.tickets.fetch_add(1, atomic::Ordering::SeqCst);
+        self.storage.insert_tls13_ticket(key, value)
+    }
+
+    fn take_tls13_ticket(&self, key: &ClientSessionKey) -> Option<Vec<u8>> {
+        self.storage.take_tls13_ticket(key)
+    }
+}
+
+struct RecordsTickets {
+    seen: Mutex<Vec<(String, ProtocolVersion, u32, u32)>>,
+}
+
+impl ObservesTickets for RecordsTickets {
+    fn ticket_stored(&self, ticket: &ReceivedTicket) {
+        let name: &str = ticket.server_name.into();
+        self.seen.lock().unwrap().push((name.to_string(),
+                                        ticket.version,
+                                        ticket.lifetime,
+                                        ticket.max_early_data_size));
+    }
+}
+
+#[test]
+fn client_reports_stored_tickets() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let observer = Arc::new(RecordsTickets { seen: Mutex::new(Vec::new()) });
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.ticket_observer = Some(observer.clone());
+        let mut server_config = make_server_config();
+        server_config.ticketer = rustls::Ticketer::new();
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+
+        let seen = observer.seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        let (ref name, seen_version, lifetime, max_early_data_size) = seen[0];
+        assert_eq!(name, "localhost");
+        assert_eq!(seen_version, *version);
+        assert!(lifetime > 0);
+        assert_eq!(max_early_data_size, 0);
+    }
+}
+
+fn count_tls13_tickets(server_config: ServerConfig, send_tickets: bool) -> usize {
+    let storage = Arc::new(ClientStorageCountingTickets::new());
+    let mut client_config = make_client_config();
+    client_config.set_persistence(storage.clone());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.set_send_tickets(send_tickets);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    storage.tickets.load(atomic::Ordering::SeqCst)
+}
+
+#[test]
+fn client_uses_each_tls13_ticket_once() {
+    let storage = Arc::new(ClientStorageCountingTickets::new());
+    let mut client_config = make_client_config();
+    client_config.set_persistence(storage.clone());
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    let server_config = Arc::new(server_config);
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(storage.tickets.load(atomic::Ordering::SeqCst), 1);
+
+    // This takes the first ticket, and receives a second.
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(storage.tickets.load(atomic::Ordering::SeqCst), 2);
+
+    let key = ClientSessionKey::new(dns_name("localhost"), &client_config);
+    assert!(storage.take_tls13_ticket(&key).is_some());
+    assert_eq!(storage.take_tls13_ticket(&key), None);
+}
+
+#[test]
+fn full_handshake_does_not_resave_stale_ticket() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let client_config = Arc::new(client_config);
+    let mut ticketing_config = make_server_config();
+    ticketing_config.ticketer = rustls::Ticketer::new();
+    let ticketing_config = Arc::new(ticketing_config);
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&ticketing_config);
+    do_handshake(&mut client, &mut server);
+
+    // This server can't use the ticket, so does a full handshake
+    // and gives us a session ID instead.
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    // The old ticket doesn't go with the new master secret, so
+    // offering the two together would fail.
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&ticketing_config);
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn server_sends_configured_number_of_tls13_tickets() {
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    assert_eq!(count_tls13_tickets(server_config.clone(), true), 1);
+
+    server_config.tls13_ticket_count = 3;
+    assert_eq!(count_tls13_tickets(server_config.clone(), true), 3);
+
+    server_config.tls13_ticket_count = 0;
+    assert_eq!(count_tls13_tickets(server_config, true), 0);
+}
+
+#[test]
+fn server_can_disable_tickets_per_session() {
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+    assert_eq!(count_tls13_tickets(server_config, false), 0);
+
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut server_config = make_server_config();
+    server_config.ticketer = rustls::Ticketer::new();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.set_send_tickets(false);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_tls12_flags().unwrap().session_ticket, false);
+}
+
+#[test]
+fn client_error_is_sticky() {
+    let client_config = make_client_config();
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    client.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    let mut err = client.process_new_packets();
+    assert_eq!(err.is_err(), true);
+    err = client.process_new_packets();
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn server_error_is_sticky() {
+    let server_config = make_server_config();
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    server.read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref()).unwrap();
+    let mut err = server.process_new_packets();
+    assert_eq!(err.is_err(), true);
+    err = server.process_new_packets();
+    assert_eq!(err.is_err(), true);
+}
+
+#[test]
+fn server_is_send_and_sync() {
+    let server_config = make_server_config();
+    let server = ServerSession::new(&Arc::new(server_config));
+    &server as &Send;
+    &server as &Sync;
+}
+
+#[test]
+fn client_is_send_and_sync() {
+    let client_config = make_client_config();
+    let client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    &client as &Send;
+    &client as &Sync;
+}
+
+#[test]
+fn server_respects_buffer_limit_pre_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.set_buffer_limit(32);
+
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 12);
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client, b"01234567890123456789012345678901");
+}
+
+#[test]
+fn server_respects_buffer_limit_post_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    // this test will vary in behaviour depending on the default suites
+    do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(48);
+
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(server.write(b"01234567890123456789").unwrap(), 6);
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client, b"01234567890123456789012345");
+}
+
+#[test]
+fn client_respects_buffer_limit_pre_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    client.set_buffer_limit(32);
+
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 12);
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    check_read(&mut server, b"01234567890123456789012345678901");
+}
+
+#[test]
+fn client_respects_buffer_limit_post_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(48);
+
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 20);
+    assert_eq!(client.write(b"01234567890123456789").unwrap(), 6);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    check_read(&mut server, b"01234567890123456789012345");
+}
+
+fn count_tls_records(session: &mut Session) -> usize {
+    let mut data = Vec::new();
+    while session.wants_write() {
+        session.write_tls(&mut data).unwrap();
+    }
+
+    let mut count = 0;
+    let mut offs = 0;
+    while offs < data.len() {
+        let len = ((data[offs + 3] as usize) << 8) | data[offs + 4] as usize;
+        offs += 5 + len;
+        count += 1;
+    }
+    count
+}
+
+#[test]
+fn client_coalesces_small_writes() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    client.set_write_coalescing(Some(64));
+
+    for _ in 0..4 {
+        client.write_all(b"0123456789").unwrap();
+    }
+    assert!(!client.wants_write());
+
+    client.flush().unwrap();
+    assert_eq!(count_tls_records(&mut client), 1);
+
+    for _ in 0..7 {
+        client.write_all(b"0123456789").unwrap();
+    }
+    assert_eq!(count_tls_records(&mut client), 1);
+
+    client.write_all(b"tail").unwrap();
+    client.send_close_notify();
+    assert_eq!(count_tls_records(&mut client), 2);
+}
+
+#[test]
+fn coalesced_writes_arrive_in_order() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+    client.set_write_coalescing(Some(1024));
+
+    client.write_all(b"hello ").unwrap();
+    client.write_all(b"world").unwrap();
+    client.set_write_coalescing(None);
+    client.write_all(b"!").unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello world!");
+}
+
+#[test]
+fn appdata_records_respect_configured_length() {
+    let mut client_config = make_client_config();
+    client_config.max_appdata_record_len = Some(16);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.write_all(&[0x55; 40]).unwrap();
+    assert_eq!(count_tls_records(&mut client), 3);
+
+    server.write_all(&[0x55; 40]).unwrap();
+    assert_eq!(count_tls_records(&mut server), 1);
+}
+
+struct OtherSession<'a> {
+    sess: &'a mut Session,
+    pub reads: usize,
+    pub writes: usize,
+    fail_ok: bool,
+    pub last_error: Option<rustls::TLSError>,
+}
+
+impl<'a> OtherSession<'a> {
+    fn new(sess: &'a mut Session) -> OtherSession<'a> {
+        OtherSession { sess, reads: 0, writes: 0, fail_ok: false, last_error: None, }
+    }
+
+    fn new_fails(sess: &'a mut Session) -> OtherSession<'a> {
+        OtherSession { sess, reads: 0, writes: 0, fail_ok: true, last_error: None, }
+    }
+}
+
+impl<'a> io::Read for OtherSession<'a> {
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
+    }
+}
+
+impl<'a> io::Write for OtherSession<'a> {
+    fn write(&mut self, mut b: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        let l = self.sess.read_tls(b.by_ref())?;
+        let rc = self.sess.process_new_packets();
+
+        if !self.fail_ok {
+            rc.unwrap();
+        } else if rc.is_err() {
+            self.last_error = rc.err();
+        }
+
+        Ok(l)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn client_complete_io_for_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!(true, client.is_handshaking());
+    let (rdlen, wrlen) = client.complete_io(&mut OtherSession::new(&mut server)).unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert_eq!(false, client.is_handshaking());
+}
+
+#[test]
+fn client_complete_io_for_handshake_eof() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut input = io::Cursor::new(Vec::new());
+
+    assert_eq!(true, client.is_handshaking());
+    let err = client.complete_io(&mut input).unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+#[test]
+fn client_complete_io_for_handshake_with_wrong_name() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()),
+                                        dns_name("not-the-right-hostname.com"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let err = client.complete_io(&mut OtherSession::new_fails(&mut server)).unwrap_err();
+    assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+    assert_eq!(Some(&TLSError::WebPKIError(webpki::Error::CertNotValidForName)),
+               err.get_ref().unwrap().downcast_ref::<TLSError>());
+}
+
+#[test]
+fn client_complete_io_for_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"01234567890123456789").unwrap();
+    client.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+        assert!(rdlen == 0 && wrlen > 0);
+        assert_eq!(pipe.writes, 2);
+    }
+    check_read(&mut server, b"0123456789012345678901234567890123456789");
+}
+
+#[test]
+fn client_complete_io_for_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    server.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+        assert!(rdlen > 0 && wrlen == 0);
+        assert_eq!(pipe.reads, 1);
+    }
+    check_read(&mut client, b"01234567890123456789");
+}
+
+#[test]
+fn server_complete_io_for_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    assert_eq!(true, server.is_handshaking());
+    let (rdlen, wrlen) = server.complete_io(&mut OtherSession::new(&mut client)).unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert_eq!(false, server.is_handshaking());
+}
+
+#[test]
+fn server_complete_io_for_handshake_eof() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut input = io::Cursor::new(Vec::new());
+
+    assert_eq!(true, server.is_handshaking());
+    let err = server.complete_io(&mut input).unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+#[test]
+fn server_complete_io_for_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    server.write(b"01234567890123456789").unwrap();
+    server.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+        assert!(rdlen == 0 && wrlen > 0);
+        assert_eq!(pipe.writes, 2);
+    }
+    check_read(&mut client, b"0123456789012345678901234567890123456789");
+}
+
+#[test]
+fn server_complete_io_for_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    do_handshake(&mut client, &mut server);
+
+    client.write(b"01234567890123456789").unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+        assert!(rdlen > 0 && wrlen == 0);
+        assert_eq!(pipe.reads, 1);
+    }
+    check_read(&mut server, b"01234567890123456789");
+}
+
+#[test]
+fn client_stream_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+    }
+    check_read(&mut server, b"hello");
+}
+
+#[test]
+fn client_stream_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.write(b"world").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        check_read(&mut stream, b"world");
+    }
+}
+
+#[test]
+fn server_stream_write() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let mut stream = Stream::new(&mut server, &mut pipe);
+        assert_eq!(stream.write(b"hello").unwrap(), 5);
+    }
+    check_read(&mut client, b"hello");
+}
+
+#[test]
+fn server_stream_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    client.write(b"world").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let mut stream = Stream::new(&mut server, &mut pipe);
+        check_read(&mut stream, b"world");
+    }
+}
+
+#[test]
+fn server_stream_starttls_with_buffered() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    // The application read the ClientHello along with the end
+    // of the plaintext exchange.
+    let mut buffered = Vec::new();
+    client.write_tls(&mut buffered).unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let stream = Stream::starttls_with_buffered(&mut server, &mut pipe, &buffered).unwrap();
+        assert_eq!(false, stream.sess.is_handshaking());
+    }
+    assert_eq!(false, client.is_handshaking());
+}
+
+#[test]
+fn server_accepts_already_received_bytes() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let mut sniffed = Vec::new();
+    client.write_tls(&mut sniffed).unwrap();
+
+    server.read_tls_from_buffered(&sniffed).unwrap();
+    server.process_new_packets().unwrap();
+    assert_eq!(true, server.wants_write());
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(false, server.is_handshaking());
+}
+
+#[test]
+fn server_rejects_already_received_non_tls() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let err = server.read_tls_from_buffered(b"GET / HTTP/1.1\r\n").unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn stream_starttls_rejects_non_tls_buffered_data() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut input = io::Cursor::new(Vec::new());
+
+    let err = Stream::starttls_with_buffered(&mut server, &mut input, b"250 OK\r\n").err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn client_stream_read_lines() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    server.write(b"hello\nworld\n").unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let mut stream = Stream::new(&mut client, &mut pipe);
+        let mut line = String::new();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!("hello\n", line);
+        line.clear();
+        stream.read_line(&mut line).unwrap();
+        assert_eq!("world\n", line);
+    }
+}
+
+/// A transport which always times out.
+struct TimesOut;
+
+impl io::Read for TimesOut {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::TimedOut))
+    }
+}
+
+impl io::Write for TimesOut {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::TimedOut))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_write_is_not_repeated_after_timeout() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    {
+        let mut sock = TimesOut;
+        let mut stream = Stream::new(&mut client, &mut sock);
+        assert_eq!(5, stream.write(b"hello").unwrap());
+
+        // the buffered data must be sent before more can be written
+        let err = stream.write(b"world").unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        let err = stream.read(&mut [0u8; 5]).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+    }
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+}
+
+/// A transport which accepts no data.
+struct WritesNothing;
+
+impl io::Read for WritesNothing {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl io::Write for WritesNothing {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn complete_io_fails_if_transport_writes_nothing() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let err = client.complete_io(&mut WritesNothing).unwrap_err();
+    assert_eq!(io::ErrorKind::WriteZero, err.kind());
+}
+
+#[test]
+fn complete_shutdown_fails_if_transport_writes_nothing() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let err = client.complete_shutdown(&mut WritesNothing).unwrap_err();
+    assert_eq!(io::ErrorKind::WriteZero, err.kind());
+}
+
+/// An asynchronous transport to a peer `Session`.  Reads are
+/// pending until the peer has something to send; writes are
+/// pending while `write_blocked`.
+#[cfg(feature = "async")]
+struct AsyncPipe<'a> {
+    peer: &'a mut Session,
+    write_blocked: bool,
+}
+
+#[cfg(feature = "async")]
+impl<'a> futures_io::AsyncRead for AsyncPipe<'a> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 _cx: &mut Context,
+                 mut buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.peer.wants_write() {
+            Poll::Ready(self.peer.write_tls(&mut buf))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> futures_io::AsyncWrite for AsyncPipe<'a> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  _cx: &mut Context,
+                  mut buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.write_blocked {
+            return Poll::Pending;
+        }
+
+        let len = self.peer.read_tls(&mut buf)?;
+        self.peer.process_new_packets().unwrap();
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+struct NoopWaker;
+
+#[cfg(feature = "async")]
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_stream_handshakes_and_transfers() {
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    let client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    {
+        let pipe = AsyncPipe { peer: &mut server, write_blocked: false };
+        let mut stream = AsyncStream::new(client, pipe);
+        assert!(stream.poll_handshake(&mut cx).is_ready());
+        assert!(!stream.sess.is_handshaking());
+
+        match Pin::new(&mut stream).poll_write(&mut cx, b"hello") {
+            Poll::Ready(Ok(5)) => {}
+            _ => panic!("write failed"),
+        }
+
+        let mut buf = [0u8; 5];
+        assert!(Pin::new(&mut stream).poll_read(&mut cx, &mut buf).is_pending());
+
+        stream.sock.peer.write(b"world").unwrap();
+        match Pin::new(&mut stream).poll_read(&mut cx, &mut buf) {
+            Poll::Ready(Ok(5)) => assert_eq!(b"world", &buf),
+            _ => panic!("read failed"),
+        }
+
+        check_read(&mut stream.sock.peer, b"hello");
+        assert!(Pin::new(&mut stream).poll_close(&mut cx).is_ready());
+    }
+
+    assert!(server.read(&mut [0u8; 1]).is_err());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_stream_reads_while_writes_are_blocked() {
+    use futures_io::AsyncRead;
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+
+    // The client has data to send which the transport won't take,
+    // but that mustn't stop it reading what the server sent.
+    client.write(b"hello").unwrap();
+    server.write(b"world").unwrap();
+
+    let pipe = AsyncPipe { peer: &mut server, write_blocked: true };
+    let mut stream = AsyncStream::new(client, pipe);
+    let mut buf = [0u8; 5];
+    match Pin::new(&mut stream).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(5)) => assert_eq!(b"world", &buf),
+        _ => panic!("read failed"),
+    }
+    assert!(stream.sess.wants_write());
+}
+
+/// Move TLS data from `left` to `right` through a buffer we own,
+/// presenting it in small chunks.
+fn transfer_via_slices(left: &mut Session, right: &mut Session) {
+    let mut data = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut data).unwrap();
+    }
+
+    let mut pending = Vec::new();
+    for chunk in data.chunks(7) {
+        pending.extend_from_slice(chunk);
+        let used = right.read_tls_from_slice(&pending);
+        pending.drain(..used);
+    }
+
+    assert!(pending.is_empty());
+    right.process_new_packets().unwrap();
+}
+
+#[test]
+fn handshake_via_caller_owned_buffers() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer_via_slices(&mut client, &mut server);
+            transfer_via_slices(&mut server, &mut client);
+        }
+
+        client.write_all(b"hello").unwrap();
+        transfer_via_slices(&mut client, &mut server);
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
+
+#[test]
+fn server_rejects_non_tls_slice() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(server.read_tls_from_slice(b"GET / HTTP/1.1\r\n\r\n"), 0);
+    assert!(server.process_new_packets().is_err());
+}
+
+#[test]
+fn bulk_data_is_received_intact() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        do_handshake(&mut client, &mut server);
+
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        client.write_all(&data).unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+    }
+}
+
+#[test]
+fn corrupt_application_data_is_rejected() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        do_handshake(&mut client, &mut server);
+
+        client.write_all(b"hello").unwrap();
+        let mut record = Vec::new();
+        client.write_tls(&mut record).unwrap();
+        *record.last_mut().unwrap() ^= 0x01;
+
+        server.read_tls(&mut record.as_slice()).unwrap();
+        assert_eq!(server.process_new_packets(), Err(TLSError::DecryptError));
+        assert_eq!(server.process_new_packets(), Err(TLSError::DecryptError));
+    }
+}
+
+fn server_hello_random(client: &mut ClientSession, server: &mut ServerSession) -> Vec<u8> {
+    transfer(client, server);
+    server.process_new_packets().unwrap();
+
+    let mut data = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut data).unwrap();
+    }
+
+    let mut rd = data.as_slice();
+    while !rd.is_empty() {
+        client.read_tls(&mut rd).unwrap();
+    }
+    client.process_new_packets().unwrap();
+
+    assert_eq!(data[5], 0x02);
+    data[11..43].to_vec()
+}
+
+#[test]
+fn client_reports_version_negotiation() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(client.get_downgrade_info(), None);
+    do_handshake(&mut client, &mut server);
+
+    let info = client.get_downgrade_info().unwrap();
+    assert_eq!(info.offered_max, ProtocolVersion::TLSv1_3);
+    assert_eq!(info.negotiated, ProtocolVersion::TLSv1_3);
+    assert!(info.server_supported_version.is_some());
+    assert!(!info.downgraded);
+    assert!(!info.sentinel_checked);
+    assert!(!info.sentinel_found);
+}
+
+#[test]
+fn client_reports_downgrade_to_tls12() {
+    let mut server_config = make_server_config();
+    server_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let random = server_hello_random(&mut client, &mut server);
+    assert_ne!(&random[24..], b"DOWNGRD\x01");
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.get_downgrade_info(),
+               Some(rustls::DowngradeInfo {
+                   offered_max: ProtocolVersion::TLSv1_3,
+                   negotiated: ProtocolVersion::TLSv1_2,
+                   server_supported_version: None,
+                   downgraded: true,
+                   sentinel_checked: true,
+                   sentinel_found: false,
+               }));
+}
+
+#[test]
+fn tls13_server_omits_downgrade_sentinel() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let random = server_hello_random(&mut client, &mut server);
+    assert_ne!(&random[24..], b"DOWNGRD\x01");
+    do_handshake(&mut client, &mut server);
+
+    let info = client.get_downgrade_info().unwrap();
+    assert_eq!(info.offered_max, ProtocolVersion::TLSv1_2);
+    assert!(!info.downgraded);
+    assert!(!info.sentinel_checked);
+    assert!(!info.sentinel_found);
+}
+
+#[test]
+fn final_tls13_client_can_fall_back_to_tls12() {
+    // Make our ClientHello look like one from a client which offers
+    // final TLS1.3 (0x0304) instead of our draft.
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    let versions = [0x00, 0x2b, 0x00, 0x05, 0x04, 0x7f, 0x17, 0x03, 0x03];
+    let offset = hello.windows(versions.len())
+        .position(|window| window == versions)
+        .unwrap();
+    hello[offset + 5..offset + 7].copy_from_slice(&[0x03, 0x04]);
+
+    server.read_tls(&mut hello.as_slice()).unwrap();
+    server.process_new_packets().unwrap();
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+
+    let mut data = Vec::new();
+    server.write_tls(&mut data).unwrap();
+    assert_eq!(data[5], 0x02);
+    assert_ne!(&data[35..43], b"DOWNGRD\x01");
+}
+
+/// Have a TLS1.2-only server answer a client offering TLS1.3, with
+/// the downgrade sentinel forged into its ServerHello random.  If the
+/// client carries on, it rejects the ServerKeyExchange signature,
+/// which covers the random.
+fn process_forged_downgrade(protection: rustls::DowngradeProtection)
+                            -> (ClientSession, Result<(), TLSError>) {
+    let mut client_config = make_client_config();
+    client_config.downgrade_protection = protection;
+    let mut server_config = make_server_config();
+    server_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut data = Vec::new();
+    while server.wants_write() {
+        server.write_tls(&mut data).unwrap();
+    }
+    data[35..43].copy_from_slice(b"DOWNGRD\x01");
+
+    client.read_tls(&mut data.as_slice()).unwrap();
+    let result = client.process_new_packets();
+    (client, result)
+}
+
+#[test]
+fn strict_client_rejects_downgrade_sentinel() {
+    let (client, result) = process_forged_downgrade(rustls::DowngradeProtection::Strict);
+    assert_eq!(result, Err(TLSError::DowngradeDetected));
+    assert!(client.get_downgrade_info().unwrap().sentinel_found);
+}
+
+#[test]
+fn default_client_logs_downgrade_sentinel() {
+    assert_eq!(make_client_config().downgrade_protection,
+               rustls::DowngradeProtection::LogOnly);
+    let (client, result) = process_forged_downgrade(rustls::DowngradeProtection::LogOnly);
+    assert_eq!(result, Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+    assert!(client.get_downgrade_info().unwrap().sentinel_found);
+}
+
+#[test]
+fn client_can_ignore_downgrade_sentinel() {
+    let (client, result) = process_forged_downgrade(rustls::DowngradeProtection::Disabled);
+    assert_eq!(result, Err(TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)));
+    let info = client.get_downgrade_info().unwrap();
+    assert!(info.downgraded);
+    assert!(!info.sentinel_checked);
+    assert!(!info.sentinel_found);
+}
+
+#[test]
+fn rsa_pkcs1_can_be_disabled() {
+    for (client_pkcs1, server_pkcs1) in vec![(false, true), (true, false), (false, false)] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![ProtocolVersion::TLSv1_2];
+        client_config.enable_rsa_pkcs1 = client_pkcs1;
+        let mut server_config = make_server_config();
+        server_config.enable_rsa_pkcs1 = server_pkcs1;
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    }
+}
+
+/// Send `client`'s ClientHello to `server`, replacing the RSA-PSS
+/// schemes in its signature_algorithms extension with `replacement`.
+fn send_hello_without_pss(client: &mut ClientSession,
+                          server: &mut ServerSession,
+                          replacement: &[u8]) -> Result<(), TLSError> {
+    let pss = [0x08, 0x06, 0x08, 0x05, 0x08, 0x04];
+    let mut data = Vec::new();
+    client.write_tls(&mut data).unwrap();
+    let offset = data.windows(pss.len())
+        .position(|window| window == pss)
+        .unwrap();
+    data[offset..offset + pss.len()].copy_from_slice(replacement);
+
+    server.read_tls(&mut data.as_slice()).unwrap();
+    server.process_new_packets()
+}
+
+#[test]
+fn client_rejects_rsa_pkcs1_when_disabled() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.enable_rsa_pkcs1 = false;
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    let pkcs1 = [0x06, 0x01, 0x05, 0x01, 0x04, 0x01];
+    send_hello_without_pss(&mut client, &mut server, &pkcs1).unwrap();
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::PeerMisbehavedError("peer signed kx with RSA PKCS#1".to_string())));
+}
+
+#[test]
+fn server_needs_pss_when_rsa_pkcs1_disabled() {
+    let mut server_config = make_server_config();
+    server_config.enable_rsa_pkcs1 = false;
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let ecdsa_sha1 = [0x02, 0x03, 0x02, 0x03, 0x02, 0x03];
+    assert_eq!(send_hello_without_pss(&mut client, &mut server, &ecdsa_sha1),
+               Err(TLSError::PeerIncompatibleError("no supported sig scheme".to_string())));
+}
+
+/// Pretends to sign with ML-DSA-65, when it's offered.
+struct FakeMLDSAKey;
+
+impl sign::SigningKey for FakeMLDSAKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<sign::Signer>> {
+        if offered.contains(&SignatureScheme::ML_DSA_65) {
+            Some(Box::new(FakeMLDSAKey))
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::Unknown(0)
+    }
+}
+
+impl sign::Signer for FakeMLDSAKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TLSError> {
+        Ok(message.iter().rev().cloned().collect())
+    }
+
+    fn get_scheme(&self) -> SignatureScheme {
+        SignatureScheme::ML_DSA_65
+    }
+}
+
+struct FakeMLDSAVerifier {
+    verified: atomic::AtomicUsize,
+}
+
+impl rustls::VerifiesSignatures for FakeMLDSAVerifier {
+    fn supported_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ML_DSA_65]
+    }
+
+    fn verify_signature(&self,
+                        scheme: SignatureScheme,
+                        cert: &Certificate,
+                        message: &[u8],
+                        signature: &[u8]) -> Result<(), TLSError> {
+        assert_eq!(scheme, SignatureScheme::ML_DSA_65);
+        assert_eq!(cert, &get_chain()[0]);
+        self.verified.fetch_add(1, atomic::Ordering::SeqCst);
+
+        if message.iter().rev().eq(signature.iter()) {
+            Ok(())
+        } else {
+            Err(TLSError::General("bad signature".to_string()))
+        }
+    }
+}
+
+#[test]
+fn client_verifies_signatures_with_external_scheme() {
+    let signing_key: Arc<Box<sign::SigningKey>> = Arc::new(Box::new(FakeMLDSAKey));
+    let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
+    resolver.set_default(sign::CertifiedKey::new(get_chain(), signing_key))
+        .unwrap();
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(resolver);
+
+    let verifier = Arc::new(FakeMLDSAVerifier { verified: atomic::AtomicUsize::new(0) });
+    let mut client_config = make_client_config();
+    client_config.signature_verifier = Some(verifier.clone());
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(verifier.verified.load(atomic::Ordering::SeqCst), 1);
+    assert!(server.get_client_offer().unwrap()
+            .signature_schemes.contains(&SignatureScheme::ML_DSA_65));
+}
+
+#[test]
+fn external_schemes_are_not_offered_by_default() {
+    let signing_key: Arc<Box<sign::SigningKey>> = Arc::new(Box::new(FakeMLDSAKey));
+    let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
+    resolver.set_default(sign::CertifiedKey::new(get_chain(), signing_key))
+        .unwrap();
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(resolver);
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Server(
+                       TLSError::PeerIncompatibleError("no overlapping sigschemes".to_string()))));
+}
+
+#[test]
+fn connection_ids_count_sessions_per_config() {
+    let client_config = Arc::new(make_client_config());
+    let server_config = Arc::new(make_server_config());
+
+    let mut client1 = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server1 = ServerSession::new(&server_config);
+    let client2 = ClientSession::new(&client_config, dns_name("localhost"));
+    let other_client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+
+    let id1 = client1.get_connection_id();
+    let id2 = client2.get_connection_id();
+    assert_eq!(id1.seq, 0);
+    assert_eq!(id2.seq, 1);
+    assert_ne!(id1.nonce, id2.nonce);
+    assert_eq!(other_client.get_connection_id().seq, 0);
+    assert_eq!(server1.get_connection_id().seq, 0);
+    assert_eq!(ServerSession::new(&server_config).get_connection_id().seq, 1);
+
+    do_handshake(&mut client1, &mut server1);
+    assert_eq!(client1.get_connection_id(), id1);
+    assert_eq!(format!("{}", id1), format!("{:016x}-0", id1.nonce));
+}
+
+#[test]
+fn web_presets_work_together() {
+    let mut roots = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/rsa/ca.cert").unwrap());
+    roots.add_pem_file(&mut rootbuf).unwrap();
+
+    let client_config = Arc::new(ClientConfig::for_web_client(roots));
+    let server_config = Arc::new(ServerConfig::for_web_server(get_chain(), get_key()));
+    assert_eq!(client_config.alpn_protocols, vec!["h2".to_string(), "http/1.1".to_string()]);
+    assert_eq!(client_config.buffer_limit, Some(64 * 1024));
+    assert!(server_config.ticketer.enabled());
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_alpn_protocol(), Some("h2"));
+    assert_eq!(server.get_alpn_protocol(), Some("h2"));
+
+    // The buffer limit applies from the start.
+    let data = vec![0u8; 128 * 1024];
+    assert_eq!(client.write(&data).unwrap(), 64 * 1024);
+}
+
+/// Process `inbox` with the unbuffered API using a small output
+/// buffer, moving the output to `peer_inbox`.
+fn pump_unbuffered(sess: &mut Session,
+                   inbox: &mut Vec<u8>,
+                   peer_inbox: &mut Vec<u8>,
+                   received: &mut Vec<u8>) {
+    let mut output = [0u8; 512];
+    loop {
+        let status = sess.process_unbuffered(inbox, &mut output,
+                                             &mut |plain| received.extend_from_slice(plain))
+            .unwrap();
+        inbox.drain(..status.input_used);
+        peer_inbox.extend_from_slice(&output[..status.output_written]);
+
+        if !status.wants_write {
+            break;
+        }
+    }
+}
+
+#[test]
+fn unbuffered_handshake_and_data() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+        let (mut to_client, mut to_server) = (Vec::new(), Vec::new());
+        let (mut client_got, mut server_got) = (Vec::new(), Vec::new());
+
+        while client.is_handshaking() || server.is_handshaking() {
+            pump_unbuffered(&mut client, &mut to_client, &mut to_server, &mut client_got);
+            pump_unbuffered(&mut server, &mut to_server, &mut to_client, &mut server_got);
+        }
+
+        let data = (0..40_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut output = [0u8; 1000];
+        let mut sent = 0;
+        while sent < data.len() {
+            let (used, written) = client.write_unbuffered(&data[sent..], &mut output);
+            assert!(written <= output.len());
+            to_server.extend_from_slice(&output[..written]);
+            sent += used;
+        }
+
+        pump_unbuffered(&mut server, &mut to_server, &mut to_client, &mut server_got);
+        assert!(to_server.is_empty());
+        assert_eq!(server_got, data);
+
+        let (used, written) = server.write_unbuffered(b"reply", &mut output);
+        assert_eq!(used, 5);
+        to_client.extend_from_slice(&output[..written]);
+        pump_unbuffered(&mut client, &mut to_client, &mut to_server, &mut client_got);
+        assert_eq!(client_got, b"reply");
+    }
+}
+
+#[test]
+fn unbuffered_write_waits_for_handshake() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut output = [0u8; 4096];
+    let (used, written) = client.write_unbuffered(b"early", &mut output);
+    assert_eq!(used, 0);
+    assert!(written > 0);
+}
+
+#[test]
+fn unbuffered_rejects_non_tls() {
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut input = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+    let mut output = [0u8; 512];
+    assert_eq!(server.process_unbuffered(&mut input, &mut output, &mut |_| ()),
+               Err(TLSError::CorruptMessage));
+
+    // A fatal decode_error alert is waiting to be sent.
+    let (used, written) = server.write_unbuffered(b"", &mut output);
+    assert_eq!(used, 0);
+    assert_eq!(written, 7);
+    assert_eq!(output[0], 0x15);
+    assert_eq!(&output[5..7], &[0x02, 0x32]);
+}
+
+/// The payload lengths of the TLS records in `data`.
+fn record_lengths(data: &[u8]) -> Vec<usize> {
+    let mut lengths = Vec::new();
+    let mut offs = 0;
+    while offs < data.len() {
+        let len = ((data[offs + 3] as usize) << 8) | data[offs + 4] as usize;
+        lengths.push(len);
+        offs += 5 + len;
+    }
+    lengths
+}
+
+#[test]
+fn max_fragment_size_can_change_mid_session() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let mut data = Vec::new();
+    client.set_max_fragment_size(Some(100)).unwrap();
+    client.write_all(&[0u8; 1000]).unwrap();
+    while client.wants_write() {
+        client.write_tls(&mut data).unwrap();
+    }
+    assert_eq!(record_lengths(&data).len(), 10);
+
+    data.clear();
+    client.set_max_fragment_size(None).unwrap();
+    client.write_all(&[0u8; 1000]).unwrap();
+    while client.wants_write() {
+        client.write_tls(&mut data).unwrap();
+    }
+    assert_eq!(record_lengths(&data).len(), 1);
+}
+
+#[test]
+fn max_fragment_size_is_checked() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    assert!(client.set_max_fragment_size(Some(0)).is_err());
+    assert!(client.set_max_fragment_size(Some(16385)).is_err());
+    assert!(client.set_max_fragment_size(Some(16384)).is_ok());
+}
+
+#[test]
+fn peek_leaves_plaintext_for_read() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    let mut buf = [0u8; 4];
+    assert_eq!(server.peek(&mut buf), 0);
+
+    client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert_eq!(server.peek(&mut buf), 4);
+    assert_eq!(&buf, b"GET ");
+    check_read(&mut server, b"GET / HTTP/1.1\r\n");
+}
+
+#[test]
+fn read_uninit_reads_plaintext() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    client.write_all(b"hello ").unwrap();
+    client.write_all(b"world").unwrap();
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut buf = [mem::MaybeUninit::<u8>::uninit(); 8];
+    assert_eq!(server.read_uninit(&mut buf).unwrap(), 8);
+    let got = buf.iter()
+        .map(|b| unsafe { b.assume_init() })
+        .collect::<Vec<u8>>();
+    assert_eq!(&got, b"hello wo");
+
+    assert_eq!(server.read_uninit(&mut buf).unwrap(), 3);
+    let got = buf[..3].iter()
+        .map(|b| unsafe { b.assume_init() })
+        .collect::<Vec<u8>>();
+    assert_eq!(&got, b"rld");
+
+    let err = server.read_uninit(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn read_vectored_fills_each_buffer() {
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+
+    client.write_all(b"hello ").unwrap();
+    client.write_all(b"world").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut head = [0u8; 4];
+    let mut tail = [0u8; 16];
+    let got = {
+        let mut bufs = [io::IoSliceMut::new(&mut head), io::IoSliceMut::new(&mut tail)];
+        server.read_vectored(&mut bufs).unwrap()
+    };
+    assert_eq!(got, 11);
+    assert_eq!(&head, b"hell");
+    assert_eq!(&tail[..7], b"o world");
+}
+
+fn handshake_with_verifier_policy(policy: rustls::VerifierPolicy) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    client_config.set_verifier_policy(policy);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets()
+}
+
+#[test]
+fn default_verifier_policy_accepts_test_chain() {
+    assert_eq!(handshake_with_verifier_policy(rustls::VerifierPolicy::default()), Ok(()));
+}
+
+#[test]
+fn verifier_policy_rejects_small_rsa_keys() {
+    let mut policy = rustls::VerifierPolicy::default();
+    policy.min_rsa_bits = 3072;
+    assert_eq!(handshake_with_verifier_policy(policy),
+               Err(TLSError::PeerIncompatibleError("certificate 0 has a 2048-bit RSA key"
+                                                   .to_string())));
+}
+
+#[test]
+fn verifier_policy_rejects_disallowed_hashes() {
+    let mut policy = rustls::VerifierPolicy::default();
+    policy.allowed_hashes = vec![rustls::HashAlgorithm::SHA384];
+    match handshake_with_verifier_policy(policy) {
+        Err(TLSError::WebPKIError(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+/// Accepts one extra name for any certificate.
+struct AlternateName(&'static str);
+
+impl rustls::VerifiesServerName for AlternateName {
+    fn verify_server_name(&self,
+                          _cert: &Certificate,
+                          dns_name: webpki::DNSNameRef,
+                          webpki_result: Result<(), webpki::Error>) -> Result<(), TLSError> {
+        let name: &str = dns_name.into();
+        if name == self.0 {
+            return Ok(());
+        }
+
+        webpki_result.map_err(TLSError::WebPKIError)
+    }
+}
+
+fn handshake_with_name_verifier(name: &'static str) -> Result<(), TLSError> {
+    let mut client_config = make_client_config();
+    client_config.set_server_name_verifier(Arc::new(AlternateName("alternate.example")));
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name(name));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets()
+}
+
+#[test]
+fn server_name_verifier_can_accept_other_names() {
+    assert_eq!(handshake_with_name_verifier("alternate.example"), Ok(()));
+    assert_eq!(handshake_with_name_verifier("localhost"), Ok(()));
+    assert_eq!(handshake_with_name_verifier("other.example"),
+               Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+}
+
+fn handshake_with_alternate_names(alternates: &[&'static str]) -> (Result<(), TLSError>, Option<String>) {
+    let client_config = Arc::new(make_client_config());
+    let alternates = alternates.iter()
+        .map(|name| dns_name(name))
+        .collect::<Vec<_>>();
+    let mut client = ClientSession::new_with_alternate_names(&client_config,
+                                                             dns_name("other.example"),
+                                                             &alternates);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    let sni = server.get_sni_hostname().map(|s| s.to_string());
+    (client.process_new_packets(), sni)
+}
+
+#[test]
+fn client_accepts_cert_for_alternate_name() {
+    let (result, sni) = handshake_with_alternate_names(&["alternate.example", "localhost"]);
+    assert_eq!(result, Ok(()));
+    assert_eq!(sni, Some("other.example".to_string()));
+
+    let (result, _) = handshake_with_alternate_names(&["alternate.example"]);
+    assert_eq!(result, Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)));
+}
+
+#[test]
+fn alternate_name_sessions_are_not_resumed_by_plain_hostname() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config();
+        server_config.ticketer = rustls::Ticketer::new();
+        let server_config = Arc::new(server_config);
+
+        let mut client = ClientSession::new_with_alternate_names(&client_config,
+                                                                 dns_name("other.example"),
+                                                                 &[dns_name("localhost")]);
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+
+        // The certificate isn't valid for other.example, so this must
+        // do a full handshake and fail, not resume the session above.
+        let mut client = ClientSession::new(&client_config, dns_name("other.example"));
+        let mut server = ServerSession::new(&server_config);
+        assert_eq!(do_handshake_until_error(&mut client, &mut server),
+                   Err(TLSErrorFromPeer::Client(
+                           TLSError::WebPKIError(webpki::Error::CertNotValidForName))));
+
+        // A session accepting the same alternate name does resume it.
+        let mut client = ClientSession::new_with_alternate_names(&client_config,
+                                                                 dns_name("other.example"),
+                                                                 &[dns_name("localhost")]);
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client.take_events().contains(&SessionEvent::ResumptionAccepted));
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn no_server_cert_verification_accepts_any_name() {
+    let mut client_config = make_client_config();
+    client_config.root_store = RootCertStore::empty();
+    client_config.dangerous()
+        .set_certificate_verifier(Arc::new(rustls::danger::NoServerCertVerification));
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config());
+
+    let mut client = ClientSession::new(&client_config, dns_name("not-the-server.example"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+}
+
+#[cfg(feature = "dangerous_configuration")]
+struct RecordsContext(Mutex<Option<(ProtocolVersion, CipherSuite, Vec<String>, Option<String>)>>);
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for RecordsContext {
+    fn verify_server_cert(&self,
+                          _roots: &RootCertStore,
+                          _presented_certs: &[Certificate],
+                          _dns_name: webpki::DNSNameRef,
+                          _ocsp_response: &[u8],
+                          context: &rustls::ServerCertContext) -> Result<rustls::ServerCertVerified, TLSError> {
+        *self.0.lock().unwrap() = Some((context.version,
+                                        context.suite.suite,
+                                        context.offered_alpn.to_vec(),
+                                        context.alpn_protocol.map(|s| s.to_string())));
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn server_cert_verifier_sees_handshake_context() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let verifier = Arc::new(RecordsContext(Mutex::new(None)));
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.set_protocols(&["foo".to_string(), "bar".to_string()]);
+        client_config.dangerous().set_certificate_verifier(verifier.clone());
+        let mut server_config = make_server_config();
+        server_config.set_protocols(&["bar".to_string()]);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+
+        let (seen_version, seen_suite, offered, selected) = verifier.0.lock().unwrap().take().unwrap();
+        assert_eq!(seen_version, *version);
+        assert_eq!(seen_suite, client.get_negotiated_ciphersuite().unwrap().suite);
+        assert_eq!(offered, vec!["foo".to_string(), "bar".to_string()]);
+        assert_eq!(selected, Some("bar".to_string()));
+    }
+}
+
+/// Defers every decision, recording the names it was asked about.
+#[cfg(feature = "dangerous_configuration")]
+struct DefersVerification(Mutex<Vec<String>>);
+
+#[cfg(feature = "dangerous_configuration")]
+impl rustls::ServerCertVerifier for DefersVerification {
+    fn verify_server_cert(&self,
+                          _roots: &RootCertStore,
+                          _presented_certs: &[Certificate],
+                          _dns_name: webpki::DNSNameRef,
+                          _ocsp_response: &[u8],
+                          _context: &rustls::ServerCertContext) -> Result<rustls::ServerCertVerified, TLSError> {
+        unreachable!()
+    }
+
+    fn verify_server_cert_or_defer(&self,
+                                   _roots: &RootCertStore,
+                                   _presented_certs: &[Certificate],
+                                   dns_name: webpki::DNSNameRef,
+                                   _ocsp_response: &[u8],
+                                   context: &rustls::ServerCertContext)
+                                   -> Result<Option<rustls::ServerCertVerified>, TLSError> {
+        let mut names = self.0.lock().unwrap();
+        let name: &str = dns_name.into();
+        names.push(name.to_string());
+        for alternate in context.alternate_names {
+            let name: &str = alternate.as_ref().into();
+            names.push(name.to_string());
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+fn start_deferred_handshake(version: ProtocolVersion) -> (ClientSession, ServerSession) {
+    start_deferred_handshake_with(version, Arc::new(DefersVerification(Mutex::new(Vec::new()))), &[])
+}
+
+#[cfg(feature = "dangerous_configuration")]
+fn start_deferred_handshake_with(version: ProtocolVersion,
+                                 verifier: Arc<DefersVerification>,
+                                 alternate_names: &[webpki::DNSNameRef])
+                                 -> (ClientSession, ServerSession) {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![version];
+    client_config.dangerous().set_certificate_verifier(verifier);
+
+    let mut client = ClientSession::new_with_alternate_names(&Arc::new(client_config),
+                                                             dns_name("localhost"),
+                                                             alternate_names);
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    (client, server)
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn server_cert_verification_can_be_deferred() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let (mut client, mut server) = start_deferred_handshake(*version);
+        assert!(client.is_awaiting_server_cert_verdict());
+        assert!(client.is_handshaking());
+
+        // nothing happens until we have a verdict.
+        client.process_new_packets().unwrap();
+        assert!(client.is_awaiting_server_cert_verdict());
+
+        client.set_server_cert_verdict(Ok(rustls::ServerCertVerified::assertion()));
+        assert!(!client.is_awaiting_server_cert_verdict());
+        client.process_new_packets().unwrap();
+        do_handshake(&mut client, &mut server);
+        assert!(!client.is_handshaking());
+        assert_eq!(client.get_protocol_version(), Some(*version));
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn deferred_server_cert_verification_sees_alternate_names() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let verifier = Arc::new(DefersVerification(Mutex::new(Vec::new())));
+        let (client, _) = start_deferred_handshake_with(*version,
+                                                        verifier.clone(),
+                                                        &[dns_name("alternate.example")]);
+        assert!(client.is_awaiting_server_cert_verdict());
+        assert_eq!(*verifier.0.lock().unwrap(),
+                   vec!["localhost".to_string(), "alternate.example".to_string()]);
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn deferred_server_cert_verification_can_fail() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let (mut client, _) = start_deferred_handshake(*version);
+        assert!(client.is_awaiting_server_cert_verdict());
+
+        client.set_server_cert_verdict(Err(TLSError::General("revoked".to_string())));
+        assert_eq!(client.process_new_packets(),
+                   Err(TLSError::General("revoked".to_string())));
+        assert!(client.wants_write());
+    }
+}
+
+struct ConfigPerName(Arc<ServerConfig>);
+
+impl ResolvesServerConfig for ConfigPerName {
+    fn resolve(&self, hello: &ClientHelloSummary) -> Option<Arc<ServerConfig>> {
+        assert_eq!(hello.alpn_protocols, vec!["foo".to_string(), "bar".to_string()]);
+        assert!(!hello.signature_schemes.is_empty());
+        assert!(!hello.cipher_suites.is_empty());
+
+        let name: Option<&str> = hello.server_name.map(|name| name.into());
+        if name == Some("localhost") {
+            Some(self.0.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn server_config_can_be_chosen_per_client_hello() {
+    let mut vhost_config = make_server_config();
+    vhost_config.versions = vec![ProtocolVersion::TLSv1_2];
+    vhost_config.set_protocols(&["bar".to_string()]);
+
+    let mut server_config = make_server_config();
+    server_config.config_resolver = Some(Arc::new(ConfigPerName(Arc::new(vhost_config))));
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config();
+    client_config.set_protocols(&["foo".to_string(), "bar".to_string()]);
+    let client_config = Arc::new(client_config);
+
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    assert_eq!(server.get_alpn_protocol(), Some("bar"));
+
+    let mut client = ClientSession::new_with_sni(&client_config, dns_name("localhost"), false);
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(server.get_alpn_protocol(), None);
+}
+
+struct AlpnPerName;
+
+impl ChoosesAlpnProtocol for AlpnPerName {
+    fn choose(&self,
+              server_name: Option<webpki::DNSNameRef>,
+              offered: &[String]) -> Result<Option<String>, TLSError> {
+        let name: Option<&str> = server_name.map(|name| name.into());
+        match name {
+            Some("localhost") => Ok(offered.last().cloned()),
+            Some("reject.example") => Err(TLSError::General("no protocol for you".to_string())),
+            Some("bogus.example") => Ok(Some("bogus".to_string())),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn handshake_with_alpn_chooser(name: &'static str) -> (ClientSession, ServerSession, Result<(), TLSError>) {
+    let mut server_config = make_server_config();
+    server_config.set_protocols(&["ignored".to_string()]);
+    server_config.alpn_chooser = Some(Arc::new(AlpnPerName));
+
+    let mut client_config = make_client_config();
+    client_config.set_protocols(&["foo".to_string(), "bar".to_string(), "ignored".to_string()]);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name(name));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    transfer(&mut client, &mut server);
+    let result = server.process_new_packets();
+    (client, server, result)
+}
+
+#[test]
+fn server_alpn_chooser_selects_per_name() {
+    let (mut client, mut server, result) = handshake_with_alpn_chooser("localhost");
+    assert_eq!(result, Ok(()));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_alpn_protocol(), Some("ignored"));
+    assert_eq!(client.get_alpn_protocol(), Some("ignored"));
+
+    let (_, server, result) = handshake_with_alpn_chooser("other.example");
+    assert_eq!(result, Ok(()));
+    assert_eq!(server.get_alpn_protocol(), None);
+}
+
+#[test]
+fn server_alpn_chooser_can_reject() {
+    let (mut client, mut server, result) = handshake_with_alpn_chooser("reject.example");
+    assert_eq!(result, Err(TLSError::General("no protocol for you".to_string())));
+    transfer(&mut server, &mut client);
+    assert_eq!(client.process_new_packets(),
+               Err(TLSError::AlertReceived(AlertDescription::NoApplicationProtocol)));
+
+    let (_, _, result) = handshake_with_alpn_chooser("bogus.example");
+    assert!(result.is_err());
+}
+
+struct CountingKxGroup(atomic::AtomicUsize);
+
+impl rustls::kx::SupportedKxGroup for CountingKxGroup {
+    fn name(&self) -> NamedGroup {
+        NamedGroup::secp384r1
+    }
+
+    fn start(&self) -> Option<Box<rustls::kx::ActiveKeyExchange>> {
+        self.0.fetch_add(1, atomic::Ordering::SeqCst);
+        rustls::kx::SECP384R1.start()
+    }
+}
+
+#[test]
+fn custom_kx_groups_are_used() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let client_group = Arc::new(CountingKxGroup(atomic::AtomicUsize::new(0)));
+        let server_group = Arc::new(CountingKxGroup(atomic::AtomicUsize::new(0)));
+
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.kx_groups = vec![client_group.clone()];
+        let mut server_config = make_server_config();
+        server_config.kx_groups = vec![Arc::new(rustls::kx::X25519), server_group.clone()];
+        assert_eq!(server_config.get_kx_groups(),
+                   vec![NamedGroup::X25519, NamedGroup::secp384r1]);
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(client_group.0.load(atomic::Ordering::SeqCst), 1);
+        assert_eq!(server_group.0.load(atomic::Ordering::SeqCst), 1);
+    }
+}
+
+#[test]
+fn client_can_send_several_key_shares() {
+    for &(count, expect_retry) in &[(1, true), (2, true), (3, false), (10, false)] {
+        let mut client_config = make_client_config();
+        client_config.key_share_count = count;
+        let mut server_config = make_server_config();
+        server_config.kx_groups = vec![Arc::new(rustls::kx::SECP256R1)];
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(client.get_hello_retry_info().is_some(), expect_retry);
+    }
+}
+
+#[test]
+fn kx_hint_for_removed_group_is_ignored() {
+    let mut client_config = make_client_config();
+    client_config.key_share_count = 2;
+    client_config.kx_groups = vec![Arc::new(rustls::kx::X25519), Arc::new(rustls::kx::SECP256R1)];
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![Arc::new(rustls::kx::SECP256R1)];
+
+    // An earlier config offered secp384r1, and the server chose it.
+    let key = ClientSessionKey::new(dns_name("localhost"), &client_config);
+    client_config.session_persistence.set_kx_hint(&key, NamedGroup::secp384r1);
+
+    // The hint mustn't take the place of a group we can offer.
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert!(client.get_hello_retry_info().is_none());
+}
+
+#[test]
+fn no_common_kx_group_fails() {
+    let mut client_config = make_client_config();
+    client_config.kx_groups = vec![Arc::new(rustls::kx::SECP256R1)];
+    let mut server_config = make_server_config();
+    server_config.kx_groups = vec![Arc::new(rustls::kx::X25519)];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut server);
+    assert_eq!(server.process_new_packets(),
+               Err(TLSError::PeerIncompatibleError("no kx group overlap with client".to_string())));
+}
+
+struct TestRecordCipher {
+    sealing: ring::aead::SealingKey,
+    opening: ring::aead::OpeningKey,
+    iv: Vec<u8>,
+}
+
+impl TestRecordCipher {
+    fn new(key: &[u8], iv: &[u8]) -> TestRecordCipher {
+        TestRecordCipher {
+            sealing: ring::aead::SealingKey::new(&ring::aead::CHACHA20_POLY1305, key).unwrap(),
+            opening: ring::aead::OpeningKey::new(&ring::aead::CHACHA20_POLY1305, key).unwrap(),
+            iv: iv.to_vec(),
+        }
+    }
+
+    fn nonce(&self, seq: u64) -> Vec<u8> {
+        let mut nonce = self.iv.clone();
+        for i in 0..8 {
+            nonce[4 + i] ^= (seq >> (56 - 8 * i)) as u8;
+        }
+        nonce
+    }
+}
+
+impl MessageEncrypter for TestRecordCipher {
+    fn encrypt_in_place(&self,
+                        typ: ContentType,
+                        _version: ProtocolVersion,
+                        buf: &mut [u8],
+                        plain_len: usize,
+                        seq: u64) -> Result<(ContentType, ProtocolVersion), TLSError> {
+        buf[plain_len] = typ.get_u8();
+        ring::aead::seal_in_place(&self.sealing, &self.nonce(seq), &[], buf, 16)
+            .map_err(|_| TLSError::General("encrypt failed".to_string()))?;
+        Ok((ContentType::ApplicationData, ProtocolVersion::TLSv1_2))
+    }
+
+    fn encrypted_len(&self, plain_len: usize) -> usize {
+        plain_len + 1 + 16
+    }
+}
+
+impl MessageDecrypter for TestRecordCipher {
+    fn decrypt_in_place(&self,
+                        _typ: ContentType,
+                        _version: ProtocolVersion,
+                        buf: &mut [u8],
+                        seq: u64) -> Result<PlainRecord, TLSError> {
+        let plain_len = ring::aead::open_in_place(&self.opening, &self.nonce(seq), &[], 0, buf)
+            .map_err(|_| TLSError::DecryptError)?
+            .len();
+        let len = buf[..plain_len].iter().rposition(|&b| b != 0)
+            .ok_or(TLSError::DecryptError)?;
+        let typ = ContentType::read_bytes(&buf[len..len + 1]).unwrap();
+        Ok(PlainRecord { typ, version: ProtocolVersion::TLSv1_3, len })
+    }
+}
+
+struct TestAead {
+    uses: atomic::AtomicUsize,
+}
+
+impl Tls13AeadAlgorithm for TestAead {
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter> {
+        self.uses.fetch_add(1, atomic::Ordering::SeqCst);
+        Box::new(TestRecordCipher::new(key, iv))
+    }
+
+    fn encrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageEncrypter> {
+        self.uses.fetch_add(1, atomic::Ordering::SeqCst);
+        Box::new(TestRecordCipher::new(key, iv))
+    }
+}
+
+static TEST_AEAD: TestAead = TestAead { uses: atomic::AtomicUsize::new(0) };
+
+static TEST_SUITE: SupportedCipherSuite = SupportedCipherSuite {
+    suite: CipherSuite::Unknown(0xfe13),
+    kx: KeyExchangeAlgorithm::BulkOnly,
+    bulk: BulkAlgorithm::Tls13Custom(&TEST_AEAD),
+    hash: HashAlgorithm::SHA256,
+    sign: SignatureAlgorithm::Anonymous,
+    enc_key_len: 32,
+    fixed_iv_len: 12,
+    explicit_nonce_len: 0,
+};
+
+#[test]
+fn custom_tls13_cipher_suite_is_used() {
+    let mut client_config = make_client_config();
+    client_config.ciphersuites = vec![&TEST_SUITE];
+    let mut server_config = make_server_config();
+    server_config.ciphersuites = vec![&TEST_SUITE];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(Some(ProtocolVersion::TLSv1_3), client.get_protocol_version());
+    assert_eq!(CipherSuite::Unknown(0xfe13),
+               client.get_negotiated_ciphersuite().unwrap().suite);
+    assert!(TEST_AEAD.uses.load(atomic::Ordering::SeqCst) >= 4);
+
+    client.write_all(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, b"hello");
+
+    server.write_all(b"world").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"world");
+}
+
+struct LimitedAead;
+
+impl Tls13AeadAlgorithm for LimitedAead {
+    fn decrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageDecrypter> {
+        Box::new(TestRecordCipher::new(key, iv))
+    }
+
+    fn encrypter(&self, key: &[u8], iv: &[u8]) -> Box<MessageEncrypter> {
+        Box::new(TestRecordCipher::new(key, iv))
+    }
+
+    fn confidentiality_limit(&self) -> u64 {
+        4
+    }
+}
+
+static LIMITED_AEAD: LimitedAead = LimitedAead;
+
+static LIMITED_SUITE: SupportedCipherSuite = SupportedCipherSuite {
+    suite: CipherSuite::Unknown(0xfe14),
+    kx: KeyExchangeAlgorithm::BulkOnly,
+    bulk: BulkAlgorithm::Tls13Custom(&LIMITED_AEAD),
+    hash: HashAlgorithm::SHA256,
+    sign: SignatureAlgorithm::Anonymous,
+    enc_key_len: 32,
+    fixed_iv_len: 12,
+    explicit_nonce_len: 0,
+};
+
+#[test]
+fn keys_are_updated_before_confidentiality_limit() {
+    let mut client_config = make_client_config();
+    client_config.ciphersuites = vec![&LIMITED_SUITE];
+    let mut server_config = make_server_config();
+    server_config.ciphersuites = vec![&LIMITED_SUITE];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    client.take_events();
+    server.take_events();
+
+    // Each write is a record: three fit under each key.
+    let mut expected = Vec::new();
+    for i in 0..10u8 {
+        let data = [i; 10];
+        client.write_all(&data).unwrap();
+        expected.extend_from_slice(&data);
+    }
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server, &expected);
+
+    let key_updates = |events: Vec<SessionEvent>, event: SessionEvent| {
+        events.into_iter().filter(|ev| *ev == event).count()
+    };
+    assert_eq!(key_updates(client.take_events(), SessionEvent::KeyUpdateSent), 3);
+    assert_eq!(key_updates(server.take_events(), SessionEvent::KeyUpdateReceived), 3);
+
+    // The session carries on in both directions.
+    server.write_all(b"still here").unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client, b"still here");
+    assert!(client.is_usable());
+}
+
+#[test]
+fn custom_tls13_cipher_suite_is_not_used_for_tls12() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.ciphersuites = vec![&TEST_SUITE];
+    let mut server_config = make_server_config();
+    server_config.ciphersuites = vec![&TEST_SUITE];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn server_retains_client_offer() {
+    let client_config = Arc::new(make_client_config());
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    assert_eq!(None, server.get_client_offer());
+
+    do_handshake(&mut client, &mut server);
+
+    let offer = server.get_client_offer().unwrap();
+    assert_eq!(ProtocolVersion::TLSv1_2, offer.client_version);
+    for scs in &client_config.ciphersuites {
+        assert!(offer.cipher_suites.contains(&scs.suite));
+    }
+    assert!(offer.extensions.contains(&ExtensionType::ServerName));
+    assert!(offer.extensions.contains(&ExtensionType::SupportedVersions));
+    assert!(offer.supported_versions.contains(&ProtocolVersion::TLSv1_2));
+    assert!(offer.signature_schemes.contains(&SignatureScheme::RSA_PSS_SHA256));
+    assert!(offer.named_groups.contains(&NamedGroup::X25519));
+    assert!(offer.ja4().starts_with("t13d"));
+    assert!(offer.ja3_string().starts_with("771,"));
+}
+
+#[test]
+fn server_retains_client_offer_after_failed_handshake() {
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let mut server_config = make_server_config();
+    server_config.versions = vec![ProtocolVersion::TLSv1_3];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    transfer(&mut client, &mut server);
+    assert!(server.process_new_packets().is_err());
+
+    let offer = server.get_client_offer().unwrap();
+    assert_eq!(vec![ProtocolVersion::TLSv1_2], offer.supported_versions);
+}
+
+fn load_cert(filename: &str) -> Certificate {
+    pemfile::certs(&mut io::BufReader::new(fs::File::open(filename).unwrap()))
+        .unwrap()
+        .remove(0)
 }
 
 #[test]
-fn server_complete_io_for_read() {
+fn client_completes_incomplete_chain_with_extra_intermediates() {
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config.set_single_cert(vec![load_cert("test-ca/rsa/end.cert")], get_key());
+    let server_config = Arc::new(server_config);
+
     let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut server = ServerSession::new(&server_config);
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err, Err(TLSErrorFromPeer::Client(TLSError::WebPKIError(webpki::Error::UnknownIssuer))));
 
+    let mut client_config = make_client_config();
+    client_config.set_extra_intermediates(vec![load_cert("test-ca/rsa/inter.cert")]);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
     do_handshake(&mut client, &mut server);
+}
 
-    client.write(b"01234567890123456789").unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-        assert!(rdlen > 0 && wrlen == 0);
-        assert_eq!(pipe.reads, 1);
+/// A TLS1.2 ClientHello offering to resume `session_id`, without
+/// the extended master secret extension.  Our client always offers
+/// EMS, so this is built by hand.
+fn tls12_hello_without_ems(session_id: &[u8]) -> Vec<u8> {
+    use rustls::internal::msgs::handshake::{ClientHelloPayload, ClientExtension};
+    use rustls::internal::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+    use rustls::internal::msgs::handshake::{Random, SessionID};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+    use rustls::internal::msgs::enums::{Compression, ECPointFormat, HandshakeType};
+
+    let hello = ClientHelloPayload {
+        client_version: ProtocolVersion::TLSv1_2,
+        random: Random::from_slice(&[0x11; 32]),
+        session_id: SessionID::new(session_id),
+        cipher_suites: vec![CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256],
+        compression_methods: vec![Compression::Null],
+        extensions: vec![
+            ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+            ClientExtension::ECPointFormats(vec![ECPointFormat::Uncompressed]),
+            ClientExtension::SignatureAlgorithms(vec![SignatureScheme::RSA_PSS_SHA256]),
+        ],
+    };
+
+    Message {
+        typ: ContentType::Handshake,
+        version: ProtocolVersion::TLSv1_0,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
+            payload: HandshakePayload::ClientHello(hello),
+        }),
+    }.get_encoding()
+}
+
+/// Send a ClientHello without EMS, resuming a stored session which
+/// was made with EMS if `stored_with_ems`.
+fn resume_without_ems(server_config: ServerConfig, stored_with_ems: bool) -> Vec<SessionEvent> {
+    use rustls::internal::msgs::handshake::SessionID;
+    use rustls::internal::msgs::persist::ServerSessionValue;
+
+    let session_id = [0x42u8; 32];
+    let mut value = ServerSessionValue::new(None,
+                                            ProtocolVersion::TLSv1_2,
+                                            CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                                            vec![0x33; 48],
+                                            &None);
+    if stored_with_ems {
+        value.set_extended_ms_used();
     }
-    check_read(&mut server, b"01234567890123456789");
+    server_config.session_storage.put(SessionID::new(&session_id).get_encoding(),
+                                      value.get_encoding());
+
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    let hello = tls12_hello_without_ems(&session_id);
+    server.read_tls(&mut &hello[..]).unwrap();
+    server.process_new_packets().unwrap();
+    server.take_events()
 }
 
 #[test]
-fn client_stream_write() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn server_resumes_non_ems_session_by_default() {
+    let events = resume_without_ems(make_server_config(), false);
+    assert!(events.contains(&SessionEvent::ResumptionAccepted));
+}
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let mut stream = Stream::new(&mut client, &mut pipe);
-        assert_eq!(stream.write(b"hello").unwrap(), 5);
-    }
-    check_read(&mut server, b"hello");
+#[test]
+fn server_can_require_ems_for_resumption() {
+    let mut server_config = make_server_config();
+    assert!(!server_config.require_ems_for_resumption);
+    server_config.require_ems_for_resumption = true;
+
+    let events = resume_without_ems(server_config, false);
+    assert!(events.contains(&SessionEvent::ResumptionRejected));
+    assert!(!events.contains(&SessionEvent::ResumptionAccepted));
 }
 
 #[test]
-fn client_stream_read() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn server_requiring_ems_rejects_resumption_without_it() {
+    let mut server_config = make_server_config();
+    server_config.require_ems_for_resumption = true;
 
-    server.write(b"world").unwrap();
+    // The session was made with EMS, but this handshake doesn't use it.
+    let events = resume_without_ems(server_config, true);
+    assert!(events.contains(&SessionEvent::ResumptionRejected));
+    assert!(!events.contains(&SessionEvent::ResumptionAccepted));
+}
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let mut stream = Stream::new(&mut client, &mut pipe);
-        check_read(&mut stream, b"world");
+#[test]
+fn connection_enum_dispatches() {
+    let client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let server = ServerSession::new(&Arc::new(make_server_config()));
+    let mut client = Connection::from(client);
+    let mut server = Connection::from(server);
+    assert!(client.is_client());
+    assert!(server.as_server().is_some());
+    assert!(server.as_client().is_none());
+
+    while client.is_handshaking() || server.is_handshaking() {
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
     }
+
+    client.write_all(b"hello").unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(client.get_protocol_version(), server.get_protocol_version());
 }
 
 #[test]
-fn server_stream_write() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn session_overrides_shadow_config() {
+    let client_config = Arc::new(make_client_config());
+    let mut server_config = make_server_config();
+    server_config.alpn_protocols = vec!["foo".to_string(), "bar".to_string()];
+    let server_config = Arc::new(server_config);
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let mut stream = Stream::new(&mut server, &mut pipe);
-        assert_eq!(stream.write(b"hello").unwrap(), 5);
-    }
-    check_read(&mut client, b"hello");
+    let overrides = SessionOverrides {
+        alpn_protocols: Some(vec!["bar".to_string()]),
+        max_version: Some(ProtocolVersion::TLSv1_2),
+        ..SessionOverrides::default()
+    };
+    let mut client = ClientSession::new_with_overrides(&client_config, dns_name("localhost"),
+                                                       overrides);
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_alpn_protocol(), Some("bar"));
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+
+    let overrides = SessionOverrides {
+        alpn_protocols: Some(vec![]),
+        max_version: Some(ProtocolVersion::TLSv1_2),
+        ..SessionOverrides::default()
+    };
+    let mut alpn_client_config = make_client_config();
+    alpn_client_config.alpn_protocols = vec!["foo".to_string()];
+    let mut client = ClientSession::new(&Arc::new(alpn_client_config), dns_name("localhost"));
+    let mut server = ServerSession::new_with_overrides(&server_config, overrides);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.get_alpn_protocol(), None);
+    assert_eq!(server.get_protocol_version(), Some(ProtocolVersion::TLSv1_2));
+
+    // The config itself is untouched.
+    let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+    let mut server = ServerSession::new(&server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.get_protocol_version(), Some(ProtocolVersion::TLSv1_3));
 }
 
 #[test]
-fn server_stream_read() {
-    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
-    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+fn session_overrides_can_disable_resumption() {
+    let no_resumption = SessionOverrides {
+        disable_resumption: true,
+        ..SessionOverrides::default()
+    };
 
-    client.write(b"world").unwrap();
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config();
+        server_config.ticketer = rustls::Ticketer::new();
+        let server_config = Arc::new(server_config);
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let mut stream = Stream::new(&mut server, &mut pipe);
-        check_read(&mut stream, b"world");
+        // Nothing is stored by a client which disables resumption...
+        let mut client = ClientSession::new_with_overrides(&client_config, dns_name("localhost"),
+                                                           no_resumption.clone());
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+
+        let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+        let mut server = ServerSession::new(&server_config);
+        do_handshake(&mut client, &mut server);
+        let events = client.take_events();
+        assert!(!events.contains(&SessionEvent::ResumptionAccepted));
+        assert!(!events.contains(&SessionEvent::ResumptionRejected));
+
+        // ...but that last session was, and a server which disables
+        // resumption won't resume it.
+        let mut client = ClientSession::new(&client_config, dns_name("localhost"));
+        let mut server = ServerSession::new_with_overrides(&server_config, no_resumption.clone());
+        do_handshake(&mut client, &mut server);
+        assert!(server.take_events().contains(&SessionEvent::ResumptionRejected));
+        assert_eq!(server.get_tls12_flags().map(|flags| flags.session_ticket),
+                   if *version == ProtocolVersion::TLSv1_2 { Some(false) } else { None });
     }
 }
 
@@ -922,6 +4288,40 @@ fn sni_resolver_works() {
                        TLSError::General("no server certificate chain resolved".into()))));
 }
 
+#[test]
+fn sni_resolver_uses_default() {
+    let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
+    let signing_key = sign::RSASigningKey::new(&get_key())
+        .unwrap();
+    let signing_key: Arc<Box<sign::SigningKey>> = Arc::new(Box::new(signing_key));
+    resolver.set_default(sign::CertifiedKey::new(get_chain(), signing_key.clone()))
+        .unwrap();
+
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut client_config = make_client_config();
+    client_config.enable_sni = false;
+    let mut server = ServerSession::new(&server_config);
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err, Ok(()));
+
+    // The server uses the default for an unknown name, which the
+    // client then rejects.
+    let mut server = ServerSession::new(&server_config);
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("notlocalhost"));
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err,
+               Err(TLSErrorFromPeer::Client(
+                       TLSError::WebPKIError(webpki::Error::CertNotValidForName))));
+
+    let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
+    assert_eq!(Err(TLSError::General("No end-entity certificate in certificate chain".into())),
+               resolver.set_default(sign::CertifiedKey::new(vec![], signing_key.clone())));
+}
+
 #[test]
 fn sni_resolver_rejects_wrong_names() {
     let mut resolver = rustls::ResolvesServerCertUsingSNI::new();
@@ -957,6 +4357,108 @@ fn sni_resolver_rejects_bad_certs() {
                             sign::CertifiedKey::new(bad_chain, signing_key.clone())));
 }
 
+/// Only has a certificate for clients which don't send SNI.
+struct ResolvesOnlyWithoutName;
+
+impl ResolvesServerCert for ResolvesOnlyWithoutName {
+    fn resolve(&self,
+               server_name: Option<webpki::DNSNameRef>,
+               _sigschemes: &[SignatureScheme])
+        -> Option<sign::CertifiedKey> {
+        if server_name.is_some() {
+            return None;
+        }
+
+        let signing_key = sign::RSASigningKey::new(&get_key())
+            .unwrap();
+        let signing_key: Arc<Box<sign::SigningKey>> = Arc::new(Box::new(signing_key));
+        Some(sign::CertifiedKey::new(get_chain(), signing_key))
+    }
+}
+
+fn handshake_with_unknown_name(alert: Option<AlertLevel>,
+                               version: ProtocolVersion)
+                               -> (Result<(), TLSErrorFromPeer>, Vec<SessionEvent>) {
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ResolvesOnlyWithoutName);
+    server_config.unrecognized_name_alert = alert;
+    let mut client_config = make_client_config();
+    client_config.versions = vec![version];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    let result = do_handshake_until_error(&mut client, &mut server);
+    (result, server.take_events())
+}
+
+#[test]
+fn server_denies_access_for_unknown_name_by_default() {
+    let (result, events) = handshake_with_unknown_name(None, ProtocolVersion::TLSv1_2);
+    assert_eq!(result,
+               Err(TLSErrorFromPeer::Server(
+                       TLSError::General("no server certificate chain resolved".into()))));
+    assert!(events.contains(&SessionEvent::AlertSent(AlertDescription::AccessDenied)));
+}
+
+#[test]
+fn server_sends_fatal_unrecognized_name() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let (result, events) = handshake_with_unknown_name(Some(AlertLevel::Fatal), *version);
+        assert!(result.is_err());
+        assert!(events.contains(&SessionEvent::AlertSent(AlertDescription::UnrecognisedName)));
+    }
+}
+
+#[test]
+fn server_sends_warning_unrecognized_name_and_continues() {
+    let (result, events) = handshake_with_unknown_name(Some(AlertLevel::Warning),
+                                                       ProtocolVersion::TLSv1_2);
+    assert_eq!(result, Ok(()));
+    assert!(events.contains(&SessionEvent::AlertSent(AlertDescription::UnrecognisedName)));
+
+    // TLS1.3 has no warning alerts.
+    let (result, events) = handshake_with_unknown_name(Some(AlertLevel::Warning),
+                                                       ProtocolVersion::TLSv1_3);
+    assert_eq!(result, Ok(()));
+    assert!(!events.contains(&SessionEvent::AlertSent(AlertDescription::UnrecognisedName)));
+}
+
+#[test]
+fn client_records_unrecognized_name_warning() {
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ResolvesOnlyWithoutName);
+    server_config.unrecognized_name_alert = Some(AlertLevel::Warning);
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert!(!client.server_warned_unrecognized_name());
+    do_handshake(&mut client, &mut server);
+    assert!(client.server_warned_unrecognized_name());
+
+    let mut client = ClientSession::new(&Arc::new(make_client_config()), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(make_server_config()));
+    do_handshake(&mut client, &mut server);
+    assert!(!client.server_warned_unrecognized_name());
+}
+
+#[test]
+fn client_can_treat_warning_alerts_as_fatal() {
+    let mut server_config = make_server_config();
+    server_config.cert_resolver = Arc::new(ResolvesOnlyWithoutName);
+    server_config.unrecognized_name_alert = Some(AlertLevel::Warning);
+    let mut client_config = make_client_config();
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    client_config.fatal_warning_alerts = true;
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert_eq!(do_handshake_until_error(&mut client, &mut server),
+               Err(TLSErrorFromPeer::Client(
+                       TLSError::AlertReceived(AlertDescription::UnrecognisedName))));
+}
+
 fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
     let mut client_secret = [0u8; 64];
     let mut server_secret = [0u8; 64];
@@ -1067,6 +4569,33 @@ static TEST_CIPHERSUITES: [(ProtocolVersion, CipherSuite); 9] = [
     (ProtocolVersion::TLSv1_2, CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256)
 ];
 
+#[test]
+fn data_survives_every_suite() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, suite) = *item;
+        let scs = find_suite(suite);
+        if format!("{:?}", scs.sign) == "ECDSA" {
+            continue;
+        }
+
+        let mut client_config = make_client_config();
+        client_config.ciphersuites = vec![scs];
+        client_config.versions = vec![version];
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(make_server_config()));
+        do_handshake(&mut client, &mut server);
+
+        let data = (0..20_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        client.write_all(&data).unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let mut got = Vec::new();
+        server.read_to_end(&mut got).unwrap();
+        assert_eq!(got, data);
+    }
+}
+
 #[test]
 fn negotiated_ciphersuite_default() {
     do_suite_test(make_client_config(),
@@ -1121,3 +4650,230 @@ fn negotiated_ciphersuite_server() {
                       version);
     }
 }
+
+#[cfg(feature = "custom_extensions")]
+struct EchoExtension {
+    reply: bool,
+}
+
+#[cfg(feature = "custom_extensions")]
+impl rustls::CustomExtension for EchoExtension {
+    fn extension_type(&self) -> u16 {
+        0xff42
+    }
+
+    fn encode(&self, peer: Option<&[u8]>) -> Option<Vec<u8>> {
+        match peer {
+            None => Some(b"ping".to_vec()),
+            Some(_) if self.reply => Some(b"pong".to_vec()),
+            Some(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "custom_extensions")]
+#[test]
+fn custom_extensions_are_exchanged() {
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        for reply in &[true, false] {
+            let mut client_config = make_client_config();
+            client_config.versions = vec![*version];
+            client_config.add_custom_extension(Arc::new(EchoExtension { reply: false })).unwrap();
+            let mut server_config = make_server_config();
+            server_config.add_custom_extension(Arc::new(EchoExtension { reply: *reply })).unwrap();
+
+            let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+            let mut server = ServerSession::new(&Arc::new(server_config));
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(server.get_received_custom_extension(0xff42), Some(&b"ping"[..]));
+            if *reply {
+                assert_eq!(client.get_received_custom_extension(0xff42), Some(&b"pong"[..]));
+            } else {
+                assert_eq!(client.get_received_custom_extension(0xff42), None);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "custom_extensions")]
+#[test]
+fn custom_extensions_not_sent_unsolicited() {
+    let client_config = make_client_config();
+    let mut server_config = make_server_config();
+    server_config.add_custom_extension(Arc::new(EchoExtension { reply: true })).unwrap();
+
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(server.get_received_custom_extension(0xff42), None);
+}
+
+#[cfg(feature = "custom_extensions")]
+struct SilentExtension(u16);
+
+#[cfg(feature = "custom_extensions")]
+impl rustls::CustomExtension for SilentExtension {
+    fn extension_type(&self) -> u16 {
+        self.0
+    }
+
+    fn encode(&self, _peer: Option<&[u8]>) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[cfg(feature = "custom_extensions")]
+#[test]
+fn custom_extensions_reject_duplicate_and_built_in_types() {
+    let mut client_config = make_client_config();
+    let mut server_config = make_server_config();
+
+    for &typ in &[0x0000, 0x0010, 0x002b] {
+        assert!(client_config.add_custom_extension(Arc::new(SilentExtension(typ))).is_err());
+        assert!(server_config.add_custom_extension(Arc::new(SilentExtension(typ))).is_err());
+    }
+
+    client_config.add_custom_extension(Arc::new(EchoExtension { reply: false })).unwrap();
+    server_config.add_custom_extension(Arc::new(EchoExtension { reply: true })).unwrap();
+    assert!(client_config.add_custom_extension(Arc::new(SilentExtension(0xff42))).is_err());
+    assert!(server_config.add_custom_extension(Arc::new(SilentExtension(0xff42))).is_err());
+    client_config.add_custom_extension(Arc::new(SilentExtension(0xff43))).unwrap();
+    server_config.add_custom_extension(Arc::new(SilentExtension(0xff43))).unwrap();
+}
+
+fn check_chain(ck: &mut sign::CertifiedKey, secs: u64, warn_secs: u64, fix_order: bool)
+               -> Vec<sign::CertChainWarning> {
+    let mut warnings = Vec::new();
+    ck.check_chain(time::UNIX_EPOCH + time::Duration::from_secs(secs),
+                   time::Duration::from_secs(warn_secs),
+                   fix_order,
+                   &mut |w| warnings.push(w));
+    warnings
+}
+
+fn make_certified_key(chain: Vec<Certificate>) -> sign::CertifiedKey {
+    let signing_key = sign::RSASigningKey::new(&get_key())
+        .unwrap();
+    sign::CertifiedKey::new(chain, Arc::new(Box::new(signing_key)))
+}
+
+#[test]
+fn check_chain_accepts_good_chain() {
+    let mut ck = make_certified_key(get_chain());
+    assert_eq!(check_chain(&mut ck, 1_600_000_000, 86_400, true), vec![]);
+    assert_eq!(ck.cert, get_chain());
+}
+
+#[test]
+fn check_chain_reorders_chain() {
+    let mut chain = get_chain();
+    chain.swap(1, 2);
+
+    let mut ck = make_certified_key(chain.clone());
+    assert_eq!(check_chain(&mut ck, 1_600_000_000, 86_400, false),
+               vec![sign::CertChainWarning::Misordered,
+                    sign::CertChainWarning::IssuerMissing(0),
+                    sign::CertChainWarning::IssuerMissing(1)]);
+    assert_eq!(ck.cert, chain);
+
+    assert_eq!(check_chain(&mut ck, 1_600_000_000, 86_400, true),
+               vec![sign::CertChainWarning::Reordered]);
+    assert_eq!(ck.cert, get_chain());
+}
+
+#[test]
+fn check_chain_reports_missing_issuer() {
+    let mut chain = get_chain();
+    chain.remove(1);
+
+    let mut ck = make_certified_key(chain);
+    assert_eq!(check_chain(&mut ck, 1_600_000_000, 86_400, true),
+               vec![sign::CertChainWarning::IssuerMissing(0)]);
+}
+
+#[test]
+fn check_chain_reports_validity() {
+    let mut ck = make_certified_key(get_chain());
+    assert_eq!(check_chain(&mut ck, 0, 86_400, true),
+               vec![sign::CertChainWarning::NotYetValid(0),
+                    sign::CertChainWarning::NotYetValid(1),
+                    sign::CertChainWarning::NotYetValid(2)]);
+    assert_eq!(check_chain(&mut ck, 2_000_000_000, 86_400, true),
+               vec![sign::CertChainWarning::Expired(0),
+                    sign::CertChainWarning::Expired(1),
+                    sign::CertChainWarning::Expired(2)]);
+
+    let warnings = check_chain(&mut ck, 1_600_000_000, 10 * 365 * 86_400, true);
+    assert_eq!(warnings.len(), 1);
+    match warnings[0] {
+        sign::CertChainWarning::ExpiresSoon(left) => assert!(left.as_secs() > 86_400),
+        ref other => panic!("unexpected warning {:?}", other),
+    };
+}
+
+#[test]
+fn check_chain_reports_junk() {
+    let mut chain = get_chain();
+    chain.push(Certificate(b"junk".to_vec()));
+
+    let mut ck = make_certified_key(chain);
+    assert_eq!(check_chain(&mut ck, 1_600_000_000, 86_400, true),
+               vec![sign::CertChainWarning::Unparseable(3)]);
+}
+
+#[cfg(feature = "message_capture")]
+struct CaptureAll {
+    messages: Mutex<Vec<(bool, rustls::MessageDirection, rustls::ContentType, Vec<u8>)>>,
+}
+
+#[cfg(feature = "message_capture")]
+impl rustls::CapturesMessages for CaptureAll {
+    fn message(&self, is_client: bool, msg: &rustls::CapturedMessage) {
+        self.messages.lock()
+            .unwrap()
+            .push((is_client, msg.direction, msg.typ, msg.payload.to_vec()));
+    }
+}
+
+#[cfg(feature = "message_capture")]
+#[test]
+fn message_capture_sees_plaintext() {
+    use rustls::{MessageDirection, ContentType};
+
+    for version in &[ProtocolVersion::TLSv1_2, ProtocolVersion::TLSv1_3] {
+        let capture = Arc::new(CaptureAll { messages: Mutex::new(Vec::new()) });
+        let mut client_config = make_client_config();
+        client_config.versions = vec![*version];
+        client_config.set_message_capture(capture.clone());
+        let mut server_config = make_server_config();
+        server_config.set_message_capture(capture.clone());
+
+        let mut client = ClientSession::new(&Arc::new(client_config), dns_name("localhost"));
+        let mut server = ServerSession::new(&Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        client.write_all(b"hello").unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let messages = capture.messages.lock().unwrap();
+
+        // The ClientHello is first, and is seen identically by both ends.
+        let (is_client, dir, typ, ref hello) = messages[0];
+        assert_eq!((is_client, dir, typ),
+                   (true, MessageDirection::Sent, ContentType::Handshake));
+        assert_eq!(hello[0], 0x01);
+        assert!(messages.iter()
+                .any(|m| *m == (false, MessageDirection::Received,
+                                ContentType::Handshake, hello.clone())));
+
+        // Application data is seen before encryption and after decryption.
+        for &(is_client, dir) in &[(true, MessageDirection::Sent),
+                                   (false, MessageDirection::Received)] {
+            assert!(messages.iter()
+                    .any(|m| *m == (is_client, dir,
+                                    ContentType::ApplicationData, b"hello".to_vec())));
+        }
+    }
+}
@@ -38,7 +38,7 @@ mod online {
         polite();
         connect("expired.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\(CertExpired\)")
+            .expect(r"TLS error: CertNotValidAtTime \{ error: CertExpired")
             .go()
             .unwrap();
     }
@@ -143,7 +143,7 @@ mod online {
         polite();
         connect("sha1-2016.badssl.com")
             .fails()
-            .expect(r"TLS error: WebPKIError\(CertExpired\)")
+            .expect(r"TLS error: CertNotValidAtTime \{ error: CertExpired")
             .go()
             .unwrap();
     }
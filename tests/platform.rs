@@ -0,0 +1,83 @@
+// Tests for the platform hooks.  These change process-wide state,
+// so they live in their own test binary.
+
+#![cfg(feature = "dangerous_configuration")]
+
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+extern crate rustls;
+use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use rustls::{Session, NoClientAuth, TLSError};
+use rustls::internal::pemfile;
+use rustls::platform::{self, ProvidesRandomness, ProvidesTime};
+
+extern crate ring;
+use ring::rand::{SecureRandom, SystemRandom};
+
+extern crate webpki;
+
+struct CountingRandom {
+    calls: AtomicUsize,
+}
+
+impl ProvidesRandomness for CountingRandom {
+    fn fill(&self, bytes: &mut [u8]) {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        SystemRandom::new().fill(bytes).unwrap();
+    }
+}
+
+struct UnknownTime;
+
+impl ProvidesTime for UnknownTime {
+    fn unix_time(&self) -> Option<Duration> {
+        None
+    }
+}
+
+fn transfer(left: &mut Session, right: &mut Session) {
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        right.read_tls(&mut rd).unwrap();
+    }
+}
+
+#[test]
+fn platform_providers_are_used() {
+    let random = Arc::new(CountingRandom { calls: AtomicUsize::new(0) });
+    platform::set_randomness_provider(random.clone());
+    platform::set_time_provider(Arc::new(UnknownTime));
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    let chain = pemfile::certs(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.fullchain").unwrap()))
+        .unwrap();
+    let key = pemfile::rsa_private_keys(&mut io::BufReader::new(fs::File::open("test-ca/rsa/end.rsa").unwrap()))
+        .unwrap()[0]
+        .clone();
+    server_config.set_single_cert(chain, key);
+
+    let mut client_config = ClientConfig::new();
+    let mut rootbuf = io::BufReader::new(fs::File::open("test-ca/rsa/ca.cert").unwrap());
+    client_config.root_store.add_pem_file(&mut rootbuf).unwrap();
+
+    let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), name);
+    let mut server = ServerSession::new(&Arc::new(server_config));
+    assert!(random.calls.load(Ordering::SeqCst) > 0);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+
+    // The client can't check the server's certificate without the time.
+    assert_eq!(Err(TLSError::FailedToGetCurrentTime), client.process_new_packets());
+}